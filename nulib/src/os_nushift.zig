@@ -15,6 +15,9 @@ pub const Syscall = enum(usize) {
     shm_release = 4,
     shm_destroy = 5,
     shm_release_and_destroy = 6,
+    shm_acquire_with_flags = 30,
+    shm_protect = 31,
+    shm_resize = 32,
 
     accessibility_tree_new = 7,
     accessibility_tree_publish_ron = 8,
@@ -35,6 +38,17 @@ pub const Syscall = enum(usize) {
     gfx_destroy = 19,
 
     debug_print = 20,
+
+    isa_get_extensions = 22,
+
+    thread_spawn = 23,
+    thread_exit = 24,
+    thread_join = 25,
+
+    channel_new = 26,
+    channel_send = 27,
+    channel_recv = 28,
+    channel_destroy = 29,
 };
 
 pub fn SyscallArgs(comptime sys: Syscall) type {
@@ -45,6 +59,9 @@ pub fn SyscallArgs(comptime sys: Syscall) type {
         .shm_acquire => struct { shm_cap_id: usize, address: usize },
         .shm_new_and_acquire => struct { shm_type: ShmType, length: usize, address: usize },
         .shm_release, .shm_destroy, .shm_release_and_destroy => struct { shm_cap_id: usize },
+        .shm_acquire_with_flags => struct { shm_cap_id: usize, address: usize, flags: ShmFlags },
+        .shm_protect => struct { shm_cap_id: usize, flags: ShmFlags },
+        .shm_resize => struct { shm_cap_id: usize, length: usize },
 
         .accessibility_tree_new => struct {},
         .accessibility_tree_publish_ron => struct { accessibility_tree_cap_id: usize, input_shm_cap_id: usize, output_shm_cap_id: usize },
@@ -65,6 +82,17 @@ pub fn SyscallArgs(comptime sys: Syscall) type {
         .gfx_destroy => struct { gfx_cap_id: usize },
 
         .debug_print => struct { input_shm_cap_id: usize },
+
+        .isa_get_extensions => struct {},
+
+        .thread_spawn => struct { pc: usize, stack_pointer: usize, argument: usize },
+        .thread_exit => struct { exit_value: usize },
+        .thread_join => struct { thread_id: usize },
+
+        .channel_new => struct { output_shm_cap_id: usize },
+        .channel_send => struct { channel_cap_id: usize, input_shm_cap_id: usize, output_shm_cap_id: usize },
+        .channel_recv => struct { channel_cap_id: usize, output_shm_cap_id: usize },
+        .channel_destroy => struct { channel_cap_id: usize },
     };
 }
 
@@ -91,6 +119,12 @@ pub const SyscallErrorEnum = enum(usize) {
 
     gfx_unknown_present_buffer_format = 16,
     gfx_child_caps_not_destroyed = 17,
+
+    thread_not_found = 18,
+    thread_join_self = 19,
+
+    shm_invalid_flags = 20,
+    shm_cap_not_acquired = 21,
 };
 
 pub const SyscallError = error{
@@ -116,12 +150,36 @@ pub const SyscallError = error{
 
     GfxUnknownPresentBufferFormat,
     GfxChildCapsNotDestroyed,
+
+    ThreadNotFound,
+    ThreadJoinSelf,
+
+    ShmInvalidFlags,
+    ShmCapNotAcquired,
 };
 
 pub const ShmType = enum(usize) {
     four_kib = 0,
     two_mib = 1,
     one_gib = 2,
+    four_mib = 3,
+    five_twelve_gib = 4,
+    two_fifty_six_tib = 5,
+};
+
+pub const ShmFlags = packed struct(usize) {
+    r: bool = false,
+    w: bool = false,
+    x: bool = false,
+    _padding: std.meta.Int(.unsigned, @bitSizeOf(usize) - 3) = 0,
+
+    pub const read_only = ShmFlags{ .r = true };
+    pub const read_write = ShmFlags{ .r = true, .w = true };
+    pub const read_execute = ShmFlags{ .r = true, .x = true };
+
+    fn toBits(self: ShmFlags) usize {
+        return @bitCast(self);
+    }
 };
 
 pub const PresentBufferFormat = enum(usize) {
@@ -148,6 +206,9 @@ fn syscallInternal(comptime sys: Syscall, sys_args: SyscallArgs(sys), comptime i
         .shm_acquire => syscallInternalArgs(sys, .{ sys_args.shm_cap_id, sys_args.address }, ignore_errors),
         .shm_new_and_acquire => syscallInternalArgs(sys, .{ @intFromEnum(sys_args.shm_type), sys_args.length, sys_args.address }, ignore_errors),
         .shm_release, .shm_destroy, .shm_release_and_destroy => syscallInternalArgs(sys, .{sys_args.shm_cap_id}, ignore_errors),
+        .shm_acquire_with_flags => syscallInternalArgs(sys, .{ sys_args.shm_cap_id, sys_args.address, sys_args.flags.toBits() }, ignore_errors),
+        .shm_protect => syscallInternalArgs(sys, .{ sys_args.shm_cap_id, sys_args.flags.toBits() }, ignore_errors),
+        .shm_resize => syscallInternalArgs(sys, .{ sys_args.shm_cap_id, sys_args.length }, ignore_errors),
 
         // Send maxInt(usize) as the first argument. The first argument is not used yet, but may be in the future.
         .accessibility_tree_new => syscallInternalArgs(sys, .{std.math.maxInt(usize)}, ignore_errors),
@@ -169,6 +230,17 @@ fn syscallInternal(comptime sys: Syscall, sys_args: SyscallArgs(sys), comptime i
         .gfx_destroy => syscallInternalArgs(sys, .{sys_args.gfx_cap_id}, ignore_errors),
 
         .debug_print => syscallInternalArgs(sys, .{sys_args.input_shm_cap_id}, ignore_errors),
+
+        .isa_get_extensions => syscallInternalArgs(sys, .{}, ignore_errors),
+
+        .thread_spawn => syscallInternalArgs(sys, .{ sys_args.pc, sys_args.stack_pointer, sys_args.argument }, ignore_errors),
+        .thread_exit => syscallInternalArgs(sys, .{sys_args.exit_value}, ignore_errors),
+        .thread_join => syscallInternalArgs(sys, .{sys_args.thread_id}, ignore_errors),
+
+        .channel_new => syscallInternalArgs(sys, .{sys_args.output_shm_cap_id}, ignore_errors),
+        .channel_send => syscallInternalArgs(sys, .{ sys_args.channel_cap_id, sys_args.input_shm_cap_id, sys_args.output_shm_cap_id }, ignore_errors),
+        .channel_recv => syscallInternalArgs(sys, .{ sys_args.channel_cap_id, sys_args.output_shm_cap_id }, ignore_errors),
+        .channel_destroy => syscallInternalArgs(sys, .{sys_args.channel_cap_id}, ignore_errors),
     };
 }
 
@@ -178,6 +250,10 @@ fn syscallInternalArgs(comptime sys: Syscall, args: anytype, comptime ignore_err
 
     const syscall_number: usize = @intFromEnum(sys);
 
+    if (@bitSizeOf(usize) == 32) {
+        return syscallInternalArgs32(syscall_number, args, ignore_errors);
+    }
+
     if (ignore_errors) {
         // t0 is always clobbered by the hypervisor on an ecall. So it needs to
         // be included in the clobber list. Even in this ignore_errors case,
@@ -277,6 +353,54 @@ fn syscallInternalArgs(comptime sys: Syscall, args: anytype, comptime ignore_err
     return a0_output;
 }
 
+/// 32-bit apps pass each 64-bit value in a pair of registers, low half first.
+/// Arguments are usize, so their high halves are always 0. Unused arguments
+/// are passed as 0 too, so that one piece of assembly covers every syscall.
+fn syscallInternalArgs32(syscall_number: usize, args: anytype, comptime ignore_errors: bool) SyscallInternalReturnType(ignore_errors) {
+    if (args.len > 4) {
+        @compileError("syscall_internal_args does not support " ++ std.fmt.comptimePrint("{}", .{args.len}) ++ " args, please add support if needed");
+    }
+
+    var padded_args = [_]usize{0} ** 4;
+    inline for (args, 0..) |arg, i| {
+        padded_args[i] = arg;
+    }
+
+    var a0_output: usize = undefined;
+    var a1_output: usize = undefined;
+    var t0_output: usize = undefined;
+
+    // t0 and t1 are always clobbered by the hypervisor on an ecall, as in the
+    // 64-bit case.
+    asm volatile ("ecall"
+        : [ret_a0] "={a0}" (a0_output),
+          [ret_a1] "={a1}" (a1_output),
+          [ret_t0] "={t0}" (t0_output),
+        : [syscall_number] "{a0}" (syscall_number),
+          [arg1] "{a1}" (padded_args[0]),
+          [arg1_high] "{a2}" (@as(usize, 0)),
+          [arg2] "{a3}" (padded_args[1]),
+          [arg2_high] "{a4}" (@as(usize, 0)),
+          [arg3] "{a5}" (padded_args[2]),
+          [arg3_high] "{a6}" (@as(usize, 0)),
+          [arg4] "{a7}" (padded_args[3]),
+          [arg4_high] "{t0}" (@as(usize, 0)),
+        : "memory", "t0", "t1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"
+    );
+
+    if (ignore_errors) {
+        return a0_output;
+    }
+
+    // The error code's high half, in t1, is always 0.
+    if (a0_output == std.math.maxInt(usize) and a1_output == std.math.maxInt(usize)) {
+        return syscallErrorFromErrorCode(t0_output);
+    }
+
+    // Return values used so far all fit in the low half.
+    return a0_output;
+}
+
 fn syscallErrorFromErrorCode(error_code: usize) SyscallError {
     const syscall_error_enum: SyscallErrorEnum = @enumFromInt(error_code);
 
@@ -3,17 +3,24 @@
 
 use std::sync::{Arc, Mutex};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use self::accessibility_tree::AccessibilityTree;
-use super::deferred_space::{self, DeferredSpace, DeferredSpacePublish, DefaultDeferredSpace, DeferredError, DeferredSpaceError};
-use super::shm_space::{ShmSpace, ShmCapId, ShmCap};
+use super::deferred_space::{self, DeferredSpace, DeferredSpacePublish, DefaultDeferredSpace, DefaultDeferredSpaceSnapshot, DeferredError, DeferredSpaceError};
+use super::shm_space::{ShmSpace, ShmCapId, ShmCap, ShmSpaceMutex};
+use super::snapshot::SnapshotError;
 
 mod accessibility_tree;
 
 pub type AccessibilityTreeCapId = u64;
 const A11Y_CONTEXT: &str = "accessibility tree";
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AccessibilityTreeSpaceSnapshot {
+    deferred_space: DefaultDeferredSpaceSnapshot,
+    app_accessibility_tree: Option<AccessibilityTree>,
+}
+
 pub struct AccessibilityTreeSpace {
     deferred_space: DefaultDeferredSpace,
     publish_ron: PublishRon,
@@ -76,15 +83,32 @@ impl DeferredSpacePublish for Publish {
 
 impl AccessibilityTreeSpace {
     pub fn new() -> Self {
-        let app_accessibility_tree = Arc::new(Mutex::new(None));
+        Self::new_with(DefaultDeferredSpace::new(), None)
+    }
+
+    fn new_with(deferred_space: DefaultDeferredSpace, app_accessibility_tree: Option<AccessibilityTree>) -> Self {
+        let app_accessibility_tree = Arc::new(Mutex::new(app_accessibility_tree));
 
         Self {
-            deferred_space: DefaultDeferredSpace::new(),
+            deferred_space,
             publish_ron: PublishRon::new(Arc::clone(&app_accessibility_tree)),
             publish: Publish::new(Arc::clone(&app_accessibility_tree)),
         }
     }
 
+    pub(crate) fn snapshot(&self) -> Result<AccessibilityTreeSpaceSnapshot, SnapshotError> {
+        Ok(AccessibilityTreeSpaceSnapshot {
+            deferred_space: self.deferred_space.snapshot()?,
+            app_accessibility_tree: self.publish.app_accessibility_tree.lock().unwrap().clone(),
+        })
+    }
+
+    pub(crate) fn restore(accessibility_tree_space_snapshot: AccessibilityTreeSpaceSnapshot) -> Result<Self, SnapshotError> {
+        let AccessibilityTreeSpaceSnapshot { deferred_space, app_accessibility_tree } = accessibility_tree_space_snapshot;
+
+        Ok(Self::new_with(DefaultDeferredSpace::restore(deferred_space)?, app_accessibility_tree))
+    }
+
     // TODO: Should new and destroy also be part blocking, part deferred?
 
     pub fn new_accessibility_tree_cap(&mut self) -> Result<AccessibilityTreeCapId, DeferredSpaceError> {
@@ -95,11 +119,11 @@ impl AccessibilityTreeSpace {
         self.deferred_space.publish_blocking(A11Y_CONTEXT, accessibility_tree_cap_id, input_shm_cap_id, output_shm_cap_id, shm_space)
     }
 
-    pub fn publish_accessibility_tree_ron_deferred(&mut self, accessibility_tree_cap_id: AccessibilityTreeCapId, shm_space: &mut ShmSpace) -> Result<(), ()> {
+    pub fn publish_accessibility_tree_ron_deferred(&mut self, accessibility_tree_cap_id: AccessibilityTreeCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()> {
         self.deferred_space.publish_deferred(&mut self.publish_ron, accessibility_tree_cap_id, shm_space)
     }
 
-    pub fn publish_accessibility_tree_deferred(&mut self, accessibility_tree_cap_id: AccessibilityTreeCapId, shm_space: &mut ShmSpace) -> Result<(), ()> {
+    pub fn publish_accessibility_tree_deferred(&mut self, accessibility_tree_cap_id: AccessibilityTreeCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()> {
         self.deferred_space.publish_deferred(&mut self.publish, accessibility_tree_cap_id, shm_space)
     }
 
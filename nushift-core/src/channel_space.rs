@@ -0,0 +1,741 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Channels, which let apps (including apps in different tabs) talk to each
+//! other.
+//!
+//! A channel has two ends, which are made together, in one app, as two
+//! channel caps. An end can then be sent to another app in a message, which is
+//! the only way for an app to get one, so an app can only talk to apps that it
+//! has been connected to. Each app starts with one end of its host channel,
+//! whose other end is held by the hypervisor, which uses it to give the app
+//! ends of channels to the apps in other tabs that it has been connected to.
+//!
+//! A message is a Postcard payload and any number of SHM caps and channel
+//! caps. The caps are taken out of the sender's spaces and put into the
+//! receiver's, so that a cap is still only ever in one app.
+//!
+//! A receive that has nothing to receive yet doesn't hold up a deferred task
+//! worker. Instead, its task is left in progress, and the channel keeps a way
+//! to wake it, which sending to it or closing the other end does by
+//! dispatching the task again.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use itertools::Itertools;
+use postcard::Error as PostcardError;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+use crate::deferred_space::{self, DefaultDeferredSpace, DefaultDeferredSpaceCapId, DeferredError, DeferredSpace, DeferredSpaceError, PrologueReturn};
+use crate::shm_space::{ShmCap, ShmCapId, ShmSpace, ShmSpaceError, ShmSpaceMutex};
+use crate::snapshot::{SnapshotError, OpenChannelsSnafu};
+
+pub type ChannelCapId = u64;
+const CHANNEL_CONTEXT: &str = "channel";
+const CHANNEL_RECV_CONTEXT: &str = "channel receive";
+
+/// Messages that can be waiting to be received at each end. Sending fails
+/// beyond this, until the receiver catches up.
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// Dispatches a receive task that is waiting for a message again.
+pub(crate) type WakeReceiver = Box<dyn FnOnce() + Send>;
+
+struct Message {
+    payload: Vec<u8>,
+    shm_caps: Vec<ShmCap>,
+    channel_ends: Vec<ChannelEnd>,
+}
+
+#[derive(Default)]
+struct ChannelSide {
+    /// Messages sent to this side, that it hasn't received yet.
+    inbox: VecDeque<Message>,
+    waiting_receiver: Option<WakeReceiver>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Channel {
+    sides: Mutex<[ChannelSide; 2]>,
+}
+
+impl Channel {
+    /// `take_message` is only called if the message can be sent, so that
+    /// whatever it takes out of the sender doesn't have to be put back.
+    fn send(&self, to_side: usize, take_message: impl FnOnce() -> Result<Message, ChannelMessageError>) -> Result<(), ChannelMessageError> {
+        let wake_receiver = {
+            let mut sides = self.sides.lock().unwrap();
+            let to_side = &mut sides[to_side];
+            ensure!(!to_side.closed, ClosedSnafu);
+            ensure!(to_side.inbox.len() < MAX_QUEUED_MESSAGES, FullSnafu);
+
+            to_side.inbox.push_back(take_message()?);
+            to_side.waiting_receiver.take()
+        };
+
+        // Woken outside the lock, since waking takes the receiving tab's
+        // locks.
+        if let Some(wake_receiver) = wake_receiver {
+            wake_receiver();
+        }
+        Ok(())
+    }
+}
+
+enum Received {
+    Message(Message),
+    Waiting,
+    Closed,
+}
+
+/// One end of a channel. Dropping it, which happens when its cap is destroyed
+/// or the app stops, closes it.
+pub(crate) struct ChannelEnd {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+/// Makes a channel, and returns its two ends.
+pub(crate) fn new_channel() -> (ChannelEnd, ChannelEnd) {
+    let channel = Arc::new(Channel::default());
+    (ChannelEnd { channel: Arc::clone(&channel), side: 0 }, ChannelEnd { channel, side: 1 })
+}
+
+impl ChannelEnd {
+    fn other_side(&self) -> usize {
+        1 - self.side
+    }
+
+    fn send(&self, take_message: impl FnOnce() -> Result<Message, ChannelMessageError>) -> Result<(), ChannelMessageError> {
+        self.channel.send(self.other_side(), take_message)
+    }
+
+    /// Messages that were sent before the other end was closed can still be
+    /// received.
+    fn receive(&self, wake_receiver: WakeReceiver) -> Received {
+        let mut sides = self.channel.sides.lock().unwrap();
+        if let Some(message) = sides[self.side].inbox.pop_front() {
+            return Received::Message(message);
+        }
+        if sides[self.other_side()].closed {
+            return Received::Closed;
+        }
+
+        sides[self.side].waiting_receiver = Some(wake_receiver);
+        Received::Waiting
+    }
+
+    /// Puts back a message that couldn't be given to the app, so that it's
+    /// received next time.
+    fn unreceive(&self, message: Message) {
+        self.channel.sides.lock().unwrap()[self.side].inbox.push_front(message);
+    }
+
+    fn has_messages(&self) -> bool {
+        !self.channel.sides.lock().unwrap()[self.side].inbox.is_empty()
+    }
+}
+
+impl Drop for ChannelEnd {
+    fn drop(&mut self) {
+        let (inbox, wake_receiver) = {
+            let mut sides = self.channel.sides.lock().unwrap();
+            let side = &mut sides[self.side];
+            side.closed = true;
+            side.waiting_receiver = None;
+            // Nothing can receive these anymore, so their caps go with them.
+            // They're dropped outside the lock, since dropping the channel
+            // ends in them closes those channels, which takes their locks.
+            (std::mem::take(&mut side.inbox), sides[self.other_side()].waiting_receiver.take())
+        };
+        drop(inbox);
+
+        if let Some(wake_receiver) = wake_receiver {
+            wake_receiver();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelSendArgs {
+    payload: Vec<u8>,
+    shm_cap_ids: Vec<ShmCapId>,
+    channel_cap_ids: Vec<ChannelCapId>,
+}
+
+#[derive(Serialize)]
+struct ChannelRecvOutput<'output> {
+    payload: &'output [u8],
+    shm_cap_ids: &'output [ShmCapId],
+    channel_cap_ids: &'output [ChannelCapId],
+}
+
+/// A message whose caps have been put into the receiving app, with their new
+/// IDs.
+struct GivenMessage {
+    payload: Vec<u8>,
+    shm_cap_ids: Vec<ShmCapId>,
+    channel_cap_ids: Vec<ChannelCapId>,
+}
+
+struct ChannelCap {
+    end: ChannelEnd,
+    /// Receiving has its own deferred cap, so that the app can carry on
+    /// sending on the channel while a receive is waiting.
+    recv_cap_id: DefaultDeferredSpaceCapId,
+}
+
+pub(crate) enum RecvProgress {
+    Finished,
+    /// There's nothing to receive yet, so the task is still in progress, and
+    /// will be dispatched again once there is.
+    Waiting,
+}
+
+/// Channel cap IDs are the IDs of the send deferred space's caps.
+pub struct ChannelSpace {
+    send_deferred_space: DefaultDeferredSpace,
+    recv_deferred_space: DefaultDeferredSpace,
+    channels: HashMap<ChannelCapId, ChannelCap>,
+    /// The hypervisor's end of the app's host channel.
+    host_end: ChannelEnd,
+}
+
+impl ChannelSpace {
+    pub(crate) fn new() -> Self {
+        let (host_end, app_end) = new_channel();
+        let mut channel_space = Self {
+            send_deferred_space: DefaultDeferredSpace::new(),
+            recv_deferred_space: DefaultDeferredSpace::new(),
+            channels: HashMap::new(),
+            host_end,
+        };
+
+        // The deferred spaces are empty, so this can't fail, and the cap gets
+        // ID 0, which is where apps expect their host channel to be.
+        let _ = channel_space.add_channel_end(app_end);
+        channel_space
+    }
+
+    /// Channels are connections to other apps, which can't be saved, so apps
+    /// with open channels can't be snapshotted, and there's nothing else to
+    /// snapshot. The host channel is the exception, as long as nothing is
+    /// waiting to be received on it, since a restored app gets a new one.
+    pub(crate) fn check_snapshottable(&self) -> Result<(), SnapshotError> {
+        ensure!(
+            self.channels.values().all(|channel_cap| Arc::ptr_eq(&channel_cap.end.channel, &self.host_end.channel) && !channel_cap.end.has_messages()),
+            OpenChannelsSnafu,
+        );
+        Ok(())
+    }
+
+    /// Makes a channel, with both of its ends in this app, and writes their
+    /// channel cap IDs to the output cap. Either end can then be sent to
+    /// another app.
+    pub fn new_channel_caps(&mut self, output_shm_cap_id: ShmCapId, shm_space: &mut ShmSpace) -> Result<(), ChannelSpaceError> {
+        let output_shm_cap = shm_space.get_mut_shm_cap_app(output_shm_cap_id).map_err(|shm_space_error| match shm_space_error {
+            ShmSpaceError::CapNotFound => ShmCapNotFoundSnafu { id: output_shm_cap_id }.build(),
+            ShmSpaceError::PermissionDenied => ShmPermissionDeniedSnafu { id: output_shm_cap_id }.build(),
+            _ => ShmUnexpectedSnafu.build(),
+        })?;
+
+        let (first_end, second_end) = new_channel();
+        let first_channel_cap_id = self.add_channel_end(first_end).map_err(|(deferred_space_error, _)| deferred_space_error).context(DeferredSpaceSnafu)?;
+        let second_channel_cap_id = match self.add_channel_end(second_end) {
+            Ok(second_channel_cap_id) => second_channel_cap_id,
+            Err((deferred_space_error, _)) => {
+                self.take_channel_end(first_channel_cap_id);
+                return Err(deferred_space_error).context(DeferredSpaceSnafu);
+            }
+        };
+
+        if let Err(postcard_error) = postcard::to_slice(&[first_channel_cap_id, second_channel_cap_id], output_shm_cap.backing_mut()) {
+            self.take_channel_end(first_channel_cap_id);
+            self.take_channel_end(second_channel_cap_id);
+            return Err(postcard_error).context(SerializeChannelCapIdsSnafu);
+        }
+
+        Ok(())
+    }
+
+    /// Gives the app one end of a channel, in a message on its host channel
+    /// with an empty payload.
+    pub(crate) fn give_channel_end(&self, channel_end: ChannelEnd) -> Result<(), ChannelMessageError> {
+        self.host_end.send(|| Ok(Message { payload: Vec::new(), shm_caps: Vec::new(), channel_ends: vec![channel_end] }))
+    }
+
+    pub fn send_blocking(&mut self, channel_cap_id: ChannelCapId, input_shm_cap_id: ShmCapId, output_shm_cap_id: ShmCapId, shm_space: &mut ShmSpace) -> Result<(), ChannelSpaceError> {
+        self.send_deferred_space.publish_blocking(CHANNEL_CONTEXT, channel_cap_id, input_shm_cap_id, output_shm_cap_id, shm_space).context(DeferredSpaceSnafu)
+    }
+
+    /// The Err(()) variant is only used for internal errors. All other errors
+    /// are reported through the output cap.
+    ///
+    /// The SHM space is locked while the message's SHM caps are taken out of
+    /// it, and to move the input and output caps back in.
+    pub fn send_deferred(&mut self, channel_cap_id: ChannelCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()> {
+        let send_args = match self.send_deferred_space.get_or_publish_deferred_prologue(channel_cap_id) {
+            PrologueReturn::ContinueCapsPublish(input_shm_cap, _) => postcard::from_bytes::<ChannelSendArgs>(input_shm_cap.backing()),
+            PrologueReturn::ContinueCapsGet(_) => return Err(()), // Internal error. We must have started with a publish.
+            PrologueReturn::ReturnErr => return Err(()),
+        };
+
+        let mut shm_space = shm_space.lock().unwrap();
+        let send_result = match send_args {
+            Ok(send_args) => self.send_message(channel_cap_id, send_args, &mut shm_space),
+            Err(postcard_error) => Err(ChannelMessageError::DeserializeMessageError { source: postcard_error }),
+        };
+
+        // The output cap is only borrowed now, since sending channel caps
+        // destroys their deferred caps.
+        let PrologueReturn::ContinueCapsPublish(_, output_shm_cap) = self.send_deferred_space.get_or_publish_deferred_prologue(channel_cap_id) else {
+            return Err(());
+        };
+        match send_result {
+            Ok(()) => deferred_space::print_success(output_shm_cap, ()),
+            Err(channel_message_error) => {
+                tracing::debug!("Channel send failed: {channel_message_error}");
+                deferred_space::print_error(output_shm_cap, channel_message_error.deferred_error(), &channel_message_error);
+            }
+        }
+
+        self.send_deferred_space.get_or_publish_deferred_epilogue(channel_cap_id, &mut shm_space)
+    }
+
+    fn send_message(&mut self, channel_cap_id: ChannelCapId, send_args: ChannelSendArgs, shm_space: &mut ShmSpace) -> Result<(), ChannelMessageError> {
+        let ChannelSendArgs { payload, shm_cap_ids, channel_cap_ids } = send_args;
+        let channel_end = &self.channels.get(&channel_cap_id).context(ChannelCapNotFoundSnafu { id: channel_cap_id })?.end;
+        let (channel, to_side) = (Arc::clone(&channel_end.channel), channel_end.other_side());
+
+        channel.send(to_side, || {
+            ensure!(shm_cap_ids.iter().all_unique(), DuplicateShmCapIdsSnafu);
+            ensure!(channel_cap_ids.iter().all_unique(), DuplicateChannelCapIdsSnafu);
+            self.check_channel_caps_sendable(&channel, &channel_cap_ids)?;
+            let shm_caps = shm_space.transfer_shm_caps_out(&shm_cap_ids).context(ShmCapsNotSendableSnafu)?;
+            // Checked above, so these are all there.
+            let channel_ends = channel_cap_ids.iter().filter_map(|&channel_cap_id| self.take_channel_end(channel_cap_id)).collect();
+            Ok(Message { payload, shm_caps, channel_ends })
+        })
+    }
+
+    /// A channel cap can be sent if it isn't being sent or received on, and
+    /// isn't an end of the channel it's being sent on.
+    fn check_channel_caps_sendable(&self, sending_channel: &Arc<Channel>, channel_cap_ids: &[ChannelCapId]) -> Result<(), ChannelMessageError> {
+        for &channel_cap_id in channel_cap_ids {
+            let channel_cap = self.channels.get(&channel_cap_id).context(ChannelCapNotFoundSnafu { id: channel_cap_id })?;
+            ensure!(!Arc::ptr_eq(&channel_cap.end.channel, sending_channel), SendingChannelOverItselfSnafu { id: channel_cap_id });
+            ensure!(
+                !self.send_deferred_space.is_in_progress(channel_cap_id) && !self.recv_deferred_space.is_in_progress(channel_cap.recv_cap_id),
+                ChannelCapInProgressSnafu { id: channel_cap_id },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn recv_blocking(&mut self, channel_cap_id: ChannelCapId, output_shm_cap_id: ShmCapId, shm_space: &mut ShmSpace) -> Result<(), ChannelSpaceError> {
+        let recv_cap_id = self.recv_cap_id(channel_cap_id)?;
+
+        self.recv_deferred_space.get_blocking(CHANNEL_RECV_CONTEXT, recv_cap_id, output_shm_cap_id, shm_space).context(DeferredSpaceSnafu)
+    }
+
+    /// The Err(()) variant is only used for internal errors. All other errors
+    /// are reported through the output cap.
+    ///
+    /// If there's nothing to receive yet, `wake_receiver` is called once there
+    /// is, or once the other end is closed, and the task carries on being in
+    /// progress until then.
+    pub(crate) fn recv_deferred(&mut self, channel_cap_id: ChannelCapId, shm_space: &ShmSpaceMutex, wake_receiver: WakeReceiver) -> Result<RecvProgress, ()> {
+        let recv_cap_id = self.channels.get(&channel_cap_id).ok_or(())?.recv_cap_id;
+        match self.recv_deferred_space.get_or_publish_deferred_prologue(recv_cap_id) {
+            PrologueReturn::ContinueCapsPublish(_, _) => return Err(()), // Internal error. We must have started with a get.
+            PrologueReturn::ContinueCapsGet(_) => {}
+            PrologueReturn::ReturnErr => return Err(()),
+        }
+
+        let received = self.channels.get(&channel_cap_id).ok_or(())?.end.receive(wake_receiver);
+        let mut shm_space = shm_space.lock().unwrap();
+        let receive_result = match received {
+            Received::Waiting => return Ok(RecvProgress::Waiting),
+            Received::Closed => Err(ChannelMessageError::Closed),
+            Received::Message(message) => self.give_message(message, &mut shm_space).map_err(|(channel_message_error, message)| {
+                self.unreceive(channel_cap_id, message);
+                channel_message_error
+            }),
+        };
+
+        // The output cap is only borrowed now, since receiving channel caps
+        // makes new deferred caps.
+        let PrologueReturn::ContinueCapsGet(output_shm_cap) = self.recv_deferred_space.get_or_publish_deferred_prologue(recv_cap_id) else {
+            return Err(());
+        };
+        let print_result = match receive_result {
+            Ok(given_message) => {
+                let GivenMessage { ref payload, ref shm_cap_ids, ref channel_cap_ids } = given_message;
+                deferred_space::try_print_success(output_shm_cap, ChannelRecvOutput { payload, shm_cap_ids, channel_cap_ids })
+                    .map_err(|postcard_error| (postcard_error, given_message))
+            }
+            Err(channel_message_error) => {
+                tracing::debug!("Channel receive failed: {channel_message_error}");
+                deferred_space::print_error(output_shm_cap, channel_message_error.deferred_error(), &channel_message_error);
+                Ok(())
+            }
+        };
+
+        if let Err((postcard_error, given_message)) = print_result {
+            tracing::debug!("Postcard serialise error: {postcard_error}");
+            deferred_space::print_error(output_shm_cap, DeferredError::SerializeError, &postcard_error);
+            // They were only just put in, so they're caps that aren't
+            // acquired or in progress, and this can't fail.
+            match self.take_back_message(given_message, &mut shm_space) {
+                Ok(message) => self.unreceive(channel_cap_id, message),
+                Err(shm_space_error) => tracing::error!("Could not take back the SHM caps of a message that wasn't received, dropping the message: {shm_space_error}"),
+            }
+        }
+
+        self.recv_deferred_space.get_or_publish_deferred_epilogue(recv_cap_id, &mut shm_space)
+            .map(|()| RecvProgress::Finished)
+    }
+
+    /// Puts the message's SHM caps and channel caps into this app. If they
+    /// can't all be put in, none are, and the message is handed back as it
+    /// was.
+    fn give_message(&mut self, message: Message, shm_space: &mut ShmSpace) -> Result<GivenMessage, (ChannelMessageError, Message)> {
+        let Message { payload, shm_caps, channel_ends } = message;
+
+        let shm_cap_ids = match shm_space.transfer_shm_caps_in(shm_caps) {
+            Ok(shm_cap_ids) => shm_cap_ids,
+            Err((shm_space_error, shm_caps)) => {
+                return Err((ChannelMessageError::ShmCapsNotReceivable { source: shm_space_error }, Message { payload, shm_caps, channel_ends }));
+            }
+        };
+
+        let channel_cap_ids = match self.add_channel_ends(channel_ends) {
+            Ok(channel_cap_ids) => channel_cap_ids,
+            Err((deferred_space_error, channel_ends)) => {
+                // They were only just put in, so they're app caps that aren't
+                // acquired, and this can't fail.
+                let shm_caps = shm_space.transfer_shm_caps_out(&shm_cap_ids).unwrap_or_default();
+                return Err((ChannelMessageError::ChannelCapsNotReceivable { source: deferred_space_error }, Message { payload, shm_caps, channel_ends }));
+            }
+        };
+
+        Ok(GivenMessage { payload, shm_cap_ids, channel_cap_ids })
+    }
+
+    /// The opposite of `give_message`.
+    fn take_back_message(&mut self, given_message: GivenMessage, shm_space: &mut ShmSpace) -> Result<Message, ShmSpaceError> {
+        let GivenMessage { payload, shm_cap_ids, channel_cap_ids } = given_message;
+        let shm_caps = shm_space.transfer_shm_caps_out(&shm_cap_ids)?;
+        let channel_ends = channel_cap_ids.into_iter().filter_map(|channel_cap_id| self.take_channel_end(channel_cap_id)).collect();
+
+        Ok(Message { payload, shm_caps, channel_ends })
+    }
+
+    fn unreceive(&self, channel_cap_id: ChannelCapId, message: Message) {
+        if let Some(channel_cap) = self.channels.get(&channel_cap_id) {
+            channel_cap.end.unreceive(message);
+        }
+    }
+
+    /// Closes this end of the channel. A channel can't be destroyed while a
+    /// send or receive on it is in progress.
+    pub fn destroy_channel_cap(&mut self, channel_cap_id: ChannelCapId) -> Result<(), ChannelSpaceError> {
+        let recv_cap_id = self.recv_cap_id(channel_cap_id)?;
+        if self.recv_deferred_space.is_in_progress(recv_cap_id) {
+            return Err(DeferredSpaceError::InProgress { context: CHANNEL_RECV_CONTEXT.into() }).context(DeferredSpaceSnafu);
+        }
+        if self.send_deferred_space.is_in_progress(channel_cap_id) {
+            return Err(DeferredSpaceError::InProgress { context: CHANNEL_CONTEXT.into() }).context(DeferredSpaceSnafu);
+        }
+
+        self.take_channel_end(channel_cap_id);
+
+        Ok(())
+    }
+
+    /// Makes a channel cap for `channel_end`. If that fails, it's handed back.
+    fn add_channel_end(&mut self, channel_end: ChannelEnd) -> Result<ChannelCapId, (DeferredSpaceError, ChannelEnd)> {
+        let channel_cap_id = match self.send_deferred_space.new_cap(CHANNEL_CONTEXT) {
+            Ok(channel_cap_id) => channel_cap_id,
+            Err(deferred_space_error) => return Err((deferred_space_error, channel_end)),
+        };
+        let recv_cap_id = match self.recv_deferred_space.new_cap(CHANNEL_CONTEXT) {
+            Ok(recv_cap_id) => recv_cap_id,
+            Err(deferred_space_error) => {
+                // Not in progress, so this can't fail.
+                let _ = self.send_deferred_space.destroy_cap(CHANNEL_CONTEXT, channel_cap_id);
+                return Err((deferred_space_error, channel_end));
+            }
+        };
+
+        self.channels.insert(channel_cap_id, ChannelCap { end: channel_end, recv_cap_id });
+        Ok(channel_cap_id)
+    }
+
+    /// Either all of `channel_ends` get channel caps, or none do, in which
+    /// case they're handed back.
+    fn add_channel_ends(&mut self, channel_ends: Vec<ChannelEnd>) -> Result<Vec<ChannelCapId>, (DeferredSpaceError, Vec<ChannelEnd>)> {
+        let mut channel_cap_ids = Vec::with_capacity(channel_ends.len());
+        let mut channel_ends = channel_ends.into_iter();
+
+        let (deferred_space_error, failed_channel_end) = loop {
+            let Some(channel_end) = channel_ends.next() else {
+                return Ok(channel_cap_ids);
+            };
+            match self.add_channel_end(channel_end) {
+                Ok(channel_cap_id) => channel_cap_ids.push(channel_cap_id),
+                Err(error_and_channel_end) => break error_and_channel_end,
+            }
+        };
+
+        // Roll back the ones that were added.
+        let mut returned_channel_ends: Vec<ChannelEnd> = channel_cap_ids.into_iter()
+            .filter_map(|channel_cap_id| self.take_channel_end(channel_cap_id))
+            .collect();
+        returned_channel_ends.push(failed_channel_end);
+        returned_channel_ends.extend(channel_ends);
+
+        Err((deferred_space_error, returned_channel_ends))
+    }
+
+    /// Takes the end out of its channel cap, and destroys the cap. The cap
+    /// must not be in progress.
+    fn take_channel_end(&mut self, channel_cap_id: ChannelCapId) -> Option<ChannelEnd> {
+        let ChannelCap { end, recv_cap_id } = self.channels.remove(&channel_cap_id)?;
+        // Not in progress, so these can't fail.
+        let _ = self.send_deferred_space.destroy_cap(CHANNEL_CONTEXT, channel_cap_id);
+        let _ = self.recv_deferred_space.destroy_cap(CHANNEL_RECV_CONTEXT, recv_cap_id);
+
+        Some(end)
+    }
+
+    fn recv_cap_id(&self, channel_cap_id: ChannelCapId) -> Result<DefaultDeferredSpaceCapId, ChannelSpaceError> {
+        self.channels.get(&channel_cap_id)
+            .map(|channel_cap| channel_cap.recv_cap_id)
+            .ok_or_else(|| DeferredSpaceError::CapNotFound { context: CHANNEL_CONTEXT.into(), id: channel_cap_id })
+            .context(DeferredSpaceSnafu)
+    }
+}
+
+/// Errors that are written to a send or receive's output cap, or that stop the
+/// hypervisor from giving an app a channel end.
+#[derive(Snafu, SnafuCliDebug)]
+pub enum ChannelMessageError {
+    #[snafu(display("The other end of the channel has been closed."))]
+    Closed,
+    #[snafu(display("The other end of the channel has {MAX_QUEUED_MESSAGES} messages that it hasn't received yet."))]
+    Full,
+    #[snafu(display("Error deserialising the message: {source}"))]
+    DeserializeMessageError { source: PostcardError },
+    #[snafu(display("The same SHM cap was listed more than once."))]
+    DuplicateShmCapIds,
+    #[snafu(display("The message's SHM caps could not be sent: {source}"))]
+    ShmCapsNotSendable { source: ShmSpaceError },
+    #[snafu(display("The message's SHM caps could not be received, so the message is still waiting to be received: {source}"))]
+    ShmCapsNotReceivable { source: ShmSpaceError },
+    #[snafu(display("The same channel cap was listed more than once."))]
+    DuplicateChannelCapIds,
+    #[snafu(display("The channel cap with ID {id} was not found."))]
+    ChannelCapNotFound { id: ChannelCapId },
+    #[snafu(display("The channel cap with ID {id} is an end of the channel it was being sent on."))]
+    SendingChannelOverItself { id: ChannelCapId },
+    #[snafu(display("The channel cap with ID {id} has a send or receive in progress."))]
+    ChannelCapInProgress { id: ChannelCapId },
+    #[snafu(display("The message's channel caps could not be received, so the message is still waiting to be received: {source}"))]
+    ChannelCapsNotReceivable { source: DeferredSpaceError },
+}
+
+impl ChannelMessageError {
+    fn deferred_error(&self) -> DeferredError {
+        match self {
+            Self::Closed => DeferredError::ChannelClosed,
+            Self::Full => DeferredError::ChannelFull,
+            Self::DeserializeMessageError { .. } => DeferredError::DeserializeError,
+            Self::DuplicateShmCapIds | Self::ShmCapsNotSendable { .. } => DeferredError::ChannelShmCapsNotSendable,
+            Self::ShmCapsNotReceivable { .. } => DeferredError::ChannelShmCapsNotReceivable,
+            Self::DuplicateChannelCapIds
+            | Self::ChannelCapNotFound { .. }
+            | Self::SendingChannelOverItself { .. }
+            | Self::ChannelCapInProgress { .. } => DeferredError::ChannelCapsNotSendable,
+            Self::ChannelCapsNotReceivable { .. } => DeferredError::ChannelCapsNotReceivable,
+        }
+    }
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum ChannelSpaceError {
+    DeferredSpaceError { source: DeferredSpaceError },
+    #[snafu(display("Could not serialise the channel cap IDs to output_shm_cap_id: {source}"))]
+    SerializeChannelCapIdsError { source: PostcardError },
+    #[snafu(display("The SHM cap with ID {id} was not found."))]
+    ShmCapNotFound { id: ShmCapId },
+    #[snafu(display("The SHM cap with ID {id} is not allowed to be used as an output cap, possibly because it is an ELF cap."))]
+    ShmPermissionDenied { id: ShmCapId },
+    ShmUnexpectedError,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crate::shm_space::{CapType, PagingScheme, ShmType};
+
+    use super::*;
+
+    const HOST_CHANNEL_CAP_ID: ChannelCapId = 0;
+
+    #[derive(Deserialize)]
+    struct ReceivedMessage {
+        payload: Vec<u8>,
+        shm_cap_ids: Vec<ShmCapId>,
+        channel_cap_ids: Vec<ChannelCapId>,
+    }
+
+    /// An app with one end of a channel open, and an output cap for its sends
+    /// and receives.
+    struct TestApp {
+        channel_space: ChannelSpace,
+        shm_space: ShmSpaceMutex,
+        channel_cap_id: ChannelCapId,
+        output_shm_cap_id: ShmCapId,
+    }
+
+    impl TestApp {
+        /// Two apps that have been connected to each other by the hypervisor.
+        fn connected_pair() -> (Self, Self) {
+            let (first_end, second_end) = new_channel();
+            (Self::given(first_end), Self::given(second_end))
+        }
+
+        fn given(channel_end: ChannelEnd) -> Self {
+            let channel_space = ChannelSpace::new();
+            let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+            let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+            channel_space.give_channel_end(channel_end).expect("Should succeed");
+
+            let mut app = Self { channel_space, shm_space: ShmSpaceMutex::new(shm_space), channel_cap_id: HOST_CHANNEL_CAP_ID, output_shm_cap_id };
+            let received = app.recv();
+            assert!(received.payload.is_empty());
+            app.channel_cap_id = received.channel_cap_ids[0];
+            app
+        }
+
+        fn new_shm_cap(&self, first_byte: u8) -> ShmCapId {
+            let mut shm_space = self.shm_space.lock().unwrap();
+            let (shm_cap_id, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+            shm_cap.backing_mut()[0] = first_byte;
+            shm_cap_id
+        }
+
+        fn send(&mut self, payload: &[u8], shm_cap_ids: &[ShmCapId], channel_cap_ids: &[ChannelCapId]) {
+            let input_shm_cap_id = {
+                let mut shm_space = self.shm_space.lock().unwrap();
+                let (input_shm_cap_id, input_shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+                postcard::to_slice(&(payload, shm_cap_ids, channel_cap_ids), input_shm_cap.backing_mut()).expect("Should succeed");
+                input_shm_cap_id
+            };
+
+            self.channel_space.send_blocking(self.channel_cap_id, input_shm_cap_id, self.output_shm_cap_id, &mut self.shm_space.lock().unwrap()).expect("Should succeed");
+            self.channel_space.send_deferred(self.channel_cap_id, &self.shm_space).expect("Should succeed");
+        }
+
+        /// Receives a message that is already waiting.
+        fn recv(&mut self) -> ReceivedMessage {
+            self.channel_space.recv_blocking(self.channel_cap_id, self.output_shm_cap_id, &mut self.shm_space.lock().unwrap()).expect("Should succeed");
+            assert!(matches!(self.channel_space.recv_deferred(self.channel_cap_id, &self.shm_space, Box::new(|| {})), Ok(RecvProgress::Finished)));
+            let (discriminant, received): (u32, ReceivedMessage) = postcard::from_bytes(self.shm_space.lock().unwrap().get_shm_cap_app(self.output_shm_cap_id).expect("Should succeed").backing()).expect("Should succeed");
+            assert_eq!(0, discriminant);
+            received
+        }
+
+        fn output_error(&self) -> DeferredError {
+            let (discriminant, deferred_error, _message): (u32, DeferredError, String) = postcard::from_bytes(self.shm_space.lock().unwrap().get_shm_cap_app(self.output_shm_cap_id).expect("Should succeed").backing()).expect("Should succeed");
+            assert_eq!(1, discriminant);
+            deferred_error
+        }
+    }
+
+    #[test]
+    fn channel_recv_waits_for_message_then_receives_shm_caps() {
+        let (mut sender, mut receiver) = TestApp::connected_pair();
+        let (wake_send, wake_receive) = mpsc::channel();
+
+        receiver.channel_space.recv_blocking(receiver.channel_cap_id, receiver.output_shm_cap_id, &mut receiver.shm_space.lock().unwrap()).expect("Should succeed");
+        let wake_receiver = Box::new(move || wake_send.send(()).expect("Should succeed"));
+        assert!(matches!(receiver.channel_space.recv_deferred(receiver.channel_cap_id, &receiver.shm_space, wake_receiver), Ok(RecvProgress::Waiting)));
+        assert!(matches!(
+            receiver.channel_space.destroy_channel_cap(receiver.channel_cap_id),
+            Err(ChannelSpaceError::DeferredSpaceError { source: DeferredSpaceError::InProgress { .. } }),
+        ));
+
+        let sent_shm_cap_id = sender.new_shm_cap(0xab);
+        sender.send(b"hello", &[sent_shm_cap_id], &[]);
+        let (discriminant, ()): (u32, ()) = postcard::from_bytes(sender.shm_space.lock().unwrap().get_shm_cap_app(sender.output_shm_cap_id).expect("Should succeed").backing()).expect("Should succeed");
+        assert_eq!(0, discriminant);
+        assert!(matches!(sender.shm_space.lock().unwrap().get_shm_cap_app(sent_shm_cap_id), Err(ShmSpaceError::CapNotFound)));
+
+        wake_receive.try_recv().expect("Should have been woken");
+        assert!(matches!(receiver.channel_space.recv_deferred(receiver.channel_cap_id, &receiver.shm_space, Box::new(|| {})), Ok(RecvProgress::Finished)));
+        let shm_space = receiver.shm_space.lock().unwrap();
+        let (discriminant, received): (u32, ReceivedMessage) = postcard::from_bytes(shm_space.get_shm_cap_app(receiver.output_shm_cap_id).expect("Should succeed").backing()).expect("Should succeed");
+        assert_eq!(0, discriminant);
+        assert_eq!(b"hello", &received.payload[..]);
+        assert_eq!(0xab, shm_space.get_shm_cap_app(received.shm_cap_ids[0]).expect("Should succeed").backing()[0]);
+    }
+
+    #[test]
+    fn channel_send_keeps_acquired_shm_caps_and_fails_once_other_end_closed() {
+        let (mut sender, mut receiver) = TestApp::connected_pair();
+
+        let acquired_shm_cap_id = sender.new_shm_cap(0);
+        sender.shm_space.lock().unwrap().acquire_shm_cap_app(acquired_shm_cap_id, 0x10000).expect("Should succeed");
+        sender.send(b"", &[acquired_shm_cap_id], &[]);
+        assert!(matches!(sender.output_error(), DeferredError::ChannelShmCapsNotSendable));
+        assert!(sender.shm_space.lock().unwrap().get_shm_cap_app(acquired_shm_cap_id).is_ok());
+
+        receiver.channel_space.destroy_channel_cap(receiver.channel_cap_id).expect("Should succeed");
+        sender.send(b"", &[], &[]);
+        assert!(matches!(sender.output_error(), DeferredError::ChannelClosed));
+    }
+
+    #[test]
+    fn channel_new_makes_both_ends_and_one_can_be_sent_to_another_app() {
+        let (mut editor, mut previewer) = TestApp::connected_pair();
+
+        editor.channel_space.new_channel_caps(editor.output_shm_cap_id, &mut editor.shm_space.lock().unwrap()).expect("Should succeed");
+        let [kept_channel_cap_id, sent_channel_cap_id]: [ChannelCapId; 2] = postcard::from_bytes(editor.shm_space.lock().unwrap().get_shm_cap_app(editor.output_shm_cap_id).expect("Should succeed").backing()).expect("Should succeed");
+
+        editor.send(b"", &[], &[sent_channel_cap_id]);
+        assert!(matches!(editor.channel_space.destroy_channel_cap(sent_channel_cap_id), Err(ChannelSpaceError::DeferredSpaceError { source: DeferredSpaceError::CapNotFound { .. } })));
+
+        let received_channel_cap_id = previewer.recv().channel_cap_ids[0];
+        editor.channel_cap_id = kept_channel_cap_id;
+        previewer.channel_cap_id = received_channel_cap_id;
+        previewer.send(b"rendered", &[], &[]);
+        assert_eq!(b"rendered", &editor.recv().payload[..]);
+    }
+
+    #[test]
+    fn channel_send_refuses_end_of_same_channel() {
+        let (mut sender, _receiver) = TestApp::connected_pair();
+
+        sender.send(b"", &[], &[sender.channel_cap_id]);
+
+        assert!(matches!(sender.output_error(), DeferredError::ChannelCapsNotSendable));
+        assert!(sender.channel_space.channels.contains_key(&sender.channel_cap_id));
+    }
+
+    #[test]
+    fn check_snapshottable_allows_only_host_channel() {
+        let channel_space = ChannelSpace::new();
+        assert!(channel_space.check_snapshottable().is_ok());
+
+        let (sender, _receiver) = TestApp::connected_pair();
+        assert!(matches!(sender.channel_space.check_snapshottable(), Err(SnapshotError::OpenChannels)));
+    }
+}
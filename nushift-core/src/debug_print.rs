@@ -1,17 +1,23 @@
 // Copyright 2023 The Nushift Authors.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::sync::Arc;
+
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 use postcard::Error as PostcardError;
 
+use crate::hypervisor::hypervisor_event::UnboundHypervisorEvent;
+use crate::hypervisor::tab_context::TabContext;
 use crate::shm_space::{ShmCapId, ShmSpace, ShmSpaceError};
 
-pub struct DebugPrint;
+pub struct DebugPrint {
+    tab_context: Arc<dyn TabContext>,
+}
 
 impl DebugPrint {
-    pub fn new() -> Self {
-        Self
+    pub fn new(tab_context: Arc<dyn TabContext>) -> Self {
+        Self { tab_context }
     }
 
     pub fn debug_print(&self, input_shm_cap_id: ShmCapId, shm_space: &ShmSpace) -> Result<(), DebugPrintError> {
@@ -25,6 +31,12 @@ impl DebugPrint {
 
         tracing::debug!("DebugPrint: {}", debug_message);
 
+        // Debug prints are best-effort, so don't fail the syscall if nobody is
+        // listening.
+        if let Err(hypervisor_event_error) = self.tab_context.send_hypervisor_event(UnboundHypervisorEvent::DebugPrint(debug_message.into())) {
+            tracing::warn!("Could not send DebugPrint event: {hypervisor_event_error:?}");
+        }
+
         Ok(())
     }
 }
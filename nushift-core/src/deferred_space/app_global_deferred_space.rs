@@ -3,17 +3,21 @@
 
 use core::mem;
 use std::collections::{HashMap, hash_map::{Entry, VacantEntry}};
+use std::sync::Mutex;
 
 use itertools::Itertools;
 use postcard::Error as PostcardError;
 use reusable_id_pool::{ReusableIdPoolManual, ReusableIdPoolError};
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 
 use crate::accessibility_tree_space::AccessibilityTreeCapId;
+use crate::channel_space::ChannelCapId;
 use crate::gfx_space::{GfxCapId, GfxCpuPresentBufferCapId};
-use crate::nushift_subsystem::BlockingOnTasksCondvar;
-use crate::shm_space::{ShmCapId, ShmSpace, ShmSpaceError};
+use crate::hypervisor::tab_control::TabControl;
+use crate::shm_space::{ShmCapId, ShmSpaceError, ShmSpaceMutex};
+use crate::snapshot::{self, SnapshotError, TasksInProgressSnafu};
 use crate::title_space::TitleCapId;
 
 pub type TaskId = u64;
@@ -25,13 +29,25 @@ pub enum Task {
     TitlePublish { title_cap_id: TitleCapId },
     GfxGetOutputs { gfx_cap_id: GfxCapId },
     GfxCpuPresent { gfx_cpu_present_buffer_cap_id: GfxCpuPresentBufferCapId },
+    ChannelSend { channel_cap_id: ChannelCapId },
+    ChannelRecv { channel_cap_id: ChannelCapId },
 }
 
 enum ScheduledTask {
+    /// Pushed, but not yet handed to a worker.
     Waiting(Task),
+    /// Handed to a worker, which hasn't finished it yet.
+    Running,
     Finished,
 }
 
+/// Tasks that the app hasn't blocked on yet, so that it still can after being
+/// restored.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AppGlobalDeferredSpaceSnapshot {
+    finished_task_ids: Vec<TaskId>,
+}
+
 pub struct AppGlobalDeferredSpace {
     id_pool: ReusableIdPoolManual,
     space: HashMap<TaskId, ScheduledTask>,
@@ -57,43 +73,113 @@ impl AppGlobalDeferredSpace {
         Ok(TaskAllocation::new(task_id, task, vacant_entry, &mut self.id_pool))
     }
 
-    /// Sets all tasks to finished.
-    ///
-    /// Returns only tasks that were previously waiting and are now finished.
-    pub fn finish_tasks(&mut self) -> Vec<(TaskId, Task)> {
+    /// Sets all waiting tasks to running, and returns them, so that they can
+    /// be handed to workers. Each worker must call `finish_task` once it's
+    /// done.
+    pub fn start_tasks(&mut self) -> Vec<(TaskId, Task)> {
         let mut tasks = vec![];
         for (task_id, scheduled_task) in self.space.iter_mut() {
-            if let ScheduledTask::Waiting(task) = mem::replace(scheduled_task, ScheduledTask::Finished) {
-                tasks.push((*task_id, task))
+            if matches!(scheduled_task, ScheduledTask::Waiting(_)) {
+                if let ScheduledTask::Waiting(task) = mem::replace(scheduled_task, ScheduledTask::Running) {
+                    tasks.push((*task_id, task))
+                }
             }
         }
         tasks
     }
 
-    pub fn block_on_deferred_tasks(&mut self, input_shm_cap_id: ShmCapId, shm_space: &ShmSpace, blocking_on_tasks_condvar: &BlockingOnTasksCondvar) -> Result<(), AppGlobalDeferredSpaceError> {
-        let input_shm_cap = shm_space.get_shm_cap_app(input_shm_cap_id).map_err(|shm_space_error| match shm_space_error {
-            ShmSpaceError::CapNotFound => ShmCapNotFoundSnafu { id: input_shm_cap_id }.build(),
-            ShmSpaceError::PermissionDenied => ShmPermissionDeniedSnafu { id: input_shm_cap_id }.build(),
-            _ => ShmUnexpectedSnafu.build(),
-        })?;
+    /// Sets a running task to finished. Does nothing if it isn't running.
+    pub fn finish_task(&mut self, task_id: TaskId) {
+        if let Some(scheduled_task @ ScheduledTask::Running) = self.space.get_mut(&task_id) {
+            *scheduled_task = ScheduledTask::Finished;
+        }
+    }
+
+    /// Only finished tasks can be snapshotted. Waiting tasks refer to caps
+    /// that are in progress in the other deferred spaces, which can't be
+    /// snapshotted either.
+    pub(crate) fn snapshot(&self) -> Result<AppGlobalDeferredSpaceSnapshot, SnapshotError> {
+        ensure!(self.space.values().all(|scheduled_task| matches!(scheduled_task, ScheduledTask::Finished)), TasksInProgressSnafu);
 
-        let task_ids = postcard::from_bytes(input_shm_cap.backing()).context(DeserializeTaskIdsSnafu)?;
-        self.validate_task_ids(&task_ids)?;
+        Ok(AppGlobalDeferredSpaceSnapshot { finished_task_ids: self.space.keys().copied().collect() })
+    }
 
-        // Consume tasks that are already finished even at this start point.
-        let unfinished_task_ids = self.consume_finished_tasks(task_ids);
+    pub(crate) fn restore(app_global_deferred_space_snapshot: AppGlobalDeferredSpaceSnapshot) -> Result<Self, SnapshotError> {
+        let AppGlobalDeferredSpaceSnapshot { finished_task_ids } = app_global_deferred_space_snapshot;
+        let id_pool = snapshot::restore_id_pool(finished_task_ids.iter().copied())?;
 
-        if unfinished_task_ids.is_empty() {
-            return Ok(());
-        }
+        Ok(Self {
+            id_pool,
+            space: finished_task_ids.into_iter().map(|task_id| (task_id, ScheduledTask::Finished)).collect(),
+        })
+    }
+
+    /// Neither `space` nor `shm_space` stay locked while waiting, so that the
+    /// tasks being waited on can finish. Once they have, they're consumed too.
+    ///
+    /// Each of the app's threads can block on its own tasks at the same time,
+    /// but only one thread can block on a given task, since only one of them
+    /// can consume it.
+    ///
+    /// The hart counts as parked while it waits, since a task may be waiting
+    /// on another app, e.g. for a channel message.
+    pub fn block_on_deferred_tasks(space: &Mutex<Self>, input_shm_cap_id: ShmCapId, shm_space: &ShmSpaceMutex, tab_control: &TabControl) -> Result<(), AppGlobalDeferredSpaceError> {
+        let (lock, cvar) = tab_control.blocking_on_tasks();
+        let (mut guard, unfinished_task_ids) = {
+            let mut space = space.lock().unwrap();
+            let task_ids = {
+                let shm_space = shm_space.lock().unwrap();
+                let input_shm_cap = shm_space.get_shm_cap_app(input_shm_cap_id).map_err(|shm_space_error| match shm_space_error {
+                    ShmSpaceError::CapNotFound => ShmCapNotFoundSnafu { id: input_shm_cap_id }.build(),
+                    ShmSpaceError::PermissionDenied => ShmPermissionDeniedSnafu { id: input_shm_cap_id }.build(),
+                    _ => ShmUnexpectedSnafu.build(),
+                })?;
+
+                postcard::from_bytes(input_shm_cap.backing()).context(DeserializeTaskIdsSnafu)?
+            };
+            space.validate_task_ids(&task_ids)?;
+
+            // Consume tasks that are already finished even at this start point.
+            let unfinished_task_ids = space.consume_finished_tasks(task_ids);
+
+            if unfinished_task_ids.is_empty() {
+                return Ok(());
+            }
+
+            // Say which tasks are being waited on before unlocking the space,
+            // since they can only be finished once it's unlocked. Otherwise,
+            // they could finish before being waited on, and never wake us up.
+            let mut guard = lock.lock().unwrap();
+            let already_blocked_on_task_ids: Vec<TaskId> = unfinished_task_ids.iter()
+                .copied()
+                .filter(|task_id| guard.contains(task_id))
+                .collect();
+            if !already_blocked_on_task_ids.is_empty() {
+                return AlreadyBlockedOnSnafu { already_blocked_on_task_ids }.fail();
+            }
+            guard.extend(unfinished_task_ids.iter().copied());
+            tab_control.block_hart();
+            (guard, unfinished_task_ids)
+        };
 
-        // Wait on condvar for remaining tasks.
-        let (lock, cvar) = &**blocking_on_tasks_condvar;
-        let mut guard = lock.lock().unwrap();
-        *guard = unfinished_task_ids.into_iter().collect();
-        while !guard.is_empty() {
+        // Wait on condvar for remaining tasks, which the workers remove as they
+        // finish. Closing the tab also notifies the condvar, in which case stop
+        // waiting, since the tasks are never going to finish.
+        while unfinished_task_ids.iter().any(|task_id| guard.contains(task_id)) {
+            if tab_control.is_cancelled() {
+                for task_id in &unfinished_task_ids {
+                    guard.remove(task_id);
+                }
+                drop(guard);
+                tab_control.unblock_hart();
+                return CancelledSnafu.fail();
+            }
             guard = cvar.wait(guard).unwrap();
         }
+        drop(guard);
+        tab_control.unblock_hart();
+
+        space.lock().unwrap().consume_finished_tasks(unfinished_task_ids);
 
         Ok(())
     }
@@ -185,15 +271,21 @@ pub enum AppGlobalDeferredSpaceError {
     Duplicates { duplicate_task_ids: Vec<TaskId> },
     #[snafu(display("Tasks with task IDs {not_found_task_ids:?} not found."))]
     NotFound { not_found_task_ids: Vec<TaskId> },
+    #[snafu(display("Another thread is already blocked on tasks with task IDs {already_blocked_on_task_ids:?}."))]
+    AlreadyBlockedOn { already_blocked_on_task_ids: Vec<TaskId> },
     #[snafu(display("The SHM cap with ID {id} was not found."))]
     ShmCapNotFound { id: ShmCapId },
     #[snafu(display("The SHM cap with ID {id} is not allowed to be used as an input cap, possibly because it is an ELF cap."))]
     ShmPermissionDenied { id: ShmCapId },
     ShmUnexpectedError,
+    #[snafu(display("The tab was closed, or another thread stopped the app, while waiting on deferred tasks."))]
+    Cancelled,
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::shm_space::{CapType, PagingScheme, ShmSpace, ShmType};
+
     use super::*;
 
     #[test]
@@ -225,7 +317,7 @@ mod tests {
     }
 
     #[test]
-    fn finish_tasks() {
+    fn start_tasks_and_finish_task() {
         let mut space = AppGlobalDeferredSpace::new();
 
         {
@@ -236,9 +328,13 @@ mod tests {
         // Should be 1 entry in space before finishing
         assert_eq!(1, space.space.len());
 
-        // Finished tasks should be returned
-        let tasks = space.finish_tasks();
+        // Started tasks should be returned, once
+        let tasks = space.start_tasks();
         assert_eq!(vec![(0, Task::TitlePublish { title_cap_id: 0 })], tasks);
+        assert!(space.start_tasks().is_empty());
+        assert!(matches!(space.space.get(&0), Some(ScheduledTask::Running)));
+
+        space.finish_task(0);
 
         // Should still be 1 entry in space
         assert_eq!(1, space.space.len());
@@ -286,7 +382,8 @@ mod tests {
             task.task_id
         };
 
-        space.finish_tasks();
+        space.start_tasks();
+        space.finish_task(task_id);
         space.consume_finished_tasks(vec![task_id]);
 
         // There should be no entries in the space
@@ -297,4 +394,148 @@ mod tests {
         space.id_pool.release(0);
         assert_eq!(0, space.space.len());
     }
+
+    #[test]
+    fn snapshot_only_allowed_once_tasks_are_finished() {
+        let mut space = AppGlobalDeferredSpace::new();
+        let task_id = space.allocate_task(Task::TitlePublish { title_cap_id: 0 }).expect("Should work").push_task();
+
+        assert!(matches!(space.snapshot(), Err(SnapshotError::TasksInProgress)));
+
+        space.start_tasks();
+        assert!(matches!(space.snapshot(), Err(SnapshotError::TasksInProgress)));
+
+        space.finish_task(task_id);
+        let restored = AppGlobalDeferredSpace::restore(space.snapshot().expect("Should succeed")).expect("Should succeed");
+
+        assert!(matches!(restored.space.get(&task_id), Some(ScheduledTask::Finished)));
+    }
+
+    #[test]
+    fn block_on_deferred_tasks_waits_for_task_then_consumes_it() {
+        let mut space = AppGlobalDeferredSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let tab_control = TabControl::new();
+
+        let task_id = space.allocate_task(Task::TitlePublish { title_cap_id: 0 }).expect("Should work").push_task();
+        space.start_tasks();
+        let (input_shm_cap_id, input_shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        postcard::to_slice(&vec![task_id], input_shm_cap.backing_mut()).expect("Should succeed");
+        let (space, shm_space) = (Mutex::new(space), ShmSpaceMutex::new(shm_space));
+
+        let result = std::thread::scope(|scope| {
+            let blocked = scope.spawn(|| AppGlobalDeferredSpace::block_on_deferred_tasks(&space, input_shm_cap_id, &shm_space, &tab_control));
+
+            while tab_control.blocking_on_tasks().0.lock().unwrap().is_empty() {
+                std::thread::yield_now();
+            }
+            // What a worker does when it finishes a task.
+            space.lock().unwrap().finish_task(task_id);
+            let (lock, cvar) = tab_control.blocking_on_tasks();
+            lock.lock().unwrap().remove(&task_id);
+            cvar.notify_all();
+
+            blocked.join().expect("Should not panic")
+        });
+
+        assert!(matches!(result, Ok(())));
+        assert!(space.lock().unwrap().space.is_empty());
+    }
+
+    #[test]
+    fn block_on_deferred_tasks_counts_as_parked_until_resumed() {
+        let mut space = AppGlobalDeferredSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let tab_control = TabControl::new();
+
+        let task_id = space.allocate_task(Task::TitlePublish { title_cap_id: 0 }).expect("Should work").push_task();
+        space.start_tasks();
+        let (input_shm_cap_id, input_shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        postcard::to_slice(&vec![task_id], input_shm_cap.backing_mut()).expect("Should succeed");
+        let (space, shm_space) = (Mutex::new(space), ShmSpaceMutex::new(shm_space));
+
+        let result = std::thread::scope(|scope| {
+            let blocked = scope.spawn(|| AppGlobalDeferredSpace::block_on_deferred_tasks(&space, input_shm_cap_id, &shm_space, &tab_control));
+
+            while tab_control.blocking_on_tasks().0.lock().unwrap().is_empty() {
+                std::thread::yield_now();
+            }
+            // The workers don't run tasks while paused, so this would never
+            // return if the blocked machine didn't count as parked.
+            tab_control.pause();
+
+            space.lock().unwrap().finish_task(task_id);
+            let (lock, cvar) = tab_control.blocking_on_tasks();
+            lock.lock().unwrap().remove(&task_id);
+            cvar.notify_all();
+            assert!(!blocked.is_finished());
+
+            tab_control.resume();
+            blocked.join().expect("Should not panic")
+        });
+
+        assert!(matches!(result, Ok(())));
+        assert!(space.lock().unwrap().space.is_empty());
+    }
+
+    #[test]
+    fn block_on_deferred_tasks_stops_waiting_when_cancelled() {
+        let mut space = AppGlobalDeferredSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let tab_control = TabControl::new();
+
+        let task_id = space.allocate_task(Task::TitlePublish { title_cap_id: 0 }).expect("Should work").push_task();
+        let (input_shm_cap_id, input_shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        postcard::to_slice(&vec![task_id], input_shm_cap.backing_mut()).expect("Should succeed");
+        let (space, shm_space) = (Mutex::new(space), ShmSpaceMutex::new(shm_space));
+
+        let result = std::thread::scope(|scope| {
+            let blocked = scope.spawn(|| AppGlobalDeferredSpace::block_on_deferred_tasks(&space, input_shm_cap_id, &shm_space, &tab_control));
+
+            // Wait until the task is being blocked on before cancelling, so
+            // that cancelling actually has to wake up the condvar.
+            while tab_control.blocking_on_tasks().0.lock().unwrap().is_empty() {
+                std::thread::yield_now();
+            }
+            // Neither space is locked while waiting.
+            assert!(space.try_lock().is_ok());
+            assert!(shm_space.try_lock().is_ok());
+            tab_control.cancel();
+
+            blocked.join().expect("Should not panic")
+        });
+
+        assert!(matches!(result, Err(AppGlobalDeferredSpaceError::Cancelled)));
+    }
+
+    #[test]
+    fn block_on_deferred_tasks_rejects_task_another_thread_is_blocked_on() {
+        let mut space = AppGlobalDeferredSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let tab_control = TabControl::new();
+
+        let task_id = space.allocate_task(Task::TitlePublish { title_cap_id: 0 }).expect("Should work").push_task();
+        space.start_tasks();
+        let (input_shm_cap_id, input_shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        postcard::to_slice(&vec![task_id], input_shm_cap.backing_mut()).expect("Should succeed");
+        let (space, shm_space) = (Mutex::new(space), ShmSpaceMutex::new(shm_space));
+
+        std::thread::scope(|scope| {
+            let blocked = scope.spawn(|| AppGlobalDeferredSpace::block_on_deferred_tasks(&space, input_shm_cap_id, &shm_space, &tab_control));
+
+            while tab_control.blocking_on_tasks().0.lock().unwrap().is_empty() {
+                std::thread::yield_now();
+            }
+            assert!(matches!(
+                AppGlobalDeferredSpace::block_on_deferred_tasks(&space, input_shm_cap_id, &shm_space, &tab_control),
+                Err(AppGlobalDeferredSpaceError::AlreadyBlockedOn { .. }),
+            ));
+
+            tab_control.cancel();
+            assert!(matches!(blocked.join().expect("Should not panic"), Err(AppGlobalDeferredSpaceError::Cancelled)));
+        });
+
+        // The cancelled thread stopped blocking on its task.
+        assert!(tab_control.blocking_on_tasks().0.lock().unwrap().is_empty());
+    }
 }
@@ -5,6 +5,7 @@ use std::collections::{HashMap, hash_map::Entry};
 
 use num_enum::IntoPrimitive;
 use reusable_id_pool::{ReusableIdPoolManual, ReusableIdPoolError};
+use postcard::Error as PostcardError;
 use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
@@ -13,7 +14,8 @@ use snafu_cli_debug::SnafuCliDebug;
 use mockall::automock;
 
 use crate::rollback_chain::RollbackChain;
-use crate::shm_space::{OwnedShmIdAndCap, ShmCapId, ShmCap, ShmSpace, ShmSpaceError};
+use crate::snapshot::{self, SnapshotError, TasksInProgressSnafu};
+use crate::shm_space::{OwnedShmIdAndCap, ShmCapId, ShmCap, ShmSpace, ShmSpaceError, ShmSpaceMutex};
 
 pub(super) mod app_global_deferred_space;
 
@@ -190,6 +192,40 @@ impl DefaultDeferredSpace {
     pub fn new() -> Self {
         Self::new_with_id_pool(ReusableIdPoolManual::new())
     }
+
+    /// Fails if any cap is in progress, because its SHM caps have been moved
+    /// out of the SHM space and won't be in the SHM space's snapshot.
+    pub(crate) fn snapshot(&self) -> Result<DefaultDeferredSpaceSnapshot, SnapshotError> {
+        ensure!(self.space.values().all(|cap| cap.in_progress_cap.is_none()), TasksInProgressSnafu);
+
+        Ok(DefaultDeferredSpaceSnapshot { cap_ids: self.space.keys().copied().collect() })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.space.len()
+    }
+
+    /// Whether the cap exists and is currently being processed.
+    pub(crate) fn is_in_progress(&self, cap_id: DefaultDeferredSpaceCapId) -> bool {
+        matches!(self.space.get(&cap_id), Some(DefaultDeferredCap { in_progress_cap: Some(_) }))
+    }
+
+    pub(crate) fn restore(default_deferred_space_snapshot: DefaultDeferredSpaceSnapshot) -> Result<Self, SnapshotError> {
+        let DefaultDeferredSpaceSnapshot { cap_ids } = default_deferred_space_snapshot;
+        let id_pool = snapshot::restore_id_pool(cap_ids.iter().copied())?;
+
+        Ok(Self {
+            id_pool,
+            space: cap_ids.into_iter().map(|cap_id| (cap_id, DefaultDeferredCap::new())).collect(),
+        })
+    }
+}
+
+/// Deferred caps only have state while they're in progress, so all we need is
+/// which ones exist.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DefaultDeferredSpaceSnapshot {
+    cap_ids: Vec<DefaultDeferredSpaceCapId>,
 }
 
 impl<I: IdPoolBacking> DefaultDeferredSpace<I> {
@@ -326,7 +362,11 @@ impl<I: IdPoolBacking> DefaultDeferredSpace<I> {
 
     /// The Err(()) variant is only used for internal errors. All other errors
     /// should be reported through the output cap.
-    pub fn publish_deferred<S>(&mut self, deferred_space_specific: &mut S, cap_id: DefaultDeferredSpaceCapId, shm_space: &mut ShmSpace) -> Result<(), ()>
+    ///
+    /// The SHM caps being processed aren't in the SHM space, so the SHM space
+    /// is only locked at the end, to move them back in. Until then, the app's
+    /// memory accesses carry on.
+    pub fn publish_deferred<S>(&mut self, deferred_space_specific: &mut S, cap_id: DefaultDeferredSpaceCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()>
     where
         S: DeferredSpacePublish,
     {
@@ -346,12 +386,14 @@ impl<I: IdPoolBacking> DefaultDeferredSpace<I> {
             }
         }
 
-        self.get_or_publish_deferred_epilogue(cap_id, shm_space)
+        self.get_or_publish_deferred_epilogue(cap_id, &mut shm_space.lock().unwrap())
     }
 
     /// The Err(()) variant is only used for internal errors. All other errors
     /// should be reported through the output cap.
-    pub fn get_deferred<S>(&mut self, deferred_space_specific: &mut S, cap_id: DefaultDeferredSpaceCapId, shm_space: &mut ShmSpace) -> Result<(), ()>
+    ///
+    /// Like `publish_deferred`, this only locks the SHM space at the end.
+    pub fn get_deferred<S>(&mut self, deferred_space_specific: &mut S, cap_id: DefaultDeferredSpaceCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()>
     where
         S: DeferredSpaceGet,
     {
@@ -363,14 +405,12 @@ impl<I: IdPoolBacking> DefaultDeferredSpace<I> {
 
         deferred_space_specific.get(output_shm_cap);
 
-        self.get_or_publish_deferred_epilogue(cap_id, shm_space)
+        self.get_or_publish_deferred_epilogue(cap_id, &mut shm_space.lock().unwrap())
     }
 }
 
 pub fn print_success<T: Serialize>(output_shm_cap: &mut ShmCap, payload: T) {
-    let output = DeferredOutput::Success(payload);
-
-    match postcard::to_slice(&output, output_shm_cap.backing_mut()) {
+    match try_print_success(output_shm_cap, payload) {
         Ok(_) => {}
         Err(postcard_error) => {
             tracing::debug!("Postcard serialise error: {postcard_error}");
@@ -379,6 +419,15 @@ pub fn print_success<T: Serialize>(output_shm_cap: &mut ShmCap, payload: T) {
     }
 }
 
+/// Like `print_success`, but the error is returned rather than printed, for
+/// when the payload describes something that has to be undone if the app never
+/// finds out about it.
+pub fn try_print_success<T: Serialize>(output_shm_cap: &mut ShmCap, payload: T) -> Result<(), PostcardError> {
+    let output = DeferredOutput::Success(payload);
+
+    postcard::to_slice(&output, output_shm_cap.backing_mut()).map(|_| ())
+}
+
 pub fn print_error(output_shm_cap: &mut ShmCap, deferred_error: DeferredError, error: &dyn core::fmt::Display) {
     let output = DeferredOutput::<()>::Error(DeferredErrorWithMessage::new(deferred_error, error.to_string()));
 
@@ -432,6 +481,12 @@ pub enum DeferredError {
     ExtraInfoNoLongerPresent = 3,
     SerializeError = 4,
     GfxInconsistentPresentBufferLength = 5,
+    ChannelClosed = 6,
+    ChannelFull = 7,
+    ChannelShmCapsNotSendable = 8,
+    ChannelShmCapsNotReceivable = 9,
+    ChannelCapsNotSendable = 10,
+    ChannelCapsNotReceivable = 11,
 }
 
 #[cfg(test)]
@@ -441,7 +496,7 @@ mod tests {
     use memmap2::MmapMut;
     use mockall::predicate;
 
-    use crate::shm_space::{acquisitions_and_page_table::PageTableError, CapType, ShmType};
+    use crate::shm_space::{acquisitions_and_page_table::PageTableError, translation_cache::TranslationCache, CapType, PagingScheme, ShmType};
 
     use super::*;
 
@@ -555,6 +610,32 @@ mod tests {
         assert!(matches!(default_deferred_space.destroy_cap("test", cap_id), Err(DeferredSpaceError::InProgress { context }) if context == "test"));
     }
 
+    #[test]
+    fn snapshot_restore_keeps_cap_ids() {
+        let mut default_deferred_space = DefaultDeferredSpace::new();
+        let cap_id_1 = default_deferred_space.new_cap("test").expect("Should succeed");
+        let cap_id_2 = default_deferred_space.new_cap("test").expect("Should succeed");
+        default_deferred_space.destroy_cap("test", cap_id_1).expect("Should succeed");
+
+        let mut restored = DefaultDeferredSpace::restore(default_deferred_space.snapshot().expect("Should succeed")).expect("Should succeed");
+
+        assert!(!restored.contains_key(cap_id_1));
+        assert!(restored.contains_key(cap_id_2));
+        assert_eq!(cap_id_1, restored.new_cap("test").expect("Should succeed"));
+    }
+
+    #[test]
+    fn snapshot_tasks_in_progress_error_if_in_progress() {
+        let mut default_deferred_space = DefaultDeferredSpace::new();
+
+        let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
+
+        let output_shm_cap = ShmCap::new(ShmType::FourKiB, NonZeroU64::new(1).expect("Should work"), MmapMut::map_anon(8).expect("Should work"), CapType::AppCap);
+        *default_deferred_space.space.get_mut(&cap_id).expect("Should exist") = DefaultDeferredCap { in_progress_cap: Some(InProgressCap::new(None, (0, output_shm_cap))) };
+
+        assert!(matches!(default_deferred_space.snapshot(), Err(SnapshotError::TasksInProgress)));
+    }
+
     #[test]
     fn get_or_publish_deferred_prologue_ok_publish() {
         let mut default_deferred_space = DefaultDeferredSpace::new();
@@ -586,7 +667,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
@@ -607,7 +688,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         let output_shm_cap = shm_space.move_shm_cap_to_other_space(output_shm_cap_id).expect("Should succeed");
@@ -623,7 +704,7 @@ mod tests {
     fn get_or_publish_deferred_epilogue_internal_error_does_not_exist() {
         let mut default_deferred_space = DefaultDeferredSpace::new();
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
 
         assert!(matches!(default_deferred_space.get_or_publish_deferred_epilogue(0, &mut shm_space), Err(())));
     }
@@ -636,7 +717,7 @@ mod tests {
 
         *default_deferred_space.space.get_mut(&cap_id).expect("Should exist") = DefaultDeferredCap { in_progress_cap: None };
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
 
         assert!(matches!(default_deferred_space.get_or_publish_deferred_epilogue(cap_id, &mut shm_space), Err(())));
     }
@@ -647,7 +728,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         shm_space.acquire_shm_cap_app(input_shm_cap_id, 0x1000).expect("Should succeed");
@@ -657,8 +738,8 @@ mod tests {
         assert!(matches!(default_deferred_space.publish_blocking("test", cap_id, input_shm_cap_id, output_shm_cap_id, &mut shm_space), Ok(())));
 
         // Assert caps released
-        assert!(matches!(shm_space.walk(0x1000), Err(PageTableError::PageNotFound)));
-        assert!(matches!(shm_space.walk(0x2000), Err(PageTableError::PageNotFound)));
+        assert!(matches!(TranslationCache::new().walk(&shm_space, 0x1000), Err(PageTableError::PageNotFound)));
+        assert!(matches!(TranslationCache::new().walk(&shm_space, 0x2000), Err(PageTableError::PageNotFound)));
 
         // Assert caps moved out
         assert!(matches!(shm_space.get_shm_cap_app(input_shm_cap_id), Err(ShmSpaceError::CapNotFound)));
@@ -675,7 +756,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
@@ -697,7 +778,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         shm_space.acquire_shm_cap_app(output_shm_cap_id, 0x2000).expect("Should succeed");
 
@@ -705,7 +786,7 @@ mod tests {
         assert!(matches!(default_deferred_space.get_blocking("test", cap_id, output_shm_cap_id, &mut shm_space), Ok(())));
 
         // Assert cap released
-        assert!(matches!(shm_space.walk(0x2000), Err(PageTableError::PageNotFound)));
+        assert!(matches!(TranslationCache::new().walk(&shm_space, 0x2000), Err(PageTableError::PageNotFound)));
 
         // Assert cap moved out
         assert!(matches!(shm_space.get_shm_cap_app(output_shm_cap_id), Err(ShmSpaceError::CapNotFound)));
@@ -721,7 +802,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         // Assert get_blocking succeeds even though cap not acquired
@@ -741,7 +822,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
 
         // Assert publish_blocking fails, when provided with invalid SHM cap IDs
         assert!(matches!(default_deferred_space.publish_blocking("test", cap_id, 123, 456, &mut shm_space), Err(DeferredSpaceError::ShmCapNotFound { id }) if id == 123));
@@ -753,7 +834,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         shm_space.acquire_shm_cap_app(input_shm_cap_id, 0x1000).expect("Should succeed");
 
@@ -761,7 +842,7 @@ mod tests {
         assert!(matches!(default_deferred_space.publish_blocking("test", cap_id, input_shm_cap_id, 456, &mut shm_space), Err(DeferredSpaceError::ShmCapNotFound { id }) if id == 456));
 
         // Assert input cap is still acquired, i.e. the release that temporarily occurred was rolled back.
-        assert!(matches!(shm_space.walk(0x1000), Ok(_)));
+        assert!(matches!(TranslationCache::new().walk(&shm_space, 0x1000), Ok(_)));
     }
 
     #[test]
@@ -770,7 +851,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         // Assert publish_blocking fails with the expected error that the output
@@ -783,7 +864,7 @@ mod tests {
     fn get_or_publish_blocking_error_if_not_found() {
         let mut default_deferred_space = DefaultDeferredSpace::new();
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
 
         assert!(matches!(default_deferred_space.get_or_publish_blocking("test", 0, Some(123), 456, &mut shm_space), Err(DeferredSpaceError::CapNotFound { context, id }) if context == "test" && id == 0));
     }
@@ -797,7 +878,7 @@ mod tests {
         let output_shm_cap = ShmCap::new(ShmType::FourKiB, NonZeroU64::new(1).expect("Should work"), MmapMut::map_anon(8).expect("Should work"), CapType::AppCap);
         *default_deferred_space.space.get_mut(&cap_id).expect("Should exist") = DefaultDeferredCap { in_progress_cap: Some(InProgressCap::new(None, (0, output_shm_cap))) };
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
 
         assert!(matches!(default_deferred_space.get_or_publish_blocking("test", 0, Some(123), 456, &mut shm_space), Err(DeferredSpaceError::InProgress { context }) if context == "test"));
     }
@@ -808,7 +889,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
@@ -830,7 +911,9 @@ mod tests {
             }
         }
 
-        assert!(matches!(default_deferred_space.publish_deferred(&mut TestPublish, cap_id, &mut shm_space), Ok(())));
+        let shm_space = ShmSpaceMutex::new(shm_space);
+        assert!(matches!(default_deferred_space.publish_deferred(&mut TestPublish, cap_id, &shm_space), Ok(())));
+        let shm_space = shm_space.lock().unwrap();
         let output_shm_cap = shm_space.get_shm_cap_app(output_shm_cap_id).expect("Should be moved back into space");
         assert!(matches!(postcard::from_bytes(output_shm_cap.backing()), Ok(DeferredOutput::Success("done"))));
     }
@@ -839,7 +922,7 @@ mod tests {
     fn publish_deferred_internal_error() {
         let mut default_deferred_space = DefaultDeferredSpace::new();
 
-        let mut shm_space = ShmSpace::new();
+        let shm_space = ShmSpaceMutex::new(ShmSpace::new(PagingScheme::Sv39));
 
         struct TestPublish;
 
@@ -851,7 +934,7 @@ mod tests {
             }
         }
 
-        assert!(matches!(default_deferred_space.publish_deferred(&mut TestPublish, 0, &mut shm_space), Err(())));
+        assert!(matches!(default_deferred_space.publish_deferred(&mut TestPublish, 0, &shm_space), Err(())));
     }
 
     #[test]
@@ -860,7 +943,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (input_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
@@ -881,7 +964,9 @@ mod tests {
             }
         }
 
-        assert!(matches!(default_deferred_space.publish_deferred(&mut TestPublish, cap_id, &mut shm_space), Ok(())));
+        let shm_space = ShmSpaceMutex::new(shm_space);
+        assert!(matches!(default_deferred_space.publish_deferred(&mut TestPublish, cap_id, &shm_space), Ok(())));
+        let shm_space = shm_space.lock().unwrap();
         let output_shm_cap = shm_space.get_shm_cap_app(output_shm_cap_id).expect("Should be moved back into space");
         assert!(matches!(postcard::from_bytes(output_shm_cap.backing()), Ok(DeferredOutput::<()>::Error(DeferredErrorWithMessage { deferred_error: DeferredError::DeserializeError, message })) if message == postcard::Error::DeserializeBadBool.to_string()));
     }
@@ -892,7 +977,7 @@ mod tests {
 
         let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
 
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         let output_shm_cap = shm_space.move_shm_cap_to_other_space(output_shm_cap_id).expect("Should succeed");
@@ -907,7 +992,9 @@ mod tests {
             }
         }
 
-        assert!(matches!(default_deferred_space.get_deferred(&mut TestGet, cap_id, &mut shm_space), Ok(())));
+        let shm_space = ShmSpaceMutex::new(shm_space);
+        assert!(matches!(default_deferred_space.get_deferred(&mut TestGet, cap_id, &shm_space), Ok(())));
+        let shm_space = shm_space.lock().unwrap();
         let output_shm_cap = shm_space.get_shm_cap_app(output_shm_cap_id).expect("Should be moved back into space");
         assert!(matches!(postcard::from_bytes(output_shm_cap.backing()), Ok(DeferredOutput::Success("done"))));
     }
@@ -916,7 +1003,7 @@ mod tests {
     fn get_deferred_internal_error() {
         let mut default_deferred_space = DefaultDeferredSpace::new();
 
-        let mut shm_space = ShmSpace::new();
+        let shm_space = ShmSpaceMutex::new(ShmSpace::new(PagingScheme::Sv39));
 
         struct TestGet;
 
@@ -926,12 +1013,37 @@ mod tests {
             }
         }
 
-        assert!(matches!(default_deferred_space.get_deferred(&mut TestGet, 0, &mut shm_space), Err(())));
+        assert!(matches!(default_deferred_space.get_deferred(&mut TestGet, 0, &shm_space), Err(())));
+    }
+
+    #[test]
+    fn get_deferred_does_not_lock_shm_space_while_processing() {
+        let mut default_deferred_space = DefaultDeferredSpace::new();
+
+        let cap_id = default_deferred_space.new_cap("test").expect("Should succeed");
+
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+
+        let output_shm_cap = shm_space.move_shm_cap_to_other_space(output_shm_cap_id).expect("Should succeed");
+
+        *default_deferred_space.space.get_mut(&cap_id).expect("Should exist") = DefaultDeferredCap { in_progress_cap: Some(InProgressCap::new(None, (output_shm_cap_id, output_shm_cap))) };
+
+        struct TestGet<'a>(&'a ShmSpaceMutex);
+
+        impl DeferredSpaceGet for TestGet<'_> {
+            fn get(&mut self, _output_shm_cap: &mut ShmCap) {
+                assert!(self.0.try_lock().is_ok());
+            }
+        }
+
+        let shm_space = ShmSpaceMutex::new(shm_space);
+        assert!(matches!(default_deferred_space.get_deferred(&mut TestGet(&shm_space), cap_id, &shm_space), Ok(())));
     }
 
     #[test]
     fn print_success_ok() {
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (_, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         print_success(shm_cap, "test");
@@ -941,7 +1053,7 @@ mod tests {
 
     #[test]
     fn print_success_serialize_error() {
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (_, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         let too_big = vec![0u8; 8192];
@@ -952,7 +1064,7 @@ mod tests {
 
     #[test]
     fn print_error_ok() {
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
         let (_, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
 
         print_error(shm_cap, DeferredError::DeserializeError, &"some additional message");
@@ -1,25 +1,40 @@
 // Copyright 2023 The Nushift Authors.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{collections::{BTreeMap, HashMap}, ops::Bound};
+use std::{collections::{BTreeMap, hash_map::RandomState}, hash::{BuildHasher, Hasher}, ops::Bound};
 
-use elfloader::{ElfLoader, ElfLoaderErr, LoadableHeaders, Flags, VAddr, RelocationEntry};
+use elfloader::{ElfBinary, ElfLoader, ElfLoaderErr, LoadableHeaders, Flags, ProgramHeader, VAddr, RelocationEntry};
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
+use xmas_elf::{ElfFile, header, program::Type, sections::SectionData};
 
-use crate::shm_space::{SV39_BITS, CapType, ShmSpace, ShmType, ShmCapId, acquisitions_and_page_table::Sv39Flags};
+use crate::shm_space::{CapType, PagingScheme, ShmSpace, ShmSpaceError, ShmType, ShmCapId, acquisitions_and_page_table::Sv39Flags};
 
 // The loader in this file should be robust against:
 //
 // * Overlapping ELF LOAD headers (including fully overlapping, i.e. specifying the same base and size)
 // * Integer overflowing ELF LOAD headers when you add the base and size
 // * ELF LOAD headers with a size of 0
-// * An ELF with so many LOAD headers or with such a large total size that it exhausts our Sv39 stats capacity
+// * An ELF with so many LOAD headers or with such a large total size that it exhausts our Sv39 (or Sv32) stats capacity
 //
 // And more. As far as I know, the current implementation as of writing this
 // comment is robust against these four, returning an ELF loading error for
 // these four cases.
+//
+// Position-independent executables (ET_DYN) are loaded at a random base, see
+// `choose_load_base`, and every vaddr in the ELF, including in relocations, is
+// offset by it. Only the relocations that a static PIE needs are supported,
+// since there's no dynamic linker to resolve symbols against.
+//
+// elfloader doesn't know RISC-V's relocation types, and `ElfBinary::load`
+// fails on any RISC-V ELF with relocations, so `load_elf` drives the `Loader`
+// instead, and `Loader::relocate_elf` reads `.rela.dyn` itself.
+
+/// From the RISC-V ELF psABI.
+const R_RISCV_RELATIVE: u32 = 3;
 
+/// The byte ranges of the sections so far, to reject sections that overlap.
+/// Sections that only share a page are fine, see `MergedSections`.
 struct CheckedSections(BTreeMap<u64, u64>);
 
 impl CheckedSections {
@@ -27,51 +42,371 @@ impl CheckedSections {
         Self(BTreeMap::new())
     }
 
-    fn add_region(&mut self, vpn: u64, number_of_pages: u64) -> Result<(), CheckedSectionsError> {
-        // This check is correct because vpn is off from u64::MAX by a factor of
-        // 4096 (if we did reach u64::MAX, it wouldn't be correct because it
-        // would be valid to have a number of pages that reaches the end
-        // overflowing to 0 exactly).
-        let end_vpn = vpn.checked_add(number_of_pages).ok_or(VpnPlusNumPagesOverflowSnafu.build())?;
+    fn add_region(&mut self, start: u64, length: u64) -> Result<(), CheckedSectionsError> {
+        // This check is correct because sections have already been checked to
+        // be within the app's address space, which is far from u64::MAX (if we
+        // did reach u64::MAX, it wouldn't be correct because it would be valid
+        // to have a length that reaches the end overflowing to 0 exactly).
+        let end = start.checked_add(length).ok_or(StartPlusLengthOverflowSnafu.build())?;
 
         // Check if the equal or below entry intersects.
-        let mut equal_or_below = self.0.range((Bound::Unbounded, Bound::Included(&vpn)));
+        let mut equal_or_below = self.0.range((Bound::Unbounded, Bound::Included(&start)));
         let equal_or_below = equal_or_below.next_back();
 
         match equal_or_below {
-            Some((&existing_vpn, &existing_num_pages)) if existing_vpn.checked_add(existing_num_pages).expect("Should be impossible for existing entry to overflow because we validated inputs") > vpn => return OverlapsSnafu.fail(),
+            Some((&existing_start, &existing_length)) if existing_start.checked_add(existing_length).expect("Should be impossible for existing entry to overflow because we validated inputs") > start => return OverlapsSnafu.fail(),
             _ => {}
         }
 
         // Check if intersects the above entry.
-        let mut above = self.0.range((Bound::Excluded(&vpn), Bound::Unbounded));
+        let mut above = self.0.range((Bound::Excluded(&start), Bound::Unbounded));
         let above = above.next();
 
         match above {
-            Some((&above_vpn, _)) if end_vpn > above_vpn => return OverlapsSnafu.fail(),
+            Some((&above_start, _)) if end > above_start => return OverlapsSnafu.fail(),
             _ => {}
         }
 
-        self.0.insert(vpn, number_of_pages);
+        self.0.insert(start, length);
         Ok(())
     }
 }
 
 #[derive(Snafu, SnafuCliDebug)]
 enum CheckedSectionsError {
-    VpnPlusNumPagesOverflow, // Internal error and indicates a bug in Nushift's code, since the VPN and number of pages are created by us by shifting u64s >> 12
+    StartPlusLengthOverflow, // Internal error and indicates a bug in Nushift's code, since sections are checked to be within the app's address space first
     Overlaps,
 }
 
+/// The page ranges that will each be one ELF SHM cap. Linkers often put
+/// sections in the same page, e.g. `.rodata` and `.eh_frame`, or `.data` and
+/// `.bss`. A page can only be in one cap and have one set of permissions, so
+/// pages that sections share get the union of their permissions, as long as
+/// that doesn't make them both writable and executable. The pages that only
+/// one section is in keep that section's permissions, so a section can end up
+/// split over several caps.
+struct MergedSections(BTreeMap<u64, (u64, Sv39Flags)>);
+
+impl MergedSections {
+    fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    fn add_region(&mut self, vpn: u64, number_of_pages: u64, flags: Sv39Flags) -> Result<(), MergedSectionsError> {
+        let end_vpn = vpn.checked_add(number_of_pages).ok_or(MergedVpnPlusNumPagesOverflowSnafu.build())?;
+
+        // The existing regions don't overlap each other, so the ones that
+        // overlap this one are the last few that start before it ends.
+        let overlapping: Vec<(u64, u64, Sv39Flags)> = self.0.range(..end_vpn)
+            .rev()
+            .map(|(&existing_vpn, &(existing_num_pages, existing_flags))| (existing_vpn, existing_vpn + existing_num_pages, existing_flags))
+            .take_while(|&(_, existing_end_vpn, _)| existing_end_vpn > vpn)
+            .collect();
+
+        for &(_, _, existing_flags) in &overlapping {
+            ensure!(!(flags | existing_flags).contains(Sv39Flags::W | Sv39Flags::X), IncompatibleFlagsSnafu { existing_flags });
+        }
+
+        // Split this region and the ones it overlaps into ranges of pages that
+        // are in the same sections, in order. Only the pages in both get the
+        // union of the flags.
+        let mut ranges: Vec<(u64, u64, Sv39Flags)> = vec![];
+        let mut covered_up_to_vpn = vpn;
+        for &(existing_vpn, existing_end_vpn, existing_flags) in overlapping.iter().rev() {
+            self.0.remove(&existing_vpn);
+
+            if existing_vpn < vpn {
+                ranges.push((existing_vpn, vpn, existing_flags));
+            } else if existing_vpn > covered_up_to_vpn {
+                ranges.push((covered_up_to_vpn, existing_vpn, flags));
+            }
+            ranges.push((existing_vpn.max(vpn), existing_end_vpn.min(end_vpn), existing_flags | flags));
+            if existing_end_vpn > end_vpn {
+                ranges.push((end_vpn, existing_end_vpn, existing_flags));
+            }
+            covered_up_to_vpn = existing_end_vpn;
+        }
+        if covered_up_to_vpn < end_vpn {
+            ranges.push((covered_up_to_vpn, end_vpn, flags));
+        }
+
+        // Then put adjacent ranges with the same flags back together, so that
+        // e.g. `.data` and `.bss` are still one cap.
+        let mut merged_ranges: Vec<(u64, u64, Sv39Flags)> = vec![];
+        for range in ranges {
+            match merged_ranges.last_mut() {
+                Some(merged_range) if merged_range.2 == range.2 => merged_range.1 = range.1,
+                _ => merged_ranges.push(range),
+            }
+        }
+
+        for (merged_vpn, merged_end_vpn, merged_flags) in merged_ranges {
+            self.0.insert(merged_vpn, (merged_end_vpn - merged_vpn, merged_flags));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+enum MergedSectionsError {
+    MergedVpnPlusNumPagesOverflow, // Internal error and indicates a bug in Nushift's code, since the VPN and number of pages are created by us by shifting u64s >> 12
+    IncompatibleFlags { existing_flags: Sv39Flags },
+}
+
 pub struct Loader<'space> {
-    vpn_to_shm_cap_id: HashMap<u64, ShmCapId>,
+    /// The start VPN of each section, with the load base already added, to the
+    /// SHM cap backing it and its number of pages.
+    sections: BTreeMap<u64, (ShmCapId, u64)>,
+    load_base: u64,
     shm_space: &'space mut ShmSpace,
+    /// Why a section's SHM cap couldn't be made, since `ElfLoaderErr` can't
+    /// say.
+    shm_space_error: Option<ShmSpaceError>,
 }
 
 impl<'space> Loader<'space> {
-    pub fn new(shm_space: &'space mut ShmSpace) -> Self {
-        Self { vpn_to_shm_cap_id: HashMap::new(), shm_space }
+    /// `load_base` is added to every vaddr in the ELF. It should come from
+    /// `choose_load_base`.
+    pub fn new(shm_space: &'space mut ShmSpace, load_base: u64) -> Self {
+        Self { sections: BTreeMap::new(), load_base, shm_space, shm_space_error: None }
+    }
+
+    /// If loading failed because a section's SHM cap couldn't be made, e.g.
+    /// because of the tab's `max_memory_bytes`, the reason.
+    pub fn take_shm_space_error(&mut self) -> Option<ShmSpaceError> {
+        self.shm_space_error.take()
+    }
+
+    /// Applies the ELF's relocations, which must be done after `load_elf`.
+    pub fn relocate_elf(&mut self, elf_file: &ElfFile<'_>) -> Result<(), RelocationError> {
+        let Some(rela_section) = elf_file.find_section_by_name(".rela.dyn") else {
+            return Ok(());
+        };
+
+        match rela_section.get_data(elf_file).map_err(|message| MalformedRelocationsSnafu { message }.build())? {
+            SectionData::Rela64(entries) => entries.iter()
+                .try_for_each(|entry| self.relocate_one(entry.get_type(), entry.get_offset(), entry.get_addend())),
+            SectionData::Rela32(entries) => entries.iter()
+                .try_for_each(|entry| self.relocate_one(entry.get_type().into(), entry.get_offset().into(), entry.get_addend().into())),
+            _ => MalformedRelocationsSnafu { message: ".rela.dyn is not a RELA section" }.fail(),
+        }
+    }
+
+    fn relocate_one(&mut self, rtype: u32, offset: u64, addend: u64) -> Result<(), RelocationError> {
+        // Everything other than R_RISCV_RELATIVE needs a symbol, and there's no
+        // dynamic linker to resolve one.
+        ensure!(rtype == R_RISCV_RELATIVE, UnsupportedRelocationTypeSnafu { rtype, offset });
+
+        // B + A, where B is the load base and A is the addend. For RV32, the
+        // addend is truncated back to 32 bits below, so it not being sign
+        // extended doesn't matter.
+        let value = self.load_base.wrapping_add(addend);
+        let target = offset.checked_add(self.load_base).context(RelocationOutOfBoundsSnafu { offset })?;
+        let written = match self.shm_space.paging_scheme() {
+            PagingScheme::Sv32 => self.write_to_sections(target, &(value as u32).to_le_bytes()),
+            PagingScheme::Sv39 | PagingScheme::Sv48 | PagingScheme::Sv57 => self.write_to_sections(target, &value.to_le_bytes()),
+        };
+        written.map_err(|_| RelocationOutOfBoundsSnafu { offset }.build())
+    }
+
+    /// Writes `bytes` from `vaddr` (with the load base already added) on,
+    /// ignoring the sections' permissions, since both loading and relocating
+    /// write to read-only data. A section can be split over several caps (see
+    /// `MergedSections`), so this can write to more than one.
+    fn write_to_sections(&mut self, mut vaddr: u64, mut bytes: &[u8]) -> Result<(), ()> {
+        while !bytes.is_empty() {
+            let vpn = vaddr >> 12;
+            let (&start_vpn, &(shm_cap_id, number_of_pages)) = self.sections
+                .range((Bound::Unbounded, Bound::Included(&vpn)))
+                .next_back()
+                .ok_or(())?;
+            if vpn - start_vpn >= number_of_pages {
+                return Err(());
+            }
+
+            let offset = usize::try_from(vaddr - (start_vpn << 12)).map_err(|_| ())?;
+            let backing = self.shm_space.get_mut_shm_cap_elf(shm_cap_id).map_err(|_| ())?.backing_mut();
+            let length = bytes.len().min(backing.len().checked_sub(offset).ok_or(())?);
+            backing.get_mut(offset..offset + length).ok_or(())?.copy_from_slice(&bytes[..length]);
+
+            vaddr = vaddr.checked_add(u64::try_from(length).map_err(|_| ())?).ok_or(())?;
+            bytes = &bytes[length..];
+        }
+        Ok(())
+    }
+}
+
+/// Picks where to load an ELF. Fixed-address executables (ET_EXEC) are loaded
+/// where they say, i.e. a base of 0. Position-independent ones (ET_DYN) get a
+/// random base in the third quarter of the app's address space, aligned to
+/// their largest LOAD header alignment, so that their addresses aren't
+/// predictable. The rest of the address space is left for the app's own
+/// mappings.
+pub fn choose_load_base(elf_binary: &ElfBinary<'_>, paging_scheme: PagingScheme) -> u64 {
+    if !elf_binary.is_pie() {
+        return 0;
+    }
+
+    let max_align = loadable_headers(elf_binary)
+        .map(|header| header.align())
+        .max()
+        .unwrap_or(0);
+    // std seeds `RandomState` with random keys, which is all we need here.
+    let random = RandomState::new().build_hasher().finish();
+    random_load_base(max_align, paging_scheme, random)
+}
+
+fn is_load_header(header: &ProgramHeader<'_>) -> bool {
+    header.get_type() == Ok(Type::Load)
+}
+
+fn loadable_headers<'file>(elf_binary: &'file ElfBinary<'_>) -> LoadableHeaders<'file, 'file> {
+    elf_binary.program_headers().filter(is_load_header as fn(&ProgramHeader<'_>) -> bool)
+}
+
+/// Loads the LOAD headers of `elf_binary` into the loader's SHM space. This
+/// does what `ElfBinary::load` does, minus relocations, see the comment at the
+/// top of this file. Call `Loader::relocate_elf` afterwards.
+pub fn load_elf(elf_binary: &ElfBinary<'_>, loader: &mut Loader<'_>) -> Result<(), ElfLoaderErr> {
+    // The same checks as `ElfBinary::load`.
+    let elf_header = elf_binary.file.header;
+    if elf_header.pt1.version() != header::Version::Current {
+        return Err(ElfLoaderErr::UnsupportedElfVersion);
+    }
+    if elf_header.pt1.data() != header::Data::LittleEndian {
+        return Err(ElfLoaderErr::UnsupportedEndianness);
+    }
+    if !matches!(elf_header.pt1.os_abi(), header::OsAbi::SystemV | header::OsAbi::Linux) {
+        return Err(ElfLoaderErr::UnsupportedAbi);
+    }
+    if !matches!(elf_header.pt2.type_().as_type(), header::Type::Executable | header::Type::SharedObject) {
+        return Err(ElfLoaderErr::UnsupportedElfType);
+    }
+
+    loader.allocate(loadable_headers(elf_binary))?;
+
+    for header in loadable_headers(elf_binary) {
+        let region = usize::try_from(header.offset()).ok()
+            .zip(usize::try_from(header.file_size()).ok())
+            .and_then(|(offset, file_size)| elf_binary.file.input.get(offset..offset.checked_add(file_size)?))
+            .ok_or_else(|| {
+                tracing::error!(
+                    "Section at vaddr {:#x} has file offset {:#x} and file size {:#x}, which are outside the ELF, aborting loading program.",
+                    header.virtual_addr(),
+                    header.offset(),
+                    header.file_size(),
+                );
+                ElfLoaderErr::UnsupportedSectionData
+            })?;
+        loader.load(header.flags(), header.virtual_addr(), region)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum RelocationError {
+    #[snafu(display("The ELF's .rela.dyn section is malformed: {message}"))]
+    MalformedRelocations { message: &'static str },
+    #[snafu(display("Relocation type {rtype} at offset {offset:#x} is not supported, only R_RISCV_RELATIVE is. Is the app statically linked?"))]
+    UnsupportedRelocationType { rtype: u32, offset: u64 },
+    #[snafu(display("Relocation at offset {offset:#x} is not inside a loaded section"))]
+    RelocationOutOfBounds { offset: u64 },
+}
+
+/// PT_GNU_STACK, which isn't one of xmas_elf's named types.
+const PT_GNU_STACK: u32 = 0x6474_e551;
+
+/// The stack size that the ELF's PT_GNU_STACK header asks for, if it has one
+/// with a size. Linkers only give it a size when asked to, e.g. with
+/// `-z stack-size=<size>`.
+pub fn gnu_stack_size(elf_binary: &ElfBinary<'_>) -> Option<u64> {
+    elf_binary.program_headers()
+        .find(|header| matches!(header.get_type(), Ok(Type::OsSpecific(PT_GNU_STACK))))
+        .map(|header| header.mem_size())
+        .filter(|&mem_size| mem_size != 0)
+}
+
+/// The name of Nushift's ELF notes, including the terminating NUL.
+const NUSHIFT_NOTE_NAME: &[u8] = b"Nushift\0";
+/// The type of the Nushift note that asks for a paging scheme.
+const NT_NUSHIFT_PAGING_SCHEME: u32 = 1;
+
+/// The paging scheme that the ELF asks for with a `Nushift` note of type
+/// `NT_NUSHIFT_PAGING_SCHEME` in a PT_NOTE header. The note's descriptor is
+/// the number of bits of virtual address as a 4-byte little-endian number,
+/// i.e. 39, 48 or 57 for 64-bit apps. Without the note, apps get the default
+/// for their register width, see `PagingScheme::for_xlen`.
+///
+/// This has to be known before loading, since it decides where the ELF can be
+/// loaded.
+pub fn requested_paging_scheme(image: &[u8], xlen: u8) -> Result<PagingScheme, PagingSchemeNoteError> {
+    // If the ELF can't be parsed, loading it will say so.
+    let Ok(elf_file) = ElfFile::new(image) else {
+        return Ok(PagingScheme::for_xlen(xlen));
+    };
+
+    let descriptor = elf_file.program_iter()
+        .filter(|header| matches!(header.get_type(), Ok(Type::Note)))
+        .filter_map(|header| {
+            let start = usize::try_from(header.offset()).ok()?;
+            let end = start.checked_add(usize::try_from(header.file_size()).ok()?)?;
+            image.get(start..end)
+        })
+        .find_map(|notes| find_note(notes, NUSHIFT_NOTE_NAME, NT_NUSHIFT_PAGING_SCHEME));
+
+    match descriptor {
+        Some(descriptor) => paging_scheme_from_note(descriptor, xlen),
+        None => Ok(PagingScheme::for_xlen(xlen)),
+    }
+}
+
+/// The descriptor of the first note in `notes` with `name` and `note_type`.
+/// Notes are a 12-byte header of name size, descriptor size and type, then the
+/// name and the descriptor, each padded to 4 bytes.
+fn find_note<'a>(mut notes: &'a [u8], name: &[u8], note_type: u32) -> Option<&'a [u8]> {
+    while let Some(header) = notes.get(..12) {
+        let word = |index: usize| u32::from_le_bytes(header[index..index + 4].try_into().unwrap());
+        let name_size = usize::try_from(word(0)).ok()?;
+        let descriptor_size = usize::try_from(word(4)).ok()?;
+
+        let descriptor_start = name_size.checked_next_multiple_of(4)?.checked_add(12)?;
+        let descriptor_end = descriptor_start.checked_add(descriptor_size)?;
+        let descriptor = notes.get(descriptor_start..descriptor_end)?;
+        if word(8) == note_type && notes.get(12..12 + name_size) == Some(name) {
+            return Some(descriptor);
+        }
+
+        // The last note's padding may be missing.
+        let next_start = descriptor_end.checked_next_multiple_of(4)?.min(notes.len());
+        notes = &notes[next_start..];
     }
+    None
+}
+
+fn paging_scheme_from_note(descriptor: &[u8], xlen: u8) -> Result<PagingScheme, PagingSchemeNoteError> {
+    let bits: [u8; 4] = descriptor.try_into().map_err(|_| InvalidPagingSchemeNoteLengthSnafu { length: descriptor.len() }.build())?;
+    let bits = u32::from_le_bytes(bits);
+
+    u8::try_from(bits).ok()
+        .and_then(|bits| PagingScheme::for_bits(bits, xlen))
+        .context(UnsupportedPagingSchemeSnafu { bits, xlen })
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum PagingSchemeNoteError {
+    #[snafu(display("The Nushift paging scheme note's descriptor should be 4 bytes, but it is {length} bytes"))]
+    InvalidPagingSchemeNoteLength { length: usize },
+    #[snafu(display("The Nushift paging scheme note asks for {bits}-bit virtual addresses, which isn't a paging scheme that {xlen}-bit apps can use"))]
+    UnsupportedPagingScheme { bits: u32, xlen: u8 },
+}
+
+fn random_load_base(max_align: u64, paging_scheme: PagingScheme, random: u64) -> u64 {
+    let range_start = 1 << (paging_scheme.bits() - 2);
+    // Bigger alignments than the start of the range would round down out of
+    // it.
+    let align = max_align.clamp(1 << 12, range_start).next_power_of_two();
+    (range_start + random % range_start) & !(align - 1)
 }
 
 fn flags_map(flags: Flags) -> Result<Sv39Flags, ()> {
@@ -102,37 +437,49 @@ fn last_occupied_page_number(virtual_addr: u64, mem_size: u64) -> Result<u64, ()
 impl ElfLoader for Loader<'_> {
     fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), ElfLoaderErr> {
         let mut checked_sections = CheckedSections::new();
-        let mut errored_caps = vec![];
+        let mut merged_sections = MergedSections::new();
 
         for header in load_headers {
             let flags = header.flags();
 
+            let virtual_addr = header.virtual_addr().checked_add(self.load_base).ok_or_else(|| {
+                tracing::error!(
+                    "Section at vaddr {:#x} overflows when loaded at base {:#x}, aborting loading program.",
+                    header.virtual_addr(),
+                    self.load_base,
+                );
+                ElfLoaderErr::UnsupportedSectionData
+            })?;
+
             // Only allow certain combinations of flags.
             let sv39_flags = flags_map(flags).map_err(|_| {
                 tracing::error!(
                     "Section at vaddr {:#x} has unsupported flags, the only supported combinations are r--, rw-, r-x, --x.",
-                    header.virtual_addr(),
+                    virtual_addr,
                 );
                 ElfLoaderErr::UnsupportedSectionData
             })?;
 
-            let rounded_down_start_vpn = header.virtual_addr() >> 12;
+            let rounded_down_start_vpn = virtual_addr >> 12;
 
-            let last_occupied_vpn = last_occupied_page_number(header.virtual_addr(), header.mem_size())
+            let last_occupied_vpn = last_occupied_page_number(virtual_addr, header.mem_size())
                 .map_err(|_| {
                     tracing::error!(
                         "Section at vaddr {:#x} and mem_size {:#x} either overflows, or mem_size is 0, aborting loading program.",
-                        header.virtual_addr(),
+                        virtual_addr,
                         header.mem_size(),
                     );
                     ElfLoaderErr::UnsupportedSectionData
                 })?;
 
-            if last_occupied_vpn >= (1 << (SV39_BITS - 12)) {
+            let paging_scheme = self.shm_space.paging_scheme();
+            if last_occupied_vpn >= (1 << (paging_scheme.bits() - 12)) {
                 tracing::error!(
-                    "Section at vaddr {:#x} and mem_size {:#x} exceeds 2^39, which is the bounds of the Sv39 scheme, which is currently the only supported scheme.",
-                    header.virtual_addr(),
+                    "Section at vaddr {:#x} and mem_size {:#x} exceeds 2^{}, which is the bounds of the {:?} scheme that this app uses.",
+                    virtual_addr,
                     header.mem_size(),
+                    paging_scheme.bits(),
+                    paging_scheme,
                 );
                 return Err(ElfLoaderErr::UnsupportedSectionData);
             }
@@ -143,39 +490,61 @@ impl ElfLoader for Loader<'_> {
                 .checked_add(1)
                 .expect("This should not overflow because a full amount of pages fits in u64 because it's off by a factor of 4096, and because of the last_occupied_page_number logic. Panic if it does.");
 
-            checked_sections.add_region(rounded_down_start_vpn, number_of_pages)
+            checked_sections.add_region(virtual_addr, header.mem_size())
                 .map_err(|err| {
                     match err {
-                        CheckedSectionsError::VpnPlusNumPagesOverflow => tracing::error!(
-                            "Section at vaddr {:#x} and mem_size {:#x}: An internal error when adding VPN and number of pages occurred, this should never happen regardless of the data in the ELF and indicates a bug in Nushift's code.",
-                            header.virtual_addr(),
+                        CheckedSectionsError::StartPlusLengthOverflow => tracing::error!(
+                            "Section at vaddr {:#x} and mem_size {:#x}: An internal error when adding vaddr and mem_size occurred, this should never happen regardless of the data in the ELF and indicates a bug in Nushift's code.",
+                            virtual_addr,
                             header.mem_size(),
                         ),
                         CheckedSectionsError::Overlaps => tracing::error!(
-                            concat!(
-                                "Section at vaddr {:#x} and mem_size {:#x} when rounded down to the nearest 4 KiB page, overlaps a previously loaded section. ",
-                                "The reason why we don't currently allow sub-page sections that also overlap a particular page, is because we apply section permissions on a page level, and if your sections have the same permissions, we don't yet support merging them.",
-                            ),
-                            header.virtual_addr(),
+                            "Section at vaddr {:#x} and mem_size {:#x} overlaps a previously loaded section.",
+                            virtual_addr,
                             header.mem_size(),
                         ),
                     }
                     ElfLoaderErr::UnsupportedSectionData
                 })?;
 
+            merged_sections.add_region(rounded_down_start_vpn, number_of_pages, sv39_flags)
+                .map_err(|err| {
+                    match err {
+                        MergedSectionsError::MergedVpnPlusNumPagesOverflow => tracing::error!(
+                            "Section at vaddr {:#x} and mem_size {:#x}: An internal error when adding VPN and number of pages occurred, this should never happen regardless of the data in the ELF and indicates a bug in Nushift's code.",
+                            virtual_addr,
+                            header.mem_size(),
+                        ),
+                        MergedSectionsError::IncompatibleFlags { existing_flags } => tracing::error!(
+                            "Section at vaddr {:#x} and mem_size {:#x} with flags {:?} shares a 4 KiB page with a section with flags {:?}. Sections that share a page are merged, but not if that would make the page both writable and executable.",
+                            virtual_addr,
+                            header.mem_size(),
+                            sv39_flags,
+                            existing_flags,
+                        ),
+                    }
+                    ElfLoaderErr::UnsupportedSectionData
+                })?;
+        }
+
+        let mut errored_caps = vec![];
+        for (start_vpn, (number_of_pages, sv39_flags)) in merged_sections.0 {
             // If new_shm_cap fails, no need to clean up or destroy
-            // anything. It makes sure that it only increments the Sv39
+            // anything. It makes sure that it only increments the
             // stats after no other errors have occurred. If the
             // implementation of new_shm_cap changes such that this is no
             // longer the case, and you didn't check this usage of
             // new_shm_cap, well, that is not good.
-            let (shm_cap_id, _) = self.shm_space.new_shm_cap(ShmType::FourKiB, number_of_pages, CapType::ElfCap)
-                .map_err(|err| {
-                    tracing::error!("ELF loading: new_shm_cap either exhausted or is an internal error: {err:?}");
-                    ElfLoaderErr::UnsupportedSectionData
-                })?;
+            let (shm_cap_id, _) = match self.shm_space.new_shm_cap(ShmType::FourKiB, number_of_pages, CapType::ElfCap) {
+                Ok(id_and_shm_cap) => id_and_shm_cap,
+                Err(err) => {
+                    tracing::error!("ELF loading: new_shm_cap failed: {err:?}");
+                    self.shm_space_error = Some(err);
+                    return Err(ElfLoaderErr::UnsupportedSectionData);
+                }
+            };
 
-            match self.shm_space.acquire_shm_cap_elf(shm_cap_id, rounded_down_start_vpn << 12, sv39_flags) {
+            match self.shm_space.acquire_shm_cap_elf(shm_cap_id, start_vpn << 12, sv39_flags) {
                 Ok(_) => {}
                 Err(err) => {
                     tracing::error!("ELF loading: acquire_shm_cap internal error: {err:?}");
@@ -184,7 +553,7 @@ impl ElfLoader for Loader<'_> {
                 }
             }
 
-            self.vpn_to_shm_cap_id.insert(rounded_down_start_vpn, shm_cap_id);
+            self.sections.insert(start_vpn, (shm_cap_id, number_of_pages));
         }
 
         if !errored_caps.is_empty() {
@@ -207,6 +576,11 @@ impl ElfLoader for Loader<'_> {
     }
 
     fn load(&mut self, flags: Flags, base: VAddr, region: &[u8]) -> Result<(), ElfLoaderErr> {
+        let base = base.checked_add(self.load_base).ok_or_else(|| {
+            tracing::error!("Internal error adding the load base when loading ELF data, allocate should have rejected this section");
+            ElfLoaderErr::UnsupportedSectionData
+        })?;
+
         tracing::debug!(
             "Loading region with base {:#x} and length {}, flags [{}]",
             base,
@@ -220,32 +594,17 @@ impl ElfLoader for Loader<'_> {
         // permissions restrictions in allocate, and then set them here only
         // after loading. That can be done, I have just chosen the former.
 
-        let rounded_down_start_vpn = base >> 12;
-        let offset: usize = (base & ((1 << 12) - 1))
-            .try_into()
-            .map_err(|_| {
-                tracing::error!("usize on this host machine is less than 12 bits. This is unexpected.");
-                ElfLoaderErr::UnsupportedSectionData
-            })?;
-
-        let &shm_cap_id = self.vpn_to_shm_cap_id.get(&rounded_down_start_vpn).ok_or_else(|| {
-            tracing::error!("Internal error refetching SHM cap ID when loading ELF data");
+        // Sections that share a page are split over caps by which pages they
+        // share, so this isn't necessarily at the start of one, or in just one.
+        self.write_to_sections(base, region).map_err(|_| {
+            tracing::error!("Internal error refetching SHM cap when loading ELF data");
             ElfLoaderErr::UnsupportedSectionData
-        })?;
-
-        self.shm_space.get_mut_shm_cap_elf(shm_cap_id)
-            .map_err(|_| {
-                tracing::error!("Internal error refetching SHM cap when loading ELF data");
-                ElfLoaderErr::UnsupportedSectionData
-            })?
-            .backing_mut()[offset..offset.checked_add(region.len()).expect("Internal error if this overflows")]
-            .copy_from_slice(region);
-
-        Ok(())
+        })
     }
 
     fn relocate(&mut self, _entry: RelocationEntry) -> Result<(), ElfLoaderErr> {
-        // Unimplemented
+        // `load_elf` never calls this, relocations are applied by
+        // `relocate_elf` instead.
         Err(ElfLoaderErr::UnsupportedRelocationEntry)
     }
 }
@@ -305,8 +664,257 @@ mod tests {
     fn checked_sections_invalid_input() {
         let mut checked_sections = CheckedSections::new();
 
-        // u64::MAX shouldn't be a valid VPN (because a VPN is off by a factor
-        // of 4096), so it's valid to do an overflow check.
-        assert!(matches!(checked_sections.add_region(u64::MAX, 1), Err(CheckedSectionsError::VpnPlusNumPagesOverflow)));
+        // u64::MAX shouldn't be a valid address (because it's outside the
+        // app's address space), so it's valid to do an overflow check.
+        assert!(matches!(checked_sections.add_region(u64::MAX, 1), Err(CheckedSectionsError::StartPlusLengthOverflow)));
+    }
+
+    #[test]
+    fn merged_sections_sharing_a_page_get_union_of_flags() {
+        let mut merged_sections = MergedSections::new();
+
+        // Like .rodata and .eh_frame, then .data and .bss.
+        merged_sections.add_region(16, 2, Sv39Flags::R).expect("Should be allowed");
+        merged_sections.add_region(17, 1, Sv39Flags::R).expect("Should be allowed");
+        merged_sections.add_region(20, 3, Sv39Flags::RW).expect("Should be allowed");
+        merged_sections.add_region(22, 4, Sv39Flags::RW).expect("Should be allowed");
+
+        assert_eq!(vec![(16, (2, Sv39Flags::R)), (20, (6, Sv39Flags::RW))], merged_sections.0.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn merged_sections_bridging_two_regions_merges_shared_pages() {
+        let mut merged_sections = MergedSections::new();
+
+        merged_sections.add_region(1, 2, Sv39Flags::X).expect("Should be allowed");
+        merged_sections.add_region(4, 2, Sv39Flags::R).expect("Should be allowed");
+        // Adjacent, but not sharing a page, so not merged.
+        merged_sections.add_region(6, 1, Sv39Flags::RW).expect("Should be allowed");
+        merged_sections.add_region(2, 3, Sv39Flags::R).expect("Should be allowed");
+
+        assert_eq!(vec![(1, (1, Sv39Flags::X)), (2, (1, Sv39Flags::RX)), (3, (3, Sv39Flags::R)), (6, (1, Sv39Flags::RW))], merged_sections.0.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn merged_sections_only_shared_pages_get_union_of_flags() {
+        let mut merged_sections = MergedSections::new();
+
+        // Like .text, then .rodata starting in its last page.
+        merged_sections.add_region(1, 3, Sv39Flags::RX).expect("Should be allowed");
+        merged_sections.add_region(3, 3, Sv39Flags::R).expect("Should be allowed");
+        // And one inside a bigger one.
+        merged_sections.add_region(10, 5, Sv39Flags::R).expect("Should be allowed");
+        merged_sections.add_region(12, 1, Sv39Flags::X).expect("Should be allowed");
+
+        assert_eq!(
+            vec![(1, (3, Sv39Flags::RX)), (4, (2, Sv39Flags::R)), (10, (2, Sv39Flags::R)), (12, (1, Sv39Flags::RX)), (13, (2, Sv39Flags::R))],
+            merged_sections.0.into_iter().collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn merged_sections_rejects_writable_and_executable_page() {
+        let mut merged_sections = MergedSections::new();
+
+        merged_sections.add_region(1, 2, Sv39Flags::RX).expect("Should be allowed");
+        assert!(matches!(merged_sections.add_region(2, 2, Sv39Flags::RW), Err(MergedSectionsError::IncompatibleFlags { existing_flags: Sv39Flags::RX })));
+        // Left as it was.
+        assert_eq!(vec![(1, (2, Sv39Flags::RX))], merged_sections.0.into_iter().collect::<Vec<_>>());
+    }
+
+    fn note(name: &[u8], note_type: u32, descriptor: &[u8]) -> Vec<u8> {
+        let mut note = [name.len() as u32, descriptor.len() as u32, note_type].iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>();
+        note.extend(name);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note.extend(descriptor);
+        note.resize(note.len().next_multiple_of(4), 0);
+        note
+    }
+
+    #[test]
+    fn find_note_skips_other_notes() {
+        let mut notes = note(b"GNU\0", NT_NUSHIFT_PAGING_SCHEME, &[1, 2, 3, 4, 5]);
+        notes.extend(note(NUSHIFT_NOTE_NAME, 2, &[6]));
+        notes.extend(note(NUSHIFT_NOTE_NAME, NT_NUSHIFT_PAGING_SCHEME, &48u32.to_le_bytes()));
+
+        assert_eq!(Some(&48u32.to_le_bytes()[..]), find_note(&notes, NUSHIFT_NOTE_NAME, NT_NUSHIFT_PAGING_SCHEME));
+        assert_eq!(None, find_note(&notes[..notes.len() - 1], NUSHIFT_NOTE_NAME, NT_NUSHIFT_PAGING_SCHEME));
+        assert_eq!(None, find_note(&notes, NUSHIFT_NOTE_NAME, 3));
+    }
+
+    #[test]
+    fn paging_scheme_from_note_checks_bits_and_xlen() {
+        assert!(matches!(paging_scheme_from_note(&39u32.to_le_bytes(), 64), Ok(PagingScheme::Sv39)));
+        assert!(matches!(paging_scheme_from_note(&48u32.to_le_bytes(), 64), Ok(PagingScheme::Sv48)));
+        assert!(matches!(paging_scheme_from_note(&57u32.to_le_bytes(), 64), Ok(PagingScheme::Sv57)));
+        assert!(matches!(paging_scheme_from_note(&32u32.to_le_bytes(), 32), Ok(PagingScheme::Sv32)));
+
+        assert!(matches!(paging_scheme_from_note(&48u32.to_le_bytes(), 32), Err(PagingSchemeNoteError::UnsupportedPagingScheme { bits: 48, xlen: 32 })));
+        assert!(matches!(paging_scheme_from_note(&32u32.to_le_bytes(), 64), Err(PagingSchemeNoteError::UnsupportedPagingScheme { bits: 32, xlen: 64 })));
+        assert!(matches!(paging_scheme_from_note(&(256 + 48u32).to_le_bytes(), 64), Err(PagingSchemeNoteError::UnsupportedPagingScheme { bits: 304, xlen: 64 })));
+        assert!(matches!(paging_scheme_from_note(&[48], 64), Err(PagingSchemeNoteError::InvalidPagingSchemeNoteLength { length: 1 })));
+    }
+
+    #[test]
+    fn random_load_base_is_aligned_and_in_range() {
+        for random in [0, 0x1234_5678_9abc_def0, u64::MAX] {
+            let load_base = random_load_base(0x1000, PagingScheme::Sv39, random);
+            assert!((1 << 37..1 << 38).contains(&load_base));
+            assert_eq!(0, load_base & 0xfff);
+
+            let load_base = random_load_base(0x1_0000, PagingScheme::Sv32, random);
+            assert!((1 << 30..1 << 31).contains(&load_base));
+            assert_eq!(0, load_base & 0xffff);
+
+            // Silly alignments are clamped to the start of the range.
+            assert_eq!(1 << 37, random_load_base(1 << 60, PagingScheme::Sv39, random));
+        }
+    }
+
+    fn loader_with_section(shm_space: &mut ShmSpace, load_base: u64, vaddr: u64) -> Loader<'_> {
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 2, CapType::ElfCap).expect("Should succeed");
+        let mut loader = Loader::new(shm_space, load_base);
+        loader.sections.insert((load_base + vaddr) >> 12, (shm_cap_id, 2));
+        loader
+    }
+
+    /// A 64-bit RISC-V ELF with just a header, a section name table and a
+    /// `.rela.dyn` section with `relocations`, each of which is an offset, a
+    /// type and an addend.
+    fn elf_with_relocations(relocations: &[(u64, u32, u64)]) -> Vec<u8> {
+        const EHDR_SIZE: u64 = 64;
+        const RELA_SIZE: u64 = 24;
+        // Padded so that what follows is 8-byte aligned, which xmas-elf
+        // requires.
+        const SHSTRTAB: &[u8] = b"\0.shstrtab\0.rela.dyn\0\0\0\0";
+        let rela_offset = EHDR_SIZE + SHSTRTAB.len() as u64;
+        let rela_size = RELA_SIZE * relocations.len() as u64;
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&3u16.to_le_bytes()); // e_type: DYN
+        elf.extend_from_slice(&0xf3u16.to_le_bytes()); // e_machine: RISC-V
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&(rela_offset + rela_size).to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        for half in [EHDR_SIZE as u16, 56, 0, 64, 3, 1] {
+            // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+
+        elf.extend_from_slice(SHSTRTAB);
+        for &(offset, rtype, addend) in relocations {
+            // r_offset, r_info (with no symbol), r_addend
+            for word in [offset, u64::from(rtype), addend] {
+                elf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        // The null section, .shstrtab (STRTAB) and .rela.dyn (RELA).
+        for (name, sh_type, offset, size, entsize) in [(0u32, 0u32, 0, 0, 0), (1, 3, EHDR_SIZE, SHSTRTAB.len() as u64, 0), (11, 4, rela_offset, rela_size, RELA_SIZE)] {
+            elf.extend_from_slice(&name.to_le_bytes());
+            elf.extend_from_slice(&sh_type.to_le_bytes());
+            for word in [0, 0, offset, size] {
+                // sh_flags, sh_addr, sh_offset, sh_size
+                elf.extend_from_slice(&u64::to_le_bytes(word));
+            }
+            elf.extend_from_slice(&[0; 8]); // sh_link, sh_info
+            for word in [8, entsize] {
+                // sh_addralign, sh_entsize
+                elf.extend_from_slice(&u64::to_le_bytes(word));
+            }
+        }
+        elf
+    }
+
+    #[test]
+    fn relocate_elf_applies_rela_dyn() {
+        let elf = elf_with_relocations(&[(0x1008, R_RISCV_RELATIVE, 0x1234), (0x1ff8, R_RISCV_RELATIVE, 0x5678)]);
+        let elf_file = ElfFile::new(&elf).expect("Should succeed");
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let mut loader = loader_with_section(&mut shm_space, 0x20_0000_0000, 0x1000);
+
+        loader.relocate_elf(&elf_file).expect("Should succeed");
+
+        let &(shm_cap_id, _) = loader.sections.values().next().expect("Should exist");
+        let backing = shm_space.get_mut_shm_cap_elf(shm_cap_id).expect("Should succeed").backing_mut();
+        assert_eq!(0x20_0000_1234u64.to_le_bytes(), backing[0x8..0x10]);
+        assert_eq!(0x20_0000_5678u64.to_le_bytes(), backing[0xff8..0x1000]);
+    }
+
+    #[test]
+    fn relocate_elf_rejects_symbolic_relocations() {
+        // R_RISCV_64
+        let elf = elf_with_relocations(&[(0x1008, R_RISCV_RELATIVE, 0x1234), (0x1010, 2, 0)]);
+        let elf_file = ElfFile::new(&elf).expect("Should succeed");
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let mut loader = loader_with_section(&mut shm_space, 0, 0x1000);
+
+        assert!(matches!(loader.relocate_elf(&elf_file), Err(RelocationError::UnsupportedRelocationType { rtype: 2, offset: 0x1010 })));
+    }
+
+    #[test]
+    fn relocate_elf_does_nothing_without_rela_dyn() {
+        let elf = elf_with_relocations(&[]);
+        let elf_file = ElfFile::new(&elf).expect("Should succeed");
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let mut loader = Loader::new(&mut shm_space, 0);
+
+        loader.relocate_elf(&elf_file).expect("Should succeed");
+    }
+
+    #[test]
+    fn relocate_relative_writes_base_plus_addend() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let mut loader = loader_with_section(&mut shm_space, 0x20_0000_0000, 0x1000);
+
+        loader.relocate_one(R_RISCV_RELATIVE, 0x1ff8, 0x1234).expect("Should succeed");
+        // Straddling the end of the second page.
+        assert!(matches!(loader.relocate_one(R_RISCV_RELATIVE, 0x2ffc, 0x1234), Err(RelocationError::RelocationOutOfBounds { offset: 0x2ffc })));
+        // Before the section.
+        assert!(matches!(loader.relocate_one(R_RISCV_RELATIVE, 0xff8, 0x1234), Err(RelocationError::RelocationOutOfBounds { offset: 0xff8 })));
+
+        let &(shm_cap_id, _) = loader.sections.values().next().expect("Should exist");
+        let backing = shm_space.get_mut_shm_cap_elf(shm_cap_id).expect("Should succeed").backing_mut();
+        assert_eq!(0x20_0000_1234u64.to_le_bytes(), backing[0xff8..0x1000]);
+    }
+
+    #[test]
+    fn relocate_relative_writes_four_bytes_on_sv32() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv32);
+        let mut loader = loader_with_section(&mut shm_space, 0x4000_0000, 0);
+
+        loader.relocate_one(R_RISCV_RELATIVE, 0x10, 0x1234).expect("Should succeed");
+
+        let &(shm_cap_id, _) = loader.sections.values().next().expect("Should exist");
+        let backing = shm_space.get_mut_shm_cap_elf(shm_cap_id).expect("Should succeed").backing_mut();
+        assert_eq!([0x34, 0x12, 0x00, 0x40, 0x00], backing[0x10..0x15]);
+    }
+
+    #[test]
+    fn load_writes_across_caps_of_split_section() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let mut loader = loader_with_section(&mut shm_space, 0, 0x1000);
+        let (next_shm_cap_id, _) = loader.shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+        loader.sections.insert(3, (next_shm_cap_id, 1));
+
+        loader.load(Flags(0b100), 0x2ffe, &[1, 2, 3, 4]).expect("Should succeed");
+        // Past the end of the last cap.
+        assert!(matches!(loader.load(Flags(0b100), 0x3ffe, &[1, 2, 3, 4]), Err(ElfLoaderErr::UnsupportedSectionData)));
+
+        let shm_cap_ids: Vec<ShmCapId> = loader.sections.values().map(|&(shm_cap_id, _)| shm_cap_id).collect();
+        assert_eq!([1, 2], shm_space.get_mut_shm_cap_elf(shm_cap_ids[0]).expect("Should succeed").backing_mut()[0x1ffe..0x2000]);
+        assert_eq!([3, 4], shm_space.get_mut_shm_cap_elf(shm_cap_ids[1]).expect("Should succeed").backing_mut()[..2]);
+    }
+
+    #[test]
+    fn relocate_rejects_everything_but_relative() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let mut loader = loader_with_section(&mut shm_space, 0, 0);
+
+        // R_RISCV_NONE and R_RISCV_64
+        assert!(matches!(loader.relocate_one(0, 0, 0), Err(RelocationError::UnsupportedRelocationType { rtype: 0, offset: 0 })));
+        assert!(matches!(loader.relocate_one(2, 8, 0), Err(RelocationError::UnsupportedRelocationType { rtype: 2, offset: 8 })));
     }
 }
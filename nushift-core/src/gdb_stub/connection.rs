@@ -0,0 +1,362 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use core::fmt::{self, Display};
+use core::str::FromStr;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long a read from the debugger blocks before the stub checks whether the
+/// tab has been closed or paused.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Where a tab's GDB stub listens for a debugger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GdbServerAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Parses `unix:<path>` as a Unix socket, and anything else as a TCP socket
+/// address, e.g. `127.0.0.1:1234`. TCP addresses must be loopback addresses,
+/// because the stub lets whoever connects read and write the app's memory.
+impl FromStr for GdbServerAddress {
+    type Err = GdbServerAddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        let socket_addr: SocketAddr = s.parse().map_err(|_| GdbServerAddressParseError::Invalid)?;
+        if !socket_addr.ip().is_loopback() {
+            return Err(GdbServerAddressParseError::NotLoopback);
+        }
+        Ok(Self::Tcp(socket_addr))
+    }
+}
+
+impl Display for GdbServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(socket_addr) => socket_addr.fmt(f),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GdbServerAddressParseError {
+    Invalid,
+    NotLoopback,
+}
+
+impl Display for GdbServerAddressParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => f.write_str("Expected a loopback socket address like 127.0.0.1:1234, or unix:<path>"),
+            Self::NotLoopback => f.write_str("The GDB stub only listens on loopback addresses like 127.0.0.1:1234 or [::1]:1234"),
+        }
+    }
+}
+
+impl std::error::Error for GdbServerAddressParseError {}
+
+pub(super) enum GdbListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl GdbListener {
+    /// The listener is non-blocking, so that the machine thread can poll it
+    /// while the app runs.
+    ///
+    /// TCP addresses that aren't loopback addresses are refused, since anyone
+    /// who can connect can write the app's registers and memory, or kill it.
+    pub(super) fn bind(gdb_server_address: &GdbServerAddress) -> io::Result<Self> {
+        let listener = match gdb_server_address {
+            GdbServerAddress::Tcp(socket_addr) => {
+                if !socket_addr.ip().is_loopback() {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, GdbServerAddressParseError::NotLoopback));
+                }
+                let listener = TcpListener::bind(socket_addr)?;
+                listener.set_nonblocking(true)?;
+                Self::Tcp(listener)
+            }
+            #[cfg(unix)]
+            GdbServerAddress::Unix(path) => {
+                let listener = UnixListener::bind(path)?;
+                listener.set_nonblocking(true)?;
+                Self::Unix(listener, path.clone())
+            }
+        };
+        tracing::info!("Waiting for a debugger on {gdb_server_address}");
+        Ok(listener)
+    }
+
+    /// Returns `None` if no debugger is trying to connect.
+    pub(super) fn try_accept(&self) -> io::Result<Option<PacketConnection>> {
+        let stream = match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| {
+                // Packets are small and every one is waited on, so don't let
+                // Nagle's algorithm hold them back.
+                let _ = stream.set_nodelay(true);
+                GdbStream::Tcp(stream)
+            }),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => listener.accept().map(|(stream, _)| GdbStream::Unix(stream)),
+        };
+
+        match stream {
+            Ok(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                Ok(Some(PacketConnection::new(stream)))
+            }
+            Err(io_error) if io_error.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(io_error) => Err(io_error),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for GdbListener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+enum GdbStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl GdbStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for GdbStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for GdbStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Sent by the debugger, outside of any packet, to interrupt a running app.
+const INTERRUPT: u8 = 0x03;
+
+/// A connection to a debugger, which reads and writes packets of the form
+/// `$<data>#<checksum>`.
+pub(super) struct PacketConnection {
+    stream: GdbStream,
+    /// Resent if the debugger says it got it corrupted.
+    last_sent: Vec<u8>,
+}
+
+impl PacketConnection {
+    fn new(stream: GdbStream) -> Self {
+        Self { stream, last_sent: vec![] }
+    }
+
+    /// Block until a whole packet arrives, acknowledging it. Every time the
+    /// read times out, `keep_waiting` is called, and if it returns `false`,
+    /// this returns `None`.
+    ///
+    /// Disconnecting is returned as an `UnexpectedEof` error.
+    pub(super) fn read_packet(&mut self, mut keep_waiting: impl FnMut() -> bool) -> io::Result<Option<Vec<u8>>> {
+        let mut packet = None;
+
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                if !keep_waiting() {
+                    return Ok(None);
+                }
+                continue;
+            };
+
+            match (&mut packet, byte) {
+                (None, b'$') => packet = Some(vec![]),
+                // The debugger got our last packet corrupted.
+                (None, b'-') => {
+                    let last_sent = self.last_sent.clone();
+                    self.write_all(&last_sent)?;
+                }
+                // Acknowledgements, and interrupts when the app is already
+                // stopped, are otherwise of no interest.
+                (None, _) => {}
+                (Some(_), b'#') => {
+                    let mut checksum = [0; 2];
+                    for checksum_byte in &mut checksum {
+                        *checksum_byte = loop {
+                            if let Some(byte) = self.read_byte()? {
+                                break byte;
+                            }
+                            if !keep_waiting() {
+                                return Ok(None);
+                            }
+                        };
+                    }
+                    let data = packet.take().unwrap_or_default();
+
+                    if parse_hex_checksum(checksum) == Some(checksum_of(&data)) {
+                        self.write_all(b"+")?;
+                        return Ok(Some(data));
+                    }
+                    self.write_all(b"-")?;
+                }
+                (Some(data), byte) => data.push(byte),
+            }
+        }
+    }
+
+    pub(super) fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let framed = frame_packet(data);
+        self.write_all(&framed)?;
+        self.last_sent = framed;
+        Ok(())
+    }
+
+    /// Check, without blocking, whether the debugger wants to interrupt the
+    /// running app.
+    pub(super) fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut interrupted = false;
+        let result = loop {
+            match self.read_byte() {
+                Ok(Some(INTERRUPT)) => interrupted = true,
+                Ok(Some(_)) => {}
+                Ok(None) => break Ok(interrupted),
+                Err(io_error) => break Err(io_error),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    /// Returns `None` on timeout.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(io_error) if matches!(io_error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => Ok(None),
+            Err(io_error) => Err(io_error),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes)?;
+        self.stream.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |checksum, &byte| checksum.wrapping_add(byte))
+}
+
+fn parse_hex_checksum(checksum: [u8; 2]) -> Option<u8> {
+    u8::from_str_radix(core::str::from_utf8(&checksum).ok()?, 16).ok()
+}
+
+/// Escapes the bytes that can't appear in a packet as-is.
+fn frame_packet(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len() + 4);
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend([b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+
+    let checksum = checksum_of(&escaped);
+    let mut framed = Vec::with_capacity(escaped.len() + 4);
+    framed.push(b'$');
+    framed.extend(escaped);
+    framed.extend(format!("#{checksum:02x}").into_bytes());
+    framed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_packet_adds_checksum() {
+        assert_eq!(b"$OK#9a".as_slice(), frame_packet(b"OK"));
+    }
+
+    #[test]
+    fn frame_packet_escapes_special_bytes() {
+        assert_eq!(b"$}\x03#80".as_slice(), frame_packet(b"#"));
+    }
+
+    #[test]
+    fn gdb_server_address_parses_tcp_and_unix() {
+        assert_eq!(GdbServerAddress::Tcp("127.0.0.1:1234".parse().unwrap()), "127.0.0.1:1234".parse().expect("Should succeed"));
+        #[cfg(unix)]
+        assert_eq!(GdbServerAddress::Unix(PathBuf::from("/tmp/app.sock")), "unix:/tmp/app.sock".parse().expect("Should succeed"));
+        assert!("1234".parse::<GdbServerAddress>().is_err());
+    }
+
+    #[test]
+    fn gdb_server_address_refuses_non_loopback() {
+        assert_eq!(GdbServerAddressParseError::NotLoopback, "0.0.0.0:0".parse::<GdbServerAddress>().unwrap_err());
+        assert_eq!(GdbServerAddressParseError::NotLoopback, "[::]:0".parse::<GdbServerAddress>().unwrap_err());
+        assert_eq!(GdbServerAddress::Tcp("[::1]:1234".parse().unwrap()), "[::1]:1234".parse().expect("Should succeed"));
+    }
+
+    #[test]
+    fn gdb_listener_refuses_non_loopback() {
+        let Err(io_error) = GdbListener::bind(&GdbServerAddress::Tcp("0.0.0.0:0".parse().unwrap())) else { panic!("Should be refused") };
+
+        assert_eq!(ErrorKind::InvalidInput, io_error.kind());
+    }
+}
@@ -0,0 +1,673 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A GDB remote serial protocol stub, so that an app can be debugged with
+//! `riscv64-unknown-elf-gdb` (`target remote <address>`) or LLDB
+//! (`gdb-remote <address>`).
+//!
+//! The stub runs on the machine thread, in between instructions. When the app
+//! stops (at the start, on a breakpoint, after a single step, on an
+//! interrupt, or on a fault), the machine thread blocks talking to the
+//! debugger until it is told to carry on.
+//!
+//! Breakpoints are PC checks rather than patched-in `ebreak`s, because the
+//! app's code is usually not writable. An `ebreak` compiled into the app stops
+//! it too.
+
+use std::collections::HashSet;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+mod connection;
+
+pub use self::connection::{GdbServerAddress, GdbServerAddressParseError};
+use self::connection::{GdbListener, PacketConnection};
+
+/// How often to check for a debugger connecting, when waiting for one.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The most memory a single `m` packet can read, which keeps the reply within
+/// the packet size we advertise.
+const MAX_MEMORY_READ: usize = 0x1000;
+
+/// The registers GDB numbers 0 to 31 are x0 to x31, and 32 is the PC.
+const PC_REGISTER_NUMBER: usize = 32;
+const REGISTER_COUNT: usize = 33;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+const SIGKILL: u8 = 9;
+
+/// What the stub needs from a machine.
+pub(crate) trait GdbTarget {
+    /// 4 or 8.
+    fn register_bytes(&self) -> usize;
+    /// x0 to x31, then the PC.
+    fn read_registers(&self) -> Vec<u64>;
+    /// Returns `false` if there is no such register.
+    fn write_register(&mut self, number: usize, value: u64) -> bool;
+    /// Where a position-independent app was loaded, or 0.
+    fn load_base(&self) -> u64;
+    /// Returns `None` if the app itself couldn't read `addr`.
+    fn read_memory(&mut self, addr: u64) -> Option<u8>;
+    /// Returns `false` if the app itself couldn't write `addr`.
+    fn write_memory(&mut self, addr: u64, value: u8) -> bool;
+    /// Called periodically while stopped and waiting on the debugger. Pausing
+    /// the tab is still honoured while stopped, so that it can be snapshotted.
+    /// Returns `false` if the tab has been cancelled.
+    fn keep_waiting(&mut self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StopReason {
+    /// The debugger has just connected.
+    Attached,
+    /// The debugger pressed Ctrl-C.
+    Interrupted,
+    SingleStep,
+    Breakpoint,
+    /// The app ran an `ebreak`.
+    Ebreak,
+    /// The app hit a memory fault.
+    MemoryFault,
+    /// The app hit an invalid instruction or other error.
+    Fault,
+}
+
+impl StopReason {
+    fn stop_reply(self) -> Vec<u8> {
+        match self {
+            Self::Breakpoint => format!("T{SIGTRAP:02x}swbreak:;"),
+            Self::Interrupted => format!("S{SIGINT:02x}"),
+            Self::MemoryFault => format!("S{SIGSEGV:02x}"),
+            Self::Fault => format!("S{SIGILL:02x}"),
+            Self::Attached | Self::SingleStep | Self::Ebreak => format!("S{SIGTRAP:02x}"),
+        }.into_bytes()
+    }
+}
+
+/// What the machine should do once the debugger lets the app carry on.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Resume {
+    Continue,
+    Kill,
+    /// The tab was closed while stopped.
+    Cancelled,
+}
+
+pub(crate) struct GdbStub {
+    listener: GdbListener,
+    connection: Option<PacketConnection>,
+    breakpoints: HashSet<u64>,
+    stepping: bool,
+    /// Set when resuming, so that the app isn't stopped again at the
+    /// instruction it's resuming from.
+    skip_next_check: bool,
+    /// A stop noticed part-way through an instruction, or while polling, to be
+    /// reported before the next instruction.
+    pending_stop: Option<StopReason>,
+}
+
+impl GdbStub {
+    pub(crate) fn bind(gdb_server_address: &GdbServerAddress) -> io::Result<Self> {
+        Ok(Self {
+            listener: GdbListener::bind(gdb_server_address)?,
+            connection: None,
+            breakpoints: HashSet::new(),
+            stepping: false,
+            skip_next_check: false,
+            pending_stop: None,
+        })
+    }
+
+    pub(crate) fn is_attached(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Block until a debugger connects, so that it can see the app from its
+    /// first instruction. Returns `false` if the tab was cancelled first.
+    pub(crate) fn wait_for_debugger(&mut self, target: &mut impl GdbTarget) -> bool {
+        loop {
+            match self.listener.try_accept() {
+                Ok(Some(connection)) => {
+                    self.attach(connection);
+                    return true;
+                }
+                Ok(None) => {}
+                Err(io_error) => tracing::error!("Error accepting a debugger connection: {io_error}"),
+            }
+            if !target.keep_waiting() {
+                return false;
+            }
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+    }
+
+    /// Called every so often while the app runs, to pick up a debugger
+    /// (re)connecting, or asking to interrupt the app.
+    pub(crate) fn poll(&mut self) {
+        let Some(ref mut connection) = self.connection else {
+            match self.listener.try_accept() {
+                Ok(Some(connection)) => self.attach(connection),
+                Ok(None) => {}
+                Err(io_error) => tracing::error!("Error accepting a debugger connection: {io_error}"),
+            }
+            return;
+        };
+
+        match connection.poll_interrupt() {
+            Ok(true) => self.pending_stop = Some(StopReason::Interrupted),
+            Ok(false) => {}
+            Err(_) => self.detach(),
+        }
+    }
+
+    /// Called part-way through an instruction, e.g. by `ebreak`.
+    pub(crate) fn stop_after_instruction(&mut self, stop_reason: StopReason) {
+        self.pending_stop = Some(stop_reason);
+    }
+
+    /// Called before each instruction. Returns why the app should stop at
+    /// `pc`, if it should.
+    pub(crate) fn stop_reason_before(&mut self, pc: u64) -> Option<StopReason> {
+        self.connection.as_ref()?;
+        if let Some(stop_reason) = self.pending_stop.take() {
+            self.skip_next_check = false;
+            return Some(stop_reason);
+        }
+        if self.skip_next_check {
+            self.skip_next_check = false;
+            return None;
+        }
+        if self.stepping {
+            return Some(StopReason::SingleStep);
+        }
+        if self.breakpoints.contains(&pc) {
+            return Some(StopReason::Breakpoint);
+        }
+        None
+    }
+
+    /// Tell the debugger the app has stopped, then handle its requests until
+    /// it says to continue, step, detach, or kill.
+    pub(crate) fn handle_stop(&mut self, target: &mut impl GdbTarget, stop_reason: StopReason) -> Resume {
+        self.stepping = false;
+        if self.write_packet(&stop_reason.stop_reply()).is_err() {
+            self.detach();
+            return Resume::Continue;
+        }
+
+        loop {
+            let Some(ref mut connection) = self.connection else {
+                return Resume::Continue;
+            };
+            let packet = match connection.read_packet(|| target.keep_waiting()) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Resume::Cancelled,
+                Err(io_error) => {
+                    tracing::info!("Debugger disconnected: {io_error}");
+                    self.detach();
+                    return Resume::Continue;
+                }
+            };
+
+            let reply = match handle_packet(target, &mut self.breakpoints, stop_reason, &packet) {
+                Response::Reply(reply) => reply,
+                Response::Continue => {
+                    self.skip_next_check = true;
+                    return Resume::Continue;
+                }
+                Response::Step => {
+                    self.stepping = true;
+                    self.skip_next_check = true;
+                    return Resume::Continue;
+                }
+                Response::Detach => {
+                    let _ = self.write_packet(b"OK");
+                    self.detach();
+                    return Resume::Continue;
+                }
+                Response::Kill { reply } => {
+                    if reply {
+                        let _ = self.write_packet(b"OK");
+                    }
+                    self.detach();
+                    return Resume::Kill;
+                }
+            };
+            if self.write_packet(&reply).is_err() {
+                self.detach();
+                return Resume::Continue;
+            }
+        }
+    }
+
+    /// Tell the debugger the app has gone. `exit_code` is `None` if it didn't
+    /// exit by itself.
+    pub(crate) fn report_exit(&mut self, exit_code: Option<u64>) {
+        let reply = match exit_code {
+            // GDB only takes 8 bits of exit code.
+            Some(exit_code) => format!("W{:02x}", exit_code as u8),
+            None => format!("X{SIGKILL:02x}"),
+        };
+        let _ = self.write_packet(reply.as_bytes());
+        self.detach();
+    }
+
+    fn attach(&mut self, connection: PacketConnection) {
+        tracing::info!("Debugger connected");
+        self.connection = Some(connection);
+        self.pending_stop = Some(StopReason::Attached);
+    }
+
+    /// Forget everything about the debugger, so the app runs freely until
+    /// another connects.
+    fn detach(&mut self) {
+        self.connection = None;
+        self.breakpoints.clear();
+        self.stepping = false;
+        self.skip_next_check = false;
+        self.pending_stop = None;
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.connection {
+            Some(ref mut connection) => connection.write_packet(data),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Response {
+    Reply(Vec<u8>),
+    Continue,
+    Step,
+    Detach,
+    Kill { reply: bool },
+}
+
+impl Response {
+    fn ok() -> Self {
+        Self::Reply(b"OK".to_vec())
+    }
+
+    /// Tells the debugger we don't support the packet.
+    fn unsupported() -> Self {
+        Self::Reply(vec![])
+    }
+
+    fn error(errno: u8) -> Self {
+        Self::Reply(format!("E{errno:02x}").into_bytes())
+    }
+}
+
+/// EFAULT, for memory the app can't access.
+const EFAULT: u8 = 0x0e;
+/// EINVAL, for malformed packets.
+const EINVAL: u8 = 0x16;
+
+fn handle_packet(target: &mut impl GdbTarget, breakpoints: &mut HashSet<u64>, stop_reason: StopReason, packet: &[u8]) -> Response {
+    let Ok(packet) = core::str::from_utf8(packet) else {
+        // Only `X` packets carry binary data, and we don't support them.
+        return Response::unsupported();
+    };
+    let Some(command) = packet.chars().next() else {
+        return Response::unsupported();
+    };
+    let args = &packet[command.len_utf8()..];
+
+    let response = match command {
+        '?' => Some(Response::Reply(stop_reason.stop_reply())),
+        'g' => Some(read_registers(target)),
+        'G' => write_registers(target, args),
+        'p' => read_register(target, args),
+        'P' => write_register(target, args),
+        'm' => read_memory(target, args),
+        'M' => write_memory(target, args),
+        // Continuing or stepping from a different address isn't supported.
+        'c' if args.is_empty() => Some(Response::Continue),
+        's' if args.is_empty() => Some(Response::Step),
+        'Z' => set_breakpoint(breakpoints, args, true),
+        'z' => set_breakpoint(breakpoints, args, false),
+        'D' => Some(Response::Detach),
+        'k' => Some(Response::Kill { reply: false }),
+        // The debugger only sees the app's main thread, so there is only
+        // ever one thread to select.
+        'H' | 'T' => Some(Response::ok()),
+        'q' => Some(handle_query(target, args)),
+        'v' if args == "Kill" || args.starts_with("Kill;") => Some(Response::Kill { reply: true }),
+        _ => Some(Response::unsupported()),
+    };
+
+    response.unwrap_or_else(|| Response::error(EINVAL))
+}
+
+fn handle_query(target: &impl GdbTarget, query: &str) -> Response {
+    match query {
+        _ if query.starts_with("Supported") => Response::Reply(format!("PacketSize={:x};qXfer:features:read+;swbreak+", MAX_MEMORY_READ * 4).into_bytes()),
+        "Attached" => Response::Reply(b"1".to_vec()),
+        "C" => Response::Reply(b"QC1".to_vec()),
+        "fThreadInfo" => Response::Reply(b"m1".to_vec()),
+        "sThreadInfo" => Response::Reply(b"l".to_vec()),
+        // So that GDB relocates a position-independent app's symbols.
+        "Offsets" => {
+            let load_base = target.load_base();
+            Response::Reply(format!("Text={load_base:x};Data={load_base:x};Bss={load_base:x}").into_bytes())
+        }
+        _ => match query.strip_prefix("Xfer:features:read:target.xml:") {
+            Some(range) => read_target_xml(target, range).unwrap_or_else(|| Response::error(EINVAL)),
+            None => Response::unsupported(),
+        },
+    }
+}
+
+/// Describes the registers, so that GDB knows whether the app is 32- or
+/// 64-bit without being told.
+fn target_xml(register_bytes: usize) -> String {
+    let bits = register_bytes * 8;
+    let mut registers = String::new();
+    for (number, name) in REGISTER_NAMES.iter().enumerate() {
+        let register_type = match number {
+            1 | PC_REGISTER_NUMBER => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        registers.push_str(&format!(r#"<reg name="{name}" bitsize="{bits}" type="{register_type}" regnum="{number}"/>"#));
+    }
+
+    format!(concat!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+        r#"<target version="1.0"><architecture>riscv:rv{}</architecture>"#,
+        r#"<feature name="org.gnu.gdb.riscv.cpu">{}</feature></target>"#,
+    ), bits, registers)
+}
+
+const REGISTER_NAMES: [&str; REGISTER_COUNT] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+    "pc",
+];
+
+fn read_target_xml(target: &impl GdbTarget, range: &str) -> Option<Response> {
+    let (offset, length) = range.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+
+    let xml = target_xml(target.register_bytes());
+    let chunk = xml.as_bytes().get(offset..).unwrap_or_default();
+    let (marker, chunk) = if chunk.len() > length { (b'm', &chunk[..length]) } else { (b'l', chunk) };
+
+    let mut reply = vec![marker];
+    reply.extend_from_slice(chunk);
+    Some(Response::Reply(reply))
+}
+
+fn read_registers(target: &impl GdbTarget) -> Response {
+    let register_bytes = target.register_bytes();
+    let reply = target.read_registers().into_iter()
+        .map(|value| encode_register(value, register_bytes))
+        .collect::<String>();
+    Response::Reply(reply.into_bytes())
+}
+
+fn write_registers(target: &mut impl GdbTarget, args: &str) -> Option<Response> {
+    let register_hex_len = target.register_bytes() * 2;
+    if args.len() != register_hex_len * REGISTER_COUNT {
+        return None;
+    }
+
+    let values = (0..REGISTER_COUNT)
+        .map(|number| decode_register(&args[number * register_hex_len..(number + 1) * register_hex_len]))
+        .collect::<Option<Vec<u64>>>()?;
+    for (number, value) in values.into_iter().enumerate() {
+        target.write_register(number, value);
+    }
+    Some(Response::ok())
+}
+
+fn read_register(target: &impl GdbTarget, args: &str) -> Option<Response> {
+    let number = usize::from_str_radix(args, 16).ok()?;
+    let value = target.read_registers().get(number).copied()?;
+    Some(Response::Reply(encode_register(value, target.register_bytes()).into_bytes()))
+}
+
+fn write_register(target: &mut impl GdbTarget, args: &str) -> Option<Response> {
+    let (number, value) = args.split_once('=')?;
+    let number = usize::from_str_radix(number, 16).ok()?;
+    if value.len() != target.register_bytes() * 2 {
+        return None;
+    }
+    let value = decode_register(value)?;
+
+    target.write_register(number, value).then(Response::ok)
+}
+
+fn read_memory(target: &mut impl GdbTarget, args: &str) -> Option<Response> {
+    let (addr, length) = parse_addr_length(args)?;
+
+    let mut reply = String::new();
+    for offset in 0..length.min(MAX_MEMORY_READ) as u64 {
+        match target.read_memory(addr.wrapping_add(offset)) {
+            Some(byte) => reply.push_str(&format!("{byte:02x}")),
+            // A partial read is fine, as long as it's not empty.
+            None => break,
+        }
+    }
+
+    if reply.is_empty() && length > 0 {
+        Some(Response::error(EFAULT))
+    } else {
+        Some(Response::Reply(reply.into_bytes()))
+    }
+}
+
+fn write_memory(target: &mut impl GdbTarget, args: &str) -> Option<Response> {
+    let (addr_length, data) = args.split_once(':')?;
+    let (addr, length) = parse_addr_length(addr_length)?;
+    let data = decode_hex(data)?;
+    if data.len() != length {
+        return None;
+    }
+
+    for (offset, byte) in data.into_iter().enumerate() {
+        if !target.write_memory(addr.wrapping_add(offset as u64), byte) {
+            return Some(Response::error(EFAULT));
+        }
+    }
+    Some(Response::ok())
+}
+
+/// Software (`Z0`) and hardware (`Z1`) breakpoints are both PC checks.
+/// Watchpoints aren't supported.
+fn set_breakpoint(breakpoints: &mut HashSet<u64>, args: &str, insert: bool) -> Option<Response> {
+    let (breakpoint_type, rest) = args.split_once(',')?;
+    if !matches!(breakpoint_type, "0" | "1") {
+        return Some(Response::unsupported());
+    }
+    // The kind (the size of the breakpoint instruction) doesn't matter to us.
+    let (addr, _kind) = rest.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+
+    if insert {
+        breakpoints.insert(addr);
+    } else {
+        breakpoints.remove(&addr);
+    }
+    Some(Response::ok())
+}
+
+fn parse_addr_length(args: &str) -> Option<(u64, usize)> {
+    let (addr, length) = args.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+/// Registers are sent in target byte order, which is little-endian.
+fn encode_register(value: u64, register_bytes: usize) -> String {
+    value.to_le_bytes()[..register_bytes].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_register(hex: &str) -> Option<u64> {
+    let bytes = decode_hex(hex)?;
+    if bytes.len() > 8 {
+        return None;
+    }
+    let mut le_bytes = [0; 8];
+    le_bytes[..bytes.len()].copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(le_bytes))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes().chunks(2)
+        .map(|pair| u8::from_str_radix(core::str::from_utf8(pair).ok().filter(|pair| pair.len() == 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockTarget {
+        registers: Vec<u64>,
+        memory: HashMap<u64, u8>,
+        load_base: u64,
+    }
+
+    impl MockTarget {
+        fn new() -> Self {
+            Self { registers: vec![0; REGISTER_COUNT], memory: HashMap::new(), load_base: 0 }
+        }
+    }
+
+    impl GdbTarget for MockTarget {
+        fn register_bytes(&self) -> usize { 8 }
+        fn read_registers(&self) -> Vec<u64> { self.registers.clone() }
+        fn write_register(&mut self, number: usize, value: u64) -> bool {
+            match self.registers.get_mut(number) {
+                Some(register) => { *register = value; true }
+                None => false,
+            }
+        }
+        fn load_base(&self) -> u64 { self.load_base }
+        fn read_memory(&mut self, addr: u64) -> Option<u8> { self.memory.get(&addr).copied() }
+        fn write_memory(&mut self, addr: u64, value: u8) -> bool {
+            match self.memory.get_mut(&addr) {
+                Some(byte) => { *byte = value; true }
+                None => false,
+            }
+        }
+        fn keep_waiting(&mut self) -> bool { true }
+    }
+
+    fn handle(target: &mut MockTarget, breakpoints: &mut HashSet<u64>, packet: &str) -> Response {
+        handle_packet(target, breakpoints, StopReason::Attached, packet.as_bytes())
+    }
+
+    fn reply(reply: &str) -> Response {
+        Response::Reply(reply.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn reads_and_writes_pc_little_endian() {
+        let mut target = MockTarget::new();
+        let mut breakpoints = HashSet::new();
+
+        assert_eq!(Response::ok(), handle(&mut target, &mut breakpoints, "P20=0010000000000000"));
+
+        assert_eq!(0x1000, target.registers[PC_REGISTER_NUMBER]);
+        assert_eq!(reply("0010000000000000"), handle(&mut target, &mut breakpoints, "p20"));
+    }
+
+    #[test]
+    fn g_packet_contains_all_registers() {
+        let mut target = MockTarget::new();
+        target.registers[1] = 0xab;
+
+        let Response::Reply(registers) = handle(&mut target, &mut HashSet::new(), "g") else {
+            panic!("Expected a reply");
+        };
+
+        assert_eq!(REGISTER_COUNT * 16, registers.len());
+        assert_eq!(b"ab00000000000000", &registers[16..32]);
+    }
+
+    #[test]
+    fn memory_reads_stop_at_inaccessible_memory() {
+        let mut target = MockTarget::new();
+        target.memory.extend([(0x2000, 0x12), (0x2001, 0x34)]);
+        let mut breakpoints = HashSet::new();
+
+        assert_eq!(reply("1234"), handle(&mut target, &mut breakpoints, "m2000,4"));
+        assert_eq!(Response::error(EFAULT), handle(&mut target, &mut breakpoints, "m3000,4"));
+    }
+
+    #[test]
+    fn memory_writes_go_through_the_target() {
+        let mut target = MockTarget::new();
+        target.memory.extend([(0x2000, 0), (0x2001, 0)]);
+        let mut breakpoints = HashSet::new();
+
+        assert_eq!(Response::ok(), handle(&mut target, &mut breakpoints, "M2000,2:beef"));
+        assert_eq!(Some(&0xef), target.memory.get(&0x2001));
+        assert_eq!(Response::error(EFAULT), handle(&mut target, &mut breakpoints, "M2001,2:beef"));
+    }
+
+    #[test]
+    fn breakpoints_are_inserted_and_removed() {
+        let mut target = MockTarget::new();
+        let mut breakpoints = HashSet::new();
+
+        assert_eq!(Response::ok(), handle(&mut target, &mut breakpoints, "Z0,10074,4"));
+        assert!(breakpoints.contains(&0x10074));
+        assert_eq!(Response::ok(), handle(&mut target, &mut breakpoints, "z0,10074,4"));
+        assert!(breakpoints.is_empty());
+        // Watchpoints aren't supported.
+        assert_eq!(Response::unsupported(), handle(&mut target, &mut breakpoints, "Z2,10074,4"));
+    }
+
+    #[test]
+    fn target_xml_is_read_in_chunks() {
+        let mut target = MockTarget::new();
+        let xml = target_xml(8);
+
+        let Response::Reply(first) = handle(&mut target, &mut HashSet::new(), "qXfer:features:read:target.xml:0,10") else {
+            panic!("Expected a reply");
+        };
+        let Response::Reply(rest) = handle(&mut target, &mut HashSet::new(), &format!("qXfer:features:read:target.xml:10,{:x}", xml.len())) else {
+            panic!("Expected a reply");
+        };
+
+        assert_eq!(b'm', first[0]);
+        assert_eq!(b'l', rest[0]);
+        assert_eq!(xml.as_bytes(), [&first[1..], &rest[1..]].concat());
+        assert!(xml.contains("riscv:rv64"));
+    }
+
+    #[test]
+    fn offsets_are_the_load_base() {
+        let mut target = MockTarget { load_base: 0x20_0000_0000, ..MockTarget::new() };
+
+        assert_eq!(reply("Text=2000000000;Data=2000000000;Bss=2000000000"), handle(&mut target, &mut HashSet::new(), "qOffsets"));
+    }
+
+    #[test]
+    fn control_packets() {
+        let mut target = MockTarget::new();
+        let mut breakpoints = HashSet::new();
+
+        assert_eq!(Response::Continue, handle(&mut target, &mut breakpoints, "c"));
+        assert_eq!(Response::Step, handle(&mut target, &mut breakpoints, "s"));
+        assert_eq!(Response::Detach, handle(&mut target, &mut breakpoints, "D"));
+        assert_eq!(Response::Kill { reply: true }, handle(&mut target, &mut breakpoints, "vKill;1"));
+        assert_eq!(reply("S05"), handle(&mut target, &mut breakpoints, "?"));
+        assert_eq!(Response::unsupported(), handle(&mut target, &mut breakpoints, "vCont?"));
+    }
+}
@@ -11,11 +11,12 @@ use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 
-use crate::deferred_space::{self, DefaultDeferredSpace, DeferredSpace, DeferredSpaceError, DeferredSpaceGet, DefaultDeferredSpaceCapId, DeferredSpacePublish, DeferredError};
+use crate::deferred_space::{self, DefaultDeferredSpace, DefaultDeferredSpaceSnapshot, DeferredSpace, DeferredSpaceError, DeferredSpaceGet, DefaultDeferredSpaceCapId, DeferredSpacePublish, DeferredError};
 use crate::hypervisor::hypervisor_event::{UnboundHypervisorEvent, HypervisorEventError};
 use crate::hypervisor::tab_context::TabContext;
 use crate::rollback_chain::RollbackChain;
-use crate::shm_space::{ShmCap, ShmCapId, ShmSpace, ShmSpaceError};
+use crate::shm_space::{ShmCap, ShmCapId, ShmSpace, ShmSpaceError, ShmSpaceMutex};
+use crate::snapshot::{SnapshotError, CorruptedSnafu};
 
 pub type GfxCapId = u64;
 pub type GfxCpuPresentBufferCapId = u64;
@@ -30,6 +31,22 @@ pub struct GfxSpace {
     cpu_present: CpuPresent,
 }
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GfxSpaceSnapshot {
+    root_deferred_space: DefaultDeferredSpaceSnapshot,
+    cpu_present_buffer_deferred_space: DefaultDeferredSpaceSnapshot,
+    cpu_present_buffer_infos: Vec<CpuPresentBufferInfoSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CpuPresentBufferInfoSnapshot {
+    gfx_cpu_present_buffer_cap_id: GfxCpuPresentBufferCapId,
+    parent_gfx_cap_id: GfxCapId,
+    present_buffer_format: u64,
+    present_buffer_size_px: Vec<u64>,
+    present_buffer_shm_cap_id: ShmCapId,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct GfxOutput {
     id: u64,
@@ -168,6 +185,57 @@ impl GfxSpace {
         }
     }
 
+    pub(crate) fn snapshot(&self) -> Result<GfxSpaceSnapshot, SnapshotError> {
+        let cpu_present_buffer_infos = self.cpu_present.space.iter()
+            .map(|(&gfx_cpu_present_buffer_cap_id, cpu_present_buffer_info)| CpuPresentBufferInfoSnapshot {
+                gfx_cpu_present_buffer_cap_id,
+                parent_gfx_cap_id: cpu_present_buffer_info.parent_gfx_cap_id,
+                present_buffer_format: cpu_present_buffer_info.present_buffer_format.into(),
+                present_buffer_size_px: cpu_present_buffer_info.present_buffer_size_px.clone(),
+                present_buffer_shm_cap_id: cpu_present_buffer_info.present_buffer_shm_cap_id,
+            })
+            .collect();
+
+        Ok(GfxSpaceSnapshot {
+            root_deferred_space: self.root_deferred_space.snapshot()?,
+            cpu_present_buffer_deferred_space: self.cpu_present_buffer_deferred_space.snapshot()?,
+            cpu_present_buffer_infos,
+        })
+    }
+
+    /// The root tree isn't stored in the snapshot, since it can be rebuilt from
+    /// the CPU present buffers' parents.
+    pub(crate) fn restore(tab_context: Arc<dyn TabContext>, gfx_space_snapshot: GfxSpaceSnapshot) -> Result<Self, SnapshotError> {
+        let GfxSpaceSnapshot { root_deferred_space, cpu_present_buffer_deferred_space, cpu_present_buffer_infos } = gfx_space_snapshot;
+
+        let mut gfx_space = Self {
+            root_deferred_space: DefaultDeferredSpace::restore(root_deferred_space)?,
+            root_tree: HashMap::new(),
+            cpu_present_buffer_deferred_space: DefaultDeferredSpace::restore(cpu_present_buffer_deferred_space)?,
+            get_outputs: GetOutputs::new(Arc::clone(&tab_context)),
+            cpu_present: CpuPresent::new(Arc::clone(&tab_context)),
+        };
+
+        for cpu_present_buffer_info in cpu_present_buffer_infos {
+            let CpuPresentBufferInfoSnapshot { gfx_cpu_present_buffer_cap_id, parent_gfx_cap_id, present_buffer_format, present_buffer_size_px, present_buffer_shm_cap_id } = cpu_present_buffer_info;
+
+            let present_buffer_format = PresentBufferFormat::try_from(present_buffer_format)
+                .map_err(|_| CorruptedSnafu { reason: format!("Unknown present buffer format {present_buffer_format}") }.build())?;
+            ensure!(gfx_space.cpu_present_buffer_deferred_space.contains_key(gfx_cpu_present_buffer_cap_id), CorruptedSnafu { reason: format!("Gfx CPU present buffer cap {gfx_cpu_present_buffer_cap_id} has info but does not exist") });
+            ensure!(gfx_space.root_deferred_space.contains_key(parent_gfx_cap_id), CorruptedSnafu { reason: format!("Gfx CPU present buffer cap {gfx_cpu_present_buffer_cap_id} has parent {parent_gfx_cap_id}, which does not exist") });
+            ensure!(gfx_space.cpu_present.get_info(gfx_cpu_present_buffer_cap_id).is_none(), CorruptedSnafu { reason: format!("Gfx CPU present buffer cap {gfx_cpu_present_buffer_cap_id} has more than one info") });
+
+            gfx_space.cpu_present.add_info(gfx_cpu_present_buffer_cap_id, parent_gfx_cap_id, present_buffer_format, present_buffer_size_px, present_buffer_shm_cap_id);
+            gfx_space.root_tree.entry(parent_gfx_cap_id).or_default().insert(gfx_cpu_present_buffer_cap_id);
+        }
+
+        // Every CPU present buffer cap has an info, so if the counts match,
+        // no cap is missing one.
+        ensure!(gfx_space.cpu_present.space.len() == gfx_space.cpu_present_buffer_deferred_space.len(), CorruptedSnafu { reason: "Not every gfx CPU present buffer cap has info" });
+
+        Ok(gfx_space)
+    }
+
     pub fn new_gfx_cap(&mut self) -> Result<GfxCapId, GfxSpaceError> {
         self.root_deferred_space.new_cap(GFX_CONTEXT).context(DeferredSpaceSnafu)
     }
@@ -176,7 +244,7 @@ impl GfxSpace {
         self.root_deferred_space.get_blocking(GFX_CONTEXT, gfx_cap_id, output_shm_cap_id, shm_space).context(DeferredSpaceSnafu)
     }
 
-    pub fn get_outputs_deferred(&mut self, gfx_cap_id: GfxCapId, shm_space: &mut ShmSpace) -> Result<(), ()> {
+    pub fn get_outputs_deferred(&mut self, gfx_cap_id: GfxCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()> {
         self.root_deferred_space.get_deferred(&mut self.get_outputs, gfx_cap_id, shm_space)
     }
 
@@ -223,7 +291,7 @@ impl GfxSpace {
         self.cpu_present_buffer_deferred_space.publish_blocking(GFX_CPU_PRESENT_CONTEXT, gfx_cpu_present_buffer_cap_id, cpu_present_buffer_info.present_buffer_shm_cap_id, output_shm_cap_id, shm_space).context(DeferredSpaceSnafu)
     }
 
-    pub fn cpu_present_deferred(&mut self, gfx_cpu_present_buffer_cap_id: GfxCpuPresentBufferCapId, shm_space: &mut ShmSpace) -> Result<(), ()> {
+    pub fn cpu_present_deferred(&mut self, gfx_cpu_present_buffer_cap_id: GfxCpuPresentBufferCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()> {
         self.cpu_present_buffer_deferred_space.publish_deferred(&mut self.cpu_present, gfx_cpu_present_buffer_cap_id, shm_space)
     }
 
@@ -0,0 +1,198 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{Builder, JoinHandle};
+
+use crate::channel_space::RecvProgress;
+use crate::deferred_space::app_global_deferred_space::{Task, TaskId};
+use crate::nushift_subsystem::NushiftSubsystem;
+use crate::syscall_tracer::SyscallTraceEvent;
+
+/// Tasks in different spaces can run at the same time, but tasks in the same
+/// space wait for each other on the space's lock, so there's no point having
+/// more workers than spaces with deferred tasks.
+const WORKER_COUNT: usize = 3;
+
+/// `None` once the pool has been dropped.
+type TaskSend = Arc<Mutex<Option<Sender<(TaskId, Task)>>>>;
+
+/// Worker threads that run an app's deferred tasks, while the app carries on
+/// running.
+///
+/// The app finds out that a task has finished through
+/// `TabControl::blocking_on_tasks`, which is what `BlockOnDeferredTasks` waits
+/// on.
+pub(crate) struct DeferredTaskPool {
+    /// Shared with the workers, so that a channel receive that's waiting for a
+    /// message can be dispatched again once there is one. Taken when the pool
+    /// is dropped, so that the workers stop even if channels still have ways
+    /// to wake receives.
+    task_send: TaskSend,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl DeferredTaskPool {
+    pub(crate) fn new(nushift_subsystem: &Arc<NushiftSubsystem>) -> Result<Self, io::Error> {
+        let (task_send, task_receive) = mpsc::channel();
+        let task_receive = Arc::new(Mutex::new(task_receive));
+
+        let mut deferred_task_pool = Self { task_send: Arc::new(Mutex::new(Some(task_send))), workers: Vec::with_capacity(WORKER_COUNT) };
+        for _ in 0..WORKER_COUNT {
+            let nushift_subsystem = Arc::clone(nushift_subsystem);
+            let task_send = Arc::clone(&deferred_task_pool.task_send);
+            let task_receive = Arc::clone(&task_receive);
+            // If this fails, dropping `deferred_task_pool` stops the workers
+            // that were already spawned.
+            let worker = Builder::new().spawn(move || Self::work(&nushift_subsystem, &task_send, &task_receive))?;
+            deferred_task_pool.workers.push(worker);
+        }
+
+        Ok(deferred_task_pool)
+    }
+
+    /// `task` must have been started with `AppGlobalDeferredSpace::start_tasks`.
+    pub(crate) fn dispatch(&self, task_id: TaskId, task: Task) {
+        self.task_send.lock().unwrap()
+            .as_ref()
+            .expect("Only taken when dropped")
+            .send((task_id, task))
+            .expect("Workers only stop once the sender is dropped");
+    }
+
+    fn work(nushift_subsystem: &NushiftSubsystem, task_send: &TaskSend, task_receive: &Mutex<Receiver<(TaskId, Task)>>) {
+        loop {
+            // The lock is only held while receiving, not while running the
+            // task, so that other workers can receive the next task.
+            let received = task_receive.lock().unwrap().recv();
+            let Ok((task_id, task)) = received else {
+                // The pool was dropped.
+                return;
+            };
+
+            // Hold back deferred tasks while the tab is paused, since they
+            // would otherwise carry on changing the app's state.
+            nushift_subsystem.tab_control.wait_while_paused();
+
+            Self::run_task(nushift_subsystem, task_send, task_id, task);
+        }
+    }
+
+    /// Each task only locks its own space while it's processed, and the SHM
+    /// space at the end to move its SHM caps back in, so the app's memory
+    /// accesses carry on in the meantime.
+    ///
+    /// A channel receive that has nothing to receive yet is neither finished
+    /// nor holding a worker. It's sent back to the workers once it can
+    /// carry on.
+    fn run_task(subsystem: &NushiftSubsystem, task_send: &TaskSend, task_id: TaskId, task: Task) {
+        let task_description = format!("{task:?}");
+        // TODO: On internal error, terminate app (?)
+        let succeeded = match task {
+            Task::AccessibilityTreePublishRON { accessibility_tree_cap_id } => {
+                subsystem.accessibility_tree_space.lock().unwrap().publish_accessibility_tree_ron_deferred(accessibility_tree_cap_id, &subsystem.shm_space).is_ok()
+            }
+            Task::AccessibilityTreePublish { accessibility_tree_cap_id } => {
+                subsystem.accessibility_tree_space.lock().unwrap().publish_accessibility_tree_deferred(accessibility_tree_cap_id, &subsystem.shm_space).is_ok()
+            }
+            Task::TitlePublish { title_cap_id } => {
+                subsystem.title_space.lock().unwrap().publish_title_deferred(title_cap_id, &subsystem.shm_space).is_ok()
+            }
+            Task::GfxGetOutputs { gfx_cap_id } => {
+                subsystem.gfx_space.lock().unwrap().get_outputs_deferred(gfx_cap_id, &subsystem.shm_space).is_ok()
+            }
+            Task::GfxCpuPresent { gfx_cpu_present_buffer_cap_id } => {
+                subsystem.gfx_space.lock().unwrap().cpu_present_deferred(gfx_cpu_present_buffer_cap_id, &subsystem.shm_space).is_ok()
+            }
+            Task::ChannelSend { channel_cap_id } => {
+                subsystem.channel_space.lock().unwrap().send_deferred(channel_cap_id, &subsystem.shm_space).is_ok()
+            }
+            Task::ChannelRecv { channel_cap_id } => {
+                let task_send = Arc::clone(task_send);
+                let wake_receiver = Box::new(move || {
+                    // If the pool has been dropped, the tab is closing and
+                    // there's nothing left to wake.
+                    if let Some(task_send) = task_send.lock().unwrap().as_ref() {
+                        let _ = task_send.send((task_id, Task::ChannelRecv { channel_cap_id }));
+                    }
+                });
+
+                match subsystem.channel_space.lock().unwrap().recv_deferred(channel_cap_id, &subsystem.shm_space, wake_receiver) {
+                    Ok(RecvProgress::Waiting) => return,
+                    Ok(RecvProgress::Finished) => true,
+                    Err(()) => false,
+                }
+            }
+        };
+        subsystem.trace(|| SyscallTraceEvent::DeferredTaskFinished { task_id, task: task_description, succeeded });
+
+        // Finish the task before waking up the app, so that if it's blocked on
+        // this task, it can consume it.
+        subsystem.app_global_deferred_space.lock().unwrap().finish_task(task_id);
+
+        let (lock, cvar) = subsystem.tab_control.blocking_on_tasks();
+        let mut guard = lock.lock().unwrap();
+        guard.remove(&task_id);
+        cvar.notify_all();
+    }
+}
+
+impl Drop for DeferredTaskPool {
+    /// Blocks until every dispatched task has finished, so that their SHM caps
+    /// are back in the SHM space.
+    fn drop(&mut self) {
+        drop(self.task_send.lock().unwrap().take());
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                tracing::error!("A deferred task worker panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hypervisor::hypervisor_event::UnboundHypervisorEvent;
+    use crate::hypervisor::tab_context::{RecordingTabContext, TabContext};
+    use crate::hypervisor::tab_control::TabControl;
+    use crate::shm_space::{CapType, PagingScheme, ShmType};
+
+    use super::*;
+
+    #[test]
+    fn dispatched_title_publish_finishes_and_moves_caps_back() {
+        let tab_context = Arc::new(RecordingTabContext::default());
+        let nushift_subsystem = Arc::new(NushiftSubsystem::new(Arc::clone(&tab_context) as Arc<dyn TabContext>, Arc::new(TabControl::new()), PagingScheme::Sv39));
+
+        let title_cap_id = nushift_subsystem.title_space.lock().unwrap().new_title_cap().expect("Should succeed");
+        let (input_shm_cap_id, output_shm_cap_id) = {
+            let mut shm_space = nushift_subsystem.shm_space();
+            let (input_shm_cap_id, input_shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+            postcard::to_slice("Title", input_shm_cap.backing_mut()).expect("Should succeed");
+            let (output_shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+            (input_shm_cap_id, output_shm_cap_id)
+        };
+        let task_id = {
+            let mut app_global_deferred_space = nushift_subsystem.app_global_deferred_space.lock().unwrap();
+            let mut task = app_global_deferred_space.allocate_task(Task::TitlePublish { title_cap_id }).expect("Should succeed");
+            nushift_subsystem.title_space.lock().unwrap().publish_title_blocking(title_cap_id, input_shm_cap_id, output_shm_cap_id, &mut nushift_subsystem.shm_space()).expect("Should succeed");
+            task.push_task()
+        };
+
+        {
+            let deferred_task_pool = DeferredTaskPool::new(&nushift_subsystem).expect("Should succeed");
+            for (task_id, task) in nushift_subsystem.app_global_deferred_space.lock().unwrap().start_tasks() {
+                deferred_task_pool.dispatch(task_id, task);
+            }
+        }
+
+        assert!(nushift_subsystem.shm_space().get_shm_cap_app(output_shm_cap_id).is_ok());
+        assert!(matches!(tab_context.events.lock().unwrap()[..], [UnboundHypervisorEvent::TitleChange(ref title)] if title == "Title"));
+        // Finished, so it can be snapshotted.
+        assert!(nushift_subsystem.app_global_deferred_space.lock().unwrap().snapshot().is_ok());
+        assert!(nushift_subsystem.tab_control.blocking_on_tasks().0.lock().unwrap().get(&task_id).is_none());
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryIter};
+use std::time::Duration;
+
+use super::Hypervisor;
+use super::hypervisor_event::{HypervisorEvent, HypervisorEventError};
+
+impl Hypervisor {
+    /// Create a hypervisor that is not attached to a GUI, e.g. for running apps
+    /// in CI.
+    ///
+    /// Instead of being handled by a shell, every event from every tab (frames,
+    /// title changes and debug prints) is queued up in the returned
+    /// `HeadlessEvents`. Gfx output sizes and scales are whatever you pass to
+    /// the `Hypervisor` when adding or updating tabs.
+    pub fn new_headless() -> (Self, HeadlessEvents) {
+        let (sender, receiver) = mpsc::channel();
+
+        let hypervisor = Hypervisor::new(move |hypervisor_event| {
+            sender.send(hypervisor_event).map_err(|_| HypervisorEventError::SubmitCommandError)
+        });
+
+        (hypervisor, HeadlessEvents { receiver })
+    }
+}
+
+/// The events sent by the tabs of a headless `Hypervisor`.
+///
+/// The queue is unbounded, so if you're not interested in frames, you should
+/// still drain it regularly.
+pub struct HeadlessEvents {
+    receiver: Receiver<HypervisorEvent>,
+}
+
+impl HeadlessEvents {
+    /// Block until the next event. Returns `None` once the `Hypervisor` has
+    /// been dropped and all events have been received.
+    pub fn recv(&self) -> Option<HypervisorEvent> {
+        self.receiver.recv().ok()
+    }
+
+    /// Block until the next event, or until `timeout` elapses.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<HypervisorEvent, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// The next event, if one is already queued up.
+    pub fn try_recv(&self) -> Option<HypervisorEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Iterate over the events that are already queued up, without blocking.
+    pub fn try_iter(&self) -> TryIter<'_, HypervisorEvent> {
+        self.receiver.try_iter()
+    }
+}
+
+/// Blocks on each event. The iterator ends once the `Hypervisor` has been
+/// dropped and all events have been received.
+impl Iterator for HeadlessEvents {
+    type Item = HypervisorEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reusable_id_pool::{ArcId, ReusableIdPool};
+
+    use super::*;
+
+    #[test]
+    fn new_headless_queues_up_events() {
+        let (hypervisor, events) = Hypervisor::new_headless();
+        let tab_id = ReusableIdPool::new().allocate();
+
+        (hypervisor.hypervisor_event_handler)(HypervisorEvent::TitleChange(ArcId::clone(&tab_id), "Title".into())).expect("Should succeed");
+        (hypervisor.hypervisor_event_handler)(HypervisorEvent::DebugPrint(ArcId::clone(&tab_id), "Hello".into())).expect("Should succeed");
+
+        assert!(matches!(events.try_recv(), Some(HypervisorEvent::TitleChange(id, title)) if id == tab_id && title == "Title"));
+        assert!(matches!(events.try_recv(), Some(HypervisorEvent::DebugPrint(id, message)) if id == tab_id && message == "Hello"));
+        assert!(events.try_recv().is_none());
+    }
+
+    #[test]
+    fn headless_events_ends_when_hypervisor_is_dropped() {
+        let (hypervisor, mut events) = Hypervisor::new_headless();
+
+        drop(hypervisor);
+
+        assert!(events.next().is_none());
+    }
+}
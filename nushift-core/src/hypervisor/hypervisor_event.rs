@@ -8,6 +8,7 @@ use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 
 use crate::gfx_space::PresentBufferFormat;
+use crate::process_control_block::ExitReason;
 
 /// For now, do Fn not FnMut, because we actually don't need mutability for
 /// ExtEventSink::submit_command because it uses a lock. We can always expand to
@@ -19,11 +20,23 @@ pub type HypervisorEventHandler = Arc<dyn HypervisorEventHandlerFn>;
 pub enum HypervisorEvent {
     TitleChange(ArcId, String),
     GfxCpuPresent(ArcId, PresentBufferFormat, Vec<u64>, Arc<[u8]>),
+    DebugPrint(ArcId, String),
+    /// The app stopped running without an error, e.g. because it called
+    /// `Exit`. Not sent when the tab is closed.
+    TabExited(ArcId, ExitReason),
+    /// The app was stopped because of an error, e.g. an illegal instruction,
+    /// an out-of-bounds load or store, or `ebreak`. The error is formatted
+    /// for display, and the PC is where the app was when it happened, if it
+    /// was running.
+    TabCrashed(ArcId, String, Option<u64>),
 }
 
 pub(crate) enum UnboundHypervisorEvent {
     TitleChange(String),
     GfxCpuPresent(PresentBufferFormat, Vec<u64>, Arc<[u8]>),
+    DebugPrint(String),
+    TabExited(ExitReason),
+    TabCrashed(String, Option<u64>),
 }
 
 impl HypervisorEvent {
@@ -31,6 +44,9 @@ impl HypervisorEvent {
         match unbound_hyp_event {
             UnboundHypervisorEvent::TitleChange(new_title) => HypervisorEvent::TitleChange(tab_id, new_title),
             UnboundHypervisorEvent::GfxCpuPresent(present_buffer_format, size_px, buffer) => HypervisorEvent::GfxCpuPresent(tab_id, present_buffer_format, size_px, buffer),
+            UnboundHypervisorEvent::DebugPrint(message) => HypervisorEvent::DebugPrint(tab_id, message),
+            UnboundHypervisorEvent::TabExited(exit_reason) => HypervisorEvent::TabExited(tab_id, exit_reason),
+            UnboundHypervisorEvent::TabCrashed(error, pc) => HypervisorEvent::TabCrashed(tab_id, error, pc),
         }
     }
 
@@ -38,6 +54,9 @@ impl HypervisorEvent {
         match self {
             Self::TitleChange(tab_id, ..) => Some(ArcId::clone(tab_id)),
             Self::GfxCpuPresent(tab_id, ..) => Some(ArcId::clone(tab_id)),
+            Self::DebugPrint(tab_id, ..) => Some(ArcId::clone(tab_id)),
+            Self::TabExited(tab_id, ..) => Some(ArcId::clone(tab_id)),
+            Self::TabCrashed(tab_id, ..) => Some(ArcId::clone(tab_id)),
         }
     }
 }
@@ -2,19 +2,41 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::{fs, collections::HashMap};
+use std::collections::HashMap;
 
 use reusable_id_pool::{ReusableIdPool, ArcId};
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
 
+pub(super) mod deferred_task_pool;
+pub(super) mod headless;
 pub(super) mod hypervisor_event;
 pub(super) mod tab;
+pub(super) mod tab_config;
 pub(super) mod tab_context;
+pub(super) mod tab_control;
+pub(super) mod tab_image;
 
+use crate::channel_space::{self, ChannelMessageError};
+use crate::elf_loader::PagingSchemeNoteError;
+use crate::gdb_stub::GdbServerAddress;
 use crate::gfx_space::GfxOutput;
+use crate::process_control_block::{ExitReason, ProcessControlBlockError};
+use crate::shm_space::MemoryUsage;
+use crate::snapshot::{SnapshotError, TabNotFoundSnafu};
 
 use self::hypervisor_event::{HypervisorEventHandler, HypervisorEventHandlerFn};
 use self::tab::Tab;
+use self::tab_config::TabConfig;
+use self::tab_image::TabImageSource;
+
+/// The image that `Hypervisor::add_new_tab` runs. This is relative to the
+/// working directory, so it only works when running from the `nushift` crate.
+const HELLO_WORLD_IMAGE_PATH: &str = "../examples/hello-world/zig-out/bin/hello-world";
 
 pub struct Hypervisor {
     tabs: HashMap<ArcId, Tab>,
@@ -23,25 +45,23 @@ pub struct Hypervisor {
 }
 
 trait TabLoader {
-    fn load(tab: &mut Tab, hypervisor_event_handler: &HypervisorEventHandler);
+    fn load(tab: &mut Tab, source: TabImageSource, tab_config: TabConfig, hypervisor_event_handler: &HypervisorEventHandler) -> Result<(), TabLoadError>;
 }
 
 struct RealLoader;
 struct MockLoader;
 
 impl TabLoader for RealLoader {
-    fn load(tab: &mut Tab, hypervisor_event_handler: &HypervisorEventHandler) {
-        let binary_blob_result = fs::read("../examples/hello-world/zig-out/bin/hello-world");
-        match binary_blob_result {
-            Ok(binary_blob) => tab.load_and_run(binary_blob, Arc::clone(hypervisor_event_handler)),
-            Err(err) => tracing::error!("Hardcoded binary blob path error: {err:?}"),
-        }
+    fn load(tab: &mut Tab, source: TabImageSource, tab_config: TabConfig, hypervisor_event_handler: &HypervisorEventHandler) -> Result<(), TabLoadError> {
+        let image = source.into_image()?;
+        tab.load_and_run(image, tab_config, Arc::clone(hypervisor_event_handler))
     }
 }
 
 impl TabLoader for MockLoader {
-    fn load(_tab: &mut Tab, _hypervisor_event_handler: &HypervisorEventHandler) {
+    fn load(_tab: &mut Tab, _source: TabImageSource, _tab_config: TabConfig, _hypervisor_event_handler: &HypervisorEventHandler) -> Result<(), TabLoadError> {
         // Intentionally empty. This is a mock.
+        Ok(())
     }
 }
 
@@ -61,6 +81,11 @@ impl Hypervisor {
     /// owned by the `Hypervisor`.
     ///
     /// The newly-created ID is returned.
+    ///
+    /// The new tab runs the hello world example. If that fails to load, the
+    /// error is logged and the tab is still added, but nothing runs in it. Use
+    /// `add_new_tab_with_image` to run a different image and get load errors
+    /// back.
     pub fn add_new_tab(&mut self, initial_gfx_output: GfxOutput) -> ArcId {
         self.add_new_tab_impl::<RealLoader>(initial_gfx_output)
     }
@@ -72,13 +97,84 @@ impl Hypervisor {
         let new_tab_id_cloned_for_key = ArcId::clone(&new_tab_id);
 
         let mut new_tab = Tab::new(new_tab_id_cloned_for_tab, initial_gfx_output);
-        L::load(&mut new_tab, &self.hypervisor_event_handler);
+        let source = TabImageSource::Path(PathBuf::from(HELLO_WORLD_IMAGE_PATH));
+        if let Err(load_error) = L::load(&mut new_tab, source, TabConfig::default(), &self.hypervisor_event_handler) {
+            tracing::error!("Failed to load hello world tab: {load_error:?}, tab ID {new_tab_id:?}");
+        }
 
         self.tabs.insert(new_tab_id_cloned_for_key, new_tab);
 
         new_tab_id
     }
 
+    /// Add a new tab that runs the ELF image from `source`.
+    ///
+    /// The image is read and loaded before this method returns, so any problem
+    /// with it is returned as an error, in which case no tab is added.
+    /// Otherwise, the newly-created ID is returned.
+    pub fn add_new_tab_with_image(&mut self, initial_gfx_output: GfxOutput, source: TabImageSource) -> Result<ArcId, TabLoadError> {
+        self.add_new_tab_with_config_impl::<RealLoader>(initial_gfx_output, source, TabConfig::default())
+    }
+
+    /// Like `add_new_tab_with_image`, but the app runs within the limits in
+    /// `tab_config`.
+    pub fn add_new_tab_with_config(&mut self, initial_gfx_output: GfxOutput, source: TabImageSource, tab_config: TabConfig) -> Result<ArcId, TabLoadError> {
+        self.add_new_tab_with_config_impl::<RealLoader>(initial_gfx_output, source, tab_config)
+    }
+
+    fn add_new_tab_with_config_impl<L: TabLoader>(&mut self, initial_gfx_output: GfxOutput, source: TabImageSource, tab_config: TabConfig) -> Result<ArcId, TabLoadError> {
+        let new_tab_id = self.tabs_reusable_id_pool.allocate();
+
+        let mut new_tab = Tab::new(ArcId::clone(&new_tab_id), initial_gfx_output);
+        L::load(&mut new_tab, source, tab_config, &self.hypervisor_event_handler)?;
+
+        self.tabs.insert(ArcId::clone(&new_tab_id), new_tab);
+
+        Ok(new_tab_id)
+    }
+
+    /// Add a new tab that restores and carries on running the app in a
+    /// snapshot from `snapshot_tab`, read from `source`.
+    ///
+    /// As with `add_new_tab_with_config`, no tab is added if the snapshot can't
+    /// be restored.
+    pub fn add_new_tab_from_snapshot(&mut self, initial_gfx_output: GfxOutput, source: TabImageSource, tab_config: TabConfig) -> Result<ArcId, TabLoadError> {
+        let snapshot = source.into_image()?;
+        let new_tab_id = self.tabs_reusable_id_pool.allocate();
+
+        let mut new_tab = Tab::new(ArcId::clone(&new_tab_id), initial_gfx_output);
+        new_tab.restore_and_run(&snapshot, tab_config, Arc::clone(&self.hypervisor_event_handler))?;
+
+        self.tabs.insert(ArcId::clone(&new_tab_id), new_tab);
+
+        Ok(new_tab_id)
+    }
+
+    /// Save the whole state of the app in a tab, so that it can be restored
+    /// later with `add_new_tab_from_snapshot`. The tab must be paused with
+    /// `pause_tab` first.
+    pub fn snapshot_tab(&self, tab_id: &ArcId) -> Result<Vec<u8>, SnapshotError> {
+        self.tabs.get(tab_id).context(TabNotFoundSnafu)?.snapshot()
+    }
+
+    /// Connect the apps in two tabs with a channel, so that they can talk to
+    /// each other. Each app is given its end in a message on its host channel.
+    ///
+    /// This is the only way for apps in different tabs to start talking, so
+    /// apps can only talk to the apps that the embedder has connected them to,
+    /// and to apps whose channel ends those apps send them.
+    ///
+    /// If only the first app could be given its end, it finds that the other
+    /// end is closed.
+    pub fn connect_tabs(&self, first_tab_id: &ArcId, second_tab_id: &ArcId) -> Result<(), ConnectTabsError> {
+        let first_tab = self.tabs.get(first_tab_id).context(NoSuchTabSnafu)?;
+        let second_tab = self.tabs.get(second_tab_id).context(NoSuchTabSnafu)?;
+
+        let (first_end, second_end) = channel_space::new_channel();
+        first_tab.give_channel_end(first_end)?;
+        second_tab.give_channel_end(second_end)
+    }
+
     /// Close a tab.
     ///
     /// If the passed-in `tab_id` does not exist, this method does nothing.
@@ -89,6 +185,77 @@ impl Hypervisor {
         }
     }
 
+    /// Pause the app in a tab, e.g. because the tab is in the background. This
+    /// blocks until the app has stopped at an instruction boundary, and its
+    /// deferred tasks are held back until it is resumed.
+    ///
+    /// If the passed-in `tab_id` does not exist, or the app isn't running,
+    /// this method does nothing.
+    pub fn pause_tab(&mut self, tab_id: &ArcId) {
+        if let Some(tab) = self.tabs.get(tab_id) {
+            tab.pause();
+        }
+    }
+
+    /// Continue running the app in a paused tab.
+    ///
+    /// If the passed-in `tab_id` does not exist, this method does nothing.
+    pub fn resume_tab(&mut self, tab_id: &ArcId) {
+        if let Some(tab) = self.tabs.get(tab_id) {
+            tab.resume();
+        }
+    }
+
+    /// Whether a tab is paused.
+    ///
+    /// Returns `None` if the passed-in `tab_id` does not exist.
+    pub fn is_tab_paused(&self, tab_id: &ArcId) -> Option<bool> {
+        self.tabs.get(tab_id).map(Tab::is_paused)
+    }
+
+    /// Whether the app in a tab has stopped running.
+    ///
+    /// Returns `None` if the passed-in `tab_id` does not exist.
+    pub fn is_tab_finished(&self, tab_id: &ArcId) -> Option<bool> {
+        self.tabs.get(tab_id).map(Tab::is_finished)
+    }
+
+    /// The cycles consumed so far by the app in a tab, which is the number of
+    /// instructions it has executed. While the app is running, this lags
+    /// behind by up to a few thousand instructions.
+    ///
+    /// Returns `None` if the passed-in `tab_id` does not exist.
+    pub fn tab_cycles(&self, tab_id: &ArcId) -> Option<u64> {
+        self.tabs.get(tab_id).map(Tab::cycles)
+    }
+
+    /// How much memory the app in a tab is using, by page size and by who made
+    /// the caps, and its `TabConfig::max_memory_bytes`.
+    ///
+    /// Returns `None` if the passed-in `tab_id` does not exist, or if nothing
+    /// ran in the tab.
+    pub fn tab_memory_usage(&self, tab_id: &ArcId) -> Option<MemoryUsage> {
+        self.tabs.get(tab_id)?.memory_usage()
+    }
+
+    /// Block until the app in a tab stops running, and return how it stopped.
+    /// The tab itself is not closed.
+    ///
+    /// Returns `None` if the passed-in `tab_id` does not exist, if nothing ran
+    /// in the tab, or if the tab has already been waited on.
+    pub fn wait_for_tab(&mut self, tab_id: &ArcId) -> Option<Result<ExitReason, TabRunError>> {
+        self.tabs.get_mut(tab_id)?.wait()
+    }
+
+    /// Update the gfx output of one tab.
+    ///
+    /// If the passed-in `tab_id` does not exist, this method does nothing.
+    pub fn update_tab_gfx_output(&mut self, tab_id: &ArcId, gfx_output: GfxOutput) {
+        if let Some(tab) = self.tabs.get_mut(tab_id) {
+            tab.update_gfx_output(gfx_output);
+        }
+    }
+
     /// Update all tab gfx outputs, e.g. when the window scale or size changes.
     ///
     /// When you can have multiple windows (in the future, possibly), you don't
@@ -101,9 +268,58 @@ impl Hypervisor {
     }
 }
 
+#[derive(Snafu, SnafuCliDebug)]
+pub enum TabLoadError {
+    #[snafu(display("Could not read the tab image at {}", path.display()))]
+    ReadImageError { path: PathBuf, source: io::Error },
+    #[snafu(display("The tab image loader failed"))]
+    ImageLoaderError { source: Box<dyn Error + Send + Sync> },
+    #[snafu(display("The image's paging scheme note is invalid"))]
+    PagingSchemeNoteError { source: PagingSchemeNoteError },
+    #[snafu(display("Could not load the image into a machine"))]
+    MachineLoadError { source: ProcessControlBlockError },
+    #[snafu(display("Failed to create the OS hypervisor thread"))]
+    HypervisorThreadSpawnError { source: io::Error },
+    #[snafu(display("Could not read the snapshot"))]
+    SnapshotDecodeError { source: SnapshotError },
+    #[snafu(display("Could not restore the app's state from the snapshot"))]
+    SubsystemRestoreError { source: SnapshotError },
+    #[snafu(display("Could not listen for a debugger at {gdb_server_address}"))]
+    GdbStubBindError { gdb_server_address: GdbServerAddress, source: io::Error },
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum ConnectTabsError {
+    #[snafu(display("The tab was not found"))]
+    NoSuchTab,
+    #[snafu(display("The app in the tab isn't running"))]
+    AppNotRunning,
+    #[snafu(display("Could not give the app its end of the channel"))]
+    HostChannelError { source: ChannelMessageError },
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum TabRunError {
+    #[snafu(display("Failed to create the OS machine thread"))]
+    MachineThreadSpawnError { source: io::Error },
+    #[snafu(display("Failed to create the OS threads for a hart the app spawned"))]
+    HartThreadSpawnError { source: io::Error },
+    #[snafu(display("Failed to create the OS deferred task threads"))]
+    DeferredTaskThreadSpawnError { source: io::Error },
+    #[snafu(display("The machine thread panicked"))]
+    MachineThreadPanicked,
+    #[snafu(display("The hypervisor thread panicked"))]
+    HypervisorThreadPanicked,
+    #[snafu(display("The machine stopped with an error"))]
+    MachineRunError { source: ProcessControlBlockError },
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::shm_space::ShmSpaceError;
+
     use super::*;
+    use self::tab_image::TabImageLoader;
 
     #[test]
     fn hypervisor_new_creates_new() {
@@ -121,6 +337,150 @@ mod tests {
         assert_eq!(1, hypervisor.tabs.len());
     }
 
+    #[test]
+    fn hypervisor_add_new_tab_with_image_adds_new_tab() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+
+        let tab_id = hypervisor.add_new_tab_with_config_impl::<MockLoader>(GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]), TabImageSource::Bytes(vec![]), TabConfig::default())
+            .expect("Should succeed");
+
+        assert!(hypervisor.tabs.contains_key(&tab_id));
+    }
+
+    #[test]
+    fn hypervisor_add_new_tab_with_image_returns_read_error_for_missing_path() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+
+        let result = hypervisor.add_new_tab_with_image(
+            GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]),
+            TabImageSource::Path(PathBuf::from("this/path/does/not/exist.elf")),
+        );
+
+        assert!(matches!(result, Err(TabLoadError::ReadImageError { .. })));
+        assert_eq!(0, hypervisor.tabs.len());
+    }
+
+    #[test]
+    fn hypervisor_add_new_tab_with_image_returns_loader_error() {
+        struct FailingLoader;
+        impl TabImageLoader for FailingLoader {
+            fn load_image(&mut self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+                Err("no image for you".into())
+            }
+        }
+
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+
+        let result = hypervisor.add_new_tab_with_image(
+            GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]),
+            TabImageSource::Loader(Box::new(FailingLoader)),
+        );
+
+        assert!(matches!(result, Err(TabLoadError::ImageLoaderError { .. })));
+        assert_eq!(0, hypervisor.tabs.len());
+    }
+
+    #[test]
+    fn hypervisor_add_new_tab_with_image_returns_machine_load_error_for_invalid_elf() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+
+        let result = hypervisor.add_new_tab_with_image(
+            GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]),
+            TabImageSource::Bytes(b"not an ELF".to_vec()),
+        );
+
+        assert!(matches!(result, Err(TabLoadError::MachineLoadError { .. })));
+        assert_eq!(0, hypervisor.tabs.len());
+    }
+
+    /// A 64-bit RISC-V ELF with just a header and one R+X LOAD segment of
+    /// `memsz` bytes at 0x10000, which covers the whole file.
+    fn elf_with_one_load_segment(memsz: u64) -> Vec<u8> {
+        const EHDR_SIZE: u16 = 64;
+        const PHDR_SIZE: u16 = 56;
+        let file_size = u64::from(EHDR_SIZE + PHDR_SIZE);
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: EXEC
+        elf.extend_from_slice(&0xf3u16.to_le_bytes()); // e_machine: RISC-V
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&0x10000u64.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&u64::from(EHDR_SIZE).to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        for half in [EHDR_SIZE, PHDR_SIZE, 1, 64, 0, 0] {
+            // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+
+        elf.extend_from_slice(&1u32.to_le_bytes()); // p_type: LOAD
+        elf.extend_from_slice(&5u32.to_le_bytes()); // p_flags: R+X
+        for word in [0, 0x10000, 0x10000, file_size, memsz, 0x1000] {
+            // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+            elf.extend_from_slice(&u64::to_le_bytes(word));
+        }
+        elf
+    }
+
+    #[test]
+    fn hypervisor_add_new_tab_with_config_returns_shm_space_error_for_elf_over_max_memory_bytes() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+        let tab_config = TabConfig { max_memory_bytes: Some(0x1000), ..TabConfig::default() };
+
+        let result = hypervisor.add_new_tab_with_config(
+            GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]),
+            TabImageSource::Bytes(elf_with_one_load_segment(0x2000)),
+            tab_config,
+        );
+
+        assert!(matches!(
+            result,
+            Err(TabLoadError::MachineLoadError { source: ProcessControlBlockError::ElfShmSpaceError { source: ShmSpaceError::MaxMemoryBytesExceeded { requested_bytes: 0x2000, used_bytes: 0, max_memory_bytes: 0x1000 } } }),
+        ));
+        assert_eq!(0, hypervisor.tabs.len());
+    }
+
+    #[test]
+    fn hypervisor_wait_for_tab_returns_none_if_nothing_ran() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+        let tab_id = hypervisor.add_new_tab_impl::<MockLoader>(GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]));
+
+        assert_eq!(Some(true), hypervisor.is_tab_finished(&tab_id));
+        assert!(hypervisor.wait_for_tab(&tab_id).is_none());
+    }
+
+    #[test]
+    fn hypervisor_tab_cycles_is_zero_if_nothing_ran() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+        let tab_id = hypervisor.add_new_tab_impl::<MockLoader>(GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]));
+
+        assert_eq!(Some(0), hypervisor.tab_cycles(&tab_id));
+        assert_eq!(None, hypervisor.tab_cycles(&ReusableIdPool::new().allocate()));
+    }
+
+    #[test]
+    fn hypervisor_add_new_tab_from_snapshot_returns_decode_error_for_non_snapshot() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+
+        let result = hypervisor.add_new_tab_from_snapshot(
+            GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]),
+            TabImageSource::Bytes(b"not a snapshot".to_vec()),
+            TabConfig::default(),
+        );
+
+        assert!(matches!(result, Err(TabLoadError::SnapshotDecodeError { source: SnapshotError::NotASnapshot })));
+        assert_eq!(0, hypervisor.tabs.len());
+    }
+
+    #[test]
+    fn hypervisor_snapshot_tab_requires_running_tab() {
+        let mut hypervisor = Hypervisor::new(|_| Ok(()));
+        let tab_id = hypervisor.add_new_tab_impl::<MockLoader>(GfxOutput::new(0, vec![1920, 1080], vec![1.25, 1.25]));
+
+        assert!(matches!(hypervisor.snapshot_tab(&tab_id), Err(SnapshotError::NotRunning)));
+        assert!(matches!(hypervisor.snapshot_tab(&ReusableIdPool::new().allocate()), Err(SnapshotError::TabNotFound)));
+    }
+
     #[test]
     fn hypervisor_close_tab_closes_existing_tab() {
         let mut hypervisor = Hypervisor::new(|_| Ok(()));
@@ -1,25 +1,44 @@
 // Copyright 2023 The Nushift Authors.
 // SPDX-License-Identifier: Apache-2.0
 
-use core::ops::DerefMut;
-use std::collections::HashSet;
-use std::sync::{mpsc, Arc, Mutex, Condvar};
-use std::thread::{Builder, JoinHandle};
+use core::fmt::LowerHex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Builder, JoinHandle, Scope};
 
+use ckb_vm::Register;
 use reusable_id_pool::ArcId;
+use snafu::prelude::*;
 
-use crate::deferred_space::app_global_deferred_space::Task;
+use crate::channel_space::ChannelEnd;
+use crate::elf_loader;
+use crate::gdb_stub::GdbStub;
 use crate::gfx_space::GfxOutput;
+use crate::isa;
 use crate::nushift_subsystem::NushiftSubsystem;
-use crate::process_control_block::ProcessControlBlock;
+use crate::process_control_block::{ProcessControlBlock, ExitReason};
+use crate::shm_space::MemoryUsage;
+use crate::register_ipc::{SyscallEnter, SyscallReturn};
+use crate::snapshot::{self, AppSnapshot, SnapshotError, CorruptedSnafu, NotPausedSnafu, NotRunningSnafu};
+use crate::thread_space::{ThreadId, MAIN_THREAD_ID};
 
-use super::hypervisor_event::HypervisorEventHandler;
-use super::tab_context::DefaultTabContext;
+use super::deferred_task_pool::DeferredTaskPool;
+use super::hypervisor_event::{HypervisorEventHandler, UnboundHypervisorEvent};
+use super::tab_context::{DefaultTabContext, TabContext};
+use super::tab_config::TabConfig;
+use super::tab_control::TabControl;
+use super::{ConnectTabsError, AppNotRunningSnafu, HostChannelSnafu};
+use super::{TabLoadError, PagingSchemeNoteSnafu, MachineLoadSnafu, HypervisorThreadSpawnSnafu, SnapshotDecodeSnafu, SubsystemRestoreSnafu, GdbStubBindSnafu};
+use super::{TabRunError, MachineThreadSpawnSnafu, HartThreadSpawnSnafu, DeferredTaskThreadSpawnSnafu, MachineThreadPanickedSnafu, MachineRunSnafu, HypervisorThreadPanickedSnafu};
 
 pub struct Tab {
     id: ArcId,
     gfx_output: Arc<Mutex<GfxOutput>>,
-    hypervisor_thread: Option<JoinHandle<()>>,
+    tab_control: Arc<TabControl>,
+    /// Kept so that the app's state can be snapshotted. Dropped when the tab
+    /// is closed.
+    nushift_subsystem: Option<Arc<NushiftSubsystem>>,
+    hypervisor_thread: Option<JoinHandle<Result<ExitReason, TabRunError>>>,
 }
 
 impl Tab {
@@ -29,6 +48,8 @@ impl Tab {
         Self {
             id,
             gfx_output,
+            tab_control: Arc::new(TabControl::new()),
+            nushift_subsystem: None,
             hypervisor_thread: None,
         }
     }
@@ -37,48 +58,291 @@ impl Tab {
         *self.gfx_output.lock().unwrap() = gfx_output;
     }
 
-    pub fn load_and_run(&mut self, image: Vec<u8>, hypervisor_event_handler: HypervisorEventHandler) {
-        let tab_id = ArcId::clone(&self.id);
-        let gfx_output = Arc::clone(&self.gfx_output);
+    /// Load `image` into a new machine, then run it on a new hypervisor
+    /// thread, within the limits in `tab_config`.
+    ///
+    /// Loading happens on the calling thread, so that load errors can be
+    /// returned to the caller.
+    ///
+    /// 32-bit ELFs get a 32-bit machine and an Sv32 space, everything else gets
+    /// a 64-bit machine and an Sv39 space, unless its ELF note asks for Sv48 or
+    /// Sv57 (see `elf_loader::requested_paging_scheme`).
+    pub fn load_and_run(&mut self, image: Vec<u8>, tab_config: TabConfig, hypervisor_event_handler: HypervisorEventHandler) -> Result<(), TabLoadError> {
+        let xlen = isa::elf_xlen(&image);
+        let paging_scheme = elf_loader::requested_paging_scheme(&image, xlen).context(PagingSchemeNoteSnafu)?;
+        let tab_context: Arc<dyn TabContext> = Arc::new(DefaultTabContext::new(ArcId::clone(&self.id), hypervisor_event_handler, Arc::clone(&self.gfx_output)));
+        let nushift_subsystem = NushiftSubsystem::new(Arc::clone(&tab_context), Arc::clone(&self.tab_control), paging_scheme);
+        let initial_gfx_output = self.gfx_output.lock().unwrap().clone();
+
+        match xlen {
+            32 => self.run_with::<u32, _>(tab_context, nushift_subsystem, tab_config, |machine| machine.load_machine(image, &initial_gfx_output).context(MachineLoadSnafu)),
+            _ => self.run_with::<u64, _>(tab_context, nushift_subsystem, tab_config, |machine| machine.load_machine(image, &initial_gfx_output).context(MachineLoadSnafu)),
+        }
+    }
+
+    /// Restore the app in `snapshot` (as made by `Tab::snapshot`), then run it
+    /// on a new hypervisor thread, within the limits in `tab_config`.
+    pub fn restore_and_run(&mut self, snapshot: &[u8], tab_config: TabConfig, hypervisor_event_handler: HypervisorEventHandler) -> Result<(), TabLoadError> {
+        let AppSnapshot { cpu, subsystem } = snapshot::decode(snapshot).context(SnapshotDecodeSnafu)?;
+
+        let tab_context: Arc<dyn TabContext> = Arc::new(DefaultTabContext::new(ArcId::clone(&self.id), hypervisor_event_handler, Arc::clone(&self.gfx_output)));
+        let nushift_subsystem = NushiftSubsystem::restore(Arc::clone(&tab_context), Arc::clone(&self.tab_control), subsystem).context(SubsystemRestoreSnafu)?;
+        let paging_scheme = nushift_subsystem.shm_space().paging_scheme();
+        if !paging_scheme.suits_xlen(cpu.xlen) {
+            return CorruptedSnafu { reason: format!("a {}-bit machine can't have an {paging_scheme:?} space", cpu.xlen) }.fail().context(SubsystemRestoreSnafu);
+        }
+
+        // `restore_machine` checks that the machine is the snapshot's width.
+        match cpu.xlen {
+            32 => self.run_with::<u32, _>(tab_context, nushift_subsystem, tab_config, |machine| machine.restore_machine(cpu).context(MachineLoadSnafu)),
+            _ => self.run_with::<u64, _>(tab_context, nushift_subsystem, tab_config, |machine| machine.restore_machine(cpu).context(MachineLoadSnafu)),
+        }
+    }
+
+    /// Set up a machine for `nushift_subsystem` with `set_up_machine`, then
+    /// run it on a new hypervisor thread.
+    fn run_with<R, F>(&mut self, tab_context: Arc<dyn TabContext>, mut nushift_subsystem: NushiftSubsystem, tab_config: TabConfig, set_up_machine: F) -> Result<(), TabLoadError>
+    where
+        R: Register + LowerHex + Send + 'static,
+        F: FnOnce(&mut ProcessControlBlock<R>) -> Result<(), TabLoadError>,
+    {
+        nushift_subsystem.set_syscall_tracer(tab_config.syscall_tracer.clone());
+        // Before the machine is set up, so that the app's ELF and stack count.
+        nushift_subsystem.shm_space().set_max_memory_bytes(tab_config.max_memory_bytes);
+        let machine_nushift_subsystem = Arc::new(nushift_subsystem);
+
+        let (syscall_enter_send, syscall_enter_receive) = mpsc::channel();
+        let (syscall_return_send, syscall_return_receive) = mpsc::channel();
+        let subsystem_cloned_for_machine = Arc::clone(&machine_nushift_subsystem);
+        let gdb_stub = match tab_config.gdb_server_address {
+            Some(ref gdb_server_address) => Some(GdbStub::bind(gdb_server_address).context(GdbStubBindSnafu { gdb_server_address: gdb_server_address.clone() })?),
+            None => None,
+        };
+        let mut machine = ProcessControlBlock::<R>::new(MAIN_THREAD_ID, syscall_enter_send, syscall_return_receive, subsystem_cloned_for_machine, Arc::clone(&self.tab_control), tab_config.clone());
+
+        set_up_machine(&mut machine)?;
+        if let Some(gdb_stub) = gdb_stub {
+            machine.attach_gdb_stub(gdb_stub);
+        }
 
+        self.nushift_subsystem = Some(Arc::clone(&machine_nushift_subsystem));
+        let tab_id = ArcId::clone(&self.id);
+        let tab_control = Arc::clone(&self.tab_control);
         let thread_builder = Builder::new();
-        let hypervisor_thread = thread_builder.spawn(move || Self::load_and_run_impl(tab_id, gfx_output, image, hypervisor_event_handler));
+        let hypervisor_thread = thread_builder
+            .spawn(move || {
+                let (run_result, pc) = Self::run_impl(tab_id, machine, machine_nushift_subsystem, tab_control, tab_config, syscall_enter_receive, syscall_return_send);
+                Self::report_run_result(tab_context.as_ref(), &run_result, pc);
+                run_result
+            })
+            .context(HypervisorThreadSpawnSnafu)?;
+
+        self.hypervisor_thread = Some(hypervisor_thread);
+        Ok(())
+    }
 
-        // If an error occurred, log the error and return.
-        let hypervisor_thread = match hypervisor_thread {
+    /// Returns how the app stopped, and the PC of the hart that stopped it, if
+    /// known.
+    ///
+    /// `machine` is the main hart. Harts that the app spawns are run alongside
+    /// it, and they all stop once one of them stops the app.
+    fn run_impl<R>(
+        tab_id: ArcId,
+        machine: ProcessControlBlock<R>,
+        machine_nushift_subsystem: Arc<NushiftSubsystem>,
+        tab_control: Arc<TabControl>,
+        tab_config: TabConfig,
+        syscall_enter_receive: Receiver<SyscallEnter>,
+        syscall_return_send: Sender<SyscallReturn>,
+    ) -> RunOutcome
+    where
+        R: Register + LowerHex + Send + 'static,
+    {
+        // Spawned before the machine, so that if it can't be, the machine
+        // isn't left running. Dropped (which waits for its tasks) once every
+        // hart has stopped making syscalls.
+        let deferred_task_pool = match DeferredTaskPool::new(&machine_nushift_subsystem) {
             Err(os_error) => {
-                tracing::error!("Failed to create OS hypervisor thread: {:?}, tab ID {:?}", os_error, self.id);
-                return;
+                tracing::error!("Failed to create OS deferred task threads: {:?}, tab ID {:?}", os_error, tab_id);
+                return (Err(os_error).context(DeferredTaskThreadSpawnSnafu), None);
             }
-            Ok(hypervisor_thread) => hypervisor_thread,
+            Ok(deferred_task_pool) => deferred_task_pool,
         };
 
-        self.hypervisor_thread = Some(hypervisor_thread);
+        let harts = Harts {
+            tab_id: &tab_id,
+            nushift_subsystem: &machine_nushift_subsystem,
+            tab_control: &tab_control,
+            tab_config: &tab_config,
+            deferred_task_pool: &deferred_task_pool,
+            load_base: machine.load_base(),
+            stopped_by: Mutex::new(None),
+        };
+        // The scope only ends once every hart's threads have.
+        thread::scope(|scope| harts.run_hart(scope, MAIN_THREAD_ID, machine, syscall_enter_receive, syscall_return_send));
+        let stopped_by = harts.stopped_by.into_inner().unwrap();
+        drop(deferred_task_pool);
+
+        let (run_result, pc) = stopped_by.expect("The main hart always stops the app when it stops");
+        let run_result = match run_result {
+            Ok(exit_reason) => {
+                tracing::info!("Exit reason: {exit_reason:?}");
+                Ok(exit_reason)
+            }
+            Err(run_error) => {
+                tracing::error!("Run error: {:?}, tab ID {:?}", run_error, tab_id);
+                Err(run_error)
+            }
+        };
+        (run_result, pc)
     }
 
-    fn load_and_run_impl(tab_id: ArcId, gfx_output: Arc<Mutex<GfxOutput>>, image: Vec<u8>, hypervisor_event_handler: HypervisorEventHandler) {
-        let tab_context = Arc::new(DefaultTabContext::new(ArcId::clone(&tab_id), hypervisor_event_handler, gfx_output));
-        let blocking_on_tasks = Arc::new((Mutex::new(HashSet::new()), Condvar::new()));
-        let machine_nushift_subsystem = Arc::new(Mutex::new(NushiftSubsystem::new(tab_context, blocking_on_tasks)));
+    /// Let the embedder know that the app has stopped, unless it was stopped
+    /// by closing the tab, in which case the embedder already knows.
+    fn report_run_result(tab_context: &dyn TabContext, run_result: &Result<ExitReason, TabRunError>, pc: Option<u64>) {
+        let unbound_hypervisor_event = match run_result {
+            Ok(ExitReason::Cancelled) => return,
+            Ok(exit_reason) => UnboundHypervisorEvent::TabExited(*exit_reason),
+            Err(run_error) => UnboundHypervisorEvent::TabCrashed(format!("{run_error:?}"), pc),
+        };
 
-        let (syscall_enter_send, syscall_enter_receive) = mpsc::channel();
-        let (syscall_return_send, syscall_return_receive) = mpsc::channel();
-        let subsystem_cloned_for_machine = Arc::clone(&machine_nushift_subsystem);
-        let mut machine = ProcessControlBlock::<u64>::new(syscall_enter_send, syscall_return_receive, subsystem_cloned_for_machine);
+        if let Err(hypervisor_event_error) = tab_context.send_hypervisor_event(unbound_hypervisor_event) {
+            tracing::debug!("Submit failed: {hypervisor_event_error}");
+        }
+    }
 
-        let result = machine.load_machine(image);
+    /// Whether the app in this tab has stopped running. A tab that never ran
+    /// counts as finished.
+    pub fn is_finished(&self) -> bool {
+        self.hypervisor_thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
 
-        // If an error occurred, log the error and return.
-        if let Err(wrapper_error) = result {
-            tracing::error!("Failed to load machine: {:?}, tab ID: {:?}", wrapper_error, tab_id);
-            return;
+    /// Stop the app in this tab at an instruction boundary, and block until it
+    /// has stopped. Does nothing if the app isn't running.
+    pub fn pause(&self) {
+        if !self.is_finished() {
+            self.tab_control.pause();
         }
+    }
 
-        let thread_builder = Builder::new();
-        let machine_thread = thread_builder.spawn(move || machine.run());
+    pub fn resume(&self) {
+        self.tab_control.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.tab_control.is_paused()
+    }
+
+    /// The cycles consumed by the app in this tab so far.
+    pub fn cycles(&self) -> u64 {
+        self.tab_control.cycles()
+    }
+
+    /// How much memory the app in this tab is using. Still available once the
+    /// app has stopped, until the tab is closed.
+    ///
+    /// Returns `None` if nothing ran in the tab.
+    pub fn memory_usage(&self) -> Option<MemoryUsage> {
+        Some(self.nushift_subsystem.as_ref()?.shm_space().memory_usage())
+    }
+
+    /// Give the app in this tab one end of a channel, over its host channel.
+    pub(super) fn give_channel_end(&self, channel_end: ChannelEnd) -> Result<(), ConnectTabsError> {
+        ensure!(!self.is_finished(), AppNotRunningSnafu);
+        let nushift_subsystem = self.nushift_subsystem.as_ref().context(AppNotRunningSnafu)?;
+        nushift_subsystem.channel_space.lock().unwrap().give_channel_end(channel_end).context(HostChannelSnafu)
+    }
+
+    /// Save the whole state of the app in this tab, which must be paused, so
+    /// that it can be restored into a new tab with `restore_and_run`.
+    pub fn snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let nushift_subsystem = self.nushift_subsystem.as_ref().context(NotRunningSnafu)?;
+        ensure!(self.is_paused(), NotPausedSnafu);
+        // The main hart might not be parked because it's blocked on other
+        // threads or deferred tasks, which the subsystem rejects. Otherwise, a
+        // paused tab whose main hart isn't parked has stopped.
+        let subsystem = nushift_subsystem.snapshot()?;
+        let cpu = self.tab_control.parked_cpu_snapshot().context(NotRunningSnafu)?;
+
+        snapshot::encode(&AppSnapshot { cpu, subsystem })
+    }
+
+    /// Block until the app in this tab stops running, and return how it
+    /// stopped.
+    ///
+    /// Returns `None` if the tab never ran, or if it has already been waited
+    /// on.
+    pub fn wait(&mut self) -> Option<Result<ExitReason, TabRunError>> {
+        let hypervisor_thread = self.hypervisor_thread.take()?;
+
+        Some(match hypervisor_thread.join() {
+            Ok(run_result) => run_result,
+            Err(_) => HypervisorThreadPanickedSnafu.fail(),
+        })
+    }
+
+    /// Stop the app in this tab, and block until its threads have finished.
+    ///
+    /// Once this returns, the machine and its subsystem (including all SHM
+    /// mmaps) have been dropped.
+    pub fn close_tab(&mut self) {
+        self.tab_control.cancel();
+
+        match self.wait() {
+            None | Some(Ok(_)) => {}
+            Some(Err(run_error)) => tracing::error!("Tab stopped with an error while closing: {run_error:?}, tab ID {:?}", self.id),
+        }
+        self.nushift_subsystem = None;
+    }
+}
+
+/// How the app stopped, and the PC of the hart that stopped it, if known.
+type RunOutcome = (Result<ExitReason, TabRunError>, Option<u64>);
+
+/// What each of an app's harts needs to run, shared between them.
+///
+/// Each hart has a machine thread, which runs its instructions, and a
+/// hypervisor thread, which handles its syscalls, so that one hart blocking in
+/// a syscall (e.g. `BlockOnDeferredTasks` or `ThreadJoin`) doesn't hold up the
+/// others.
+struct Harts<'a> {
+    tab_id: &'a ArcId,
+    nushift_subsystem: &'a Arc<NushiftSubsystem>,
+    tab_control: &'a Arc<TabControl>,
+    tab_config: &'a TabConfig,
+    deferred_task_pool: &'a DeferredTaskPool,
+    /// Spawned harts report the main hart's load base to a debugger.
+    load_base: u64,
+    /// From whichever hart stopped the app first.
+    stopped_by: Mutex<Option<RunOutcome>>,
+}
+
+impl Harts<'_> {
+    /// Runs `machine` on a new machine thread, and handles its syscalls on
+    /// this thread until it stops.
+    fn run_hart<'scope, R>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        thread_id: ThreadId,
+        mut machine: ProcessControlBlock<R>,
+        syscall_enter_receive: Receiver<SyscallEnter>,
+        syscall_return_send: Sender<SyscallReturn>,
+    )
+    where
+        R: Register + LowerHex + Send + 'static,
+    {
+        let tab_control_cloned_for_machine = Arc::clone(self.tab_control);
+        let machine_thread = Builder::new().spawn_scoped(scope, move || {
+            // Set on drop, so that it's also set if the machine panics.
+            let _machine_stopped = MachineStoppedGuard(tab_control_cloned_for_machine);
+            let run_result = machine.run();
+            (run_result, machine.current_pc())
+        });
         let machine_thread = match machine_thread {
             Err(os_error) => {
-                tracing::error!("Failed to create OS tab thread: {:?}, tab ID {:?}", os_error, tab_id);
+                tracing::error!("Failed to create OS tab thread: {:?}, tab ID {:?}", os_error, self.tab_id);
+                self.tab_control.set_machine_stopped();
+                self.stop_app(Err(os_error).context(MachineThreadSpawnSnafu), None);
                 return;
             }
             Ok(machine_thread) => machine_thread,
@@ -88,87 +352,119 @@ impl Tab {
         // disconnected because the thread ended. This is totally normal, and
         // continue to join the thread in this case. Otherwise, process the
         // message.
+        let mut thread_exit_value = None;
         while let Ok(receive) = syscall_enter_receive.recv() {
-            let syscall_return = {
-                let mut subsystem = machine_nushift_subsystem.lock().unwrap();
-                subsystem.ecall(receive)
-            };
+            let syscall_return = self.nushift_subsystem.ecall(thread_id, receive);
+            if let SyscallReturn::ThreadExit { exit_value } = syscall_return {
+                thread_exit_value = Some(exit_value);
+            }
+
+            // Start the harts that this syscall spawned (if any) before
+            // returning, so that from the moment the app has their IDs, pausing
+            // waits for them too.
+            self.start_harts::<R>(scope);
+
             syscall_return_send.send(syscall_return).expect("Since we just received, other thread should be waiting on our send");
 
-            // Call the non-blocking bit of ecall here. Asynchronous tasks?
-            // If this "non-blocking" bit locks the subsystem, it's not going to
-            // be non-blocking with the bits of the app that access memory.
-            //
-            // This "non-blocking" bit does currently entirely lock the
-            // subsystem.
+            // Hand the tasks that this syscall started (if any) to the
+            // workers, which run them while the app carries on.
             //
-            // It must be made sure that a task being set up in the
-            // AppGlobalDeferredSpace and the blocking part being processed in
-            // the relevant space have both been done before dispatching tasks.
-            // In other words, when we make the locking more fine-grained later,
-            // those two things must still be locked together.
-            let mut guard = machine_nushift_subsystem.lock().unwrap();
-            let subsystem = guard.deref_mut();
-            let tasks = subsystem.app_global_deferred_space.finish_tasks();
+            // A task is set up in the AppGlobalDeferredSpace and has its
+            // blocking part processed in the relevant space while the
+            // AppGlobalDeferredSpace is locked (see `NushiftSubsystem`), so
+            // `start_tasks` only ever sees tasks that are ready to be run.
+            let tasks = self.nushift_subsystem.app_global_deferred_space.lock().unwrap().start_tasks();
             for (task_id, task) in tasks {
-                match task {
-                    Task::AccessibilityTreePublishRON { accessibility_tree_cap_id } => {
-                        match subsystem.accessibility_tree_space.publish_accessibility_tree_ron_deferred(accessibility_tree_cap_id, &mut subsystem.shm_space) {
-                            Ok(_) => {}
-                            Err(_) => {} // TODO: On internal error, terminate app (?)
-                        }
-                    }
-                    Task::AccessibilityTreePublish { accessibility_tree_cap_id } => {
-                        match subsystem.accessibility_tree_space.publish_accessibility_tree_deferred(accessibility_tree_cap_id, &mut subsystem.shm_space) {
-                            Ok(_) => {}
-                            Err(_) => {} // TODO: On internal error, terminate app (?)
-                        }
-                    }
-                    Task::TitlePublish { title_cap_id } => {
-                        match subsystem.title_space.publish_title_deferred(title_cap_id, &mut subsystem.shm_space) {
-                            Ok(_) => {}
-                            Err(_) => {} // TODO: On internal error, terminate app (?)
-                        }
-                    }
-                    Task::GfxGetOutputs { gfx_cap_id } => {
-                        match subsystem.gfx_space.get_outputs_deferred(gfx_cap_id, &mut subsystem.shm_space) {
-                            Ok(_) => {}
-                            Err(_) => {} // TODO: On internal error, terminate app (?)
-                        }
-                    }
-                    Task::GfxCpuPresent { gfx_cpu_present_buffer_cap_id } => {
-                        match subsystem.gfx_space.cpu_present_deferred(gfx_cpu_present_buffer_cap_id, &mut subsystem.shm_space) {
-                            Ok(_) => {}
-                            Err(_) => {} // TODO: On internal error, terminate app (?)
-                        }
-                    }
-                }
-
-                let (lock, cvar) = &*subsystem.blocking_on_tasks;
-                let mut guard = lock.lock().unwrap();
-                guard.remove(&task_id);
-                cvar.notify_one(); // TODO: Should this change to `notify_all` when an app can have multiple threads? Is that even how the hypervisor architecture is going to work?
+                self.deferred_task_pool.dispatch(task_id, task);
             }
         }
 
-        let run_result = match machine_thread.join() {
+        let (run_result, pc) = match machine_thread.join() {
             Err(join_error) => {
-                tracing::error!("Thread panicked: {:?}, tab ID {:?}", join_error, tab_id);
-                return;
+                tracing::error!("Thread panicked: {:?}, tab ID {:?}", join_error, self.tab_id);
+                (MachineThreadPanickedSnafu.fail(), None)
             }
-            Ok(run_result) => run_result,
+            Ok((run_result, pc)) => (run_result.context(MachineRunSnafu), pc),
         };
 
-        match run_result {
-            Ok(exit_reason) => tracing::info!("Exit reason: {exit_reason:?}"),
-            Err(run_error) => tracing::error!("Run error: {:?}, tab ID {:?}", run_error, tab_id),
+        match (run_result, thread_exit_value) {
+            // Only this hart's thread exited, so the app carries on.
+            (Ok(ExitReason::UserExit { .. }), Some(exit_value)) => self.tab_control.thread_exited(thread_id, exit_value),
+            (run_result, _) => self.stop_app(run_result, pc),
         }
     }
 
-    pub fn close_tab(&mut self) {
-        // TODO: Cooperatively terminate the thread. The cooperation will
-        // probably need to be in the interpreter loop if the app is in a
-        // CPU-bound loop with no yielding and no memory accesses (which yield).
-        self.hypervisor_thread = None;
+    /// Starts a hart for each thread that the app has spawned since this was
+    /// last called, in the main hart's address space.
+    fn start_harts<'scope, R>(&'scope self, scope: &'scope Scope<'scope, '_>)
+    where
+        R: Register + LowerHex + Send + 'static,
+    {
+        let thread_starts = self.nushift_subsystem.thread_space.lock().unwrap().start_threads();
+        for (thread_id, thread_start) in thread_starts {
+            let (syscall_enter_send, syscall_enter_receive) = mpsc::channel();
+            let (syscall_return_send, syscall_return_receive) = mpsc::channel();
+            let mut machine = ProcessControlBlock::<R>::new(thread_id, syscall_enter_send, syscall_return_receive, Arc::clone(self.nushift_subsystem), Arc::clone(self.tab_control), self.tab_config.clone());
+            machine.load_thread(thread_start, self.load_base);
+
+            self.tab_control.hart_started();
+            let hypervisor_thread = Builder::new().spawn_scoped(scope, move || self.run_hart(scope, thread_id, machine, syscall_enter_receive, syscall_return_send));
+            if let Err(os_error) = hypervisor_thread {
+                tracing::error!("Failed to create OS hypervisor thread: {:?}, tab ID {:?}", os_error, self.tab_id);
+                self.tab_control.set_machine_stopped();
+                self.stop_app(Err(os_error).context(HartThreadSpawnSnafu), None);
+            }
+        }
+    }
+
+    /// Stops every hart, and remembers how the app stopped, unless another
+    /// hart stopped it first.
+    fn stop_app(&self, run_result: Result<ExitReason, TabRunError>, pc: Option<u64>) {
+        self.stopped_by.lock().unwrap().get_or_insert((run_result, pc));
+        self.tab_control.cancel();
+    }
+}
+
+struct MachineStoppedGuard(Arc<TabControl>);
+
+impl Drop for MachineStoppedGuard {
+    fn drop(&mut self) {
+        self.0.set_machine_stopped();
+    }
+}
+
+impl Drop for Tab {
+    fn drop(&mut self) {
+        // Don't leave the app running, e.g. when the whole hypervisor is
+        // dropped without closing each tab.
+        self.close_tab();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hypervisor::tab_context::RecordingTabContext;
+
+    use super::*;
+
+    #[test]
+    fn report_run_result_sends_exit_and_crash() {
+        let tab_context = RecordingTabContext::default();
+
+        Tab::report_run_result(&tab_context, &Ok(ExitReason::UserExit { exit_reason: 3 }), Some(0x1000));
+        Tab::report_run_result(&tab_context, &MachineThreadPanickedSnafu.fail(), Some(0x2000));
+
+        let events = tab_context.events.lock().unwrap();
+        assert!(matches!(events[0], UnboundHypervisorEvent::TabExited(ExitReason::UserExit { exit_reason: 3 })));
+        assert!(matches!(events[1], UnboundHypervisorEvent::TabCrashed(ref error, Some(0x2000)) if error.contains("panicked")));
+    }
+
+    #[test]
+    fn report_run_result_does_not_send_when_cancelled() {
+        let tab_context = RecordingTabContext::default();
+
+        Tab::report_run_result(&tab_context, &Ok(ExitReason::Cancelled), None);
+
+        assert!(tab_context.events.lock().unwrap().is_empty());
     }
 }
@@ -0,0 +1,36 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::gdb_stub::GdbServerAddress;
+use crate::launch::LaunchInfo;
+use crate::syscall_tracer::SyscallTracer;
+
+/// Limits and settings for the app running in a tab.
+///
+/// The default has no limits, which is what tabs added with `add_new_tab` and
+/// `add_new_tab_with_image` get.
+#[derive(Debug, Clone, Default)]
+pub struct TabConfig {
+    /// Stop the app with `ExitReason::InstructionBudgetExceeded` once it has
+    /// executed this many instructions.
+    pub max_instructions: Option<u64>,
+    /// Throttle the app so that it executes at most this many instructions per
+    /// second, on average. The app is only slowed down every few thousand
+    /// instructions, so very low values will make it run in bursts.
+    pub max_instructions_per_second: Option<u64>,
+    /// Listen for a GDB remote serial protocol debugger here. The app doesn't
+    /// start until a debugger connects.
+    pub gdb_server_address: Option<GdbServerAddress>,
+    /// Record every syscall the app makes, and every deferred task finishing.
+    pub syscall_tracer: Option<SyscallTracer>,
+    /// The most bytes of SHM caps that the app can have, including the ones
+    /// the hypervisor makes for its ELF segments and stack. Making, growing or
+    /// receiving a cap that would go over fails with `ShmCapacityNotAvailable`,
+    /// or the app doesn't load if its ELF and stack alone would.
+    pub max_memory_bytes: Option<u64>,
+    /// The size of the stack that the hypervisor sets up for the app, unless
+    /// the app's ELF asks for a size itself. Defaults to `DEFAULT_STACK_SIZE`.
+    pub stack_size: Option<u64>,
+    /// Passed to the app at entry.
+    pub launch_info: LaunchInfo,
+}
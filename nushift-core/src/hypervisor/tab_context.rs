@@ -34,3 +34,22 @@ impl TabContext for DefaultTabContext {
         vec![self.gfx_output.lock().unwrap()]
     }
 }
+
+/// A `TabContext` for tests that records the hypervisor events it's sent.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct RecordingTabContext {
+    pub(crate) events: Mutex<Vec<UnboundHypervisorEvent>>,
+}
+
+#[cfg(test)]
+impl TabContext for RecordingTabContext {
+    fn send_hypervisor_event(&self, unbound_hypervisor_event: UnboundHypervisorEvent) -> Result<(), HypervisorEventError> {
+        self.events.lock().unwrap().push(unbound_hypervisor_event);
+        Ok(())
+    }
+
+    fn get_gfx_outputs(&self) -> Vec<MutexGuard<'_, GfxOutput>> {
+        unimplemented!("This is a mock, this method is not expected to be called")
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use crate::deferred_space::app_global_deferred_space::TaskId;
+use crate::process_control_block::CpuSnapshot;
+use crate::thread_space::{ThreadId, MAIN_THREAD_ID};
+
+/// Shared between a tab and the threads running its app, so that the tab can
+/// tell those threads to stop or pause, and so that the threads can report
+/// back how much the app has run.
+///
+/// The threads have to cooperate: each hart's machine thread checks
+/// `is_cancelled` and parks if paused every so often in its interpreter loop,
+/// and each hart's hypervisor thread checks `is_cancelled` whenever it wakes
+/// up from waiting on deferred tasks or another hart.
+pub(crate) struct TabControl {
+    cancelled: AtomicBool,
+    cycles: AtomicU64,
+    blocking_on_tasks: (Mutex<HashSet<TaskId>>, Condvar),
+    thread_exits: (Mutex<HashMap<ThreadId, u64>>, Condvar),
+    pause: (Mutex<PauseState>, Condvar),
+}
+
+struct PauseState {
+    paused: bool,
+    /// Harts whose machine threads haven't ended yet. The main hart counts
+    /// from the start, so that pausing before it has started still waits for
+    /// it.
+    harts_running: usize,
+    /// Harts that are parked, or blocked (see `block_hart`).
+    harts_parked: usize,
+    /// Each parked hart's CPU state, so that the tab can be snapshotted
+    /// without reaching into the machine threads.
+    parked_cpus: HashMap<ThreadId, CpuSnapshot>,
+}
+
+impl TabControl {
+    pub(crate) fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            cycles: AtomicU64::new(0),
+            blocking_on_tasks: (Mutex::new(HashSet::new()), Condvar::new()),
+            thread_exits: (Mutex::new(HashMap::new()), Condvar::new()),
+            pause: (Mutex::new(PauseState { paused: false, harts_running: 1, harts_parked: 0, parked_cpus: HashMap::new() }), Condvar::new()),
+        }
+    }
+
+    /// The task IDs that the app's threads are currently blocked on, and the
+    /// condvar that is notified whenever one of them finishes (or the tab is
+    /// cancelled).
+    pub(crate) fn blocking_on_tasks(&self) -> &(Mutex<HashSet<TaskId>>, Condvar) {
+        &self.blocking_on_tasks
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// The cycles the app has consumed so far, i.e. the number of instructions
+    /// it has executed. This is only updated every few thousand instructions
+    /// while the app is running.
+    pub(crate) fn cycles(&self) -> u64 {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_cycles(&self, cycles: u64) {
+        self.cycles.store(cycles, Ordering::Relaxed);
+    }
+
+    /// Each hart adds the cycles it has consumed since it last reported.
+    pub(crate) fn add_cycles(&self, cycles: u64) {
+        self.cycles.fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    /// Ask the app's threads to stop, and wake up anything waiting on deferred
+    /// tasks, other threads, or paused so that it notices.
+    ///
+    /// This is also how the rest of an app's harts are stopped once one of
+    /// them has stopped the app.
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+
+        // The flag is set before taking the locks, and a waiter checks the flag
+        // while holding the lock, so a waiter either sees the flag or is
+        // already waiting and gets this notification.
+        {
+            let (lock, cvar) = &self.blocking_on_tasks;
+            let _guard = lock.lock().unwrap();
+            cvar.notify_all();
+        }
+        {
+            let (lock, cvar) = &self.thread_exits;
+            let _guard = lock.lock().unwrap();
+            cvar.notify_all();
+        }
+        {
+            let (lock, cvar) = &self.pause;
+            let _guard = lock.lock().unwrap();
+            cvar.notify_all();
+        }
+    }
+
+    /// Ask every hart to park at its next check, and block until they all have
+    /// (or have stopped, or the tab is cancelled).
+    ///
+    /// Once this returns, every machine is at an instruction boundary (or
+    /// blocked joining another hart) and isn't going to touch its state until
+    /// `resume` is called.
+    pub(crate) fn pause(&self) {
+        let (lock, cvar) = &self.pause;
+        let mut guard = lock.lock().unwrap();
+        guard.paused = true;
+        while guard.harts_parked < guard.harts_running && !self.is_cancelled() {
+            guard = cvar.wait(guard).unwrap();
+        }
+    }
+
+    pub(crate) fn resume(&self) {
+        let (lock, cvar) = &self.pause;
+        let mut guard = lock.lock().unwrap();
+        guard.paused = false;
+        cvar.notify_all();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.pause.0.lock().unwrap().paused
+    }
+
+    /// Called by a hart's machine thread. If the tab is paused, park until it
+    /// is resumed or cancelled. `snapshot_cpu` is only called if parking.
+    ///
+    /// Returns whether the machine parked.
+    pub(crate) fn park_machine_while_paused(&self, thread_id: ThreadId, snapshot_cpu: impl FnOnce() -> CpuSnapshot) -> bool {
+        let (lock, cvar) = &self.pause;
+        let mut guard = lock.lock().unwrap();
+        if !guard.paused || self.is_cancelled() {
+            return false;
+        }
+
+        guard.harts_parked += 1;
+        guard.parked_cpus.insert(thread_id, snapshot_cpu());
+        cvar.notify_all();
+        while guard.paused && !self.is_cancelled() {
+            guard = cvar.wait(guard).unwrap();
+        }
+        guard.harts_parked -= 1;
+        guard.parked_cpus.remove(&thread_id);
+        true
+    }
+
+    /// The CPU state of the main hart, if it is currently parked.
+    pub(crate) fn parked_cpu_snapshot(&self) -> Option<CpuSnapshot> {
+        self.pause.0.lock().unwrap().parked_cpus.get(&MAIN_THREAD_ID).cloned()
+    }
+
+    /// Called by the deferred task workers before running each task, so that
+    /// a paused tab doesn't make progress that way either.
+    pub(crate) fn wait_while_paused(&self) {
+        let (lock, cvar) = &self.pause;
+        let mut guard = lock.lock().unwrap();
+        while guard.paused && !self.is_cancelled() {
+            guard = cvar.wait(guard).unwrap();
+        }
+    }
+
+    /// Called before a spawned hart's machine thread is started. The main hart
+    /// is already counted.
+    pub(crate) fn hart_started(&self) {
+        self.pause.0.lock().unwrap().harts_running += 1;
+    }
+
+    /// Called when a hart's machine thread ends, however it ends, so that
+    /// `pause` doesn't wait for a machine that will never park.
+    pub(crate) fn set_machine_stopped(&self) {
+        let (lock, cvar) = &self.pause;
+        let mut guard = lock.lock().unwrap();
+        guard.harts_running = guard.harts_running.saturating_sub(1);
+        cvar.notify_all();
+    }
+
+    /// Called by a hart's hypervisor thread once the hart has exited with
+    /// `ThreadExit`, so that whoever joins it gets `exit_value`.
+    pub(crate) fn thread_exited(&self, thread_id: ThreadId, exit_value: u64) {
+        let (lock, cvar) = &self.thread_exits;
+        lock.lock().unwrap().insert(thread_id, exit_value);
+        cvar.notify_all();
+    }
+
+    /// Called by a hart's hypervisor thread to block the hart until
+    /// `thread_id` has exited, and take its exit value. Returns `None` if the
+    /// tab was cancelled first.
+    ///
+    /// The blocked hart counts as parked while it waits, see `block_hart`.
+    pub(crate) fn wait_for_thread_exit(&self, thread_id: ThreadId) -> Option<u64> {
+        self.block_hart();
+
+        let exit_value = {
+            let (lock, cvar) = &self.thread_exits;
+            let mut guard = lock.lock().unwrap();
+            loop {
+                if let Some(exit_value) = guard.remove(&thread_id) {
+                    break Some(exit_value);
+                }
+                if self.is_cancelled() {
+                    break None;
+                }
+                guard = cvar.wait(guard).unwrap();
+            }
+        };
+
+        self.unblock_hart();
+        exit_value
+    }
+
+    /// Called by a hart's hypervisor thread before blocking the hart on
+    /// something that might not happen while the tab is paused, like another
+    /// hart exiting, or a message arriving from another app. The hart counts
+    /// as parked until `unblock_hart`, since otherwise pausing could wait for
+    /// it forever.
+    pub(crate) fn block_hart(&self) {
+        let (lock, cvar) = &self.pause;
+        lock.lock().unwrap().harts_parked += 1;
+        cvar.notify_all();
+    }
+
+    /// Called once whatever the hart was blocked on has happened. The hart was
+    /// counted as parked, so it carries on counting as parked (and stays
+    /// blocked) until the tab is resumed.
+    pub(crate) fn unblock_hart(&self) {
+        let (lock, cvar) = &self.pause;
+        let mut guard = lock.lock().unwrap();
+        while guard.paused && !self.is_cancelled() {
+            guard = cvar.wait(guard).unwrap();
+        }
+        guard.harts_parked -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_sets_cancelled() {
+        let tab_control = TabControl::new();
+        assert!(!tab_control.is_cancelled());
+
+        tab_control.cancel();
+
+        assert!(tab_control.is_cancelled());
+    }
+
+    #[test]
+    fn pause_returns_once_machine_is_parked() {
+        let tab_control = TabControl::new();
+
+        std::thread::scope(|scope| {
+            let machine = scope.spawn(|| {
+                let mut parked = false;
+                while !tab_control.is_cancelled() {
+                    parked |= tab_control.park_machine_while_paused(MAIN_THREAD_ID, CpuSnapshot::default);
+                }
+                parked
+            });
+
+            tab_control.pause();
+            assert_eq!(1, tab_control.pause.0.lock().unwrap().harts_parked);
+            assert!(tab_control.parked_cpu_snapshot().is_some());
+
+            tab_control.resume();
+            tab_control.cancel();
+            assert!(machine.join().expect("Should not panic"));
+        });
+
+        assert!(tab_control.parked_cpu_snapshot().is_none());
+    }
+
+    #[test]
+    fn pause_does_not_wait_for_stopped_machine() {
+        let tab_control = TabControl::new();
+        tab_control.set_machine_stopped();
+
+        tab_control.pause();
+
+        assert!(tab_control.is_paused());
+    }
+
+    #[test]
+    fn pause_counts_hart_waiting_for_thread_exit_as_parked() {
+        let tab_control = TabControl::new();
+        tab_control.hart_started();
+
+        std::thread::scope(|scope| {
+            let spawned = scope.spawn(|| {
+                while !tab_control.park_machine_while_paused(1, CpuSnapshot::default) {
+                    std::thread::yield_now();
+                }
+                tab_control.thread_exited(1, 7);
+            });
+            let joining = scope.spawn(|| tab_control.wait_for_thread_exit(1));
+
+            tab_control.pause();
+            assert!(tab_control.parked_cpu_snapshot().is_none());
+
+            tab_control.resume();
+            spawned.join().expect("Should not panic");
+            assert_eq!(Some(7), joining.join().expect("Should not panic"));
+        });
+    }
+}
@@ -0,0 +1,37 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use snafu::prelude::*;
+
+use super::TabLoadError;
+use super::{ReadImageSnafu, ImageLoaderSnafu};
+
+/// Where the ELF image for a new tab comes from.
+pub enum TabImageSource {
+    /// Read the image from a file on disk.
+    Path(PathBuf),
+    /// An image that is already in memory.
+    Bytes(Vec<u8>),
+    /// An embedder-provided loader, e.g. for fetching the image over the
+    /// network or out of an archive.
+    Loader(Box<dyn TabImageLoader>),
+}
+
+/// A pluggable source of tab images.
+pub trait TabImageLoader {
+    fn load_image(&mut self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+}
+
+impl TabImageSource {
+    pub(crate) fn into_image(self) -> Result<Vec<u8>, TabLoadError> {
+        match self {
+            Self::Path(path) => fs::read(&path).context(ReadImageSnafu { path }),
+            Self::Bytes(image) => Ok(image),
+            Self::Loader(mut loader) => loader.load_image().context(ImageLoaderSnafu),
+        }
+    }
+}
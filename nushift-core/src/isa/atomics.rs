@@ -0,0 +1,239 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The A extension, which ckb-vm doesn't have. ckb-vm fails to decode these
+//! instructions, and then they're decoded and run here instead.
+//!
+//! An app can have several harts (see `ThreadSpawn`), so each AMO loads and
+//! stores through `AtomicMemory`, which holds the app's SHM space for the
+//! whole read-modify-write. Every other memory access holds it too, so nothing
+//! gets in between, and since every access is ordered by that lock anyway, the
+//! aq/rl bits don't need to do anything.
+//!
+//! An SC succeeds if the address still holds the value its LR loaded, like in
+//! QEMU. That can't tell whether the value was changed and changed back in
+//! between, but that's fine for the usual compare-and-swap loops.
+
+use ckb_vm::{CoreMachine, Error as CKBVMError, Register};
+
+const AMO_OPCODE: u32 = 0b010_1111;
+const WIDTH_WORD: u32 = 0b010;
+const WIDTH_DOUBLEWORD: u32 = 0b011;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AmoOp {
+    LoadReserved,
+    StoreConditional,
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    MinU,
+    MaxU,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AmoWidth {
+    Word,
+    Doubleword,
+}
+
+/// What an LR reserved, which an SC to the same address checks against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LoadReservation {
+    address: u64,
+    /// Zero-extended, for words.
+    value: u64,
+}
+
+/// Memory that a whole read-modify-write can be done on at once, without any
+/// other hart's access to the same memory getting in between.
+pub(crate) trait AtomicMemory {
+    /// Loads the `width` value at `address`, zero-extended, and stores what
+    /// `modify` returns for it, if anything. Returns what was loaded.
+    fn read_modify_write(&mut self, address: u64, width: AmoWidth, modify: impl FnOnce(u64) -> Option<u64>) -> Result<u64, CKBVMError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AmoInstruction {
+    op: AmoOp,
+    width: AmoWidth,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+}
+
+impl AmoInstruction {
+    /// `None` if `instruction` isn't an A extension instruction that a machine
+    /// with `xlen`-bit registers has.
+    pub(crate) fn decode(instruction: u32, xlen: u8) -> Option<Self> {
+        if instruction & 0x7f != AMO_OPCODE {
+            return None;
+        }
+        let width = match (instruction >> 12) & 0b111 {
+            WIDTH_WORD => AmoWidth::Word,
+            WIDTH_DOUBLEWORD if xlen == 64 => AmoWidth::Doubleword,
+            _ => return None,
+        };
+        let rs2 = ((instruction >> 20) & 0x1f) as usize;
+        let op = match instruction >> 27 {
+            0b00010 if rs2 == 0 => AmoOp::LoadReserved,
+            0b00011 => AmoOp::StoreConditional,
+            0b00001 => AmoOp::Swap,
+            0b00000 => AmoOp::Add,
+            0b00100 => AmoOp::Xor,
+            0b01100 => AmoOp::And,
+            0b01000 => AmoOp::Or,
+            0b10000 => AmoOp::Min,
+            0b10100 => AmoOp::Max,
+            0b11000 => AmoOp::MinU,
+            0b11100 => AmoOp::MaxU,
+            _ => return None,
+        };
+
+        Some(Self {
+            op,
+            width,
+            rd: ((instruction >> 7) & 0x1f) as usize,
+            rs1: ((instruction >> 15) & 0x1f) as usize,
+            rs2,
+        })
+    }
+
+    /// Run the instruction and move on to the next one. `load_reservation` is
+    /// what the hart's last LR reserved, if it hasn't been used up by an SC
+    /// yet.
+    pub(crate) fn execute<M>(self, machine: &mut M, load_reservation: &mut Option<LoadReservation>) -> Result<(), CKBVMError>
+    where
+        M: CoreMachine + AtomicMemory,
+    {
+        let address = machine.registers()[self.rs1].to_u64();
+        let source = machine.registers()[self.rs2].to_u64();
+        let size = match self.width {
+            AmoWidth::Word => 4,
+            AmoWidth::Doubleword => 8,
+        };
+        if address & (size - 1) != 0 {
+            return Err(CKBVMError::External(format!("Misaligned atomic memory access at {address:#x}")));
+        }
+
+        let rd_value = match self.op {
+            AmoOp::LoadReserved => {
+                let loaded = machine.read_modify_write(address, self.width, |_| None)?;
+                *load_reservation = Some(LoadReservation { address, value: loaded });
+                self.sign_extend(loaded)
+            }
+            AmoOp::StoreConditional => match load_reservation.take() {
+                Some(reservation) if reservation.address == address => {
+                    let loaded = machine.read_modify_write(address, self.width, |loaded| (loaded == reservation.value).then_some(source))?;
+                    u64::from(loaded != reservation.value)
+                }
+                _ => 1,
+            },
+            op => {
+                let loaded = machine.read_modify_write(address, self.width, |loaded| Some(amo_store_value(op, self.width, loaded, source)))?;
+                self.sign_extend(loaded)
+            }
+        };
+
+        // Writes to x0 are discarded.
+        if self.rd != 0 {
+            machine.set_register(self.rd, M::REG::from_u64(rd_value));
+        }
+        let next_pc = machine.pc().to_u64().wrapping_add(4);
+        machine.update_pc(M::REG::from_u64(next_pc));
+        machine.commit_pc();
+        Ok(())
+    }
+
+    /// Words are sign-extended, as they are for every other RV64 instruction
+    /// that puts a word in a register.
+    fn sign_extend(self, loaded: u64) -> u64 {
+        match self.width {
+            AmoWidth::Word => loaded as u32 as i32 as i64 as u64,
+            AmoWidth::Doubleword => loaded,
+        }
+    }
+}
+
+/// What an AMO writes back to memory, given what was `loaded` from memory and
+/// the `source` register. For words, only the low 32 bits of each are used.
+fn amo_store_value(op: AmoOp, width: AmoWidth, loaded: u64, source: u64) -> u64 {
+    match width {
+        AmoWidth::Word => {
+            let (loaded, source) = (loaded as u32, source as u32);
+            u64::from(match op {
+                AmoOp::Min => (loaded as i32).min(source as i32) as u32,
+                AmoOp::Max => (loaded as i32).max(source as i32) as u32,
+                op => amo_unsigned_store_value(op, loaded.into(), source.into()) as u32,
+            })
+        }
+        AmoWidth::Doubleword => match op {
+            AmoOp::Min => (loaded as i64).min(source as i64) as u64,
+            AmoOp::Max => (loaded as i64).max(source as i64) as u64,
+            op => amo_unsigned_store_value(op, loaded, source),
+        },
+    }
+}
+
+fn amo_unsigned_store_value(op: AmoOp, loaded: u64, source: u64) -> u64 {
+    match op {
+        AmoOp::Swap => source,
+        AmoOp::Add => loaded.wrapping_add(source),
+        AmoOp::Xor => loaded ^ source,
+        AmoOp::And => loaded & source,
+        AmoOp::Or => loaded | source,
+        AmoOp::MinU => loaded.min(source),
+        AmoOp::MaxU => loaded.max(source),
+        AmoOp::LoadReserved | AmoOp::StoreConditional | AmoOp::Min | AmoOp::Max => unreachable!("Not an unsigned AMO"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_amoadd_w() {
+        // amoadd.w a0, a2, (a1)
+        assert_eq!(
+            Some(AmoInstruction { op: AmoOp::Add, width: AmoWidth::Word, rd: 10, rs1: 11, rs2: 12 }),
+            AmoInstruction::decode(0x00c5_a52f, 64),
+        );
+    }
+
+    #[test]
+    fn decode_lr_d_only_on_rv64() {
+        // lr.d a0, (a1)
+        let lr_d = 0x1005_b52f;
+
+        assert_eq!(
+            Some(AmoInstruction { op: AmoOp::LoadReserved, width: AmoWidth::Doubleword, rd: 10, rs1: 11, rs2: 0 }),
+            AmoInstruction::decode(lr_d, 64),
+        );
+        assert_eq!(None, AmoInstruction::decode(lr_d, 32));
+    }
+
+    #[test]
+    fn decode_rejects_other_instructions() {
+        // addi a0, a0, 1
+        assert_eq!(None, AmoInstruction::decode(0x0015_0513, 64));
+        // lr.w with a nonzero rs2 is reserved
+        assert_eq!(None, AmoInstruction::decode(0x1015_a52f, 64));
+    }
+
+    #[test]
+    fn amo_store_value_word_min_is_signed() {
+        assert_eq!(0xffff_ffff, amo_store_value(AmoOp::Min, AmoWidth::Word, 1, u64::MAX));
+        assert_eq!(1, amo_store_value(AmoOp::MinU, AmoWidth::Word, 1, u64::MAX));
+    }
+
+    #[test]
+    fn amo_store_value_word_add_wraps_at_32_bits() {
+        assert_eq!(0, amo_store_value(AmoOp::Add, AmoWidth::Word, 0xffff_ffff, 1));
+        assert_eq!(0x1_0000_0000, amo_store_value(AmoOp::Add, AmoWidth::Doubleword, 0xffff_ffff, 1));
+    }
+}
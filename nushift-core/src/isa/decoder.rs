@@ -0,0 +1,139 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decoding instructions anywhere in the app's space.
+//!
+//! ckb-vm's decoder refuses to decode past the end of its own memory, which is
+//! 4 MiB, since it doesn't know that our memory is the SHM space. Its
+//! instruction cache also never forgets an instruction, even if the cap it was
+//! decoded from has since been released. So instead, this fetches through the
+//! machine's `execute_load16` (and so `walk_execute`) at any address, and
+//! throws away its cache whenever the SHM space's mappings change.
+
+use ckb_vm::{
+    Error as CKBVMError,
+    Register,
+    instructions::{b, i, m, rvc, Instruction, InstructionFactory},
+};
+
+const CACHE_SIZE: usize = 1 << 12;
+
+pub(crate) struct InstructionDecoder {
+    factories: Vec<InstructionFactory>,
+    version: u32,
+    /// Direct-mapped on the PC. Each slot remembers which PC it was decoded
+    /// from, since many PCs share a slot.
+    cache: Box<[Option<(u64, Instruction)>]>,
+    /// The `ShmSpace::mapping_generation` that `cache` is valid for.
+    mapping_generation: u64,
+}
+
+impl InstructionDecoder {
+    fn new(factories: Vec<InstructionFactory>, version: u32) -> Self {
+        Self {
+            factories,
+            version,
+            cache: vec![None; CACHE_SIZE].into_boxed_slice(),
+            mapping_generation: 0,
+        }
+    }
+
+    /// The same instructions as ckb-vm's `build_decoder` would decode.
+    pub(crate) fn for_isa<R: Register>(isa: u8, version: u32) -> Self {
+        let mut factories: Vec<InstructionFactory> = vec![rvc::factory::<R>, i::factory::<R>, m::factory::<R>];
+        if isa & ckb_vm::ISA_B != 0 {
+            factories.push(b::factory::<R>);
+        }
+        Self::new(factories, version)
+    }
+
+    /// Decode the instruction at `pc`, fetching it with `execute_load16` if it
+    /// isn't cached. `mapping_generation` is the SHM space's current one.
+    ///
+    /// Instructions are fetched 16 bits at a time, so that a compressed
+    /// instruction at the very end of an executable region doesn't fault.
+    pub(crate) fn decode<F>(&mut self, pc: u64, mapping_generation: u64, mut execute_load16: F) -> Result<Instruction, CKBVMError>
+    where
+        F: FnMut(u64) -> Result<u16, CKBVMError>,
+    {
+        if mapping_generation != self.mapping_generation {
+            self.cache.fill(None);
+            self.mapping_generation = mapping_generation;
+        }
+
+        // The lowest bit of the PC is always zero.
+        let cache_index = (pc >> 1) as usize & (CACHE_SIZE - 1);
+        if let Some((cached_pc, instruction)) = self.cache[cache_index] {
+            if cached_pc == pc {
+                return Ok(instruction);
+            }
+        }
+
+        let low_bits = execute_load16(pc)?;
+        let instruction_bits = if low_bits & 0b11 == 0b11 {
+            u32::from(low_bits) | (u32::from(execute_load16(pc.wrapping_add(2))?) << 16)
+        } else {
+            u32::from(low_bits)
+        };
+
+        let instruction = self.factories.iter()
+            .find_map(|factory| factory(instruction_bits, self.version))
+            .ok_or(CKBVMError::InvalidInstruction { pc, instruction: instruction_bits })?;
+        self.cache[cache_index] = Some((pc, instruction));
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Decodes anything except all zeroes, as the bits themselves.
+    fn identity_factory(instruction_bits: u32, _version: u32) -> Option<Instruction> {
+        (instruction_bits != 0).then_some(u64::from(instruction_bits))
+    }
+
+    fn memory(halfwords: &[(u64, u16)]) -> HashMap<u64, u16> {
+        halfwords.iter().copied().collect()
+    }
+
+    #[test]
+    fn decode_fetches_beyond_four_mib() {
+        let mut decoder = InstructionDecoder::new(vec![identity_factory], 1);
+        let pc = 0x40_0000_0ffe;
+        let memory = memory(&[(pc, 0x0513), (pc + 2, 0x0015), (pc + 4, 0x4505)]);
+        let fetch = |addr| memory.get(&addr).copied().ok_or(CKBVMError::MemOutOfBound);
+
+        assert_eq!(0x0015_0513, decoder.decode(pc, 0, fetch).expect("Should succeed"));
+        // Compressed, so the halfword after it isn't fetched.
+        assert_eq!(0x4505, decoder.decode(pc + 4, 0, fetch).expect("Should succeed"));
+    }
+
+    #[test]
+    fn decode_caches_until_mapping_generation_changes() {
+        let mut decoder = InstructionDecoder::new(vec![identity_factory], 1);
+        let mut fetches = 0;
+        let mut fetch = |_addr| {
+            fetches += 1;
+            Ok(0x4505)
+        };
+
+        decoder.decode(0x1000, 1, &mut fetch).expect("Should succeed");
+        decoder.decode(0x1000, 1, &mut fetch).expect("Should succeed");
+        decoder.decode(0x1000, 2, &mut fetch).expect("Should succeed");
+
+        assert_eq!(2, fetches);
+    }
+
+    #[test]
+    fn decode_reports_invalid_instruction_bits() {
+        let mut decoder = InstructionDecoder::new(vec![identity_factory], 1);
+
+        assert!(matches!(
+            decoder.decode(0x1000, 0, |_addr| Ok(0)),
+            Err(CKBVMError::InvalidInstruction { pc: 0x1000, instruction: 0 }),
+        ));
+    }
+}
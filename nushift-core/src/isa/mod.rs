@@ -0,0 +1,339 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Which RISC-V extensions apps can use, and checking an app's ELF against
+//! them.
+//!
+//! Most extensions are interpreted by ckb-vm. Extensions ckb-vm doesn't have
+//! are interpreted by us, for instructions ckb-vm fails to decode (see
+//! `atomics`). F and D will be done the same way, once there is somewhere to
+//! keep the floating point registers, which is why they already have bits in
+//! `IsaExtensions`.
+
+use bitflags::bitflags;
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+use xmas_elf::header::HeaderPt2;
+use xmas_elf::ElfFile;
+
+pub(crate) mod atomics;
+pub(crate) mod decoder;
+
+bitflags! {
+    /// RISC-V extensions, as returned by the `IsaGetExtensions` syscall.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IsaExtensions: u64 {
+        const I = 1 << 0;
+        const M = 1 << 1;
+        const A = 1 << 2;
+        const F = 1 << 3;
+        const D = 1 << 4;
+        const C = 1 << 5;
+        const ZBA = 1 << 6;
+        const ZBB = 1 << 7;
+        const ZBC = 1 << 8;
+        const ZBS = 1 << 9;
+    }
+}
+
+impl IsaExtensions {
+    /// What the hypervisor currently supports.
+    pub const SUPPORTED: Self = Self::I.union(Self::M).union(Self::A).union(Self::C)
+        .union(Self::ZBA).union(Self::ZBB).union(Self::ZBC).union(Self::ZBS);
+
+    /// The ISA flags to give ckb-vm. A isn't one of them because we interpret
+    /// it ourselves.
+    pub(crate) fn ckb_vm_isa(self) -> u8 {
+        if self.intersects(Self::ZBA | Self::ZBB | Self::ZBC | Self::ZBS) {
+            ckb_vm::ISA_IMC | ckb_vm::ISA_B
+        } else {
+            ckb_vm::ISA_IMC
+        }
+    }
+
+    /// The extensions named in a RISC-V ISA string, like `m` or `zba`.
+    /// `None` if we don't know the extension at all.
+    fn from_isa_string_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "i" | "e" => Self::I,
+            "m" | "zmmul" => Self::M,
+            "a" | "zaamo" | "zalrsc" => Self::A,
+            "f" => Self::F,
+            "d" => Self::D,
+            "c" | "zca" => Self::C,
+            "b" => Self::ZBA | Self::ZBB | Self::ZBS,
+            "zba" => Self::ZBA,
+            "zbb" => Self::ZBB,
+            "zbc" => Self::ZBC,
+            "zbs" => Self::ZBS,
+            // Implied by I in older versions of the spec, so toolchains list
+            // them as a matter of course. We don't support the instructions,
+            // but neither did I before they were split out.
+            "zicsr" | "zifencei" => Self::empty(),
+            _ => return None,
+        })
+    }
+}
+
+/// Toolchains put extensions in an app's attributes whether or not the app
+/// uses them. For example, Zig lists F in soft-float apps. So F and D are let
+/// through with a warning, and the app faults if it actually uses them.
+const TOLERATED: IsaExtensions = IsaExtensions::F.union(IsaExtensions::D);
+
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+
+const RISCV_ATTRIBUTES_SECTION: &str = ".riscv.attributes";
+const TAG_FILE: u64 = 1;
+const TAG_RISCV_ARCH: u64 = 5;
+
+const EI_CLASS: usize = 4;
+const ELFCLASS32: u8 = 1;
+
+/// The register width of the machine that the ELF in `image` needs, going by
+/// its class. Anything that isn't a 32-bit ELF, including things that aren't
+/// ELFs at all, is treated as 64-bit and left for the loader to reject.
+pub(crate) fn elf_xlen(image: &[u8]) -> u8 {
+    match image.get(EI_CLASS) {
+        Some(&ELFCLASS32) => 32,
+        _ => 64,
+    }
+}
+
+/// Check that the app in `elf_file` can run on a machine with `xlen`-bit
+/// registers and the `SUPPORTED` extensions.
+pub(crate) fn check_elf(elf_file: &ElfFile, xlen: u8) -> Result<(), IsaError> {
+    let e_flags = match elf_file.header.pt2 {
+        HeaderPt2::Header32(header) => header.flags,
+        HeaderPt2::Header64(header) => header.flags,
+    };
+    if e_flags & EF_RISCV_FLOAT_ABI != 0 {
+        // The float ABI only affects how the app's own functions call each
+        // other, so this is fine as long as no floating point is actually
+        // used.
+        tracing::warn!("The app uses a hard-float ABI, but floating point extensions are not supported yet");
+    }
+
+    let attributes = elf_file.section_iter()
+        .find(|section| section.get_name(elf_file) == Ok(RISCV_ATTRIBUTES_SECTION))
+        .map(|section| section.raw_data(elf_file));
+    // Assembled apps may not have attributes, in which case there's nothing
+    // more we can check.
+    let Some(attributes) = attributes else {
+        return Ok(());
+    };
+    let arch = riscv_arch_attribute(attributes).context(MalformedAttributesSnafu)?;
+
+    match arch {
+        Some(arch) => check_arch(&arch, xlen),
+        None => Ok(()),
+    }
+}
+
+/// Check an ISA string like `rv64i2p1_m2p0_a2p1_c2p0_zba1p0`.
+fn check_arch(arch: &str, xlen: u8) -> Result<(), IsaError> {
+    let arch = arch.to_ascii_lowercase();
+    let expected_prefix = format!("rv{xlen}");
+    let base_and_extensions = arch.strip_prefix(&expected_prefix).context(XlenMismatchSnafu { arch: arch.clone(), xlen })?;
+
+    let mut extensions = IsaExtensions::empty();
+    let mut unknown = vec![];
+    for name in extension_names(base_and_extensions) {
+        match IsaExtensions::from_isa_string_name(&name) {
+            Some(extension) => extensions |= extension,
+            None => unknown.push(name),
+        }
+    }
+
+    let unsupported = extensions.difference(IsaExtensions::SUPPORTED);
+    ensure!(unknown.is_empty() && TOLERATED.contains(unsupported), UnsupportedExtensionsSnafu {
+        arch,
+        unsupported: unknown.into_iter().chain(unsupported.iter_names().map(|(name, _)| name.to_ascii_lowercase())).collect::<Vec<_>>(),
+    });
+    if !unsupported.is_empty() {
+        tracing::warn!("The app may use {unsupported:?}, which are not supported yet. If it does, it will fault.");
+    }
+
+    Ok(())
+}
+
+/// The extension names in the part of an ISA string after `rv64`, without
+/// their versions. Single-letter extensions come first, then multi-letter
+/// extensions separated by underscores.
+fn extension_names(base_and_extensions: &str) -> Vec<String> {
+    let mut names = vec![];
+    for (index, part) in base_and_extensions.split('_').filter(|part| !part.is_empty()).enumerate() {
+        if index > 0 && part.len() > 1 && matches!(part.as_bytes()[0], b'z' | b's' | b'x') {
+            names.push(part.trim_end_matches(|ch: char| ch.is_ascii_digit() || ch == 'p').to_string());
+            continue;
+        }
+
+        // A run of single letters, each optionally followed by a version like
+        // `2p1`.
+        names.extend(part.chars().filter(char::is_ascii_alphabetic).filter(|&ch| ch != 'p').map(String::from));
+    }
+    names
+}
+
+/// Finds `Tag_RISCV_arch` in the contents of a `.riscv.attributes` section.
+/// Returns `None` (in the `Option`) if the section is malformed.
+fn riscv_arch_attribute(attributes: &[u8]) -> Option<Option<String>> {
+    let (&format_version, mut rest) = attributes.split_first()?;
+    if format_version != b'A' {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let (subsection, after) = take_sized(rest)?;
+        rest = after;
+
+        let (vendor, mut subsection) = take_ntbs(subsection)?;
+        if vendor != "riscv" {
+            continue;
+        }
+
+        while !subsection.is_empty() {
+            let (tag, after_tag) = take_uleb128(subsection)?;
+            // The size covers the tag too, so re-read it as part of the
+            // sub-subsection.
+            let size = u32::from_le_bytes(after_tag.get(..4)?.try_into().ok()?) as usize;
+            let sub_subsection = subsection.get(..size)?;
+            subsection = &subsection[size..];
+            if tag != TAG_FILE {
+                continue;
+            }
+
+            let mut attribute = sub_subsection.get(subsection_header_len(sub_subsection)?..)?;
+            while !attribute.is_empty() {
+                let (attribute_tag, after) = take_uleb128(attribute)?;
+                // Odd tags have string values, and even tags have number
+                // values.
+                if attribute_tag % 2 == 1 {
+                    let (value, after) = take_ntbs(after)?;
+                    if attribute_tag == TAG_RISCV_ARCH {
+                        return Some(Some(value.to_string()));
+                    }
+                    attribute = after;
+                } else {
+                    attribute = take_uleb128(after)?.1;
+                }
+            }
+        }
+    }
+
+    Some(None)
+}
+
+/// A `u32` size (which includes itself), then that many bytes.
+fn take_sized(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let size = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    if size < 4 {
+        return None;
+    }
+    Some((bytes.get(4..size)?, &bytes[size..]))
+}
+
+fn subsection_header_len(sub_subsection: &[u8]) -> Option<usize> {
+    let (_, after_tag) = take_uleb128(sub_subsection)?;
+    Some(sub_subsection.len() - after_tag.len() + 4)
+}
+
+fn take_ntbs(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let nul = bytes.iter().position(|&byte| byte == 0)?;
+    Some((core::str::from_utf8(&bytes[..nul]).ok()?, &bytes[nul + 1..]))
+}
+
+fn take_uleb128(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (index, &byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[index + 1..]));
+        }
+    }
+    None
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum IsaError {
+    #[snafu(display("The app's .riscv.attributes section is malformed"))]
+    MalformedAttributes,
+    #[snafu(display("The app is built for {arch}, but this machine is {xlen}-bit"))]
+    XlenMismatch { arch: String, xlen: u8 },
+    #[snafu(display("The app is built for {arch}, which uses extensions that are not supported: {}", unsupported.join(", ")))]
+    UnsupportedExtensions { arch: String, unsupported: Vec<String> },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `.riscv.attributes` section as Zig writes it.
+    fn attributes(arch: &str) -> Vec<u8> {
+        let mut file_attributes = vec![4, 16, 5];
+        file_attributes.extend(arch.as_bytes());
+        file_attributes.push(0);
+
+        let mut sub_subsection = vec![TAG_FILE as u8];
+        sub_subsection.extend((file_attributes.len() as u32 + 5).to_le_bytes());
+        sub_subsection.extend(file_attributes);
+
+        let mut subsection = b"riscv\0".to_vec();
+        subsection.extend(sub_subsection);
+
+        let mut section = vec![b'A'];
+        section.extend((subsection.len() as u32 + 4).to_le_bytes());
+        section.extend(subsection);
+        section
+    }
+
+    #[test]
+    fn riscv_arch_attribute_is_found() {
+        assert_eq!(Some(Some("rv64i2p0_m2p0_a2p0_f2p0_c2p0".into())), riscv_arch_attribute(&attributes("rv64i2p0_m2p0_a2p0_f2p0_c2p0")));
+    }
+
+    #[test]
+    fn riscv_arch_attribute_rejects_truncated_section() {
+        let attributes = attributes("rv64imac");
+
+        assert_eq!(None, riscv_arch_attribute(&attributes[..attributes.len() - 3]));
+    }
+
+    #[test]
+    fn extension_names_strips_versions() {
+        assert_eq!(vec!["i", "m", "a", "c", "zicsr", "zba"], extension_names("i2p1_m2p0_a2p1_c2p0_zicsr2p0_zba1p0"));
+        assert_eq!(vec!["i", "m", "a", "c"], extension_names("imac"));
+    }
+
+    #[test]
+    fn check_arch_accepts_supported_and_tolerated_extensions() {
+        assert!(check_arch("rv64i2p1_m2p0_a2p1_c2p0_zicsr2p0_zifencei2p0_zba1p0_zbb1p0", 64).is_ok());
+        assert!(check_arch("rv64i2p0_m2p0_a2p0_f2p0_c2p0", 64).is_ok());
+    }
+
+    #[test]
+    fn check_arch_rejects_unsupported_extensions() {
+        let Err(IsaError::UnsupportedExtensions { unsupported, .. }) = check_arch("rv64i2p1_m2p0_v1p0_zvl128b1p0", 64) else {
+            panic!("Should be UnsupportedExtensions");
+        };
+
+        assert_eq!(vec!["v", "zvl128b"], unsupported);
+    }
+
+    #[test]
+    fn check_arch_rejects_other_xlen() {
+        assert!(matches!(check_arch("rv32imac", 64), Err(IsaError::XlenMismatch { xlen: 64, .. })));
+    }
+
+    #[test]
+    fn elf_xlen_follows_elf_class() {
+        assert_eq!(32, elf_xlen(b"\x7fELF\x01\x01\x01"));
+        assert_eq!(64, elf_xlen(b"\x7fELF\x02\x01\x01"));
+        assert_eq!(64, elf_xlen(b"\x7fEL"));
+    }
+
+    #[test]
+    fn ckb_vm_isa_enables_b_for_bitmanip() {
+        assert_eq!(ckb_vm::ISA_IMC | ckb_vm::ISA_B, IsaExtensions::SUPPORTED.ckb_vm_isa());
+        assert_eq!(ckb_vm::ISA_IMC, (IsaExtensions::I | IsaExtensions::M | IsaExtensions::C).ckb_vm_isa());
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the hypervisor sets up for an app before its first instruction: a
+//! stack, and information about how the app was launched.
+//!
+//! The stack is an SHM cap at the very top of the app's address space, with
+//! the page below it left unmapped as a guard page, so that overflowing the
+//! stack faults rather than running into whatever is below it. The launch
+//! information is Postcard-encoded at the top of the stack, and at entry, `sp`
+//! and `a0` both point to it, and `a1` is its length in bytes.
+
+use serde::Serialize;
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+use crate::gfx_space::GfxOutput;
+use crate::shm_space::{CapType, ShmSpace, ShmSpaceError, ShmType, acquisitions_and_page_table::Sv39Flags};
+
+/// The stack size when neither the ELF's PT_GNU_STACK header nor the tab's
+/// config asks for one.
+pub const DEFAULT_STACK_SIZE: u64 = 1 << 20;
+
+const PAGE_SIZE: u64 = 1 << 12;
+
+/// The RISC-V psABI wants `sp` 16-byte aligned.
+const STACK_ALIGN: usize = 16;
+
+/// How an app was launched, which the app gets at entry. See the module
+/// documentation.
+#[derive(Debug, Clone, Default)]
+pub struct LaunchInfo {
+    /// The URL that the app was launched from, if any. Apps get its query
+    /// parameters already decoded, as well as the URL itself.
+    pub url: Option<String>,
+    /// The user's locale as a BCP 47 language tag, e.g. `en-AU`.
+    pub locale: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LaunchInfoPayload<'a> {
+    url: Option<&'a str>,
+    query_parameters: Vec<(String, String)>,
+    locale: Option<&'a str>,
+    gfx_output: &'a GfxOutput,
+}
+
+/// Where the app's registers should point at entry.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct AppEntry {
+    pub(crate) stack_pointer: u64,
+    pub(crate) launch_info_address: u64,
+    pub(crate) launch_info_length: u64,
+}
+
+/// Creates the stack cap, with at least `stack_size` bytes, and writes the
+/// launch information into it.
+pub(crate) fn set_up_stack(shm_space: &mut ShmSpace, stack_size: u64, launch_info: &LaunchInfo, initial_gfx_output: &GfxOutput) -> Result<AppEntry, LaunchError> {
+    let payload = postcard::to_stdvec(&LaunchInfoPayload {
+        url: launch_info.url.as_deref(),
+        query_parameters: launch_info.url.as_deref().map(query_parameters).unwrap_or_default(),
+        locale: launch_info.locale.as_deref(),
+        gfx_output: initial_gfx_output,
+    }).context(SerializeSnafu)?;
+
+    let number_of_pages = stack_size.div_ceil(PAGE_SIZE).max(1);
+    let stack_bytes = number_of_pages * PAGE_SIZE;
+    let stack_top: u64 = 1 << shm_space.paging_scheme().bits();
+    let stack_bottom = stack_top
+        .checked_sub(stack_bytes)
+        .filter(|&stack_bottom| stack_bottom >= PAGE_SIZE)
+        .context(StackTooLargeSnafu { stack_size })?;
+    ensure!(payload.len() as u64 <= stack_bytes, LaunchInfoTooLargeSnafu { length: payload.len(), stack_size: stack_bytes });
+
+    let (shm_cap_id, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, number_of_pages, CapType::ElfCap).context(ShmSpaceSnafu)?;
+    let backing = shm_cap.backing_mut();
+    let launch_info_offset = (backing.len() - payload.len()) & !(STACK_ALIGN - 1);
+    backing[launch_info_offset..launch_info_offset + payload.len()].copy_from_slice(&payload);
+
+    if let Err(acquire_error) = shm_space.acquire_shm_cap_elf(shm_cap_id, stack_bottom, Sv39Flags::RW) {
+        // Not acquired, so this can't fail.
+        let _ = shm_space.destroy_shm_cap(shm_cap_id, CapType::ElfCap);
+        return Err(acquire_error).context(ShmSpaceSnafu);
+    }
+
+    let launch_info_address = stack_bottom + launch_info_offset as u64;
+    Ok(AppEntry {
+        stack_pointer: launch_info_address,
+        launch_info_address,
+        launch_info_length: payload.len() as u64,
+    })
+}
+
+/// The name-value pairs in `url`'s query, percent-decoded, and with `+` as a
+/// space as in HTML forms. Anything that isn't valid UTF-8 once decoded is
+/// replaced, rather than failing the launch.
+fn query_parameters(url: &str) -> Vec<(String, String)> {
+    let Some((_, query)) = url.split_once('?') else {
+        return Vec::new();
+    };
+    let query = query.split_once('#').map_or(query, |(query, _)| query);
+
+    query.split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| {
+            let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let encoded = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        let hex_byte = encoded.get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (encoded[index], hex_byte) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum LaunchError {
+    #[snafu(display("A stack of {stack_size} bytes doesn't fit in the app's address space"))]
+    StackTooLarge { stack_size: u64 },
+    #[snafu(display("The launch information is {length} bytes, which doesn't fit in the {stack_size}-byte stack"))]
+    LaunchInfoTooLarge { length: usize, stack_size: u64 },
+    SerializeError { source: postcard::Error },
+    ShmSpaceError { source: ShmSpaceError },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shm_space::PagingScheme;
+
+    use super::*;
+
+    fn gfx_output() -> GfxOutput {
+        GfxOutput::new(0, vec![1280, 720], vec![1.0, 1.0])
+    }
+
+    #[test]
+    fn set_up_stack_puts_launch_info_at_top_of_stack_below_guard_page() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv32);
+        let launch_info = LaunchInfo { url: Some("https://example.com/app?q=a+b%21&debug".into()), locale: Some("en-AU".into()) };
+
+        let app_entry = set_up_stack(&mut shm_space, 0x1800, &launch_info, &gfx_output()).expect("Should succeed");
+
+        assert_eq!(0, app_entry.stack_pointer % 16);
+        assert!(app_entry.stack_pointer >= (1 << 32) - 0x2000);
+        assert!(app_entry.launch_info_address + app_entry.launch_info_length <= 1 << 32);
+        // Two pages, rounded up, and nothing below them.
+        assert!(shm_space.translate((1 << 32) - 0x2000, Sv39Flags::RW).is_ok());
+        assert!(shm_space.translate((1 << 32) - 0x2001, Sv39Flags::R).is_err());
+
+        let page_translation = shm_space.translate(app_entry.launch_info_address, Sv39Flags::R).expect("Should succeed");
+        let walked = shm_space.translated_page(&page_translation, app_entry.launch_info_address).expect("Should succeed");
+        let payload = &walked.space_slice[walked.byte_offset_in_space_slice..][..app_entry.launch_info_length as usize];
+        let expected = postcard::to_stdvec(&LaunchInfoPayload {
+            url: launch_info.url.as_deref(),
+            query_parameters: vec![("q".into(), "a b!".into()), ("debug".into(), "".into())],
+            locale: Some("en-AU"),
+            gfx_output: &gfx_output(),
+        }).expect("Should succeed");
+        assert_eq!(expected, payload);
+    }
+
+    #[test]
+    fn set_up_stack_rejects_stack_overlapping_elf() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv32);
+        let (elf_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_elf(elf_cap_id, (1 << 32) - 0x1000, Sv39Flags::RX).expect("Should succeed");
+
+        assert!(matches!(
+            set_up_stack(&mut shm_space, DEFAULT_STACK_SIZE, &LaunchInfo::default(), &gfx_output()),
+            Err(LaunchError::ShmSpaceError { source: ShmSpaceError::OverlapsExistingAcquisition }),
+        ));
+        assert!(matches!(
+            set_up_stack(&mut shm_space, 1 << 32, &LaunchInfo::default(), &gfx_output()),
+            Err(LaunchError::StackTooLarge { .. }),
+        ));
+    }
+
+    #[test]
+    fn query_parameters_are_decoded() {
+        assert_eq!(Vec::<(String, String)>::new(), query_parameters("https://example.com/"));
+        assert_eq!(
+            vec![("a".into(), "1".into()), ("b c".into(), "100%".into()), ("d".into(), "%zz".into())],
+            query_parameters("https://example.com/?a=1&b+c=100%25&&d=%zz#a=2"),
+        );
+    }
+}
@@ -2,19 +2,38 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod accessibility_tree_space;
+mod channel_space;
 mod debug_print;
 mod deferred_space;
 mod elf_loader;
+mod gdb_stub;
 mod gfx_space;
 mod hypervisor;
+mod isa;
+mod launch;
 mod nushift_subsystem;
 mod process_control_block;
 mod protected_memory;
 mod register_ipc;
 mod rollback_chain;
 mod shm_space;
+mod snapshot;
+mod syscall_tracer;
+mod thread_space;
 mod title_space;
 
+pub use crate::channel_space::ChannelMessageError;
+pub use crate::elf_loader::PagingSchemeNoteError;
+pub use crate::gdb_stub::{GdbServerAddress, GdbServerAddressParseError};
 pub use crate::gfx_space::{GfxOutput, PresentBufferFormat};
-pub use crate::hypervisor::Hypervisor;
+pub use crate::hypervisor::{ConnectTabsError, Hypervisor, TabLoadError, TabRunError};
+pub use crate::hypervisor::headless::HeadlessEvents;
+pub use crate::hypervisor::tab_config::TabConfig;
+pub use crate::hypervisor::tab_image::{TabImageSource, TabImageLoader};
 pub use crate::hypervisor::hypervisor_event::{HypervisorEvent, HypervisorEventError};
+pub use crate::isa::{IsaError, IsaExtensions};
+pub use crate::launch::{LaunchError, LaunchInfo, DEFAULT_STACK_SIZE};
+pub use crate::process_control_block::{ExitReason, ProcessControlBlockError};
+pub use crate::shm_space::{MemoryUsage, ShmSpaceError, ShmType};
+pub use crate::snapshot::{SnapshotError, SNAPSHOT_VERSION};
+pub use crate::syscall_tracer::{SyscallTracer, SyscallTraceEvent, SyscallTraceResult};
@@ -1,20 +1,26 @@
 // Copyright 2023 The Nushift Authors.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex, Condvar};
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use ckb_vm::Register;
 use num_enum::{TryFromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
 
 use crate::debug_print::{DebugPrint, DebugPrintError};
 use crate::hypervisor::tab_context::TabContext;
-use crate::accessibility_tree_space::AccessibilityTreeSpace;
-use crate::deferred_space::app_global_deferred_space::{AppGlobalDeferredSpace, AppGlobalDeferredSpaceError, Task, TaskId};
-use crate::deferred_space::DeferredSpaceError;
-use crate::gfx_space::{GfxSpace, GfxSpaceError};
-use crate::register_ipc::{SyscallEnter, SyscallReturn, SYSCALL_NUM_REGISTER_INDEX, FIRST_ARG_REGISTER_INDEX, SECOND_ARG_REGISTER_INDEX, THIRD_ARG_REGISTER_INDEX, FOURTH_ARG_REGISTER_INDEX};
-use crate::shm_space::{CapType, ShmType, ShmSpace, ShmSpaceError};
+use crate::hypervisor::tab_control::TabControl;
+use crate::isa::IsaExtensions;
+use crate::accessibility_tree_space::{AccessibilityTreeSpace, AccessibilityTreeSpaceSnapshot};
+use crate::channel_space::{ChannelSpace, ChannelSpaceError};
+use crate::deferred_space::app_global_deferred_space::{AppGlobalDeferredSpace, AppGlobalDeferredSpaceError, AppGlobalDeferredSpaceSnapshot, Task};
+use crate::deferred_space::{DeferredSpaceError, DefaultDeferredSpaceSnapshot};
+use crate::gfx_space::{GfxSpace, GfxSpaceError, GfxSpaceSnapshot};
+use crate::register_ipc::{SyscallEnter, SyscallReturn, SYSCALL_NUM_REGISTER_INDEX, FIRST_ARG_REGISTER_INDEX, SECOND_ARG_REGISTER_INDEX, THIRD_ARG_REGISTER_INDEX, FOURTH_ARG_REGISTER_INDEX, RETURN_VAL_REGISTER_INDEX, ERROR_RETURN_VAL_REGISTER_INDEX};
+use crate::shm_space::{CapType, MappingGeneration, PagingScheme, ShmFlags, ShmType, ShmSpace, ShmSpaceError, ShmSpaceMutex, ShmSpaceSnapshot};
+use crate::snapshot::{SnapshotError, MultipleThreadsSnafu};
+use crate::syscall_tracer::{SyscallTracer, SyscallTraceEvent, SyscallTraceResult};
+use crate::thread_space::{ThreadId, ThreadSpace, ThreadSpaceError, ThreadStart, MAIN_THREAD_ID};
 use crate::title_space::TitleSpace;
 
 // Regarding the use of `u64`s in this file:
@@ -22,14 +28,11 @@ use crate::title_space::TitleSpace;
 // It is intended that riscv32 should use the same numbers. I don't want to have
 // duplicate enums/duplicate code for riscv32 apps.
 //
-// My idea is that riscv32 apps can encode the numbers in a multi-register
-// encoding. But at the same time, I don't want that to use as many as 3
-// registers, so these numbers should be u63, not u64.
-//
-// (Technically, 2 registers can encode slightly more than a u63, but a u63
-// fits.)
+// riscv32 apps encode the numbers in register pairs, which the process control
+// block puts back together (see `AbiLayout`), so by the time they get here
+// they're `u64`s either way.
 
-#[derive(TryFromPrimitive)]
+#[derive(TryFromPrimitive, Debug)]
 #[repr(u64)]
 enum Syscall {
     Exit = 0,
@@ -40,6 +43,9 @@ enum Syscall {
     ShmRelease = 4,
     ShmDestroy = 5,
     ShmReleaseAndDestroy = 6,
+    ShmAcquireWithFlags = 30,
+    ShmProtect = 31,
+    ShmResize = 32,
 
     AccessibilityTreeNew = 7,
     AccessibilityTreePublishRON = 8,
@@ -60,9 +66,34 @@ enum Syscall {
     GfxDestroy = 19,
 
     DebugPrint = 20,
+
+    IsaGetExtensions = 22,
+
+    ThreadSpawn = 23,
+    ThreadExit = 24,
+    ThreadJoin = 25,
+
+    ChannelNew = 26,
+    ChannelSend = 27,
+    ChannelRecv = 28,
+    ChannelDestroy = 29,
 }
 
-#[derive(IntoPrimitive)]
+impl Syscall {
+    /// Whether a successful call returns the ID of a deferred task it started.
+    fn starts_deferred_task(&self) -> bool {
+        matches!(self,
+            Self::AccessibilityTreePublishRON
+            | Self::AccessibilityTreePublish
+            | Self::TitlePublish
+            | Self::GfxGetOutputs
+            | Self::GfxCpuPresent
+            | Self::ChannelSend
+            | Self::ChannelRecv)
+    }
+}
+
+#[derive(IntoPrimitive, TryFromPrimitive, Debug)]
 #[repr(u64)]
 pub enum SyscallError {
     UnknownSyscall = 0,
@@ -87,40 +118,56 @@ pub enum SyscallError {
 
     GfxUnknownPresentBufferFormat = 16,
     GfxChildCapsNotDestroyed = 17,
+
+    ThreadNotFound = 18,
+    ThreadJoinSelf = 19,
+
+    ShmInvalidFlags = 20,
+    ShmCapNotAcquired = 21,
 }
 
-fn set_error<R: Register>(error: SyscallError) -> SyscallReturn<R> {
-    SyscallReturn::new_return(R::from_u64(u64::MAX), R::from_u64(error.into()))
+fn set_error(error: SyscallError) -> SyscallReturn {
+    SyscallReturn::new_return(u64::MAX, error.into())
 }
 
-fn set_success<R: Register>(return_value: u64) -> SyscallReturn<R> {
-    SyscallReturn::new_return(R::from_u64(return_value), R::from_u64(u64::MAX))
+fn set_success(return_value: u64) -> SyscallReturn {
+    SyscallReturn::new_return(return_value, u64::MAX)
 }
 
-fn user_exit<R>(exit_reason: u64) -> SyscallReturn<R> {
+fn user_exit(exit_reason: u64) -> SyscallReturn {
     SyscallReturn::UserExit { exit_reason }
 }
 
-fn marshall_shm_space_error<R: Register>(shm_space_error: ShmSpaceError) -> SyscallReturn<R> {
+fn thread_exit(exit_value: u64) -> SyscallReturn {
+    SyscallReturn::ThreadExit { exit_value }
+}
+
+fn marshall_shm_space_error(shm_space_error: ShmSpaceError) -> SyscallReturn {
     match shm_space_error {
         ShmSpaceError::DuplicateId
         | ShmSpaceError::AcquireReleaseInternalError => set_error(SyscallError::InternalError),
         ShmSpaceError::Exhausted => set_error(SyscallError::Exhausted),
         ShmSpaceError::InvalidLength => set_error(SyscallError::ShmInvalidLength),
         ShmSpaceError::CapacityNotAvailable
+        | ShmSpaceError::MaxMemoryBytesExceeded { .. }
         | ShmSpaceError::BackingCapacityNotAvailable { .. }
         | ShmSpaceError::BackingCapacityNotAvailableOverflows => set_error(SyscallError::ShmCapacityNotAvailable),
         ShmSpaceError::CurrentlyAcquiredCap { .. }
-        | ShmSpaceError::DestroyingCurrentlyAcquiredCap { .. } => set_error(SyscallError::ShmCapCurrentlyAcquired),
+        | ShmSpaceError::DestroyingCurrentlyAcquiredCap { .. }
+        | ShmSpaceError::TransferringCurrentlyAcquiredCap { .. } => set_error(SyscallError::ShmCapCurrentlyAcquired),
         ShmSpaceError::CapNotFound => set_error(SyscallError::CapNotFound),
+        ShmSpaceError::UnsupportedShmType { .. } => set_error(SyscallError::ShmUnknownShmType),
         ShmSpaceError::AddressOutOfBounds => set_error(SyscallError::ShmAddressOutOfBounds),
         ShmSpaceError::AddressNotAligned => set_error(SyscallError::ShmAddressNotAligned),
         ShmSpaceError::OverlapsExistingAcquisition => set_error(SyscallError::ShmOverlapsExistingAcquisition),
         ShmSpaceError::PermissionDenied => set_error(SyscallError::PermissionDenied),
+        ShmSpaceError::WriteAndExecute
+        | ShmSpaceError::WriteWithoutRead => set_error(SyscallError::ShmInvalidFlags),
+        ShmSpaceError::NotAcquiredCap => set_error(SyscallError::ShmCapNotAcquired),
     }
 }
 
-fn marshall_deferred_space_error<R: Register>(deferred_space_error: DeferredSpaceError) -> SyscallReturn<R> {
+fn marshall_deferred_space_error(deferred_space_error: DeferredSpaceError) -> SyscallReturn {
     match deferred_space_error {
         DeferredSpaceError::DuplicateId
         | DeferredSpaceError::ShmSpaceInternalError { .. }
@@ -133,7 +180,7 @@ fn marshall_deferred_space_error<R: Register>(deferred_space_error: DeferredSpac
     }
 }
 
-fn marshall_app_global_deferred_space_error<R: Register>(app_global_deferred_space_error: AppGlobalDeferredSpaceError) -> SyscallReturn<R> {
+fn marshall_app_global_deferred_space_error(app_global_deferred_space_error: AppGlobalDeferredSpaceError) -> SyscallReturn {
     match app_global_deferred_space_error {
         AppGlobalDeferredSpaceError::DuplicateId
         | AppGlobalDeferredSpaceError::ShmUnexpectedError => set_error(SyscallError::InternalError),
@@ -141,12 +188,15 @@ fn marshall_app_global_deferred_space_error<R: Register>(app_global_deferred_spa
         AppGlobalDeferredSpaceError::DeserializeTaskIdsError { .. } => set_error(SyscallError::DeserializeError),
         AppGlobalDeferredSpaceError::Duplicates { .. } => set_error(SyscallError::DeferredDuplicateTaskIds),
         AppGlobalDeferredSpaceError::NotFound { .. } => set_error(SyscallError::DeferredTaskIdsNotFound),
+        AppGlobalDeferredSpaceError::AlreadyBlockedOn { .. } => set_error(SyscallError::InProgress),
         AppGlobalDeferredSpaceError::ShmCapNotFound { .. } => set_error(SyscallError::CapNotFound),
         AppGlobalDeferredSpaceError::ShmPermissionDenied { .. } => set_error(SyscallError::PermissionDenied),
+        // The app is being stopped, so it will never see this.
+        AppGlobalDeferredSpaceError::Cancelled => set_error(SyscallError::InternalError),
     }
 }
 
-fn marshall_gfx_space_error<R: Register>(gfx_space_error: GfxSpaceError) -> SyscallReturn<R> {
+fn marshall_gfx_space_error(gfx_space_error: GfxSpaceError) -> SyscallReturn {
     match gfx_space_error {
         GfxSpaceError::DeferredSpaceError { source } => marshall_deferred_space_error(source),
         GfxSpaceError::ChildCapsNotDestroyed { .. } => set_error(SyscallError::GfxChildCapsNotDestroyed),
@@ -158,7 +208,29 @@ fn marshall_gfx_space_error<R: Register>(gfx_space_error: GfxSpaceError) -> Sysc
     }
 }
 
-fn marshall_debug_print_error<R: Register>(debug_print_error: DebugPrintError) -> SyscallReturn<R> {
+fn marshall_channel_space_error(channel_space_error: ChannelSpaceError) -> SyscallReturn {
+    match channel_space_error {
+        ChannelSpaceError::DeferredSpaceError { source } => marshall_deferred_space_error(source),
+        ChannelSpaceError::SerializeChannelCapIdsError { .. } => set_error(SyscallError::InternalError),
+        ChannelSpaceError::ShmCapNotFound { .. } => set_error(SyscallError::CapNotFound),
+        ChannelSpaceError::ShmPermissionDenied { .. } => set_error(SyscallError::PermissionDenied),
+        ChannelSpaceError::ShmUnexpectedError => set_error(SyscallError::InternalError),
+    }
+}
+
+fn marshall_thread_space_error(thread_space_error: ThreadSpaceError) -> SyscallReturn {
+    match thread_space_error {
+        ThreadSpaceError::DuplicateId => set_error(SyscallError::InternalError),
+        ThreadSpaceError::Exhausted => set_error(SyscallError::Exhausted),
+        ThreadSpaceError::NotFound { .. } => set_error(SyscallError::ThreadNotFound),
+        ThreadSpaceError::JoiningSelf => set_error(SyscallError::ThreadJoinSelf),
+        ThreadSpaceError::AlreadyBeingJoined { .. } => set_error(SyscallError::InProgress),
+        // The app is being stopped, so it will never see this.
+        ThreadSpaceError::Cancelled => set_error(SyscallError::InternalError),
+    }
+}
+
+fn marshall_debug_print_error(debug_print_error: DebugPrintError) -> SyscallReturn {
     match debug_print_error {
         DebugPrintError::DeserializeStringError { .. } => set_error(SyscallError::DeserializeError),
         DebugPrintError::ShmCapNotFound { .. } => set_error(SyscallError::CapNotFound),
@@ -167,44 +239,170 @@ fn marshall_debug_print_error<R: Register>(debug_print_error: DebugPrintError) -
     }
 }
 
-pub type BlockingOnTasksCondvar = Arc<(Mutex<HashSet<TaskId>>, Condvar)>;
-
+/// Each space has its own lock, so that the app's memory accesses (which only
+/// need the SHM space) aren't held up by deferred tasks, and deferred tasks in
+/// different spaces don't hold each other up.
+///
+/// To avoid deadlocks, locks must be taken in this order: the app global
+/// deferred space, then at most one of the accessibility tree, title, gfx and
+/// channel spaces, then the SHM space.
+///
+/// Allocating a deferred task and doing the blocking part of its syscall must
+/// look atomic to whoever finishes tasks, which is why the app global deferred
+/// space is locked first, and stays locked until the task is pushed.
+///
+/// The thread space is never locked together with any other space.
+///
+/// Every one of the app's harts makes syscalls, each from its own hypervisor
+/// thread, so any number of syscalls can be in here at once.
 pub struct NushiftSubsystem {
-    pub(crate) shm_space: ShmSpace,
-    pub(crate) app_global_deferred_space: AppGlobalDeferredSpace,
-    pub(crate) blocking_on_tasks: BlockingOnTasksCondvar,
-    pub(crate) accessibility_tree_space: AccessibilityTreeSpace,
-    pub(crate) title_space: TitleSpace,
-    pub(crate) gfx_space: GfxSpace,
+    pub(crate) shm_space: ShmSpaceMutex,
+    /// The SHM space's, shared so that it can be read without locking it.
+    mapping_generation: Arc<MappingGeneration>,
+    pub(crate) app_global_deferred_space: Mutex<AppGlobalDeferredSpace>,
+    pub(crate) tab_control: Arc<TabControl>,
+    pub(crate) accessibility_tree_space: Mutex<AccessibilityTreeSpace>,
+    pub(crate) title_space: Mutex<TitleSpace>,
+    pub(crate) gfx_space: Mutex<GfxSpace>,
+    pub(crate) channel_space: Mutex<ChannelSpace>,
+    pub(crate) thread_space: Mutex<ThreadSpace>,
     pub(crate) debug_print: DebugPrint,
+    syscall_tracer: Option<SyscallTracer>,
+}
+
+/// Everything in `NushiftSubsystem` that belongs to the app. The tab context
+/// and tab control belong to the tab it's restored into.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NushiftSubsystemSnapshot {
+    shm_space: ShmSpaceSnapshot,
+    app_global_deferred_space: AppGlobalDeferredSpaceSnapshot,
+    accessibility_tree_space: AccessibilityTreeSpaceSnapshot,
+    title_space: DefaultDeferredSpaceSnapshot,
+    gfx_space: GfxSpaceSnapshot,
 }
 
 impl NushiftSubsystem {
-    pub(crate) fn new(tab_context: Arc<dyn TabContext>, blocking_on_tasks: BlockingOnTasksCondvar) -> Self {
+    /// `paging_scheme` should suit the app's register width, see
+    /// `PagingScheme::for_xlen`.
+    pub(crate) fn new(tab_context: Arc<dyn TabContext>, tab_control: Arc<TabControl>, paging_scheme: PagingScheme) -> Self {
+        let shm_space = ShmSpace::new(paging_scheme);
         NushiftSubsystem {
-            shm_space: ShmSpace::new(),
-            app_global_deferred_space: AppGlobalDeferredSpace::new(),
-            blocking_on_tasks,
-            accessibility_tree_space: AccessibilityTreeSpace::new(),
-            title_space: TitleSpace::new(Arc::clone(&tab_context)),
-            gfx_space: GfxSpace::new(Arc::clone(&tab_context)),
-            debug_print: DebugPrint::new(),
+            mapping_generation: shm_space.shared_mapping_generation(),
+            shm_space: ShmSpaceMutex::new(shm_space),
+            app_global_deferred_space: Mutex::new(AppGlobalDeferredSpace::new()),
+            tab_control,
+            accessibility_tree_space: Mutex::new(AccessibilityTreeSpace::new()),
+            title_space: Mutex::new(TitleSpace::new(Arc::clone(&tab_context))),
+            gfx_space: Mutex::new(GfxSpace::new(Arc::clone(&tab_context))),
+            channel_space: Mutex::new(ChannelSpace::new()),
+            thread_space: Mutex::new(ThreadSpace::new()),
+            debug_print: DebugPrint::new(Arc::clone(&tab_context)),
+            syscall_tracer: None,
         }
     }
 
-    pub(crate) fn shm_space(&self) -> &ShmSpace {
-        &self.shm_space
+    /// The deferred spaces are snapshotted first, because they fail if any
+    /// tasks are in progress, and in that case the SHM space (which would be
+    /// missing the in-progress SHM caps) isn't worth copying.
+    ///
+    /// The app must be paused, so that nothing changes between the spaces
+    /// being snapshotted one at a time.
+    ///
+    /// Only the main thread's CPU state goes in a snapshot, so apps with other
+    /// threads, even exited ones that haven't been joined, can't be
+    /// snapshotted. Neither can apps with channels open (apart from the host
+    /// channel), since the other ends aren't in the snapshot.
+    pub(crate) fn snapshot(&self) -> Result<NushiftSubsystemSnapshot, SnapshotError> {
+        ensure!(self.thread_space.lock().unwrap().is_single_threaded(), MultipleThreadsSnafu);
+        self.channel_space.lock().unwrap().check_snapshottable()?;
+        let app_global_deferred_space = self.app_global_deferred_space.lock().unwrap().snapshot()?;
+        let accessibility_tree_space = self.accessibility_tree_space.lock().unwrap().snapshot()?;
+        let title_space = self.title_space.lock().unwrap().snapshot()?;
+        let gfx_space = self.gfx_space.lock().unwrap().snapshot()?;
+
+        Ok(NushiftSubsystemSnapshot {
+            shm_space: self.shm_space().snapshot(),
+            app_global_deferred_space,
+            accessibility_tree_space,
+            title_space,
+            gfx_space,
+        })
+    }
+
+    pub(crate) fn restore(tab_context: Arc<dyn TabContext>, tab_control: Arc<TabControl>, nushift_subsystem_snapshot: NushiftSubsystemSnapshot) -> Result<Self, SnapshotError> {
+        let NushiftSubsystemSnapshot { shm_space, app_global_deferred_space, accessibility_tree_space, title_space, gfx_space } = nushift_subsystem_snapshot;
+
+        let shm_space = ShmSpace::restore(shm_space)?;
+        Ok(NushiftSubsystem {
+            mapping_generation: shm_space.shared_mapping_generation(),
+            shm_space: ShmSpaceMutex::new(shm_space),
+            app_global_deferred_space: Mutex::new(AppGlobalDeferredSpace::restore(app_global_deferred_space)?),
+            tab_control,
+            accessibility_tree_space: Mutex::new(AccessibilityTreeSpace::restore(accessibility_tree_space)?),
+            title_space: Mutex::new(TitleSpace::restore(Arc::clone(&tab_context), title_space)?),
+            gfx_space: Mutex::new(GfxSpace::restore(Arc::clone(&tab_context), gfx_space)?),
+            channel_space: Mutex::new(ChannelSpace::new()),
+            thread_space: Mutex::new(ThreadSpace::new()),
+            debug_print: DebugPrint::new(Arc::clone(&tab_context)),
+            syscall_tracer: None,
+        })
+    }
+
+    /// Locks the SHM space, which comes last in the lock order.
+    pub(crate) fn shm_space(&self) -> MutexGuard<'_, ShmSpace> {
+        self.shm_space.lock().unwrap()
     }
 
-    pub(crate) fn shm_space_mut(&mut self) -> &mut ShmSpace {
-        &mut self.shm_space
+    /// The SHM space's `mapping_generation`, without locking it.
+    pub(crate) fn mapping_generation(&self) -> u64 {
+        self.mapping_generation.get()
     }
 
-    pub fn ecall<R: Register>(&mut self, registers: SyscallEnter<R>) -> SyscallReturn<R> {
-        // TODO: When 32-bit apps are supported, convert into u64 from multiple
-        // registers, instead of `.to_u64()` which can only act on a single
-        // register here)
-        let syscall = Syscall::try_from(registers[SYSCALL_NUM_REGISTER_INDEX].to_u64());
+    pub(crate) fn set_syscall_tracer(&mut self, syscall_tracer: Option<SyscallTracer>) {
+        self.syscall_tracer = syscall_tracer;
+    }
+
+    /// `syscall_trace_event` is only called if the tab is being traced.
+    pub(crate) fn trace(&self, syscall_trace_event: impl FnOnce() -> SyscallTraceEvent) {
+        if let Some(ref syscall_tracer) = self.syscall_tracer {
+            syscall_tracer.trace(syscall_trace_event());
+        }
+    }
+
+    /// `thread_id` is the thread making the syscall.
+    pub fn ecall(&self, thread_id: ThreadId, registers: SyscallEnter) -> SyscallReturn {
+        if self.syscall_tracer.is_none() {
+            return self.ecall_impl(thread_id, registers);
+        }
+
+        let number = registers[SYSCALL_NUM_REGISTER_INDEX];
+        let args = [FIRST_ARG_REGISTER_INDEX, SECOND_ARG_REGISTER_INDEX, THIRD_ARG_REGISTER_INDEX, FOURTH_ARG_REGISTER_INDEX]
+            .map(|index| registers[index]);
+        let syscall_return = self.ecall_impl(thread_id, registers);
+
+        self.trace(|| {
+            let syscall = Syscall::try_from(number).ok();
+            let result = match syscall_return {
+                SyscallReturn::UserExit { exit_reason } => SyscallTraceResult::Exit(exit_reason),
+                SyscallReturn::ThreadExit { exit_value } => SyscallTraceResult::Exit(exit_value),
+                SyscallReturn::Return(ref syscall_return) => match syscall_return[ERROR_RETURN_VAL_REGISTER_INDEX] {
+                    u64::MAX => SyscallTraceResult::Success(syscall_return[RETURN_VAL_REGISTER_INDEX]),
+                    error => SyscallTraceResult::Error(SyscallError::try_from(error).map_or_else(|_| error.to_string(), |error| format!("{error:?}"))),
+                },
+            };
+            let allocated_task_id = match (&syscall, &result) {
+                (Some(syscall), &SyscallTraceResult::Success(task_id)) if syscall.starts_deferred_task() => Some(task_id),
+                _ => None,
+            };
+
+            SyscallTraceEvent::Syscall { number, syscall: syscall.map(|syscall| format!("{syscall:?}")), args, result, allocated_task_id }
+        });
+
+        syscall_return
+    }
+
+    fn ecall_impl(&self, thread_id: ThreadId, registers: SyscallEnter) -> SyscallReturn {
+        let syscall = Syscall::try_from(registers[SYSCALL_NUM_REGISTER_INDEX]);
 
         match syscall {
             Err(_) => {
@@ -212,17 +410,17 @@ impl NushiftSubsystem {
             }
 
             Ok(Syscall::Exit) => {
-                user_exit(registers[FIRST_ARG_REGISTER_INDEX].to_u64())
+                user_exit(registers[FIRST_ARG_REGISTER_INDEX])
             }
 
             Ok(Syscall::ShmNew) => {
-                let shm_type = match ShmType::try_from(registers[FIRST_ARG_REGISTER_INDEX].to_u64()) {
+                let shm_type = match ShmType::try_from(registers[FIRST_ARG_REGISTER_INDEX]) {
                     Ok(shm_type) => shm_type,
                     Err(_) => return set_error(SyscallError::ShmUnknownShmType),
                 };
-                let length = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
+                let length = registers[SECOND_ARG_REGISTER_INDEX];
 
-                let shm_cap_id = match self.shm_space_mut().new_shm_cap(shm_type, length, CapType::AppCap) {
+                let shm_cap_id = match self.shm_space().new_shm_cap(shm_type, length, CapType::AppCap) {
                     Ok((shm_cap_id, _)) => shm_cap_id,
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 };
@@ -230,10 +428,10 @@ impl NushiftSubsystem {
                 set_success(shm_cap_id)
             }
             Ok(Syscall::ShmAcquire) => {
-                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
-                let address = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let address = registers[SECOND_ARG_REGISTER_INDEX];
 
-                match self.shm_space_mut().acquire_shm_cap_app(shm_cap_id, address) {
+                match self.shm_space().acquire_shm_cap_app(shm_cap_id, address) {
                     Ok(_) => {}
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 }
@@ -241,23 +439,24 @@ impl NushiftSubsystem {
                 set_success(0)
             }
             Ok(Syscall::ShmNewAndAcquire) => {
-                let shm_type = match ShmType::try_from(registers[FIRST_ARG_REGISTER_INDEX].to_u64()) {
+                let shm_type = match ShmType::try_from(registers[FIRST_ARG_REGISTER_INDEX]) {
                     Ok(shm_type) => shm_type,
                     Err(_) => return set_error(SyscallError::ShmUnknownShmType),
                 };
-                let length = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
-                let address = registers[THIRD_ARG_REGISTER_INDEX].to_u64();
+                let length = registers[SECOND_ARG_REGISTER_INDEX];
+                let address = registers[THIRD_ARG_REGISTER_INDEX];
+                let mut shm_space = self.shm_space();
 
-                let shm_cap_id = match self.shm_space_mut().new_shm_cap(shm_type, length, CapType::AppCap) {
+                let shm_cap_id = match shm_space.new_shm_cap(shm_type, length, CapType::AppCap) {
                     Ok((shm_cap_id, _)) => shm_cap_id,
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 };
 
-                match self.shm_space_mut().acquire_shm_cap_app(shm_cap_id, address) {
+                match shm_space.acquire_shm_cap_app(shm_cap_id, address) {
                     Ok(_) => {}
                     Err(shm_space_error) => {
                         // If an acquire error occurs, roll back the just-created cap.
-                        let shm_space_error = self.shm_space_mut().destroy_shm_cap(shm_cap_id, CapType::AppCap)
+                        let shm_space_error = shm_space.destroy_shm_cap(shm_cap_id, CapType::AppCap)
                             .map_err(|_| ShmSpaceError::AcquireReleaseInternalError)
                             // If error occurred in destroy, use that (now mapped to internal) error. Otherwise, use the original shm_space_error.
                             .map_or_else(|err| err, |_| shm_space_error);
@@ -269,9 +468,9 @@ impl NushiftSubsystem {
                 set_success(shm_cap_id)
             }
             Ok(Syscall::ShmRelease) => {
-                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.shm_space_mut().release_shm_cap_app(shm_cap_id) {
+                match self.shm_space().release_shm_cap_app(shm_cap_id) {
                     Ok(_) => {}
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 }
@@ -279,9 +478,9 @@ impl NushiftSubsystem {
                 set_success(0)
             }
             Ok(Syscall::ShmDestroy) => {
-                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.shm_space_mut().destroy_shm_cap(shm_cap_id, CapType::AppCap) {
+                match self.shm_space().destroy_shm_cap(shm_cap_id, CapType::AppCap) {
                     Ok(_) => {}
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 }
@@ -289,15 +488,54 @@ impl NushiftSubsystem {
                 set_success(0)
             }
             Ok(Syscall::ShmReleaseAndDestroy) => {
-                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let mut shm_space = self.shm_space();
 
-                match self.shm_space_mut().release_shm_cap_app(shm_cap_id) {
+                match shm_space.release_shm_cap_app(shm_cap_id) {
                     Ok(_) => {}
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 }
 
                 // If the release succeeded, destroy should never fail, thus do not rollback (re-acquire).
-                match self.shm_space_mut().destroy_shm_cap(shm_cap_id, CapType::AppCap) {
+                match shm_space.destroy_shm_cap(shm_cap_id, CapType::AppCap) {
+                    Ok(_) => {}
+                    Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
+                }
+
+                set_success(0)
+            }
+            Ok(Syscall::ShmAcquireWithFlags) => {
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let address = registers[SECOND_ARG_REGISTER_INDEX];
+                let Some(shm_flags) = ShmFlags::from_bits(registers[THIRD_ARG_REGISTER_INDEX]) else {
+                    return set_error(SyscallError::ShmInvalidFlags);
+                };
+
+                match self.shm_space().acquire_shm_cap_app_with_flags(shm_cap_id, address, shm_flags) {
+                    Ok(_) => {}
+                    Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
+                }
+
+                set_success(0)
+            }
+            Ok(Syscall::ShmProtect) => {
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let Some(shm_flags) = ShmFlags::from_bits(registers[SECOND_ARG_REGISTER_INDEX]) else {
+                    return set_error(SyscallError::ShmInvalidFlags);
+                };
+
+                match self.shm_space().protect_shm_cap_app(shm_cap_id, shm_flags) {
+                    Ok(_) => {}
+                    Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
+                }
+
+                set_success(0)
+            }
+            Ok(Syscall::ShmResize) => {
+                let shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let length = registers[SECOND_ARG_REGISTER_INDEX];
+
+                match self.shm_space().resize_shm_cap_app(shm_cap_id, length) {
                     Ok(_) => {}
                     Err(shm_space_error) => return marshall_shm_space_error(shm_space_error),
                 }
@@ -306,7 +544,7 @@ impl NushiftSubsystem {
             }
 
             Ok(Syscall::AccessibilityTreeNew) => {
-                let accessibility_tree_cap_id = match self.accessibility_tree_space.new_accessibility_tree_cap() {
+                let accessibility_tree_cap_id = match self.accessibility_tree_space.lock().unwrap().new_accessibility_tree_cap() {
                     Ok(accessibility_tree_cap_id) => accessibility_tree_cap_id,
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 };
@@ -314,16 +552,17 @@ impl NushiftSubsystem {
                 set_success(accessibility_tree_cap_id)
             }
             Ok(Syscall::AccessibilityTreePublishRON) => {
-                let accessibility_tree_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
-                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
-                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX].to_u64();
+                let accessibility_tree_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX];
 
-                let mut task = match self.app_global_deferred_space.allocate_task(Task::AccessibilityTreePublishRON { accessibility_tree_cap_id }) {
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::AccessibilityTreePublishRON { accessibility_tree_cap_id }) {
                     Ok(task) => task,
                     Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
                 };
 
-                match self.accessibility_tree_space.publish_accessibility_tree_blocking(accessibility_tree_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space) {
+                match self.accessibility_tree_space.lock().unwrap().publish_accessibility_tree_blocking(accessibility_tree_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space()) {
                     Ok(_) => {}
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 }
@@ -333,16 +572,17 @@ impl NushiftSubsystem {
                 set_success(task_id)
             }
             Ok(Syscall::AccessibilityTreePublish) => {
-                let accessibility_tree_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
-                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
-                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX].to_u64();
+                let accessibility_tree_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX];
 
-                let mut task = match self.app_global_deferred_space.allocate_task(Task::AccessibilityTreePublish { accessibility_tree_cap_id }) {
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::AccessibilityTreePublish { accessibility_tree_cap_id }) {
                     Ok(task) => task,
                     Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
                 };
 
-                match self.accessibility_tree_space.publish_accessibility_tree_blocking(accessibility_tree_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space) {
+                match self.accessibility_tree_space.lock().unwrap().publish_accessibility_tree_blocking(accessibility_tree_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space()) {
                     Ok(_) => {}
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 }
@@ -352,9 +592,9 @@ impl NushiftSubsystem {
                 set_success(task_id)
             }
             Ok(Syscall::AccessibilityTreeDestroy) => {
-                let accessibility_tree_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let accessibility_tree_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.accessibility_tree_space.destroy_accessibility_tree_cap(accessibility_tree_cap_id) {
+                match self.accessibility_tree_space.lock().unwrap().destroy_accessibility_tree_cap(accessibility_tree_cap_id) {
                     Ok(_) => {}
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 }
@@ -363,7 +603,7 @@ impl NushiftSubsystem {
             }
 
             Ok(Syscall::TitleNew) => {
-                let title_cap_id = match self.title_space.new_title_cap() {
+                let title_cap_id = match self.title_space.lock().unwrap().new_title_cap() {
                     Ok(title_cap_id) => title_cap_id,
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 };
@@ -371,16 +611,17 @@ impl NushiftSubsystem {
                 set_success(title_cap_id)
             }
             Ok(Syscall::TitlePublish) => {
-                let title_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
-                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
-                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX].to_u64();
+                let title_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX];
 
-                let mut task = match self.app_global_deferred_space.allocate_task(Task::TitlePublish { title_cap_id }) {
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::TitlePublish { title_cap_id }) {
                     Ok(task) => task,
                     Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
                 };
 
-                match self.title_space.publish_title_blocking(title_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space) {
+                match self.title_space.lock().unwrap().publish_title_blocking(title_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space()) {
                     Ok(_) => {}
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 }
@@ -390,9 +631,9 @@ impl NushiftSubsystem {
                 set_success(task_id)
             }
             Ok(Syscall::TitleDestroy) => {
-                let title_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let title_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.title_space.destroy_title_cap(title_cap_id) {
+                match self.title_space.lock().unwrap().destroy_title_cap(title_cap_id) {
                     Ok(_) => {}
                     Err(deferred_space_error) => return marshall_deferred_space_error(deferred_space_error),
                 }
@@ -401,9 +642,9 @@ impl NushiftSubsystem {
             }
 
             Ok(Syscall::BlockOnDeferredTasks) => {
-                let input_shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let input_shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.app_global_deferred_space.block_on_deferred_tasks(input_shm_cap_id, &self.shm_space, &self.blocking_on_tasks) {
+                match AppGlobalDeferredSpace::block_on_deferred_tasks(&self.app_global_deferred_space, input_shm_cap_id, &self.shm_space, &self.tab_control) {
                     Ok(_) => {}
                     Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
                 }
@@ -412,7 +653,7 @@ impl NushiftSubsystem {
             }
 
             Ok(Syscall::GfxNew) => {
-                let gfx_cap_id = match self.gfx_space.new_gfx_cap() {
+                let gfx_cap_id = match self.gfx_space.lock().unwrap().new_gfx_cap() {
                     Ok(gfx_cap_id) => gfx_cap_id,
                     Err(gfx_space_error) => return marshall_gfx_space_error(gfx_space_error),
                 };
@@ -420,15 +661,16 @@ impl NushiftSubsystem {
                 set_success(gfx_cap_id)
             }
             Ok(Syscall::GfxGetOutputs) => {
-                let gfx_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
-                let output_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
+                let gfx_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
 
-                let mut task = match self.app_global_deferred_space.allocate_task(Task::GfxGetOutputs { gfx_cap_id }) {
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::GfxGetOutputs { gfx_cap_id }) {
                     Ok(task) => task,
                     Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
                 };
 
-                match self.gfx_space.get_outputs_blocking(gfx_cap_id, output_shm_cap_id, &mut self.shm_space) {
+                match self.gfx_space.lock().unwrap().get_outputs_blocking(gfx_cap_id, output_shm_cap_id, &mut self.shm_space()) {
                     Ok(_) => {}
                     Err(gfx_space_error) => return marshall_gfx_space_error(gfx_space_error),
                 }
@@ -438,10 +680,10 @@ impl NushiftSubsystem {
                 set_success(task_id)
             }
             Ok(Syscall::GfxCpuPresentBufferNew) => {
-                let gfx_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
-                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
+                let gfx_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
 
-                let gfx_cpu_present_buffer_cap_id = match self.gfx_space.new_gfx_cpu_present_buffer_cap(gfx_cap_id, input_shm_cap_id, &self.shm_space) {
+                let gfx_cpu_present_buffer_cap_id = match self.gfx_space.lock().unwrap().new_gfx_cpu_present_buffer_cap(gfx_cap_id, input_shm_cap_id, &self.shm_space()) {
                     Ok(gfx_cpu_present_buffer_cap_id) => gfx_cpu_present_buffer_cap_id,
                     Err(gfx_space_error) => return marshall_gfx_space_error(gfx_space_error),
                 };
@@ -449,24 +691,25 @@ impl NushiftSubsystem {
                 set_success(gfx_cpu_present_buffer_cap_id)
             }
             Ok(Syscall::GfxCpuPresent) => {
-                let gfx_cpu_present_buffer_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let gfx_cpu_present_buffer_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
                 // TODO: This is not used yet.
-                let _gfx_output_id = registers[SECOND_ARG_REGISTER_INDEX].to_u64();
+                let _gfx_output_id = registers[SECOND_ARG_REGISTER_INDEX];
                 // TODO: This is not used yet, we cannot implement it within the
                 // current Druid framework. It will also likely need to be
                 // changed/extended. For example, if the blitting starts but
                 // does not finish until beyond the end of the vblank interval,
                 // there may need to be another option that extends the vblank
                 // (VRR).
-                let _wait_for_vblank = registers[THIRD_ARG_REGISTER_INDEX].to_u64();
-                let output_shm_cap_id = registers[FOURTH_ARG_REGISTER_INDEX].to_u64();
+                let _wait_for_vblank = registers[THIRD_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[FOURTH_ARG_REGISTER_INDEX];
 
-                let mut task = match self.app_global_deferred_space.allocate_task(Task::GfxCpuPresent { gfx_cpu_present_buffer_cap_id }) {
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::GfxCpuPresent { gfx_cpu_present_buffer_cap_id }) {
                     Ok(task) => task,
                     Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
                 };
 
-                match self.gfx_space.cpu_present_blocking(gfx_cpu_present_buffer_cap_id, output_shm_cap_id, &mut self.shm_space) {
+                match self.gfx_space.lock().unwrap().cpu_present_blocking(gfx_cpu_present_buffer_cap_id, output_shm_cap_id, &mut self.shm_space()) {
                     Ok(_) => {}
                     Err(gfx_space_error) => return marshall_gfx_space_error(gfx_space_error),
                 }
@@ -476,9 +719,9 @@ impl NushiftSubsystem {
                 set_success(task_id)
             }
             Ok(Syscall::GfxCpuPresentBufferDestroy) => {
-                let gfx_cpu_present_buffer_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let gfx_cpu_present_buffer_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.gfx_space.destroy_gfx_cpu_present_buffer_cap(gfx_cpu_present_buffer_cap_id) {
+                match self.gfx_space.lock().unwrap().destroy_gfx_cpu_present_buffer_cap(gfx_cpu_present_buffer_cap_id) {
                     Ok(_) => {}
                     Err(gfx_space_error) => return marshall_gfx_space_error(gfx_space_error),
                 }
@@ -486,9 +729,9 @@ impl NushiftSubsystem {
                 set_success(0)
             }
             Ok(Syscall::GfxDestroy) => {
-                let gfx_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let gfx_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.gfx_space.destroy_gfx_cap(gfx_cap_id) {
+                match self.gfx_space.lock().unwrap().destroy_gfx_cap(gfx_cap_id) {
                     Ok(_) => {}
                     Err(gfx_space_error) => return marshall_gfx_space_error(gfx_space_error),
                 }
@@ -497,15 +740,204 @@ impl NushiftSubsystem {
             }
 
             Ok(Syscall::DebugPrint) => {
-                let input_shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX].to_u64();
+                let input_shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
 
-                match self.debug_print.debug_print(input_shm_cap_id, &self.shm_space) {
+                match self.debug_print.debug_print(input_shm_cap_id, &self.shm_space()) {
                     Ok(_) => {}
                     Err(debug_print_error) => return marshall_debug_print_error(debug_print_error),
                 }
 
                 set_success(0)
             }
+
+            Ok(Syscall::IsaGetExtensions) => set_success(IsaExtensions::SUPPORTED.bits()),
+
+            Ok(Syscall::ThreadSpawn) => {
+                let thread_start = ThreadStart {
+                    pc: registers[FIRST_ARG_REGISTER_INDEX],
+                    stack_pointer: registers[SECOND_ARG_REGISTER_INDEX],
+                    argument: registers[THIRD_ARG_REGISTER_INDEX],
+                };
+
+                let new_thread_id = match self.thread_space.lock().unwrap().spawn_thread(thread_start) {
+                    Ok(new_thread_id) => new_thread_id,
+                    Err(thread_space_error) => return marshall_thread_space_error(thread_space_error),
+                };
+
+                set_success(new_thread_id)
+            }
+            Ok(Syscall::ThreadExit) => {
+                let exit_value = registers[FIRST_ARG_REGISTER_INDEX];
+
+                // The app stops when its main thread does, so this is the same
+                // as Exit there.
+                if thread_id == MAIN_THREAD_ID {
+                    user_exit(exit_value)
+                } else {
+                    thread_exit(exit_value)
+                }
+            }
+            Ok(Syscall::ThreadJoin) => {
+                let joined_thread_id = registers[FIRST_ARG_REGISTER_INDEX];
+
+                let exit_value = match ThreadSpace::join_thread(&self.thread_space, thread_id, joined_thread_id, &self.tab_control) {
+                    Ok(exit_value) => exit_value,
+                    Err(thread_space_error) => return marshall_thread_space_error(thread_space_error),
+                };
+
+                set_success(exit_value)
+            }
+
+            Ok(Syscall::ChannelNew) => {
+                let output_shm_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+
+                match self.channel_space.lock().unwrap().new_channel_caps(output_shm_cap_id, &mut self.shm_space()) {
+                    Ok(_) => {}
+                    Err(channel_space_error) => return marshall_channel_space_error(channel_space_error),
+                }
+
+                set_success(0)
+            }
+            Ok(Syscall::ChannelSend) => {
+                let channel_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let input_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[THIRD_ARG_REGISTER_INDEX];
+
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::ChannelSend { channel_cap_id }) {
+                    Ok(task) => task,
+                    Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
+                };
+
+                match self.channel_space.lock().unwrap().send_blocking(channel_cap_id, input_shm_cap_id, output_shm_cap_id, &mut self.shm_space()) {
+                    Ok(_) => {}
+                    Err(channel_space_error) => return marshall_channel_space_error(channel_space_error),
+                }
+
+                let task_id = task.push_task();
+
+                set_success(task_id)
+            }
+            Ok(Syscall::ChannelRecv) => {
+                let channel_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+                let output_shm_cap_id = registers[SECOND_ARG_REGISTER_INDEX];
+
+                let mut app_global_deferred_space = self.app_global_deferred_space.lock().unwrap();
+                let mut task = match app_global_deferred_space.allocate_task(Task::ChannelRecv { channel_cap_id }) {
+                    Ok(task) => task,
+                    Err(app_global_deferred_space_error) => return marshall_app_global_deferred_space_error(app_global_deferred_space_error),
+                };
+
+                match self.channel_space.lock().unwrap().recv_blocking(channel_cap_id, output_shm_cap_id, &mut self.shm_space()) {
+                    Ok(_) => {}
+                    Err(channel_space_error) => return marshall_channel_space_error(channel_space_error),
+                }
+
+                let task_id = task.push_task();
+
+                set_success(task_id)
+            }
+            Ok(Syscall::ChannelDestroy) => {
+                let channel_cap_id = registers[FIRST_ARG_REGISTER_INDEX];
+
+                match self.channel_space.lock().unwrap().destroy_channel_cap(channel_cap_id) {
+                    Ok(_) => {}
+                    Err(channel_space_error) => return marshall_channel_space_error(channel_space_error),
+                }
+
+                set_success(0)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::hypervisor::tab_context::RecordingTabContext;
+
+    use super::*;
+
+    fn syscall(syscall_num: u64, first_arg: u64) -> SyscallEnter {
+        SyscallEnter::new(syscall_num, first_arg, 0, 0, 0)
+    }
+
+    #[test]
+    fn ecall_traces_syscalls() {
+        let mut nushift_subsystem = NushiftSubsystem::new(Arc::new(RecordingTabContext::default()), Arc::new(TabControl::new()), PagingScheme::Sv39);
+        let events = Arc::new(Mutex::new(vec![]));
+        let events_cloned = Arc::clone(&events);
+        nushift_subsystem.set_syscall_tracer(Some(SyscallTracer::callback(move |syscall_trace_event| events_cloned.lock().unwrap().push(syscall_trace_event.clone()))));
+
+        nushift_subsystem.ecall(MAIN_THREAD_ID, syscall(Syscall::TitleNew as u64, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, syscall(Syscall::TitleDestroy as u64, 1234));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, syscall(999, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, syscall(Syscall::Exit as u64, 3));
+
+        let events = events.lock().unwrap();
+        assert_eq!(SyscallTraceEvent::Syscall { number: 10, syscall: Some("TitleNew".into()), args: [0; 4], result: SyscallTraceResult::Success(0), allocated_task_id: None }, events[0]);
+        assert_eq!(SyscallTraceEvent::Syscall { number: 12, syscall: Some("TitleDestroy".into()), args: [1234, 0, 0, 0], result: SyscallTraceResult::Error("CapNotFound".into()), allocated_task_id: None }, events[1]);
+        assert_eq!(SyscallTraceEvent::Syscall { number: 999, syscall: None, args: [0; 4], result: SyscallTraceResult::Error("UnknownSyscall".into()), allocated_task_id: None }, events[2]);
+        assert_eq!(SyscallTraceEvent::Syscall { number: 0, syscall: Some("Exit".into()), args: [3, 0, 0, 0], result: SyscallTraceResult::Exit(3), allocated_task_id: None }, events[3]);
+    }
+
+    #[test]
+    fn shm_resize_over_max_memory_bytes_gives_capacity_not_available() {
+        let mut nushift_subsystem = NushiftSubsystem::new(Arc::new(RecordingTabContext::default()), Arc::new(TabControl::new()), PagingScheme::Sv39);
+        nushift_subsystem.shm_space().set_max_memory_bytes(Some(2 * 4096));
+        let events = Arc::new(Mutex::new(vec![]));
+        let events_cloned = Arc::clone(&events);
+        nushift_subsystem.set_syscall_tracer(Some(SyscallTracer::callback(move |syscall_trace_event| events_cloned.lock().unwrap().push(syscall_trace_event.clone()))));
+
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmNew as u64, ShmType::FourKiB as u64, 1, 0, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmResize as u64, 0, 3, 0, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmResize as u64, 0, 2, 0, 0));
+
+        let events = events.lock().unwrap();
+        assert!(matches!(&events[0], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Success(0), .. }));
+        assert!(matches!(&events[1], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Error(error), .. } if error == "ShmCapacityNotAvailable"));
+        assert!(matches!(&events[2], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Success(0), .. }));
+        assert_eq!(2 * 4096, nushift_subsystem.shm_space().memory_usage().app_cap_bytes);
+    }
+
+    #[test]
+    fn shm_new_over_max_memory_bytes_gives_capacity_not_available() {
+        let mut nushift_subsystem = NushiftSubsystem::new(Arc::new(RecordingTabContext::default()), Arc::new(TabControl::new()), PagingScheme::Sv39);
+        nushift_subsystem.shm_space().set_max_memory_bytes(Some(4096));
+        let events = Arc::new(Mutex::new(vec![]));
+        let events_cloned = Arc::clone(&events);
+        nushift_subsystem.set_syscall_tracer(Some(SyscallTracer::callback(move |syscall_trace_event| events_cloned.lock().unwrap().push(syscall_trace_event.clone()))));
+
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmNew as u64, ShmType::FourKiB as u64, 2, 0, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmNewAndAcquire as u64, ShmType::FourKiB as u64, 2, 0x10000, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmNew as u64, ShmType::FourKiB as u64, u64::MAX, 0, 0));
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmNew as u64, ShmType::FourKiB as u64, 1, 0, 0));
+
+        let events = events.lock().unwrap();
+        assert!(matches!(&events[0], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Error(error), .. } if error == "ShmCapacityNotAvailable"));
+        assert!(matches!(&events[1], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Error(error), .. } if error == "ShmCapacityNotAvailable"));
+        assert!(matches!(&events[2], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Error(error), .. } if error == "ShmCapacityNotAvailable"));
+        assert!(matches!(&events[3], SyscallTraceEvent::Syscall { result: SyscallTraceResult::Success(0), .. }));
+    }
+
+    #[test]
+    fn mapping_generation_follows_shm_space_without_locking_it() {
+        let nushift_subsystem = NushiftSubsystem::new(Arc::new(RecordingTabContext::default()), Arc::new(TabControl::new()), PagingScheme::Sv39);
+        let mapping_generation = nushift_subsystem.mapping_generation();
+
+        nushift_subsystem.ecall(MAIN_THREAD_ID, SyscallEnter::new(Syscall::ShmNewAndAcquire as u64, ShmType::FourKiB as u64, 1, 0x10000, 0));
+        let shm_space = nushift_subsystem.shm_space();
+
+        assert_ne!(mapping_generation, nushift_subsystem.mapping_generation());
+        assert_eq!(shm_space.mapping_generation(), nushift_subsystem.mapping_generation());
+    }
+
+    #[test]
+    fn thread_exit_from_main_thread_exits_app() {
+        let nushift_subsystem = NushiftSubsystem::new(Arc::new(RecordingTabContext::default()), Arc::new(TabControl::new()), PagingScheme::Sv39);
+
+        assert!(matches!(nushift_subsystem.ecall(MAIN_THREAD_ID, syscall(Syscall::ThreadExit as u64, 3)), SyscallReturn::UserExit { exit_reason: 3 }));
+        assert!(matches!(nushift_subsystem.ecall(1, syscall(Syscall::ThreadExit as u64, 3)), SyscallReturn::ThreadExit { exit_value: 3 }));
+    }
+}
@@ -5,9 +5,12 @@ use core::fmt::{Display, LowerHex};
 use core::marker::PhantomData;
 use std::error::Error;
 use std::sync::mpsc::{Sender, Receiver};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use ckb_vm::{
+    registers::{A0, A1, SP},
     DefaultCoreMachine,
     SupportMachine,
     CoreMachine,
@@ -15,40 +18,74 @@ use ckb_vm::{
     Register,
     Error as CKBVMError,
     Bytes,
-    decoder::build_decoder,
     instructions::execute,
     Memory,
-    registers::{A0, A1, A2, A3, A4, T0},
 };
 use elfloader::{ElfLoaderErr, ElfBinary};
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 
-use super::elf_loader::Loader;
+use super::elf_loader::{self, Loader, RelocationError};
+use super::gdb_stub::{GdbStub, GdbTarget, Resume, StopReason};
+use super::hypervisor::tab_config::TabConfig;
+use super::hypervisor::tab_control::TabControl;
+use super::gfx_space::GfxOutput;
+use super::isa::{self, IsaError, IsaExtensions, atomics::{AmoInstruction, AmoWidth, AtomicMemory, LoadReservation}, decoder::InstructionDecoder};
+use super::launch::{self, LaunchError, DEFAULT_STACK_SIZE};
 use super::nushift_subsystem::NushiftSubsystem;
 use super::protected_memory::{ProtectedMemory, ProtectedMemoryError};
-use super::register_ipc::{SyscallEnter, SyscallReturn, RETURN_VAL_REGISTER_INDEX, ERROR_RETURN_VAL_REGISTER_INDEX};
-use super::shm_space::{ShmSpace, acquisitions_and_page_table::PageTableError};
-
-const SYSCALL_NUM_REGISTER: usize = A0;
-const FIRST_ARG_REGISTER: usize = A1;
-const SECOND_ARG_REGISTER: usize = A2;
-const THIRD_ARG_REGISTER: usize = A3;
-const FOURTH_ARG_REGISTER: usize = A4;
-
-const RETURN_VAL_REGISTER: usize = A0;
-/// a1 is used by the RISC-V calling conventions for a second return value,
-/// rather than t0, but my concern is with the whole 32-bit app thing using
-/// multiple registers to encode a 64-bit value. Maybe the 32-bit ABI will just
-/// use a0 and a2 and the 64-bit will use a0 and a1. For now, using t0.
-const ERROR_RETURN_VAL_REGISTER: usize = T0;
-
+use super::register_ipc::{AbiLayout, SyscallEnter, SyscallReturn, RETURN_VAL_REGISTER_INDEX, ERROR_RETURN_VAL_REGISTER_INDEX};
+use super::shm_space::{ShmSpace, ShmSpaceError, acquisitions_and_page_table::PageTableError, translation_cache::TranslationCache};
+use super::thread_space::{ThreadId, ThreadStart};
+
+/// How many instructions to run between checks of whether the tab has been
+/// cancelled or paused, updates of the tab's cycle count, and throttling.
+/// Checking is cheap, but not free, and a tab doesn't need to stop on the exact
+/// instruction.
+///
+/// This is also how often a hart checks whether the app's harts between them
+/// have used up the instruction budget.
+const CHECK_INTERVAL: u64 = 4096;
+
+/// The longest a throttled machine sleeps before checking again whether the
+/// tab has been cancelled.
+const MAX_THROTTLE_SLEEP: Duration = Duration::from_millis(10);
+
+/// One of the app's harts. Each hart has its own registers, and runs on its
+/// own thread, but they all share the app's `NushiftSubsystem`.
 pub struct ProcessControlBlock<R> {
+    /// Which of the app's threads this hart runs.
+    thread_id: ThreadId,
     machine: Machine<R>,
     exit_reason: ExitReason,
-    syscall_enter: Sender<SyscallEnter<R>>,
-    syscall_return: Receiver<SyscallReturn<R>>,
-    locked_subsystem: Arc<Mutex<NushiftSubsystem>>,
+    syscall_enter: Sender<SyscallEnter>,
+    syscall_return: Receiver<SyscallReturn>,
+    nushift_subsystem: Arc<NushiftSubsystem>,
+    tab_control: Arc<TabControl>,
+    tab_config: TabConfig,
+    /// Only present if the tab was configured with a GDB server address.
+    gdb_stub: Option<GdbStub>,
+    /// What this hart's last LR instruction reserved, until an SC uses it up.
+    load_reservation: Option<LoadReservation>,
+    translation_cache: TranslationCache,
+    /// Where a position-independent app was loaded, or 0.
+    load_base: u64,
+    /// How many of this hart's cycles have been added to the tab's count.
+    reported_cycles: u64,
+}
+
+/// The CPU state of a machine, as part of a snapshot.
+///
+/// Registers are stored as `u64`s whatever the machine's register width, and
+/// `xlen` says what that width was.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub(crate) struct CpuSnapshot {
+    pub(crate) xlen: u8,
+    registers: Vec<u64>,
+    pc: u64,
+    cycles: u64,
+    load_base: u64,
 }
 
 enum Machine<R> {
@@ -63,43 +100,134 @@ pub enum ExitReason {
         #[allow(dead_code)] // Allow field that isn't used in code, but is printed to the console
         exit_reason: u64,
     },
+    /// The tab was closed while the app was still running.
+    Cancelled,
+    /// The app used up the `max_instructions` in its `TabConfig`.
+    InstructionBudgetExceeded,
+    /// A debugger attached to the tab killed the app.
+    KilledByDebugger,
 }
 
 impl<R> ProcessControlBlock<R>
 where
     R: Register + LowerHex,
 {
-    pub fn new(syscall_enter: Sender<SyscallEnter<R>>, syscall_return: Receiver<SyscallReturn<R>>, locked_subsystem: Arc<Mutex<NushiftSubsystem>>) -> Self {
+    pub fn new(thread_id: ThreadId, syscall_enter: Sender<SyscallEnter>, syscall_return: Receiver<SyscallReturn>, nushift_subsystem: Arc<NushiftSubsystem>, tab_control: Arc<TabControl>, tab_config: TabConfig) -> Self {
         Self {
+            thread_id,
             machine: Machine::Unloaded,
             exit_reason: ExitReason::NotExited,
             syscall_enter,
             syscall_return,
-            locked_subsystem,
+            nushift_subsystem,
+            tab_control,
+            tab_config,
+            gdb_stub: None,
+            load_reservation: None,
+            translation_cache: TranslationCache::new(),
+            load_base: 0,
+            reported_cycles: 0,
         }
     }
 
-    pub fn load_machine(&mut self, image: Vec<u8>) -> Result<(), ProcessControlBlockError> {
-        let mut core_machine = DefaultCoreMachine::<R, StubMemory<R>>::new(
-            ckb_vm::ISA_IMC,
+    /// Let a debugger attach to the app. The app waits for one to connect
+    /// before running its first instruction.
+    pub(crate) fn attach_gdb_stub(&mut self, gdb_stub: GdbStub) {
+        self.gdb_stub = Some(gdb_stub);
+    }
+
+    fn new_core_machine(&self) -> DefaultCoreMachine<R, StubMemory<R>> {
+        DefaultCoreMachine::<R, StubMemory<R>>::new(
+            IsaExtensions::SUPPORTED.ckb_vm_isa(),
             ckb_vm::machine::VERSION1,
-            u64::MAX,
-        );
+            // One cycle per instruction, so max cycles is the instruction
+            // budget.
+            self.tab_config.max_instructions.unwrap_or(u64::MAX),
+        )
+    }
+
+    /// Loads the ELF in `image`, and sets up its stack and launch information
+    /// (see the `launch` module).
+    pub fn load_machine(&mut self, image: Vec<u8>, initial_gfx_output: &GfxOutput) -> Result<(), ProcessControlBlockError> {
+        let mut core_machine = self.new_core_machine();
 
         {
-            let mut subsystem = self.locked_subsystem.lock().unwrap();
-            let mut loader = Loader::new(subsystem.shm_space_mut());
+            let mut shm_space = self.nushift_subsystem.shm_space();
             let elf_binary = ElfBinary::new(&image).context(ElfLoadingSnafu)?;
-            elf_binary.load(&mut loader).context(ElfLoadingSnafu)?;
+            isa::check_elf(&elf_binary.file, R::BITS).context(IsaSnafu)?;
+            let load_base = elf_loader::choose_load_base(&elf_binary, shm_space.paging_scheme());
+            let mut loader = Loader::new(&mut shm_space, load_base);
+            if let Err(elf_loader_err) = elf_loader::load_elf(&elf_binary, &mut loader) {
+                return match loader.take_shm_space_error() {
+                    Some(shm_space_error) => Err(shm_space_error).context(ElfShmSpaceSnafu),
+                    None => Err(elf_loader_err).context(ElfLoadingSnafu),
+                };
+            }
+            loader.relocate_elf(&elf_binary.file).context(ElfRelocationSnafu)?;
 
-            core_machine.update_pc(R::from_u64(elf_binary.entry_point()));
+            // allocate has checked that the LOAD headers fit at this base, and
+            // the entry point is expected to be in one of them, but if it
+            // isn't, the app just faults on its first instruction.
+            core_machine.update_pc(R::from_u64(elf_binary.entry_point().wrapping_add(load_base)));
+            self.load_base = load_base;
             core_machine.commit_pc();
+
+            let stack_size = elf_loader::gnu_stack_size(&elf_binary)
+                .or(self.tab_config.stack_size)
+                .unwrap_or(DEFAULT_STACK_SIZE);
+            let app_entry = launch::set_up_stack(&mut shm_space, stack_size, &self.tab_config.launch_info, initial_gfx_output).context(StackSetUpSnafu)?;
+            core_machine.set_register(SP, R::from_u64(app_entry.stack_pointer));
+            core_machine.set_register(A0, R::from_u64(app_entry.launch_info_address));
+            core_machine.set_register(A1, R::from_u64(app_entry.launch_info_length));
         }
 
-        // TODO: Should we use the STACK loadable header in the ELF in contrast
-        // to initialising the stack in the code?
+        self.machine = Machine::Loaded(core_machine);
+        Ok(())
+    }
+
+    /// Like `load_machine`, but for a thread spawned by the app with
+    /// `ThreadSpawn`, in the already loaded app. The app sets up the thread's
+    /// stack itself.
+    pub(crate) fn load_thread(&mut self, thread_start: ThreadStart, load_base: u64) {
+        let mut core_machine = self.new_core_machine();
+
+        core_machine.update_pc(R::from_u64(thread_start.pc));
+        core_machine.commit_pc();
+        core_machine.set_register(SP, R::from_u64(thread_start.stack_pointer));
+        core_machine.set_register(A0, R::from_u64(thread_start.argument));
+        self.load_base = load_base;
 
         self.machine = Machine::Loaded(core_machine);
+    }
+
+    /// Where a position-independent app was loaded, or 0.
+    pub(crate) fn load_base(&self) -> u64 {
+        self.load_base
+    }
+
+    /// Like `load_machine`, but picks up from a snapshot instead of an ELF.
+    /// The subsystem must already have been restored from the same snapshot.
+    ///
+    /// The cycles carry on from the snapshot, so a `max_instructions` budget
+    /// covers the app's whole life, not just the part since it was restored.
+    pub(crate) fn restore_machine(&mut self, cpu_snapshot: CpuSnapshot) -> Result<(), ProcessControlBlockError> {
+        let mut core_machine = self.new_core_machine();
+
+        let CpuSnapshot { xlen, registers, pc, cycles, load_base } = cpu_snapshot;
+        ensure!(xlen == R::BITS, RestoreXlenMismatchSnafu { xlen });
+        ensure!(registers.len() == core_machine.registers().len(), RestoreRegisterCountMismatchSnafu { count: registers.len() });
+        // x0 is hardwired to zero, so don't trust the snapshot's value.
+        for (index, value) in registers.into_iter().enumerate().skip(1) {
+            core_machine.set_register(index, R::from_u64(value));
+        }
+        core_machine.update_pc(R::from_u64(pc));
+        core_machine.commit_pc();
+        core_machine.set_cycles(cycles);
+        self.load_base = load_base;
+
+        self.tab_control.set_cycles(cycles);
+        self.reported_cycles = cycles;
+        self.machine = Machine::Loaded(core_machine);
         Ok(())
     }
 
@@ -108,23 +236,164 @@ where
             return RunMachineNotLoadedSnafu.fail();
         }
 
-        // TODO: The decoder is based on PC being in the first 4 MiB, which is an issue.
-        let mut decoder = build_decoder::<R>(self.isa(), self.version());
+        let mut decoder = InstructionDecoder::for_isa::<R>(self.isa(), self.version());
 
         self.set_running()?;
+        if !self.wait_for_debugger() {
+            return Ok(ExitReason::Cancelled);
+        }
+        // Throttling is on the app's harts between them, so it goes by the
+        // tab's count.
+        let start_cycles = self.tab_control.cycles();
+        let mut throttle = self.tab_config.max_instructions_per_second.map(|max_instructions_per_second| Throttle::new(max_instructions_per_second, start_cycles));
+        let mut instructions_until_check = CHECK_INTERVAL;
+        // Held between the checks, see `HartWithShmSpace`.
+        let nushift_subsystem = Arc::clone(&self.nushift_subsystem);
+        let mut held_shm_space = None;
         while self.is_running()? {
+            // An app in a CPU-bound loop never yields to us otherwise, so this
+            // is where closing or pausing a tab actually stops the app.
+            instructions_until_check -= 1;
+            if instructions_until_check == 0 {
+                instructions_until_check = CHECK_INTERVAL;
+                // Pausing, snapshotting and the debugger all need the SHM
+                // space, and this is also where a batch of instructions ends.
+                held_shm_space = None;
+                self.report_cycles()?;
+                let cycles = self.tab_control.cycles();
+                if self.tab_control.park_machine_while_paused(self.thread_id, || self.cpu_snapshot()) {
+                    // Don't let the app make up for the time it was paused.
+                    throttle = throttle.map(|throttle| Throttle::new(throttle.max_instructions_per_second, self.tab_control.cycles()));
+                }
+                if let Some(ref throttle) = throttle {
+                    throttle.sleep(cycles, &self.tab_control);
+                }
+                if let Some(ref mut gdb_stub) = self.gdb_stub {
+                    gdb_stub.poll();
+                }
+                if self.tab_control.is_cancelled() {
+                    self.exit_reason = ExitReason::Cancelled;
+                    break;
+                }
+                // Each hart's own count stops it exactly at the budget, but
+                // the other harts' instructions count too.
+                if self.tab_config.max_instructions.is_some_and(|max_instructions| self.tab_control.cycles() >= max_instructions) {
+                    self.exit_reason = ExitReason::InstructionBudgetExceeded;
+                    break;
+                }
+            }
+
+            // The debugger reads and writes memory itself.
+            if self.gdb_stub.is_some() {
+                held_shm_space = None;
+            }
+            if let Some(exit_reason) = self.debug_before_instruction() {
+                self.exit_reason = exit_reason;
+                break;
+            }
+
+            if !self.count_instruction()? {
+                self.exit_reason = ExitReason::InstructionBudgetExceeded;
+                break;
+            }
+
             // We don't have `if self.reset_signal()` here because we're not supporting reset right now
-            let instruction = {
-                let pc = self.pc().to_u64();
-                let memory = self.memory_mut();
-                decoder.decode(memory, pc).context(DecodeSnafu)?
-            };
-            execute(instruction, self).context(ExecuteSnafu)?;
+            if let Err(step_error) = self.step(&mut decoder, &nushift_subsystem, &mut held_shm_space) {
+                drop(held_shm_space);
+                self.report_cycles()?;
+                self.debug_fault(&step_error);
+                self.report_exit_to_debugger();
+                return Err(step_error);
+            }
         }
 
+        drop(held_shm_space);
+        self.report_cycles()?;
+        self.report_exit_to_debugger();
         Ok(self.exit_reason)
     }
 
+    /// `held_shm_space` is the SHM space of `nushift_subsystem`, if this hart
+    /// is holding it from an earlier instruction in the batch.
+    fn step<'space>(&mut self, decoder: &mut InstructionDecoder, nushift_subsystem: &'space NushiftSubsystem, held_shm_space: &mut Option<MutexGuard<'space, ShmSpace>>) -> Result<(), ProcessControlBlockError> {
+        // Doesn't lock the SHM space, so a cached instruction is decoded
+        // without locking it at all.
+        let mapping_generation = nushift_subsystem.mapping_generation();
+        let mut hart = HartWithShmSpace { pcb: self, nushift_subsystem, held_shm_space };
+        let decoded = {
+            let pc = hart.pc().to_u64();
+            decoder.decode(pc, mapping_generation, |addr| hart.execute_load16(addr))
+        };
+        match decoded {
+            Ok(instruction) => execute(instruction, &mut hart).context(ExecuteSnafu),
+            // ckb-vm doesn't know the A extension, so try it ourselves.
+            Err(CKBVMError::InvalidInstruction { pc, instruction }) => {
+                let amo_instruction = AmoInstruction::decode(instruction, R::BITS)
+                    .ok_or(CKBVMError::InvalidInstruction { pc, instruction })
+                    .context(DecodeSnafu)?;
+                let mut load_reservation = hart.pcb.load_reservation.take();
+                let result = amo_instruction.execute(&mut hart, &mut load_reservation);
+                hart.pcb.load_reservation = load_reservation;
+                result.context(ExecuteSnafu)
+            }
+            Err(decode_error) => Err(decode_error).context(DecodeSnafu),
+        }
+    }
+
+    /// The GDB stub is taken out of `self` while it runs, so that it can use
+    /// `self` as its target.
+    fn with_gdb_stub<T>(&mut self, f: impl FnOnce(&mut GdbStub, &mut Self) -> T) -> Option<T> {
+        let mut gdb_stub = self.gdb_stub.take()?;
+        let result = f(&mut gdb_stub, self);
+        self.gdb_stub = Some(gdb_stub);
+        Some(result)
+    }
+
+    /// Returns `false` if the tab was closed while waiting.
+    fn wait_for_debugger(&mut self) -> bool {
+        self.with_gdb_stub(|gdb_stub, pcb| gdb_stub.wait_for_debugger(pcb)).unwrap_or(true)
+    }
+
+    /// If a debugger wants the app stopped before the next instruction, hand
+    /// over to it until it lets the app carry on. Returns an exit reason if the
+    /// app shouldn't carry on at all.
+    fn debug_before_instruction(&mut self) -> Option<ExitReason> {
+        let pc = self.pc().to_u64();
+        self.with_gdb_stub(|gdb_stub, pcb| {
+            let stop_reason = gdb_stub.stop_reason_before(pc)?;
+            match gdb_stub.handle_stop(pcb, stop_reason) {
+                Resume::Continue => None,
+                Resume::Kill => Some(ExitReason::KilledByDebugger),
+                Resume::Cancelled => Some(ExitReason::Cancelled),
+            }
+        }).flatten()
+    }
+
+    /// Let a debugger look at the app after it faults. The app can't carry on
+    /// afterwards, whatever the debugger says.
+    fn debug_fault(&mut self, step_error: &ProcessControlBlockError) {
+        let stop_reason = match step_error {
+            ProcessControlBlockError::DecodeError { source: CKBVMError::MemOutOfBound }
+            | ProcessControlBlockError::ExecuteError { source: CKBVMError::MemOutOfBound } => StopReason::MemoryFault,
+            _ => StopReason::Fault,
+        };
+        self.with_gdb_stub(|gdb_stub, pcb| {
+            if gdb_stub.is_attached() {
+                gdb_stub.handle_stop(pcb, stop_reason);
+            }
+        });
+    }
+
+    fn report_exit_to_debugger(&mut self) {
+        let exit_code = match self.exit_reason {
+            ExitReason::UserExit { exit_reason } => Some(exit_reason),
+            _ => None,
+        };
+        if let Some(ref mut gdb_stub) = self.gdb_stub {
+            gdb_stub.report_exit(exit_code);
+        }
+    }
+
     pub fn user_exit(&mut self, exit_reason: u64) {
         if let Machine::Loaded(ref mut machine) = self.machine {
             self.exit_reason = ExitReason::UserExit { exit_reason };
@@ -148,6 +417,93 @@ where
             RunMachineNotLoadedSnafu.fail()
         }
     }
+
+    /// Count one more instruction against the instruction budget. Returns
+    /// `false` if the budget is used up.
+    fn count_instruction(&mut self) -> Result<bool, ProcessControlBlockError> {
+        if let Machine::Loaded(ref mut machine) = self.machine {
+            match machine.add_cycles(1) {
+                Ok(()) => Ok(true),
+                Err(CKBVMError::CyclesExceeded) => Ok(false),
+                Err(ckb_vm_error) => Err(ckb_vm_error).context(CountCyclesSnafu),
+            }
+        } else {
+            RunMachineNotLoadedSnafu.fail()
+        }
+    }
+
+    /// The PC, or `None` if the machine isn't loaded. Unlike `pc`, this is
+    /// fine to call after the machine has stopped with an error.
+    pub fn current_pc(&self) -> Option<u64> {
+        match self.machine {
+            Machine::Loaded(ref machine) => Some(machine.pc().to_u64()),
+            Machine::Unloaded => None,
+        }
+    }
+
+    fn cycles(&self) -> Result<u64, ProcessControlBlockError> {
+        if let Machine::Loaded(ref machine) = self.machine {
+            Ok(machine.cycles())
+        } else {
+            RunMachineNotLoadedSnafu.fail()
+        }
+    }
+
+    /// Add the cycles this hart has consumed since it last reported to the
+    /// tab's count, which is shared by all of the app's harts.
+    fn report_cycles(&mut self) -> Result<(), ProcessControlBlockError> {
+        let cycles = self.cycles()?;
+        self.tab_control.add_cycles(cycles - self.reported_cycles);
+        self.reported_cycles = cycles;
+        Ok(())
+    }
+
+    /// Only called from the run loop, where the machine is always loaded.
+    fn cpu_snapshot(&self) -> CpuSnapshot {
+        match self.machine {
+            Machine::Loaded(ref machine) => CpuSnapshot {
+                xlen: R::BITS,
+                registers: machine.registers().iter().map(R::to_u64).collect(),
+                pc: machine.pc().to_u64(),
+                cycles: machine.cycles(),
+                load_base: self.load_base,
+            },
+            _ => panic!("process_control_block.rs: Machine attempted to be used but not loaded"),
+        }
+    }
+}
+
+/// Slows a machine down to a maximum number of instructions per second.
+struct Throttle {
+    max_instructions_per_second: u64,
+    start_time: Instant,
+    start_cycles: u64,
+}
+
+impl Throttle {
+    fn new(max_instructions_per_second: u64, start_cycles: u64) -> Self {
+        Self { max_instructions_per_second, start_time: Instant::now(), start_cycles }
+    }
+
+    /// How long the machine has to wait, at `now`, so that it isn't ahead of
+    /// where it should be.
+    fn delay(&self, cycles: u64, now: Instant) -> Duration {
+        let instructions = cycles.saturating_sub(self.start_cycles);
+        let target_elapsed = Duration::from_secs_f64(instructions as f64 / self.max_instructions_per_second.max(1) as f64);
+        target_elapsed.saturating_sub(now.saturating_duration_since(self.start_time))
+    }
+
+    /// Sleep off any delay, in short enough steps that cancelling the tab still
+    /// stops it promptly.
+    fn sleep(&self, cycles: u64, tab_control: &TabControl) {
+        loop {
+            let delay = self.delay(cycles, Instant::now());
+            if delay.is_zero() || tab_control.is_cancelled() {
+                break;
+            }
+            thread::sleep(delay.min(MAX_THROTTLE_SLEEP));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -171,10 +527,23 @@ pub enum ProcessControlBlockError {
         #[snafu(source(from(ElfLoaderErr, ElfLoaderErrImplementingError::new)))]
         source: ElfLoaderErrImplementingError,
     },
+    #[snafu(display("The app's relocations couldn't be applied: {source}"))]
+    ElfRelocationError { source: RelocationError },
+    #[snafu(display("The app's ELF segments couldn't be given SHM caps: {source}"))]
+    ElfShmSpaceError { source: ShmSpaceError },
+    #[snafu(display("The app can't run on this machine: {source}"))]
+    IsaError { source: IsaError },
+    #[snafu(display("Setting up the app's stack failed: {source}"))]
+    StackSetUpError { source: LaunchError },
     #[snafu(display("Attempted to run a machine that is not loaded"))]
     RunMachineNotLoaded,
     DecodeError { source: CKBVMError },
     ExecuteError { source: CKBVMError },
+    CountCyclesError { source: CKBVMError },
+    #[snafu(display("The snapshot has {count} registers, which doesn't match the machine"))]
+    RestoreRegisterCountMismatch { count: usize },
+    #[snafu(display("The snapshot is of a {xlen}-bit machine, which doesn't match the machine"))]
+    RestoreXlenMismatch { xlen: u8 },
 }
 
 macro_rules! proxy_to_self_machine {
@@ -200,7 +569,9 @@ where
     R: Register + LowerHex,
 {
     type REG = R;
-    type MEM = Self;
+    /// Instructions access memory through `HartWithShmSpace`, so this is only
+    /// the stub that the core machine was made with.
+    type MEM = StubMemory<R>;
 
     fn pc(&self) -> &Self::REG {
         proxy_to_self_machine!(self, pc)
@@ -215,11 +586,11 @@ where
     }
 
     fn memory(&self) -> &Self::MEM {
-        self
+        proxy_to_self_machine!(self, memory)
     }
 
     fn memory_mut(&mut self) -> &mut Self::MEM {
-        self
+        proxy_to_self_machine!(mut self, memory_mut)
     }
 
     fn registers(&self) -> &[Self::REG] {
@@ -244,20 +615,26 @@ where
     R: Register + LowerHex,
 {
     fn ecall(&mut self) -> Result<(), CKBVMError> {
+        // RV32 apps pass 64-bit values in register pairs.
+        let abi_layout = AbiLayout::for_xlen(R::BITS);
+        let [first_arg, second_arg, third_arg, fourth_arg] = abi_layout.args.map(|abi_registers| abi_registers.read(self.registers()));
         let send = SyscallEnter::new(
-            self.registers()[SYSCALL_NUM_REGISTER].clone(),
-            self.registers()[FIRST_ARG_REGISTER].clone(),
-            self.registers()[SECOND_ARG_REGISTER].clone(),
-            self.registers()[THIRD_ARG_REGISTER].clone(),
-            self.registers()[FOURTH_ARG_REGISTER].clone(),
+            abi_layout.syscall_num.read(self.registers()),
+            first_arg,
+            second_arg,
+            third_arg,
+            fourth_arg,
         );
         self.syscall_enter.send(send).expect("Send should succeed");
         let recv = self.syscall_return.recv().expect("Receive should succeed");
         match recv {
-            SyscallReturn::UserExit { exit_reason } => self.user_exit(exit_reason),
+            // This hart stops either way. For ThreadExit, its hypervisor thread
+            // knows not to stop the rest of the app too.
+            SyscallReturn::UserExit { exit_reason }
+            | SyscallReturn::ThreadExit { exit_value: exit_reason } => self.user_exit(exit_reason),
             SyscallReturn::Return(recv) => {
-                self.set_register(RETURN_VAL_REGISTER, recv[RETURN_VAL_REGISTER_INDEX].clone());
-                self.set_register(ERROR_RETURN_VAL_REGISTER, recv[ERROR_RETURN_VAL_REGISTER_INDEX].clone());
+                abi_layout.return_val.write(recv[RETURN_VAL_REGISTER_INDEX], |idx, value| self.set_register(idx, value));
+                abi_layout.error_return_val.write(recv[ERROR_RETURN_VAL_REGISTER_INDEX], |idx, value| self.set_register(idx, value));
             }
         }
         // ecall should always return Ok (i.e. not terminate the app). If this
@@ -266,13 +643,158 @@ where
     }
 
     fn ebreak(&mut self) -> Result<(), CKBVMError> {
-        // Terminate app.
-        // TODO: As an improvement to terminating the app, provide debugging functionality.
+        // Stop in the debugger, if there is one.
+        if let Some(ref mut gdb_stub) = self.gdb_stub {
+            if gdb_stub.is_attached() {
+                gdb_stub.stop_after_instruction(StopReason::Ebreak);
+                return Ok(());
+            }
+        }
+
+        // Otherwise, terminate app.
         Err(CKBVMError::External(format!("ebreak encountered; terminating app. PC: {:#x}", self.pc())))
     }
 }
 
-impl<R> Memory for ProcessControlBlock<R>
+impl<R> GdbTarget for ProcessControlBlock<R>
+where
+    R: Register + LowerHex,
+{
+    fn register_bytes(&self) -> usize {
+        usize::from(R::BITS / 8)
+    }
+
+    fn read_registers(&self) -> Vec<u64> {
+        let mut registers: Vec<u64> = self.registers().iter().map(R::to_u64).collect();
+        registers.push(self.pc().to_u64());
+        registers
+    }
+
+    fn write_register(&mut self, number: usize, value: u64) -> bool {
+        let register_count = self.registers().len();
+        match number {
+            // x0 is hardwired to zero.
+            0 => true,
+            _ if number < register_count => {
+                self.set_register(number, R::from_u64(value));
+                true
+            }
+            _ if number == register_count => {
+                self.update_pc(R::from_u64(value));
+                self.commit_pc();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn load_base(&self) -> u64 {
+        self.load_base
+    }
+
+    fn read_memory(&mut self, addr: u64) -> Option<u8> {
+        let shm_space = self.nushift_subsystem.shm_space();
+        ProtectedMemory::load8(&shm_space, &mut self.translation_cache, addr).ok()
+    }
+
+    fn write_memory(&mut self, addr: u64, value: u8) -> bool {
+        let mut shm_space = self.nushift_subsystem.shm_space();
+        ProtectedMemory::store8(&mut shm_space, &mut self.translation_cache, addr, value).is_ok()
+    }
+
+    fn keep_waiting(&mut self) -> bool {
+        self.tab_control.park_machine_while_paused(self.thread_id, || self.cpu_snapshot());
+        !self.tab_control.is_cancelled()
+    }
+}
+
+/// A hart in the middle of a batch of instructions, which is what ckb-vm runs
+/// them on.
+///
+/// Rather than locking the SHM space for every memory access, a hart locks it
+/// at its first access, and holds it for the rest of the batch. It lets go at
+/// the end of the batch, for syscalls (which lock it themselves), and as soon
+/// as anything else is waiting for it, so that other harts, syscalls and
+/// deferred tasks don't wait any longer than they would for a single access.
+struct HartWithShmSpace<'hart, 'space, R> {
+    pcb: &'hart mut ProcessControlBlock<R>,
+    nushift_subsystem: &'space NushiftSubsystem,
+    held_shm_space: &'hart mut Option<MutexGuard<'space, ShmSpace>>,
+}
+
+impl<R> HartWithShmSpace<'_, '_, R> {
+    /// Locks the SHM space, unless this hart is already holding it and nothing
+    /// else wants it.
+    fn shm_space_and_translation_cache(&mut self) -> (&mut ShmSpace, &mut TranslationCache) {
+        if self.held_shm_space.is_some() && self.nushift_subsystem.shm_space.is_contended() {
+            *self.held_shm_space = None;
+        }
+        let nushift_subsystem = self.nushift_subsystem;
+        let shm_space: &mut ShmSpace = self.held_shm_space.get_or_insert_with(|| nushift_subsystem.shm_space());
+        (shm_space, &mut self.pcb.translation_cache)
+    }
+}
+
+impl<R> CoreMachine for HartWithShmSpace<'_, '_, R>
+where
+    R: Register + LowerHex,
+{
+    type REG = R;
+    type MEM = Self;
+
+    fn pc(&self) -> &Self::REG {
+        self.pcb.pc()
+    }
+
+    fn update_pc(&mut self, pc: Self::REG) {
+        self.pcb.update_pc(pc)
+    }
+
+    fn commit_pc(&mut self) {
+        self.pcb.commit_pc()
+    }
+
+    fn memory(&self) -> &Self::MEM {
+        self
+    }
+
+    fn memory_mut(&mut self) -> &mut Self::MEM {
+        self
+    }
+
+    fn registers(&self) -> &[Self::REG] {
+        self.pcb.registers()
+    }
+
+    fn set_register(&mut self, idx: usize, value: Self::REG) {
+        self.pcb.set_register(idx, value)
+    }
+
+    fn version(&self) -> u32 {
+        self.pcb.version()
+    }
+
+    fn isa(&self) -> u8 {
+        self.pcb.isa()
+    }
+}
+
+impl<R> CKBVMMachine for HartWithShmSpace<'_, '_, R>
+where
+    R: Register + LowerHex,
+{
+    fn ecall(&mut self) -> Result<(), CKBVMError> {
+        // The syscall locks the SHM space itself.
+        *self.held_shm_space = None;
+        self.pcb.ecall()
+    }
+
+    fn ebreak(&mut self) -> Result<(), CKBVMError> {
+        self.pcb.ebreak()
+    }
+}
+
+impl<R> Memory for HartWithShmSpace<'_, '_, R>
 where
     R: Register + LowerHex,
 {
@@ -350,47 +872,83 @@ where
     }
 }
 
-fn load_impl<T, L, F, U, R>(pcb: &ProcessControlBlock<R>, addr: &R, protected_memory_load: L, from_val: F) -> Result<U, CKBVMError>
+impl<R> AtomicMemory for HartWithShmSpace<'_, '_, R>
+where
+    R: Register + LowerHex,
+{
+    fn read_modify_write(&mut self, address: u64, width: AmoWidth, modify: impl FnOnce(u64) -> Option<u64>) -> Result<u64, CKBVMError> {
+        // The SHM space stays locked from the load to the store, which is what
+        // makes this atomic, since every other hart's memory accesses lock it
+        // too.
+        let (shm_space, translation_cache) = self.shm_space_and_translation_cache();
+        let result = match width {
+            AmoWidth::Word => ProtectedMemory::load32(shm_space, translation_cache, address).map(u64::from).and_then(|loaded| {
+                match modify(loaded) {
+                    Some(value) => ProtectedMemory::store32(shm_space, translation_cache, address, value as u32).map(|()| loaded),
+                    None => Ok(loaded),
+                }
+            }),
+            AmoWidth::Doubleword => ProtectedMemory::load64(shm_space, translation_cache, address).and_then(|loaded| {
+                match modify(loaded) {
+                    Some(value) => ProtectedMemory::store64(shm_space, translation_cache, address, value).map(|()| loaded),
+                    None => Ok(loaded),
+                }
+            }),
+        };
+
+        result.map_err(|err| {
+            match err {
+                ProtectedMemoryError::WalkError {
+                    source: PageTableError::PermissionDenied { shm_cap_id, required_permissions, present_permissions }
+                } => tracing::error!("Permission denied atomic access: addr {address:#x}, PC {:#x}, owning cap ID {shm_cap_id}, required permissions: {required_permissions:?}, present permissions: {present_permissions:?}", self.pc()),
+                _ => tracing::error!("Out of bounds atomic access: addr {address:#x}, PC {:#x}", self.pc()),
+            }
+            CKBVMError::MemOutOfBound
+        })
+    }
+}
+
+fn load_impl<T, L, F, U, R>(hart: &mut HartWithShmSpace<'_, '_, R>, addr: &R, protected_memory_load: L, from_val: F) -> Result<U, CKBVMError>
 where
-    L: FnOnce(&ShmSpace, u64) -> Result<T, ProtectedMemoryError>,
+    L: FnOnce(&ShmSpace, &mut TranslationCache, u64) -> Result<T, ProtectedMemoryError>,
     F: FnOnce(T) -> U,
     R: Register + LowerHex,
 {
-    let subsystem = pcb.locked_subsystem.lock().unwrap();
-    protected_memory_load(subsystem.shm_space(), R::to_u64(addr))
+    let (shm_space, translation_cache) = hart.shm_space_and_translation_cache();
+    protected_memory_load(shm_space, translation_cache, R::to_u64(addr))
         .map(from_val)
         .map_err(|err| {
             match err {
                 ProtectedMemoryError::WalkError {
                     source: PageTableError::PermissionDenied { shm_cap_id, required_permissions, present_permissions }
-                } => tracing::error!("Permission denied load: addr {addr:#x}, PC {:#x}, owning cap ID {shm_cap_id}, required permissions: {required_permissions:?}, present permissions: {present_permissions:?}", pcb.pc()),
-                _ => tracing::error!("Out of bounds load: addr {addr:#x}, PC {:#x}", pcb.pc()),
+                } => tracing::error!("Permission denied load: addr {addr:#x}, PC {:#x}, owning cap ID {shm_cap_id}, required permissions: {required_permissions:?}, present permissions: {present_permissions:?}", hart.pc()),
+                _ => tracing::error!("Out of bounds load: addr {addr:#x}, PC {:#x}", hart.pc()),
             }
             CKBVMError::MemOutOfBound
         })
 }
 
-fn store_impl<T, S, F, U, R>(pcb: &ProcessControlBlock<R>, addr: &R, value: &U, protected_memory_store: S, to_val: F) -> Result<(), CKBVMError>
+fn store_impl<T, S, F, U, R>(hart: &mut HartWithShmSpace<'_, '_, R>, addr: &R, value: &U, protected_memory_store: S, to_val: F) -> Result<(), CKBVMError>
 where
-    S: FnOnce(&mut ShmSpace, u64, T) -> Result<(), ProtectedMemoryError>,
+    S: FnOnce(&mut ShmSpace, &mut TranslationCache, u64, T) -> Result<(), ProtectedMemoryError>,
     F: FnOnce(&U) -> T,
     R: Register + LowerHex,
 {
-    let mut subsystem = pcb.locked_subsystem.lock().unwrap();
-    protected_memory_store(subsystem.shm_space_mut(), R::to_u64(addr), to_val(value))
+    let (shm_space, translation_cache) = hart.shm_space_and_translation_cache();
+    protected_memory_store(shm_space, translation_cache, R::to_u64(addr), to_val(value))
         .map_err(|err| {
             match err {
                 ProtectedMemoryError::WalkError {
                     source: PageTableError::PermissionDenied { shm_cap_id, required_permissions, present_permissions }
-                } => tracing::error!("Permission denied store: addr {addr:#x}, PC {:#x}, owning cap ID {shm_cap_id}, required permissions: {required_permissions:?}, present permissions: {present_permissions:?}", pcb.pc()),
-                _ => tracing::error!("Out of bounds store: addr {addr:#x}, PC {:#x}", pcb.pc()),
+                } => tracing::error!("Permission denied store: addr {addr:#x}, PC {:#x}, owning cap ID {shm_cap_id}, required permissions: {required_permissions:?}, present permissions: {present_permissions:?}", hart.pc()),
+                _ => tracing::error!("Out of bounds store: addr {addr:#x}, PC {:#x}", hart.pc()),
             }
             CKBVMError::MemOutOfBound
         })
 }
 
 #[derive(Default)]
-struct StubMemory<R>(PhantomData<R>);
+pub struct StubMemory<R>(PhantomData<R>);
 
 impl<R: Register> Memory for StubMemory<R> {
     type REG = R;
@@ -466,3 +1024,22 @@ impl<R: Register> Memory for StubMemory<R> {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_delay_is_zero_when_behind() {
+        let throttle = Throttle::new(1000, 0);
+
+        assert_eq!(Duration::ZERO, throttle.delay(1000, throttle.start_time + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn throttle_delay_waits_out_the_difference_when_ahead() {
+        let throttle = Throttle::new(1000, 500);
+
+        assert_eq!(Duration::from_millis(1500), throttle.delay(2500, throttle.start_time + Duration::from_millis(500)));
+    }
+}
@@ -3,35 +3,42 @@
 
 use core::ops::Index;
 
+use ckb_vm::Register;
+use ckb_vm::registers::{A0, A1, A2, A3, A4, A5, A6, A7, T0, T1};
+
 // Instead of using the structs in this file for mpsc IPC, I would like to go
 // back to using a condition variable. However with the current structure of the
 // ckb-vm library, it might not be possible to do that.
 
-pub struct SyscallEnter<R>([R; 5]);
-impl<R> SyscallEnter<R> {
-    pub fn new(syscall_num: R, first_arg: R, second_arg: R, third_arg: R, fourth_arg: R) -> Self {
+/// The syscall number and arguments, already put together from however many
+/// registers the app's `AbiLayout` spreads them over.
+pub struct SyscallEnter([u64; 5]);
+impl SyscallEnter {
+    pub fn new(syscall_num: u64, first_arg: u64, second_arg: u64, third_arg: u64, fourth_arg: u64) -> Self {
         Self([syscall_num, first_arg, second_arg, third_arg, fourth_arg])
     }
 }
-impl<R> Index<SyscallEnterIndex> for SyscallEnter<R> {
-    type Output = R;
+impl Index<SyscallEnterIndex> for SyscallEnter {
+    type Output = u64;
     fn index(&self, index: SyscallEnterIndex) -> &Self::Output {
         &self.0[index.0]
     }
 }
 
-pub enum SyscallReturn<R> {
+pub enum SyscallReturn {
     UserExit { exit_reason: u64 },
-    Return(Return<R>),
+    /// Only the calling thread exits, not the whole app.
+    ThreadExit { exit_value: u64 },
+    Return(Return),
 }
-impl<R> SyscallReturn<R> {
-    pub fn new_return(return_val: R, error_return_val: R) -> Self {
+impl SyscallReturn {
+    pub fn new_return(return_val: u64, error_return_val: u64) -> Self {
         Self::Return(Return([return_val, error_return_val]))
     }
 }
-pub struct Return<R>([R; 2]);
-impl<R> Index<SyscallReturnIndex> for Return<R> {
-    type Output = R;
+pub struct Return([u64; 2]);
+impl Index<SyscallReturnIndex> for Return {
+    type Output = u64;
     fn index(&self, index: SyscallReturnIndex) -> &Self::Output {
         &self.0[index.0]
     }
@@ -47,3 +54,90 @@ pub const FOURTH_ARG_REGISTER_INDEX: SyscallEnterIndex = SyscallEnterIndex(4);
 pub struct SyscallReturnIndex(usize);
 pub const RETURN_VAL_REGISTER_INDEX: SyscallReturnIndex = SyscallReturnIndex(0);
 pub const ERROR_RETURN_VAL_REGISTER_INDEX: SyscallReturnIndex = SyscallReturnIndex(1);
+
+/// Where one of the hypervisor ABI's 64-bit values is in an app's registers.
+#[derive(Debug, Clone, Copy)]
+pub enum AbiRegisters {
+    One(usize),
+    /// Low half first.
+    Pair(usize, usize),
+}
+
+impl AbiRegisters {
+    pub fn read<R: Register>(self, registers: &[R]) -> u64 {
+        match self {
+            Self::One(index) => registers[index].to_u64(),
+            Self::Pair(low, high) => u64::from(registers[low].to_u32()) | (u64::from(registers[high].to_u32()) << 32),
+        }
+    }
+
+    pub fn write<R: Register>(self, value: u64, mut set_register: impl FnMut(usize, R)) {
+        match self {
+            Self::One(index) => set_register(index, R::from_u64(value)),
+            Self::Pair(low, high) => {
+                set_register(low, R::from_u32(value as u32));
+                set_register(high, R::from_u32((value >> 32) as u32));
+            }
+        }
+    }
+}
+
+/// Which registers the syscall number, arguments and return values are passed
+/// in, for an app of a particular register width.
+pub struct AbiLayout {
+    pub syscall_num: AbiRegisters,
+    pub args: [AbiRegisters; 4],
+    pub return_val: AbiRegisters,
+    pub error_return_val: AbiRegisters,
+}
+
+/// a1 is used by the RISC-V calling conventions for a second return value,
+/// rather than t0, but RV32 apps need a1 for the high half of the return value.
+/// So the error goes in t0 for both.
+pub const RV64_ABI_LAYOUT: AbiLayout = AbiLayout {
+    syscall_num: AbiRegisters::One(A0),
+    args: [AbiRegisters::One(A1), AbiRegisters::One(A2), AbiRegisters::One(A3), AbiRegisters::One(A4)],
+    return_val: AbiRegisters::One(A0),
+    error_return_val: AbiRegisters::One(T0),
+};
+
+/// Every value is a register pair, except the syscall number, which is always
+/// small. There aren't enough argument registers for the fourth argument's
+/// high half, so it goes in t0, which the hypervisor clobbers anyway.
+pub const RV32_ABI_LAYOUT: AbiLayout = AbiLayout {
+    syscall_num: AbiRegisters::One(A0),
+    args: [AbiRegisters::Pair(A1, A2), AbiRegisters::Pair(A3, A4), AbiRegisters::Pair(A5, A6), AbiRegisters::Pair(A7, T0)],
+    return_val: AbiRegisters::Pair(A0, A1),
+    error_return_val: AbiRegisters::Pair(T0, T1),
+};
+
+impl AbiLayout {
+    pub fn for_xlen(xlen: u8) -> &'static Self {
+        if xlen == 32 { &RV32_ABI_LAYOUT } else { &RV64_ABI_LAYOUT }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abi_registers_pair_round_trips() {
+        let mut registers = [0u32; 32];
+
+        AbiRegisters::Pair(A7, T0).write(0x1234_5678_9abc_def0, |index, value| registers[index] = value);
+
+        assert_eq!(0x9abc_def0, registers[A7]);
+        assert_eq!(0x1234_5678, registers[T0]);
+        assert_eq!(0x1234_5678_9abc_def0, AbiRegisters::Pair(A7, T0).read(&registers));
+    }
+
+    #[test]
+    fn abi_registers_one_uses_whole_register() {
+        let mut registers = [0u64; 32];
+
+        AbiRegisters::One(A0).write(u64::MAX, |index, value| registers[index] = value);
+
+        assert_eq!(u64::MAX, AbiRegisters::One(A0).read(&registers));
+    }
+}
@@ -1,13 +1,13 @@
 // Copyright 2023 The Nushift Authors.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{array, collections::{BTreeMap, HashMap}, ops::Bound};
+use std::{array, collections::{BTreeMap, HashMap}, num::NonZeroU64, ops::Bound};
 
 use bitflags::bitflags;
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 
-use super::{ShmCapId, ShmCapLength, ShmCapOffset, ShmSpaceMap, ShmType, ShmCap, SV39_BITS};
+use super::{ShmCapId, ShmCapLength, ShmCapOffset, ShmSpaceMap, ShmType, ShmCap, PagingScheme, SV39_BITS};
 
 // Costs of mapping, unmapping and accessing memory in this file:
 //
@@ -17,32 +17,57 @@ use super::{ShmCapId, ShmCapLength, ShmCapOffset, ShmSpaceMap, ShmType, ShmCap,
 //
 // For accesses, we walk a page table which is technically constant time, though
 // far from free. Both the page table and the BST should be kept consistent.
+// Most accesses don't even walk the page table, because each machine caches
+// its walks in a `TranslationCache`.
 
 pub struct AcquisitionsAndPageTable {
     acquisitions: Acquisitions,
-    page_table: Box<PageTableLevel1>,
+    page_table: PageTable,
+}
+
+enum PageTable {
+    Sv32(Box<Sv32PageTable>),
+    Sv39(Box<PageTableLevel1>),
+    Sv48(Box<Sv48PageTable>),
+    Sv57(Box<Sv57PageTable>),
 }
 
 impl AcquisitionsAndPageTable {
-    pub fn new() -> Self {
-        Self { acquisitions: Acquisitions::new(), page_table: Box::new(PageTableLevel1::new()) }
+    pub fn new(paging_scheme: PagingScheme) -> Self {
+        let page_table = match paging_scheme {
+            PagingScheme::Sv32 => PageTable::Sv32(Box::new(Sv32PageTable::new())),
+            PagingScheme::Sv39 => PageTable::Sv39(Box::new(PageTableLevel1::new())),
+            PagingScheme::Sv48 => PageTable::Sv48(Box::new(Sv48PageTable::new())),
+            PagingScheme::Sv57 => PageTable::Sv57(Box::new(Sv57PageTable::new())),
+        };
+        Self { acquisitions: Acquisitions::new(), page_table }
+    }
+
+    pub fn paging_scheme(&self) -> PagingScheme {
+        match self.page_table {
+            PageTable::Sv32(_) => PagingScheme::Sv32,
+            PageTable::Sv39(_) => PagingScheme::Sv39,
+            PageTable::Sv48(_) => PagingScheme::Sv48,
+            PageTable::Sv57(_) => PagingScheme::Sv57,
+        }
     }
 
     pub fn try_acquire(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap, address: u64, flags: Sv39Flags) -> Result<(), AcquireError> {
         // Check that it isn't already acquired.
         self.check_not_acquired(shm_cap_id).map_err(|address| AcquiringAlreadyAcquiredCapSnafu { address }.build())?;
 
-        // Check that it doesn't exceed Sv39. First check 2^64, then 2^39.
+        // Check that it doesn't exceed the address space. First check 2^64,
+        // then e.g. 2^39.
         let length_in_bytes = shm_cap.shm_type().page_bytes()
             .checked_mul(shm_cap.length_u64())
-            .ok_or_else(|| AcquireExceedsSv39Snafu.build())?;
+            .ok_or_else(|| AcquireExceedsAddressSpaceSnafu.build())?;
 
         let end_address = address
             .checked_add(length_in_bytes)
-            .ok_or_else(|| AcquireExceedsSv39Snafu.build())?;
+            .ok_or_else(|| AcquireExceedsAddressSpaceSnafu.build())?;
 
-        if end_address > 1 << SV39_BITS {
-            return AcquireExceedsSv39Snafu.fail();
+        if end_address > 1 << self.paging_scheme().bits() {
+            return AcquireExceedsAddressSpaceSnafu.fail();
         }
 
         // Check that address is page aligned.
@@ -77,6 +102,61 @@ impl AcquisitionsAndPageTable {
         }
     }
 
+    /// The address a cap is acquired at, and the flags it was acquired with.
+    pub fn acquisition(&self, shm_cap_id: ShmCapId) -> Option<(ShmAcquisitionAddress, Sv39Flags)> {
+        let address = *self.acquisitions.is_acquired(shm_cap_id)?;
+        let flags = self.page_table.entry(address)?.flags;
+        Some((address, flags))
+    }
+
+    /// Changes the flags that an acquired cap is mapped with, keeping it at the
+    /// same address.
+    pub fn try_protect(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap, flags: Sv39Flags) -> Result<(), AcquireError> {
+        let address = *self.acquisitions.is_acquired(shm_cap_id).ok_or_else(|| ProtectingNonAcquiredCapSnafu.build())?;
+        // Inserting overwrites the cap's existing entries, which are all there
+        // since it's acquired, so this shouldn't fail.
+        self.page_table.insert(shm_cap_id, shm_cap, address, flags).context(PageTableInsertOrRemoveSnafu)
+    }
+
+    /// Keeps an acquired cap at the same address and with the same flags as
+    /// it changes from `shm_cap` to `new_length` pages, so that its pages past
+    /// the new length are unmapped, or its new pages are mapped after its
+    /// existing ones. Does nothing if the cap isn't acquired.
+    pub fn try_resize(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap, new_length: ShmCapLength) -> Result<(), AcquireError> {
+        let Some((address, flags)) = self.acquisition(shm_cap_id) else {
+            return Ok(());
+        };
+
+        // The same address space checks as `try_acquire`. The address is
+        // already aligned.
+        let length_in_bytes = shm_cap.shm_type().page_bytes()
+            .checked_mul(shm_cap.length_u64())
+            .ok_or_else(|| AcquireExceedsAddressSpaceSnafu.build())?;
+        let new_length_in_bytes = shm_cap.shm_type().page_bytes()
+            .checked_mul(new_length.get())
+            .ok_or_else(|| AcquireExceedsAddressSpaceSnafu.build())?;
+        let new_end_address = address
+            .checked_add(new_length_in_bytes)
+            .ok_or_else(|| AcquireExceedsAddressSpaceSnafu.build())?;
+        if new_end_address > 1 << self.paging_scheme().bits() {
+            return AcquireExceedsAddressSpaceSnafu.fail();
+        }
+
+        self.acquisitions.remove(shm_cap_id).map_err(|_| RollbackSnafu.build())?;
+        if self.acquisitions.try_insert(shm_cap_id, address, new_length_in_bytes).is_err() {
+            // Put the old length back, which was there a moment ago so is
+            // still allowed.
+            self.acquisitions.insert(shm_cap_id, address, length_in_bytes);
+            return AcquireIntersectsExistingAcquisitionSnafu.fail();
+        }
+
+        // The page table only needs the cap's type and length, not its
+        // backing, which is resized after this.
+        let resized_shm_cap = ShmCap::new(shm_cap.shm_type(), new_length, (), shm_cap.cap_type());
+        self.page_table.remove(shm_cap_id, shm_cap, address).context(PageTableInsertOrRemoveSnafu)?;
+        self.page_table.insert(shm_cap_id, &resized_shm_cap, address, flags).context(PageTableInsertOrRemoveSnafu)
+    }
+
     pub fn try_release(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap) -> Result<u64, AcquireError> {
         // Remove from acquisitions.
         let address = self.acquisitions.remove(shm_cap_id).map_err(|_| ReleasingNonAcquiredCapSnafu.build())?;
@@ -101,29 +181,52 @@ impl AcquisitionsAndPageTable {
         Ok(address)
     }
 
-    pub fn walk<'space>(&self, vaddr: u64, shm_space_map: &'space ShmSpaceMap) -> Result<WalkResult<'space>, PageTableError> {
-        self.walk_immut_or_mut(vaddr, shm_space_map, Sv39Flags::R)
-    }
+    /// Which page `vaddr` is in, if it has `required_permissions`. This
+    /// doesn't borrow the page, so that the translation can be cached.
+    pub fn translate(&self, vaddr: u64, shm_space_map: &ShmSpaceMap, required_permissions: Sv39Flags) -> Result<PageTranslation, PageTableError> {
+        // The tables below the top level only look at their own bits of
+        // `vaddr`, so anything above the top level's bits would wrap around.
+        if vaddr >> self.paging_scheme().bits() != 0 {
+            return PageNotFoundSnafu.fail();
+        }
 
-    pub fn walk_mut<'space>(&self, vaddr: u64, shm_space_map: &'space mut ShmSpaceMap) -> Result<WalkResultMut<'space>, PageTableError> {
-        self.walk_immut_or_mut(vaddr, shm_space_map, Sv39Flags::RW)
-    }
+        let (entry, shm_cap) = match self.page_table {
+            PageTable::Sv32(ref page_table) => Self::walk_sv32(page_table, vaddr, shm_space_map, required_permissions)?,
+            PageTable::Sv39(ref page_table) => page_table.walk(vaddr, shm_space_map, required_permissions, 1)?,
+            PageTable::Sv48(ref page_table) => page_table.walk(vaddr, shm_space_map, required_permissions, 1)?,
+            PageTable::Sv57(ref page_table) => page_table.walk(vaddr, shm_space_map, required_permissions, 1)?,
+        };
+
+        if entry.shm_cap_offset >= shm_cap.length_u64() {
+            return PageEntryCorruptedSnafu { shm_cap_id: entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: Some(entry.shm_cap_offset), shm_cap_length: Some(shm_cap.length()) }.fail();
+        }
+        let byte_start: usize = entry.shm_cap_offset
+            .checked_mul(shm_cap.shm_type().page_bytes())
+            .ok_or(PageEntryCorruptedSnafu { shm_cap_id: entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: Some(entry.shm_cap_offset), shm_cap_length: Some(shm_cap.length()) }.build())?
+            .try_into()
+            .map_err(|_| PageTooLargeToFitInHostPlatformWordSnafu { shm_cap_id: entry.shm_cap_id, shm_type: shm_cap.shm_type(), offset: entry.shm_cap_offset }.build())?;
+        let byte_end = byte_start
+            .checked_add(
+                shm_cap.shm_type().page_bytes().try_into().map_err(|_| PageTooLargeToFitInHostPlatformWordSnafu { shm_cap_id: entry.shm_cap_id, shm_type: shm_cap.shm_type(), offset: entry.shm_cap_offset }.build())?
+            )
+            .ok_or(PageEntryCorruptedSnafu { shm_cap_id: entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: Some(entry.shm_cap_offset), shm_cap_length: Some(shm_cap.length()) }.build())?;
 
-    pub fn walk_execute<'space>(&self, vaddr: u64, shm_space_map: &'space ShmSpaceMap) -> Result<WalkResult<'space>, PageTableError> {
-        self.walk_immut_or_mut(vaddr, shm_space_map, Sv39Flags::X)
+        Ok(PageTranslation { shm_cap_id: entry.shm_cap_id, byte_start, byte_end, flags: entry.flags })
     }
 
-    fn walk_immut_or_mut<SMR: SpaceMapRef>(&self, vaddr: u64, shm_space_map: SMR, required_permissions: Sv39Flags) -> Result<<SMR::ShmCapRef as CapRef>::Result, PageTableError> {
+    /// `first_level` is the level of `page_table`, which is 1 unless it's
+    /// below Sv48's or Sv57's extra levels.
+    fn walk_sv39<'a>(page_table: &'a PageTableLevel1, vaddr: u64, shm_space_map: &'a ShmSpaceMap, required_permissions: Sv39Flags, first_level: u8) -> Result<(&'a PageTableEntry, &'a ShmCap), PageTableError> {
         let vpn = vaddr >> 12;
-        let vpn2 = vpn >> 18;
-        let level_2_table = self.page_table.entries[vpn2 as usize].as_ref().ok_or(PageNotFoundSnafu.build())?;
+        let vpn2 = (vpn >> 18) & ((1 << PageTableLevel1::ENTRIES_BITS) - 1);
+        let level_2_table = page_table.entries[vpn2 as usize].as_ref().ok_or(PageNotFoundSnafu.build())?;
 
-        let (entry, shm_cap_ref) = 'superpage_check: {
+        Ok('superpage_check: {
             let leaf_table = match level_2_table {
                 PageTableLevel2::OneGiBSuperpage(pte) => {
-                    let shm_cap_ref = shm_space_map.get_shm_cap(pte.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: pte.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
-                    Self::check_shm_type_mismatch_and_permissions(1, pte, shm_cap_ref.as_ref(), ShmType::OneGiB, required_permissions)?;
-                    break 'superpage_check (pte, shm_cap_ref);
+                    let shm_cap = shm_space_map.get(&pte.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: pte.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
+                    Self::check_shm_type_mismatch_and_permissions(first_level, pte, shm_cap, ShmType::OneGiB, required_permissions)?;
+                    break 'superpage_check (pte, shm_cap);
                 }
                 PageTableLevel2::Entries(entries) => {
                     let vpn1 = (vpn >> 9) & ((1 << 9) - 1);
@@ -133,41 +236,41 @@ impl AcquisitionsAndPageTable {
 
             let four_k_entry = match leaf_table {
                 PageTableLeaf::TwoMiBSuperpage(pte) => {
-                    let shm_cap_ref = shm_space_map.get_shm_cap(pte.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: pte.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
-                    Self::check_shm_type_mismatch_and_permissions(2, pte, shm_cap_ref.as_ref(), ShmType::TwoMiB, required_permissions)?;
-                    break 'superpage_check (pte, shm_cap_ref);
+                    let shm_cap = shm_space_map.get(&pte.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: pte.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
+                    Self::check_shm_type_mismatch_and_permissions(first_level + 1, pte, shm_cap, ShmType::TwoMiB, required_permissions)?;
+                    break 'superpage_check (pte, shm_cap);
                 }
                 PageTableLeaf::Entries(entries) => {
                     let vpn0 = vpn & ((1 << 9) - 1);
                     entries[vpn0 as usize].as_ref().ok_or(PageNotFoundSnafu.build())?
                 }
             };
-            let shm_cap_ref = shm_space_map.get_shm_cap(four_k_entry.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: four_k_entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
-            Self::check_shm_type_mismatch_and_permissions(3, four_k_entry, shm_cap_ref.as_ref(), ShmType::FourKiB, required_permissions)?;
+            let shm_cap = shm_space_map.get(&four_k_entry.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: four_k_entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
+            Self::check_shm_type_mismatch_and_permissions(first_level + 2, four_k_entry, shm_cap, ShmType::FourKiB, required_permissions)?;
 
-            (four_k_entry, shm_cap_ref)
-        };
+            (four_k_entry, shm_cap)
+        })
+    }
 
-        let shm_cap = shm_cap_ref.as_ref();
-        if entry.shm_cap_offset >= shm_cap.length_u64() {
-            return PageEntryCorruptedSnafu { shm_cap_id: entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: Some(entry.shm_cap_offset), shm_cap_length: Some(shm_cap.length()) }.fail();
-        }
-        let byte_start: usize = entry.shm_cap_offset
-            .checked_mul(shm_cap.shm_type().page_bytes())
-            .ok_or(PageEntryCorruptedSnafu { shm_cap_id: entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: Some(entry.shm_cap_offset), shm_cap_length: Some(shm_cap.length()) }.build())?
-            .try_into()
-            .map_err(|_| PageTooLargeToFitInHostPlatformWordSnafu { shm_cap_id: entry.shm_cap_id, shm_type: shm_cap.shm_type(), offset: entry.shm_cap_offset }.build())?;
-        let byte_end = byte_start
-            .checked_add(
-                shm_cap.shm_type().page_bytes().try_into().map_err(|_| PageTooLargeToFitInHostPlatformWordSnafu { shm_cap_id: entry.shm_cap_id, shm_type: shm_cap.shm_type(), offset: entry.shm_cap_offset }.build())?
-            )
-            .ok_or(PageEntryCorruptedSnafu { shm_cap_id: entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: Some(entry.shm_cap_offset), shm_cap_length: Some(shm_cap.length()) }.build())?;
+    fn walk_sv32<'a>(page_table: &'a Sv32PageTable, vaddr: u64, shm_space_map: &'a ShmSpaceMap, required_permissions: Sv39Flags) -> Result<(&'a PageTableEntry, &'a ShmCap), PageTableError> {
+        let vpn1 = vaddr >> 22;
+        let leaf_table = page_table.entries.get(vpn1 as usize).and_then(Option::as_ref).ok_or(PageNotFoundSnafu.build())?;
 
-        let byte_offset_in_space_slice = (vaddr & (shm_cap.shm_type().page_bytes() - 1))
-            .try_into()
-            .map_err(|_| PageTooLargeToFitInHostPlatformWordSnafu { shm_cap_id: entry.shm_cap_id, shm_type: shm_cap.shm_type(), offset: entry.shm_cap_offset }.build())?;
+        let four_k_entry = match leaf_table {
+            Sv32PageTableLeaf::FourMiBSuperpage(pte) => {
+                let shm_cap = shm_space_map.get(&pte.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: pte.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
+                Self::check_shm_type_mismatch_and_permissions(1, pte, shm_cap, ShmType::FourMiB, required_permissions)?;
+                return Ok((pte, shm_cap));
+            }
+            Sv32PageTableLeaf::Entries(entries) => {
+                let vpn0 = (vaddr >> 12) & ((1 << Sv32PageTable::ENTRIES_BITS) - 1);
+                entries[vpn0 as usize].as_ref().ok_or(PageNotFoundSnafu.build())?
+            }
+        };
+        let shm_cap = shm_space_map.get(&four_k_entry.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: four_k_entry.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
+        Self::check_shm_type_mismatch_and_permissions(2, four_k_entry, shm_cap, ShmType::FourKiB, required_permissions)?;
 
-        Ok(shm_cap_ref.backing_reslice_and_result(byte_start, byte_end, byte_offset_in_space_slice))
+        Ok((four_k_entry, shm_cap))
     }
 
     fn check_shm_type_mismatch_and_permissions(current_level: u8, entry: &PageTableEntry, shm_cap: &ShmCap, expected_shm_type: ShmType, required_permissions: Sv39Flags) -> Result<(), PageTableError> {
@@ -181,6 +284,23 @@ impl AcquisitionsAndPageTable {
     }
 }
 
+/// Which bytes of which cap's backing a page is, and the page's permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageTranslation {
+    pub(crate) shm_cap_id: ShmCapId,
+    pub(crate) byte_start: usize,
+    pub(crate) byte_end: usize,
+    pub(crate) flags: Sv39Flags,
+}
+
+impl PageTranslation {
+    /// Doesn't overflow, because the page fit in `usize` when it was
+    /// translated, and page sizes are powers of two.
+    pub(crate) fn byte_offset_in_page(&self, vaddr: u64) -> usize {
+        (vaddr & ((self.byte_end - self.byte_start) as u64 - 1)) as usize
+    }
+}
+
 pub struct WalkResult<'space> {
     /// This is always one page. The page size depends on the SHM cap that was
     /// walked.
@@ -195,71 +315,14 @@ pub struct WalkResultMut<'space> {
     pub(crate) byte_offset_in_space_slice: usize,
 }
 
-trait SpaceMapRef {
-    type ShmCapRef: AsRef<ShmCap> + CapRef;
-
-    fn get_shm_cap(self, shm_cap_id: ShmCapId) -> Option<Self::ShmCapRef>;
-}
-
-impl<'space> SpaceMapRef for &'space ShmSpaceMap {
-    type ShmCapRef = &'space ShmCap;
-
-    fn get_shm_cap(self, shm_cap_id: ShmCapId) -> Option<Self::ShmCapRef> {
-        self.get(&shm_cap_id)
-    }
-}
-
-impl<'space> SpaceMapRef for &'space mut ShmSpaceMap {
-    type ShmCapRef = &'space mut ShmCap;
-
-    fn get_shm_cap(self, shm_cap_id: ShmCapId) -> Option<Self::ShmCapRef> {
-        self.get_mut(&shm_cap_id)
-    }
-}
-
-impl<'space> AsRef<ShmCap> for &'space ShmCap {
-    fn as_ref(&self) -> &ShmCap {
-        self
-    }
-}
-
-impl<'space> AsRef<ShmCap> for &'space mut ShmCap {
-    fn as_ref(&self) -> &ShmCap {
-        self
-    }
-}
-
-trait CapRef {
-    type Result;
-
-    fn backing_reslice_and_result(self, byte_start: usize, byte_end: usize, byte_offset_in_space_slice: usize) -> Self::Result;
-}
-
-impl<'space> CapRef for &'space ShmCap {
-    type Result = WalkResult<'space>;
-
-    fn backing_reslice_and_result(self, byte_start: usize, byte_end: usize, byte_offset_in_space_slice: usize) -> WalkResult<'space> {
-        let space_slice = &self.backing()[byte_start..byte_end];
-        WalkResult { space_slice, byte_offset_in_space_slice }
-    }
-}
-
-impl<'space> CapRef for &'space mut ShmCap {
-    type Result = WalkResultMut<'space>;
-
-    fn backing_reslice_and_result(self, byte_start: usize, byte_end: usize, byte_offset_in_space_slice: usize) -> WalkResultMut<'space> {
-        let space_slice = &mut self.backing_mut()[byte_start..byte_end];
-        WalkResultMut { space_slice, byte_offset_in_space_slice }
-    }
-}
-
 #[derive(Snafu, SnafuCliDebug)]
 pub enum AcquireError {
-    AcquireExceedsSv39,
+    AcquireExceedsAddressSpace,
     AcquireAddressNotPageAligned,
     AcquireIntersectsExistingAcquisition,
     AcquiringAlreadyAcquiredCap { address: u64 },
     ReleasingNonAcquiredCap,
+    ProtectingNonAcquiredCap,
     PageTableInsertOrRemoveError { source: PageTableError }, // Should never occur, indicates a bug in Nushift's code
     RollbackError, // Should never occur, indicates a bug in Nushift's code
 }
@@ -345,6 +408,35 @@ impl Acquisitions {
     }
 }
 
+impl PageTable {
+    fn entry(&self, vaddr: u64) -> Option<&PageTableEntry> {
+        match self {
+            Self::Sv32(page_table) => page_table.entry(vaddr),
+            Self::Sv39(page_table) => page_table.entry(vaddr),
+            Self::Sv48(page_table) => page_table.entry(vaddr),
+            Self::Sv57(page_table) => page_table.entry(vaddr),
+        }
+    }
+
+    fn insert<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64, flags: Sv39Flags) -> Result<(), PageTableError> {
+        match self {
+            Self::Sv32(page_table) => page_table.insert(shm_cap_id, shm_cap, address, flags),
+            Self::Sv39(page_table) => page_table.insert(shm_cap_id, shm_cap, address, flags),
+            Self::Sv48(page_table) => page_table.insert(shm_cap_id, shm_cap, address, flags),
+            Self::Sv57(page_table) => page_table.insert(shm_cap_id, shm_cap, address, flags),
+        }
+    }
+
+    fn remove<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64) -> Result<(), PageTableError> {
+        match self {
+            Self::Sv32(page_table) => page_table.remove(shm_cap_id, shm_cap, address),
+            Self::Sv39(page_table) => page_table.remove(shm_cap_id, shm_cap, address),
+            Self::Sv48(page_table) => page_table.remove(shm_cap_id, shm_cap, address),
+            Self::Sv57(page_table) => page_table.remove(shm_cap_id, shm_cap, address),
+        }
+    }
+}
+
 pub struct PageTableLevel1 {
     entries: [Option<PageTableLevel2>; Self::NUM_ENTRIES],
 }
@@ -359,7 +451,37 @@ enum PageTableLeaf {
     TwoMiBSuperpage(PageTableEntry),
 }
 
+/// Sv48 adds a level above Sv39's table, each entry of which is a whole Sv39
+/// table or a 512 GiB superpage.
+type Sv48PageTable = UpperPageTable<PageTableLevel1>;
+
+/// Sv57 adds another level above Sv48's, with 256 TiB superpages.
+type Sv57PageTable = UpperPageTable<Sv48PageTable>;
+
+/// One of Sv48's or Sv57's extra levels, above a table `T` of the level below.
+struct UpperPageTable<T> {
+    entries: [Option<UpperPageTableEntry<T>>; PageTableLevel1::NUM_ENTRIES],
+}
+
+enum UpperPageTableEntry<T> {
+    Table(Box<T>),
+    Superpage(PageTableEntry),
+}
+
+/// The two-level table of the Sv32 scheme. There is one fewer level than Sv39,
+/// but each level has 1024 entries instead of 512, and its superpages are 4
+/// MiB.
+pub struct Sv32PageTable {
+    entries: [Option<Sv32PageTableLeaf>; Self::NUM_ENTRIES],
+}
+
+enum Sv32PageTableLeaf {
+    Entries(Box<[Option<PageTableEntry>; Sv32PageTable::NUM_ENTRIES]>),
+    FourMiBSuperpage(PageTableEntry),
+}
+
 bitflags! {
+    /// Sv32 page table entries have the same flags.
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Sv39Flags: u8 {
         const V = 1 << 0;
@@ -391,48 +513,110 @@ enum PageTableOp {
     Remove,
 }
 
+/// Consecutive pages of a cap, starting at its page `first_offset`. This is
+/// the whole cap, except where Sv48's or Sv57's extra levels split a cap
+/// between the tables below them.
+#[derive(Debug, Clone, Copy)]
+struct PageRun {
+    shm_type: ShmType,
+    length: ShmCapLength,
+    first_offset: ShmCapOffset,
+}
+
+impl PageRun {
+    fn whole<B>(shm_cap: &ShmCap<B>) -> Self {
+        Self { shm_type: shm_cap.shm_type(), length: shm_cap.length(), first_offset: 0 }
+    }
+}
+
+/// A table that Sv48's or Sv57's extra levels can have below them.
+trait LowerPageTable {
+    /// The number of bits of virtual address that the whole table spans.
+    const BITS: u8;
+    /// The superpage that an entry of the level above has instead of one of
+    /// these tables, which is the same size.
+    const SUPERPAGE_ABOVE: ShmType;
+
+    fn new() -> Self;
+
+    /// The entry for the page containing `vaddr`, whatever size that page is.
+    /// Bits of `vaddr` above `BITS` are ignored.
+    fn entry(&self, vaddr: u64) -> Option<&PageTableEntry>;
+
+    /// Bits of `vaddr` above `BITS` are ignored. `level` is this table's
+    /// level, counting the top level as 1, for reporting corrupted entries.
+    fn walk<'a>(&'a self, vaddr: u64, shm_space_map: &'a ShmSpaceMap, required_permissions: Sv39Flags, level: u8) -> Result<(&'a PageTableEntry, &'a ShmCap), PageTableError>;
+
+    /// The same preconditions as `PageTableLevel1::insert`, with `address`
+    /// relative to the start of this table.
+    fn insert_or_remove_run(&mut self, op: PageTableOp, shm_cap_id: ShmCapId, run: PageRun, address: u64) -> Result<(), PageTableError>;
+}
+
 impl PageTableLevel1 {
     const ENTRIES_BITS: u8 = 9;
     const NUM_ENTRIES: usize = 1 << Self::ENTRIES_BITS;
 
-    fn new() -> Self {
-        Self { entries: array::from_fn(|_| None) }
-    }
-
     /// Check `is_allowed()` on Acquisitions before calling this. This also
     /// doesn't check whether `address` is aligned nor fits within Sv39, which
     /// should be checked by something.
     fn insert<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64, flags: Sv39Flags) -> Result<(), PageTableError> {
-        self.insert_or_remove(PageTableOp::Insert { flags }, shm_cap_id, shm_cap, address)
+        self.insert_or_remove_run(PageTableOp::Insert { flags }, shm_cap_id, PageRun::whole(shm_cap), address)
     }
 
     /// Check `is_allowed()` on Acquisitions before calling this. This also
     /// doesn't check whether `address` is aligned nor fits within Sv39, which
     /// should be checked by something.
     fn remove<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64) -> Result<(), PageTableError> {
-        self.insert_or_remove(PageTableOp::Remove, shm_cap_id, shm_cap, address)
+        self.insert_or_remove_run(PageTableOp::Remove, shm_cap_id, PageRun::whole(shm_cap), address)
+    }
+}
+
+impl LowerPageTable for PageTableLevel1 {
+    const BITS: u8 = SV39_BITS;
+    const SUPERPAGE_ABOVE: ShmType = ShmType::FiveTwelveGiB;
+
+    fn new() -> Self {
+        Self { entries: array::from_fn(|_| None) }
+    }
+
+    fn entry(&self, vaddr: u64) -> Option<&PageTableEntry> {
+        let vpn2 = (vaddr >> 30) & ((1 << Self::ENTRIES_BITS) - 1);
+        let vpn1 = (vaddr >> 21) & ((1 << PageTableLevel2::ENTRIES_BITS) - 1);
+        let vpn0 = (vaddr >> 12) & ((1 << PageTableLeaf::ENTRIES_BITS) - 1);
+
+        match self.entries[vpn2 as usize].as_ref()? {
+            PageTableLevel2::OneGiBSuperpage(pte) => Some(pte),
+            PageTableLevel2::Entries(entries) => match entries[vpn1 as usize].as_ref()? {
+                PageTableLeaf::TwoMiBSuperpage(pte) => Some(pte),
+                PageTableLeaf::Entries(entries) => entries[vpn0 as usize].as_ref(),
+            },
+        }
+    }
+
+    fn walk<'a>(&'a self, vaddr: u64, shm_space_map: &'a ShmSpaceMap, required_permissions: Sv39Flags, level: u8) -> Result<(&'a PageTableEntry, &'a ShmCap), PageTableError> {
+        AcquisitionsAndPageTable::walk_sv39(self, vaddr, shm_space_map, required_permissions, level)
     }
 
     /// Check `is_allowed()` on Acquisitions before calling this. This also
     /// doesn't check whether `address` is aligned nor fits within Sv39, which
     /// should be checked by something.
-    fn insert_or_remove<B>(&mut self, op: PageTableOp, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64) -> Result<(), PageTableError> {
+    fn insert_or_remove_run(&mut self, op: PageTableOp, shm_cap_id: ShmCapId, run: PageRun, address: u64) -> Result<(), PageTableError> {
         let vpn2 = address >> 30;
         let vpn1 = (address >> 21) & ((1 << 9) - 1);
         let vpn0 = (address >> 12) & ((1 << 9) - 1);
 
-        match shm_cap.shm_type() {
+        match run.shm_type {
             ShmType::OneGiB => {
                 let (start, end) = (
                     vpn2,
-                    vpn2.checked_add(shm_cap.length_u64())
-                        .ok_or(PageInsertOutOfBoundsSnafu { shm_type: ShmType::OneGiB, length: shm_cap.length(), address }.build())?,
+                    vpn2.checked_add(run.length.get())
+                        .ok_or(PageInsertOutOfBoundsSnafu { shm_type: ShmType::OneGiB, length: run.length, address }.build())?,
                 );
                 if end > PageTableLevel1::NUM_ENTRIES as u64 {
-                    return PageInsertOutOfBoundsSnafu { shm_type: ShmType::OneGiB, length: shm_cap.length(), address }.fail();
+                    return PageInsertOutOfBoundsSnafu { shm_type: ShmType::OneGiB, length: run.length, address }.fail();
                 }
                 for i in start..end {
-                    let offset = i - start;
+                    let offset = run.first_offset + (i - start);
                     match op {
                         PageTableOp::Insert { flags } => self.entries[i as usize] = Some(PageTableLevel2::OneGiBSuperpage(PageTableEntry { shm_cap_id, shm_cap_offset: offset, flags })),
                         PageTableOp::Remove => self.entries[i as usize] = None,
@@ -444,13 +628,13 @@ impl PageTableLevel1 {
             ShmType::TwoMiB => {
                 let (start_vpn1, end_vpn1) = (
                     vpn1,
-                    vpn1.checked_add(shm_cap.length_u64())
-                        .ok_or(PageInsertOutOfBoundsSnafu { shm_type: ShmType::TwoMiB, length: shm_cap.length(), address }.build())?,
+                    vpn1.checked_add(run.length.get())
+                        .ok_or(PageInsertOutOfBoundsSnafu { shm_type: ShmType::TwoMiB, length: run.length, address }.build())?,
                 );
 
                 let absolute_end_vpn1 = (vpn2 << PageTableLevel2::ENTRIES_BITS) + end_vpn1;
                 if absolute_end_vpn1 > 1u64 << PageTableLevel1::ENTRIES_BITS << PageTableLevel2::ENTRIES_BITS {
-                    return PageInsertOutOfBoundsSnafu { shm_type: ShmType::TwoMiB, length: shm_cap.length(), address }.fail();
+                    return PageInsertOutOfBoundsSnafu { shm_type: ShmType::TwoMiB, length: run.length, address }.fail();
                 }
 
                 for current_vpn1 in start_vpn1..end_vpn1 {
@@ -466,7 +650,7 @@ impl PageTableLevel1 {
                     let current_vpn1_index = (current_vpn1 & ((1 << PageTableLevel2::ENTRIES_BITS) - 1)) as usize;
                     match op {
                         PageTableOp::Insert { flags } => {
-                            level_2_table[current_vpn1_index] = Some(PageTableLeaf::TwoMiBSuperpage(PageTableEntry { shm_cap_id, shm_cap_offset: (current_vpn1 - start_vpn1), flags }));
+                            level_2_table[current_vpn1_index] = Some(PageTableLeaf::TwoMiBSuperpage(PageTableEntry { shm_cap_id, shm_cap_offset: run.first_offset + (current_vpn1 - start_vpn1), flags }));
                         }
                         PageTableOp::Remove => {
                             level_2_table[current_vpn1_index] = None;
@@ -480,16 +664,18 @@ impl PageTableLevel1 {
                 Ok(())
             }
 
+            ShmType::FourMiB | ShmType::FiveTwelveGiB | ShmType::TwoFiftySixTiB => PageInsertUnsupportedShmTypeSnafu { shm_type: run.shm_type }.fail(),
+
             ShmType::FourKiB => {
                 let (start_vpn0, end_vpn0) = (
                     vpn0,
-                    vpn0.checked_add(shm_cap.length_u64())
-                        .ok_or(PageInsertOutOfBoundsSnafu { shm_type: ShmType::FourKiB, length: shm_cap.length(), address }.build())?,
+                    vpn0.checked_add(run.length.get())
+                        .ok_or(PageInsertOutOfBoundsSnafu { shm_type: ShmType::FourKiB, length: run.length, address }.build())?,
                 );
 
                 let absolute_end_vpn0 = (vpn2 << PageTableLevel2::ENTRIES_BITS << PageTableLeaf::ENTRIES_BITS) + (vpn1 << PageTableLeaf::ENTRIES_BITS) + end_vpn0;
                 if absolute_end_vpn0 > 1u64 << PageTableLevel1::ENTRIES_BITS << PageTableLevel2::ENTRIES_BITS << PageTableLeaf::ENTRIES_BITS {
-                    return PageInsertOutOfBoundsSnafu { shm_type: ShmType::FourKiB, length: shm_cap.length(), address }.fail();
+                    return PageInsertOutOfBoundsSnafu { shm_type: ShmType::FourKiB, length: run.length, address }.fail();
                 }
 
                 for current_vpn0 in start_vpn0..end_vpn0 {
@@ -514,7 +700,7 @@ impl PageTableLevel1 {
 
                     let current_vpn0_index = (current_vpn0 & ((1 << PageTableLeaf::ENTRIES_BITS) - 1)) as usize;
                     match op {
-                        PageTableOp::Insert { flags } => leaf_table[current_vpn0_index] = Some(PageTableEntry { shm_cap_id, shm_cap_offset: (current_vpn0 - start_vpn0), flags }),
+                        PageTableOp::Insert { flags } => leaf_table[current_vpn0_index] = Some(PageTableEntry { shm_cap_id, shm_cap_offset: run.first_offset + (current_vpn0 - start_vpn0), flags }),
                         PageTableOp::Remove => {
                             leaf_table[current_vpn0_index] = None;
 
@@ -535,6 +721,173 @@ impl PageTableLevel1 {
     }
 }
 
+impl<T: LowerPageTable> UpperPageTable<T> {
+    /// The same preconditions as `PageTableLevel1::insert`, but for Sv48 or
+    /// Sv57.
+    fn insert<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64, flags: Sv39Flags) -> Result<(), PageTableError> {
+        self.insert_or_remove_run(PageTableOp::Insert { flags }, shm_cap_id, PageRun::whole(shm_cap), address)
+    }
+
+    /// The same preconditions as `PageTableLevel1::remove`, but for Sv48 or
+    /// Sv57.
+    fn remove<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64) -> Result<(), PageTableError> {
+        self.insert_or_remove_run(PageTableOp::Remove, shm_cap_id, PageRun::whole(shm_cap), address)
+    }
+
+    fn index(vaddr: u64) -> usize {
+        ((vaddr >> T::BITS) & ((1 << PageTableLevel1::ENTRIES_BITS) - 1)) as usize
+    }
+}
+
+impl<T: LowerPageTable> LowerPageTable for UpperPageTable<T> {
+    const BITS: u8 = T::BITS + PageTableLevel1::ENTRIES_BITS;
+    // Sv48's table is the only one of these that's below another level, the
+    // one Sv57 adds.
+    const SUPERPAGE_ABOVE: ShmType = ShmType::TwoFiftySixTiB;
+
+    fn new() -> Self {
+        Self { entries: array::from_fn(|_| None) }
+    }
+
+    fn entry(&self, vaddr: u64) -> Option<&PageTableEntry> {
+        match self.entries[Self::index(vaddr)].as_ref()? {
+            UpperPageTableEntry::Superpage(pte) => Some(pte),
+            UpperPageTableEntry::Table(table) => table.entry(vaddr),
+        }
+    }
+
+    fn walk<'a>(&'a self, vaddr: u64, shm_space_map: &'a ShmSpaceMap, required_permissions: Sv39Flags, level: u8) -> Result<(&'a PageTableEntry, &'a ShmCap), PageTableError> {
+        match self.entries[Self::index(vaddr)].as_ref().ok_or(PageNotFoundSnafu.build())? {
+            UpperPageTableEntry::Superpage(pte) => {
+                let shm_cap = shm_space_map.get(&pte.shm_cap_id).ok_or_else(|| PageEntryCorruptedSnafu { shm_cap_id: pte.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None }.build())?;
+                AcquisitionsAndPageTable::check_shm_type_mismatch_and_permissions(level, pte, shm_cap, T::SUPERPAGE_ABOVE, required_permissions)?;
+                Ok((pte, shm_cap))
+            }
+            UpperPageTableEntry::Table(table) => table.walk(vaddr, shm_space_map, required_permissions, level + 1),
+        }
+    }
+
+    /// Superpages of this level go straight in. Smaller pages are split into
+    /// a run for each table below that they fall in.
+    fn insert_or_remove_run(&mut self, op: PageTableOp, shm_cap_id: ShmCapId, run: PageRun, address: u64) -> Result<(), PageTableError> {
+        let page_bits = run.shm_type.page_bits();
+        if page_bits > T::BITS {
+            return PageInsertUnsupportedShmTypeSnafu { shm_type: run.shm_type }.fail();
+        }
+
+        let pages_per_entry_bits = T::BITS - page_bits;
+        let start = address >> page_bits;
+        let end = start.checked_add(run.length.get())
+            .ok_or(PageInsertOutOfBoundsSnafu { shm_type: run.shm_type, length: run.length, address }.build())?;
+        if end > (PageTableLevel1::NUM_ENTRIES as u64) << pages_per_entry_bits {
+            return PageInsertOutOfBoundsSnafu { shm_type: run.shm_type, length: run.length, address }.fail();
+        }
+
+        if run.shm_type == T::SUPERPAGE_ABOVE {
+            for current in start..end {
+                self.entries[current as usize] = match op {
+                    PageTableOp::Insert { flags } => Some(UpperPageTableEntry::Superpage(PageTableEntry { shm_cap_id, shm_cap_offset: run.first_offset + (current - start), flags })),
+                    PageTableOp::Remove => None,
+                };
+            }
+            return Ok(());
+        }
+
+        let mut current = start;
+        while current < end {
+            let index = current >> pages_per_entry_bits;
+            let table = self.entries[index as usize].get_or_insert_with(|| UpperPageTableEntry::Table(Box::new(T::new())));
+            let table = match table {
+                // Like Sv32, report this level's index as vpn2.
+                UpperPageTableEntry::Superpage(_) => return PageInsertCorruptedSnafu { shm_cap_id, vpn2: index, current_vpn1: None, current_vpn0: None }.fail(),
+                UpperPageTableEntry::Table(table) => table,
+            };
+
+            let table_end = ((index + 1) << pages_per_entry_bits).min(end);
+            let table_run = PageRun {
+                shm_type: run.shm_type,
+                length: NonZeroU64::new(table_end - current).expect("current is less than both end and the start of the next table"),
+                first_offset: run.first_offset + (current - start),
+            };
+            let table_address = (current << page_bits) & ((1 << T::BITS) - 1);
+            // TODO: Like Sv39, tables below aren't freed when they become
+            // empty.
+            table.insert_or_remove_run(op, shm_cap_id, table_run, table_address)?;
+
+            current = table_end;
+        }
+        Ok(())
+    }
+}
+
+impl Sv32PageTable {
+    const ENTRIES_BITS: u8 = 10;
+    const NUM_ENTRIES: usize = 1 << Self::ENTRIES_BITS;
+
+    fn new() -> Self {
+        Self { entries: array::from_fn(|_| None) }
+    }
+
+    /// The entry for the page containing `vaddr`, whatever size that page is.
+    fn entry(&self, vaddr: u64) -> Option<&PageTableEntry> {
+        let vpn1 = vaddr >> 22;
+        let vpn0 = (vaddr >> 12) & ((1 << Self::ENTRIES_BITS) - 1);
+
+        match self.entries.get(vpn1 as usize)?.as_ref()? {
+            Sv32PageTableLeaf::FourMiBSuperpage(pte) => Some(pte),
+            Sv32PageTableLeaf::Entries(entries) => entries[vpn0 as usize].as_ref(),
+        }
+    }
+
+    /// The same preconditions as `PageTableLevel1::insert`, but for Sv32.
+    fn insert<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64, flags: Sv39Flags) -> Result<(), PageTableError> {
+        self.insert_or_remove(PageTableOp::Insert { flags }, shm_cap_id, shm_cap, address)
+    }
+
+    /// The same preconditions as `PageTableLevel1::remove`, but for Sv32.
+    fn remove<B>(&mut self, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64) -> Result<(), PageTableError> {
+        self.insert_or_remove(PageTableOp::Remove, shm_cap_id, shm_cap, address)
+    }
+
+    fn insert_or_remove<B>(&mut self, op: PageTableOp, shm_cap_id: ShmCapId, shm_cap: &ShmCap<B>, address: u64) -> Result<(), PageTableError> {
+        let shm_type = shm_cap.shm_type();
+        let (start, pages_per_entry_bits) = match shm_type {
+            ShmType::FourMiB => (address >> 22, 0),
+            ShmType::FourKiB => (address >> 12, Self::ENTRIES_BITS),
+            _ => return PageInsertUnsupportedShmTypeSnafu { shm_type }.fail(),
+        };
+        let end = start.checked_add(shm_cap.length_u64())
+            .ok_or(PageInsertOutOfBoundsSnafu { shm_type, length: shm_cap.length(), address }.build())?;
+        if end > (Self::NUM_ENTRIES as u64) << pages_per_entry_bits {
+            return PageInsertOutOfBoundsSnafu { shm_type, length: shm_cap.length(), address }.fail();
+        }
+
+        for current in start..end {
+            let entry = match op {
+                PageTableOp::Insert { flags } => Some(PageTableEntry { shm_cap_id, shm_cap_offset: current - start, flags }),
+                PageTableOp::Remove => None,
+            };
+
+            if shm_type == ShmType::FourMiB {
+                self.entries[current as usize] = entry.map(Sv32PageTableLeaf::FourMiBSuperpage);
+                continue;
+            }
+
+            let current_vpn1 = current >> Self::ENTRIES_BITS;
+            let leaf_table = self.entries[current_vpn1 as usize].get_or_insert_with(|| Sv32PageTableLeaf::Entries(Box::new(array::from_fn(|_| None))));
+            let leaf_table = match leaf_table {
+                // Sv32 has no vpn2, so report the top level as 0.
+                Sv32PageTableLeaf::FourMiBSuperpage(_) => return PageInsertCorruptedSnafu { shm_cap_id, vpn2: 0u64, current_vpn1: Some(current_vpn1), current_vpn0: Some(current) }.fail(),
+                Sv32PageTableLeaf::Entries(entries) => entries,
+            };
+            // TODO: Like Sv39, leaf tables aren't freed when they become
+            // empty.
+            leaf_table[(current & ((1 << Self::ENTRIES_BITS) - 1)) as usize] = entry;
+        }
+        Ok(())
+    }
+}
+
 impl PageTableLevel2 {
     const ENTRIES_BITS: u8 = 9;
     const NUM_ENTRIES: usize = 1 << Self::ENTRIES_BITS;
@@ -551,6 +904,8 @@ pub enum PageTableError {
     PageInsertOutOfBounds { shm_type: ShmType, length: ShmCapLength, address: u64 },
     #[snafu(display("Could not insert page due to pages already being present. Although attempting to map to an already-mapped range is a user-visible error, this particular variant should never occur and indicates a bug in Nushift's code."))]
     PageInsertCorrupted { shm_cap_id: ShmCapId, vpn2: u64, current_vpn1: Option<u64>, current_vpn0: Option<u64> },
+    #[snafu(display("{shm_type:?} pages can't be inserted into this page table. This should have been checked when the cap was created, so this indicates a bug in Nushift's code."))]
+    PageInsertUnsupportedShmType { shm_type: ShmType },
     #[snafu(display("The requested page was not present"))]
     PageNotFound,
     #[snafu(display("The page did not have the permissions that were required"))]
@@ -1023,5 +1378,126 @@ mod tests {
             assert!(leaf_entries.iter().all(|entry| matches!(entry, None)));
             assert!(level_2_entries[2..].iter().all(|entry| matches!(entry, None))); // Expect remaining 2 MiB pages to not be populated
         }
+
+        #[test]
+        fn sv32_insert_four_kib_across_four_mib_boundary() {
+            let mut page_table = Sv32PageTable::new();
+            let address = (1u64 << 22) - (1 << 12);
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FourKiB, non_zero(2), &[0u8; 0], CapType::AppCap), address, Sv39Flags::RW),
+                Ok(()),
+            ));
+            assert!(matches!(page_table.entry(address), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 0, flags: Sv39Flags::RW })));
+            assert!(matches!(page_table.entry(address + (1 << 12)), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 1, flags: Sv39Flags::RW })));
+            assert!(page_table.entry(address + (2 << 12)).is_none());
+        }
+
+        #[test]
+        fn sv32_insert_four_mib_out_of_bounds() {
+            let mut page_table = Sv32PageTable::new();
+            let address = 1023u64 << 22;
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FourMiB, non_zero(2), &[0u8; 0], CapType::AppCap), address, Sv39Flags::RW),
+                Err(PageTableError::PageInsertOutOfBounds { shm_type: ShmType::FourMiB, .. }),
+            ));
+            assert!(page_table.entries.iter().all(Option::is_none));
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FourMiB, non_zero(1), &[0u8; 0], CapType::AppCap), address, Sv39Flags::RW),
+                Ok(()),
+            ));
+            assert!(matches!(page_table.entry(address + 0x1234), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 0, flags: Sv39Flags::RW })));
+        }
+
+        #[test]
+        fn sv39_insert_four_mib_unsupported() {
+            let mut page_table = PageTableLevel1::new();
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FourMiB, non_zero(1), &[0u8; 0], CapType::AppCap), 0, Sv39Flags::RW),
+                Err(PageTableError::PageInsertUnsupportedShmType { shm_type: ShmType::FourMiB }),
+            ));
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FiveTwelveGiB, non_zero(1), &[0u8; 0], CapType::AppCap), 0, Sv39Flags::RW),
+                Err(PageTableError::PageInsertUnsupportedShmType { shm_type: ShmType::FiveTwelveGiB }),
+            ));
+        }
+
+        #[test]
+        fn sv48_insert_five_twelve_gib_boundary() {
+            let mut page_table = Sv48PageTable::new();
+            let address = 510u64 << 39;
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FiveTwelveGiB, non_zero(3), &[0u8; 0], CapType::AppCap), address, Sv39Flags::RW),
+                Err(PageTableError::PageInsertOutOfBounds { shm_type: ShmType::FiveTwelveGiB, .. }),
+            ));
+            assert!(page_table.entries.iter().all(Option::is_none));
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FiveTwelveGiB, non_zero(2), &[0u8; 0], CapType::AppCap), address, Sv39Flags::RW),
+                Ok(()),
+            ));
+            assert!(page_table.entries[..510].iter().all(Option::is_none));
+            assert!(matches!(page_table.entries[510], Some(UpperPageTableEntry::Superpage(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 0, flags: Sv39Flags::RW }))));
+            assert!(matches!(page_table.entry((511u64 << 39) + 0x1234), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 1, flags: Sv39Flags::RW })));
+
+            assert!(matches!(
+                page_table.insert(2, &ShmCap::new(ShmType::TwoFiftySixTiB, non_zero(1), &[0u8; 0], CapType::AppCap), 0, Sv39Flags::RW),
+                Err(PageTableError::PageInsertUnsupportedShmType { shm_type: ShmType::TwoFiftySixTiB }),
+            ));
+        }
+
+        #[test]
+        fn sv48_insert_and_remove_two_mib_across_sv39_tables() {
+            let mut page_table = Sv48PageTable::new();
+            // The last two 2 MiB pages of the first Sv39 table, and the first
+            // three of the second.
+            let address = (1u64 << 39) - (2 << 21);
+            let shm_cap = ShmCap::new(ShmType::TwoMiB, non_zero(5), &[0u8; 0], CapType::AppCap);
+
+            assert!(matches!(page_table.insert(1, &shm_cap, address, Sv39Flags::RW), Ok(())));
+            for i in 0..5 {
+                assert!(matches!(page_table.entry(address + (i << 21)), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: m_offset, flags: Sv39Flags::RW }) if *m_offset == i));
+            }
+            assert!(page_table.entry(address - (1 << 21)).is_none());
+            assert!(page_table.entry(address + (5 << 21)).is_none());
+            assert!(matches!(page_table.entries[0], Some(UpperPageTableEntry::Table(_))));
+            assert!(matches!(page_table.entries[1], Some(UpperPageTableEntry::Table(_))));
+            assert!(page_table.entries[2..].iter().all(Option::is_none));
+
+            assert!(matches!(page_table.remove(1, &shm_cap, address), Ok(())));
+            for i in 0..5 {
+                assert!(page_table.entry(address + (i << 21)).is_none());
+            }
+        }
+
+        #[test]
+        fn sv57_insert_four_kib_across_sv48_tables() {
+            let mut page_table = Sv57PageTable::new();
+            let address = (1u64 << 48) - (1 << 12);
+
+            assert!(matches!(
+                page_table.insert(1, &ShmCap::new(ShmType::FourKiB, non_zero(2), &[0u8; 0], CapType::AppCap), address, Sv39Flags::RX),
+                Ok(()),
+            ));
+            assert!(matches!(page_table.entry(address), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 0, flags: Sv39Flags::RX })));
+            assert!(matches!(page_table.entry(address + (1 << 12)), Some(PageTableEntry { shm_cap_id: 1, shm_cap_offset: 1, flags: Sv39Flags::RX })));
+            assert!(page_table.entry(address + (2 << 12)).is_none());
+
+            assert!(matches!(
+                page_table.insert(2, &ShmCap::new(ShmType::TwoFiftySixTiB, non_zero(1), &[0u8; 0], CapType::AppCap), 3u64 << 48, Sv39Flags::RW),
+                Ok(()),
+            ));
+            assert!(matches!(page_table.entry((3u64 << 48) + 0x1234), Some(PageTableEntry { shm_cap_id: 2, shm_cap_offset: 0, flags: Sv39Flags::RW })));
+            // Smaller pages inside the superpage mean the page table is
+            // corrupted, since acquisitions should have stopped this.
+            assert!(matches!(
+                page_table.insert(3, &ShmCap::new(ShmType::FourKiB, non_zero(1), &[0u8; 0], CapType::AppCap), 3u64 << 48, Sv39Flags::RW),
+                Err(PageTableError::PageInsertCorrupted { shm_cap_id: 3, vpn2: 3, .. }),
+            ));
+        }
     }
 }
@@ -1,21 +1,29 @@
 // Copyright 2023 The Nushift Authors.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{collections::{HashMap, hash_map::Entry}, io, ops::{Deref, DerefMut}, num::NonZeroU64};
+use std::{collections::{HashMap, hash_map::Entry}, io, ops::{Deref, DerefMut}, num::NonZeroU64, sync::{Arc, LockResult, Mutex, MutexGuard, TryLockError, atomic::{AtomicU64, AtomicUsize, Ordering}}};
 
+use bitflags::bitflags;
 use memmap2::MmapMut;
 use num_enum::TryFromPrimitive;
 use reusable_id_pool::{ReusableIdPoolError, ReusableIdPoolManual};
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use snafu_cli_debug::SnafuCliDebug;
 
-use self::acquisitions_and_page_table::{AcquisitionsAndPageTable, AcquireError, WalkResult, PageTableError, WalkResultMut, Sv39Flags};
+use crate::snapshot::{self, SnapshotError, CorruptedSnafu};
+
+use self::acquisitions_and_page_table::{AcquisitionsAndPageTable, AcquireError, WalkResult, PageTableError, PageTranslation, WalkResultMut, Sv39Flags};
 
 pub mod acquisitions_and_page_table;
+pub mod translation_cache;
 
+pub const SV57_BITS: u8 = 57;
+pub const SV48_BITS: u8 = 48;
 pub const SV39_BITS: u8 = 39;
+pub const SV32_BITS: u8 = 32;
 
-#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u64)]
 pub enum ShmType {
     // Support page sizes available in the Sv39 scheme.
@@ -23,34 +31,157 @@ pub enum ShmType {
     TwoMiB = 1,
     OneGiB = 2,
 
-    // If supporting the Sv32 scheme (needed for supporting RV32 i.e. 32-bit
-    // apps), FourMiB which corresponds to the superpage in that scheme will be
-    // supported.
-    // FourMiB = ...,
+    // The superpage in the Sv32 scheme, which is used by RV32 i.e. 32-bit
+    // apps. FourKiB is shared with Sv39.
+    FourMiB = 3,
 
-    // When/if Sv48 is supported in the future, the FiveTwelveGiB superpage in
-    // that scheme will be supported.
-    // FiveTwelveGiB = ...,
+    // The extra superpages in the Sv48 and Sv57 schemes. Sv48 has
+    // FiveTwelveGiB as well as Sv39's page sizes, and Sv57 has TwoFiftySixTiB
+    // as well as Sv48's.
+    FiveTwelveGiB = 4,
+    TwoFiftySixTiB = 5,
 }
 
 impl ShmType {
+    /// The number of bits of address within a page.
+    pub fn page_bits(&self) -> u8 {
+        match self {
+            Self::FourKiB => 12,
+            Self::TwoMiB => 21,
+            Self::OneGiB => 30,
+            Self::FourMiB => 22,
+            Self::FiveTwelveGiB => 39,
+            Self::TwoFiftySixTiB => 48,
+        }
+    }
+
     pub fn page_bytes(&self) -> u64 {
+        1 << self.page_bits()
+    }
+
+    /// Which entry of `SpaceStats` counts pages of this type. Sv39's 2 MiB and
+    /// Sv32's 4 MiB superpages are never in the same space, so they share one.
+    fn stats_index(&self) -> usize {
         match self {
-            Self::FourKiB => 1 << 12,
-            Self::TwoMiB => 1 << 21,
-            Self::OneGiB => 1 << 30,
+            Self::OneGiB => 0,
+            Self::TwoMiB | Self::FourMiB => 1,
+            Self::FourKiB => 2,
+            Self::FiveTwelveGiB => 3,
+            Self::TwoFiftySixTiB => 4,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The virtual addressing scheme of an app's space. By default this follows
+/// from the app's register width, but RV64 apps can ask for a bigger space
+/// with an ELF note, see `elf_loader::requested_paging_scheme`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PagingScheme {
+    /// For RV32 apps.
+    Sv32,
+    /// For RV64 apps.
+    Sv39,
+    /// For RV64 apps that need more than Sv39's 512 GiB.
+    Sv48,
+    /// For RV64 apps that need more than Sv48's 256 TiB.
+    Sv57,
+}
+
+impl PagingScheme {
+    pub fn for_xlen(xlen: u8) -> Self {
+        if xlen == 32 { Self::Sv32 } else { Self::Sv39 }
+    }
+
+    /// The scheme with `bits` bits of virtual address, if an app with
+    /// `xlen`-bit registers can use it.
+    pub fn for_bits(bits: u8, xlen: u8) -> Option<Self> {
+        let paging_scheme = match bits {
+            SV32_BITS => Self::Sv32,
+            SV39_BITS => Self::Sv39,
+            SV48_BITS => Self::Sv48,
+            SV57_BITS => Self::Sv57,
+            _ => return None,
+        };
+        paging_scheme.suits_xlen(xlen).then_some(paging_scheme)
+    }
+
+    /// Sv32 is the only scheme for RV32 apps, and RV64 apps can have any of
+    /// the others.
+    pub fn suits_xlen(&self, xlen: u8) -> bool {
+        (*self == Self::Sv32) == (xlen == 32)
+    }
+
+    /// The number of bits of virtual address.
+    pub fn bits(&self) -> u8 {
+        match self {
+            Self::Sv32 => SV32_BITS,
+            Self::Sv39 => SV39_BITS,
+            Self::Sv48 => SV48_BITS,
+            Self::Sv57 => SV57_BITS,
+        }
+    }
+
+    /// The page sizes of this scheme, biggest first.
+    pub fn shm_types(&self) -> &'static [ShmType] {
+        match self {
+            Self::Sv32 => &[ShmType::FourMiB, ShmType::FourKiB],
+            Self::Sv39 => &[ShmType::OneGiB, ShmType::TwoMiB, ShmType::FourKiB],
+            Self::Sv48 => &[ShmType::FiveTwelveGiB, ShmType::OneGiB, ShmType::TwoMiB, ShmType::FourKiB],
+            Self::Sv57 => &[ShmType::TwoFiftySixTiB, ShmType::FiveTwelveGiB, ShmType::OneGiB, ShmType::TwoMiB, ShmType::FourKiB],
+        }
+    }
+
+    pub fn supports(&self, shm_type: ShmType) -> bool {
+        self.shm_types().contains(&shm_type)
+    }
+}
+
+bitflags! {
+    /// The permissions an app asks for when it acquires or protects an SHM
+    /// cap. These are the ABI's bits, not the page table's.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ShmFlags: u64 {
+        const R = 1 << 0;
+        const W = 1 << 1;
+        const X = 1 << 2;
+    }
+}
+
+impl ShmFlags {
+    /// Writable pages must be readable, and can't also be executable (W^X).
+    /// No permissions at all is fine, which makes a guard region that faults
+    /// on every access.
+    fn sv39_flags(self) -> Result<Sv39Flags, ShmSpaceError> {
+        ensure!(!self.contains(Self::W | Self::X), WriteAndExecuteSnafu);
+        ensure!(!self.contains(Self::W) || self.contains(Self::R), WriteWithoutReadSnafu);
+
+        let mut sv39_flags = Sv39Flags::empty();
+        sv39_flags.set(Sv39Flags::R, self.contains(Self::R));
+        sv39_flags.set(Sv39Flags::W, self.contains(Self::W));
+        sv39_flags.set(Sv39Flags::X, self.contains(Self::X));
+        Ok(sv39_flags)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CapType {
     /// An app-created SHM cap.
     AppCap,
-    /// A system-created SHM cap that stores program ELF data.
+    /// A system-created SHM cap that stores program ELF data, or is the stack
+    /// that the hypervisor sets up for the app.
     ElfCap,
 }
 
+impl CapType {
+    /// Which entry of `SpaceStats::cap_type_bytes` counts caps of this type.
+    fn stats_index(&self) -> usize {
+        match self {
+            Self::AppCap => 0,
+            Self::ElfCap => 1,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ShmCap<B = MmapMut> {
     shm_type: ShmType,
@@ -105,45 +236,180 @@ pub type ShmCapOffset = u64;
 pub type ShmSpaceMap = HashMap<ShmCapId, ShmCap>;
 pub type OwnedShmIdAndCap = (ShmCapId, ShmCap);
 
-/// 0 = number of 1 GiB caps, 1 = number of 2 MiB caps, 2 = number of 4 KiB caps
-type Sv39SpaceStats = [u32; 3];
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SpaceStats {
+    /// 0 = number of 1 GiB caps, 1 = number of 2 MiB (or in Sv32, 4 MiB) caps,
+    /// 2 = number of 4 KiB caps, 3 = number of 512 GiB caps, 4 = number of 256
+    /// TiB caps
+    pages: [u64; 5],
+    /// 0 = bytes of app caps, 1 = bytes of ELF caps
+    cap_type_bytes: [u64; 2],
+}
+
+impl SpaceStats {
+    fn used_bytes(&self) -> u64 {
+        self.cap_type_bytes.iter().sum()
+    }
+}
+
+/// How much host memory an app's SHM caps are using, which is what counts
+/// towards its `TabConfig::max_memory_bytes`. Caps that the app has lent to a
+/// deferred task still count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The bytes in caps of each of the app's page sizes, biggest first.
+    pub shm_type_bytes: Vec<(ShmType, u64)>,
+    /// The bytes in caps that the app made.
+    pub app_cap_bytes: u64,
+    /// The bytes in caps that the hypervisor made for the app's ELF segments
+    /// and stack.
+    pub elf_cap_bytes: u64,
+    /// The limit from the tab's `TabConfig`, if it has one.
+    pub max_memory_bytes: Option<u64>,
+}
+
+impl MemoryUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.app_cap_bytes + self.elf_cap_bytes
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ShmSpaceSnapshot {
+    paging_scheme: PagingScheme,
+    shm_caps: Vec<ShmCapSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShmCapSnapshot {
+    id: ShmCapId,
+    shm_type: ShmType,
+    length: u64,
+    cap_type: CapType,
+    backing: Vec<u8>,
+    /// The address and flags, if acquired.
+    acquisition: Option<(u64, u8)>,
+}
+
+/// Goes up every time a cap is acquired, released or moved out of or into a
+/// space, so that anything cached from the page table knows when it's out of
+/// date.
+///
+/// It's atomic, and shared outside of the space's lock (see
+/// `NushiftSubsystem::mapping_generation`), so that a hart can check it before
+/// every instruction without locking the space.
+#[derive(Default)]
+pub struct MappingGeneration(AtomicU64);
+
+impl MappingGeneration {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Only called with the space locked, so bumps never race each other.
+    fn bump(&self) {
+        self.0.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// The lock around an app's SHM space.
+///
+/// A hart holds it for a batch of instructions at a time, rather than locking
+/// it for every memory access (see `HartWithShmSpace`), so this counts
+/// who's waiting for it, and the hart lets go of it as soon as anyone is.
+pub struct ShmSpaceMutex {
+    mutex: Mutex<ShmSpace>,
+    waiters: AtomicUsize,
+}
+
+impl ShmSpaceMutex {
+    pub fn new(shm_space: ShmSpace) -> Self {
+        Self { mutex: Mutex::new(shm_space), waiters: AtomicUsize::new(0) }
+    }
+
+    /// The same as `Mutex::lock`, but counted as waiting if it has to wait.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, ShmSpace>> {
+        match self.mutex.try_lock() {
+            Ok(shm_space) => return Ok(shm_space),
+            Err(TryLockError::Poisoned(poison_error)) => return Err(poison_error),
+            Err(TryLockError::WouldBlock) => {}
+        }
+
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        let shm_space = self.mutex.lock();
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        shm_space
+    }
+
+    /// Only tests need this, to check that nothing is holding the space.
+    #[cfg(test)]
+    pub fn try_lock(&self) -> std::sync::TryLockResult<MutexGuard<'_, ShmSpace>> {
+        self.mutex.try_lock()
+    }
+
+    /// Whether anything is waiting to lock the space. Only a hint, since it
+    /// can change straight afterwards.
+    pub fn is_contended(&self) -> bool {
+        self.waiters.load(Ordering::Relaxed) != 0
+    }
+}
 
 pub struct ShmSpace {
     id_pool: ReusableIdPoolManual,
     space: ShmSpaceMap,
     acquisitions: AcquisitionsAndPageTable,
-    stats: Sv39SpaceStats,
+    stats: SpaceStats,
+    mapping_generation: Arc<MappingGeneration>,
+    /// From the tab's `TabConfig`. Not part of snapshots, since the tab that a
+    /// snapshot is restored into has its own.
+    max_memory_bytes: Option<u64>,
 }
 
 impl ShmSpace {
-    pub fn new() -> Self {
+    pub fn new(paging_scheme: PagingScheme) -> Self {
         ShmSpace {
             id_pool: ReusableIdPoolManual::new(),
             space: HashMap::new(),
-            acquisitions: AcquisitionsAndPageTable::new(),
-            stats: [0; 3],
+            acquisitions: AcquisitionsAndPageTable::new(paging_scheme),
+            stats: SpaceStats::default(),
+            mapping_generation: Arc::new(MappingGeneration::default()),
+            max_memory_bytes: None,
         }
     }
 
-    pub fn new_shm_cap(&mut self, shm_type: ShmType, length: u64, cap_type: CapType) -> Result<(ShmCapId, &mut ShmCap), ShmSpaceError> {
-        let length = NonZeroU64::new(length).ok_or(InvalidLengthSnafu.build())?;
-        let length_u64 = length.get();
+    /// Caps that are already in the space stay, even if they add up to more
+    /// than `max_memory_bytes`, but no more can be made until enough have been
+    /// destroyed.
+    pub fn set_max_memory_bytes(&mut self, max_memory_bytes: Option<u64>) {
+        self.max_memory_bytes = max_memory_bytes;
+    }
 
-        if length_u64 > self.sv39_available_pages(shm_type).into() {
-            return CapacityNotAvailableSnafu.fail();
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            shm_type_bytes: self.paging_scheme().shm_types().iter()
+                .map(|&shm_type| (shm_type, self.stats.pages[shm_type.stats_index()] << shm_type.page_bits()))
+                .collect(),
+            app_cap_bytes: self.stats.cap_type_bytes[CapType::AppCap.stats_index()],
+            elf_cap_bytes: self.stats.cap_type_bytes[CapType::ElfCap.stats_index()],
+            max_memory_bytes: self.max_memory_bytes,
         }
+    }
 
-        // Since we have got past the sv39_available_pages check and it returns
-        // a u32, we now know length is < 2^32.
-        let sv39_length = length_u64 as u32;
+    pub fn paging_scheme(&self) -> PagingScheme {
+        self.acquisitions.paging_scheme()
+    }
 
-        let mmap_mut = MmapMut::map_anon(
-            shm_type.page_bytes()
-                .checked_mul(length_u64)
-                .ok_or(BackingCapacityNotAvailableOverflowsSnafu.build())?
-                .try_into()
-                .map_err(|_| BackingCapacityNotAvailableOverflowsSnafu.build())?
-        ).context(BackingCapacityNotAvailableSnafu)?;
+    pub fn mapping_generation(&self) -> u64 {
+        self.mapping_generation.get()
+    }
+
+    /// For reading the mapping generation without locking the space.
+    pub(crate) fn shared_mapping_generation(&self) -> Arc<MappingGeneration> {
+        Arc::clone(&self.mapping_generation)
+    }
+
+    pub fn new_shm_cap(&mut self, shm_type: ShmType, length: u64, cap_type: CapType) -> Result<(ShmCapId, &mut ShmCap), ShmSpaceError> {
+        let (length, mmap_mut) = self.new_backing(shm_type, length)?;
 
         let id = self.id_pool.try_allocate()
             .map_err(|rip_err| match rip_err { ReusableIdPoolError::TooManyLiveIDs => ExhaustedSnafu.build() })?;
@@ -153,15 +419,118 @@ impl ShmSpace {
             Entry::Vacant(vacant_entry) => vacant_entry.insert(ShmCap::new(shm_type, length, mmap_mut, cap_type)),
         };
 
-        Self::sv39_increment_stats(&mut self.stats, shm_type, sv39_length);
+        Self::increment_stats(&mut self.stats, shm_type, cap_type, length.get());
 
         Ok((id, shm_cap))
     }
 
+    /// Checks that there is capacity for a cap of `length` pages of
+    /// `shm_type`, and maps its backing.
+    fn new_backing(&self, shm_type: ShmType, length: u64) -> Result<(ShmCapLength, MmapMut), ShmSpaceError> {
+        ensure!(self.paging_scheme().supports(shm_type), UnsupportedShmTypeSnafu { shm_type, paging_scheme: self.paging_scheme() });
+        let length = NonZeroU64::new(length).ok_or(InvalidLengthSnafu.build())?;
+
+        if length.get() > self.available_pages(shm_type) {
+            return CapacityNotAvailableSnafu.fail();
+        }
+        self.check_max_memory_bytes(shm_type, length.get())?;
+
+        Ok((length, Self::map_backing(shm_type, length)?))
+    }
+
+    /// Checks that `length` more pages of `shm_type` keep the app within its
+    /// `max_memory_bytes`, if it has one.
+    fn check_max_memory_bytes(&self, shm_type: ShmType, length: u64) -> Result<(), ShmSpaceError> {
+        let Some(max_memory_bytes) = self.max_memory_bytes else {
+            return Ok(());
+        };
+
+        let requested_bytes = shm_type.page_bytes()
+            .checked_mul(length)
+            .ok_or(BackingCapacityNotAvailableOverflowsSnafu.build())?;
+        let used_bytes = self.stats.used_bytes();
+        ensure!(
+            used_bytes.checked_add(requested_bytes).is_some_and(|total_bytes| total_bytes <= max_memory_bytes),
+            MaxMemoryBytesExceededSnafu { requested_bytes, used_bytes, max_memory_bytes },
+        );
+        Ok(())
+    }
+
+    fn map_backing(shm_type: ShmType, length: ShmCapLength) -> Result<MmapMut, ShmSpaceError> {
+        MmapMut::map_anon(
+            shm_type.page_bytes()
+                .checked_mul(length.get())
+                .ok_or(BackingCapacityNotAvailableOverflowsSnafu.build())?
+                .try_into()
+                .map_err(|_| BackingCapacityNotAvailableOverflowsSnafu.build())?
+        ).context(BackingCapacityNotAvailableSnafu)
+    }
+
+    /// Copy every cap, including its backing, and where it is acquired.
+    ///
+    /// Caps that are moved out of the space while a deferred task is
+    /// processing them are not included, so the deferred spaces must be
+    /// checked for in-progress caps first.
+    pub(crate) fn snapshot(&self) -> ShmSpaceSnapshot {
+        let shm_caps = self.space.iter()
+            .map(|(&id, shm_cap)| ShmCapSnapshot {
+                id,
+                shm_type: shm_cap.shm_type(),
+                length: shm_cap.length_u64(),
+                cap_type: shm_cap.cap_type(),
+                backing: shm_cap.backing().to_vec(),
+                acquisition: self.acquisitions.acquisition(id).map(|(address, flags)| (address, flags.bits())),
+            })
+            .collect();
+
+        ShmSpaceSnapshot { paging_scheme: self.paging_scheme(), shm_caps }
+    }
+
+    /// The reverse of `snapshot`. The snapshot may come from anywhere, so
+    /// everything in it is checked the same way as if the app had made the
+    /// same caps and acquisitions itself.
+    pub(crate) fn restore(shm_space_snapshot: ShmSpaceSnapshot) -> Result<Self, SnapshotError> {
+        let mut shm_space = Self::new(shm_space_snapshot.paging_scheme);
+        let mut acquisitions = vec![];
+
+        for shm_cap_snapshot in shm_space_snapshot.shm_caps {
+            let ShmCapSnapshot { id, shm_type, length, cap_type, backing, acquisition } = shm_cap_snapshot;
+
+            let (length, mut mmap_mut) = shm_space.new_backing(shm_type, length)
+                .map_err(|shm_space_error| CorruptedSnafu { reason: format!("SHM cap {id}: {shm_space_error}") }.build())?;
+            ensure!(backing.len() == mmap_mut.len(), CorruptedSnafu { reason: format!("SHM cap {id} has {} bytes of backing but should have {}", backing.len(), mmap_mut.len()) });
+            mmap_mut.copy_from_slice(&backing);
+
+            match shm_space.space.entry(id) {
+                Entry::Occupied(_) => return CorruptedSnafu { reason: format!("SHM cap {id} is present more than once") }.fail(),
+                Entry::Vacant(vacant_entry) => vacant_entry.insert(ShmCap::new(shm_type, length, mmap_mut, cap_type)),
+            };
+            Self::increment_stats(&mut shm_space.stats, shm_type, cap_type, length.get());
+
+            if let Some((address, flags)) = acquisition {
+                acquisitions.push((id, cap_type, address, flags));
+            }
+        }
+
+        shm_space.id_pool = snapshot::restore_id_pool(shm_space.space.keys().copied())?;
+
+        for (id, cap_type, address, flags) in acquisitions {
+            let flags = Sv39Flags::from_bits(flags).context(CorruptedSnafu { reason: format!("SHM cap {id} has invalid flags {flags:#x}") })?;
+            shm_space.acquire_shm_cap_impl(id, cap_type, address, flags)
+                .map_err(|shm_space_error| CorruptedSnafu { reason: format!("SHM cap {id} could not be acquired at {address:#x}: {shm_space_error}") }.build())?;
+        }
+
+        Ok(shm_space)
+    }
+
     pub fn acquire_shm_cap_app(&mut self, shm_cap_id: ShmCapId, address: u64) -> Result<(), ShmSpaceError> {
         self.acquire_shm_cap_impl(shm_cap_id, CapType::AppCap, address, Sv39Flags::RW)
     }
 
+    pub fn acquire_shm_cap_app_with_flags(&mut self, shm_cap_id: ShmCapId, address: u64, shm_flags: ShmFlags) -> Result<(), ShmSpaceError> {
+        self.acquire_shm_cap_impl(shm_cap_id, CapType::AppCap, address, shm_flags.sv39_flags()?)
+    }
+
     pub fn acquire_shm_cap_elf(&mut self, shm_cap_id: ShmCapId, address: u64, flags: Sv39Flags) -> Result<(), ShmSpaceError> {
         self.acquire_shm_cap_impl(shm_cap_id, CapType::ElfCap, address, flags)
     }
@@ -176,11 +545,81 @@ impl ShmSpace {
         self.acquisitions.try_acquire(shm_cap_id, shm_cap, address, flags)
             .map_err(|acquire_error| match acquire_error {
                 AcquireError::AcquiringAlreadyAcquiredCap { address } => CurrentlyAcquiredCapSnafu { address }.build(),
-                AcquireError::AcquireExceedsSv39 => AddressOutOfBoundsSnafu.build(),
+                AcquireError::AcquireExceedsAddressSpace => AddressOutOfBoundsSnafu.build(),
                 AcquireError::AcquireAddressNotPageAligned => AddressNotAlignedSnafu.build(),
                 AcquireError::AcquireIntersectsExistingAcquisition => OverlapsExistingAcquisitionSnafu.build(),
                 _ => AcquireReleaseInternalSnafu.build(),
-            })
+            })?;
+        self.mapping_generation.bump();
+        Ok(())
+    }
+
+    /// Changes the permissions of an acquired app cap, e.g. to make code that
+    /// an app has just written executable.
+    pub fn protect_shm_cap_app(&mut self, shm_cap_id: ShmCapId, shm_flags: ShmFlags) -> Result<(), ShmSpaceError> {
+        let shm_cap = self.space.get(&shm_cap_id).ok_or_else(|| CapNotFoundSnafu.build())?;
+        if shm_cap.cap_type() != CapType::AppCap {
+            return PermissionDeniedSnafu.fail();
+        }
+        let flags = shm_flags.sv39_flags()?;
+
+        self.acquisitions.try_protect(shm_cap_id, shm_cap, flags)
+            .map_err(|acquire_error| match acquire_error {
+                AcquireError::ProtectingNonAcquiredCap => NotAcquiredCapSnafu.build(),
+                _ => AcquireReleaseInternalSnafu.build(),
+            })?;
+        self.mapping_generation.bump();
+        Ok(())
+    }
+
+    /// Changes an app cap to `new_length` pages of its type, keeping its
+    /// contents up to the smaller of the two lengths. New pages are zeroed.
+    ///
+    /// If the cap is acquired, it stays at the same address, so growing it
+    /// fails if the bigger range would overlap another acquisition or not fit
+    /// in the address space.
+    pub fn resize_shm_cap_app(&mut self, shm_cap_id: ShmCapId, new_length: u64) -> Result<(), ShmSpaceError> {
+        let shm_cap = self.space.get(&shm_cap_id).ok_or_else(|| CapNotFoundSnafu.build())?;
+        if shm_cap.cap_type() != CapType::AppCap {
+            return PermissionDeniedSnafu.fail();
+        }
+        let new_length = NonZeroU64::new(new_length).ok_or(InvalidLengthSnafu.build())?;
+        let (shm_type, length) = (shm_cap.shm_type(), shm_cap.length());
+        if new_length > length {
+            let grown_length = new_length.get() - length.get();
+            if grown_length > self.available_pages(shm_type) {
+                return CapacityNotAvailableSnafu.fail();
+            }
+            self.check_max_memory_bytes(shm_type, grown_length)?;
+        }
+
+        // Mapped before the page table changes, so that if this fails,
+        // nothing has changed.
+        //
+        // TODO: On Linux, mremap could grow or shrink the backing in place
+        // instead of copying it, but memmap2 doesn't have it in the version we
+        // use, and it would need unsafe code.
+        let mut new_mmap_mut = Self::map_backing(shm_type, new_length)?;
+
+        self.acquisitions.try_resize(shm_cap_id, shm_cap, new_length)
+            .map_err(|acquire_error| match acquire_error {
+                AcquireError::AcquireExceedsAddressSpace => AddressOutOfBoundsSnafu.build(),
+                AcquireError::AcquireIntersectsExistingAcquisition => OverlapsExistingAcquisitionSnafu.build(),
+                _ => AcquireReleaseInternalSnafu.build(),
+            })?;
+
+        let shm_cap = self.space.get_mut(&shm_cap_id).ok_or_else(|| AcquireReleaseInternalSnafu.build())?;
+        let kept_bytes = shm_cap.backing.len().min(new_mmap_mut.len());
+        new_mmap_mut[..kept_bytes].copy_from_slice(&shm_cap.backing[..kept_bytes]);
+        // Counted again at its new length.
+        Self::decrement_stats_transferred(&mut self.stats, shm_cap);
+        shm_cap.backing = new_mmap_mut;
+        shm_cap.length = new_length;
+        Self::increment_stats(&mut self.stats, shm_type, CapType::AppCap, new_length.get());
+
+        // Even if the cap isn't acquired, its backing has moved.
+        self.mapping_generation.bump();
+        Ok(())
     }
 
     /// Allows releasing non-acquired cap, returns a `None` as the success result if so.
@@ -201,7 +640,10 @@ impl ShmSpace {
         }
 
         match self.acquisitions.try_release(shm_cap_id, shm_cap) {
-            Ok(address) => Ok(Some(address)),
+            Ok(address) => {
+                self.mapping_generation.bump();
+                Ok(Some(address))
+            }
             Err(AcquireError::ReleasingNonAcquiredCap) => Ok(None), // Silently allow releasing non-acquired cap.
             Err(_) => AcquireReleaseInternalSnafu.fail(),
         }
@@ -217,35 +659,141 @@ impl ShmSpace {
 
         let shm_cap = self.space.remove(&shm_cap_id).ok_or_else(|| CapNotFoundSnafu.build())?; // This error should be impossible to get to because we checked contains_key above, but still use this error variant.
         self.id_pool.release(shm_cap_id);
-        Self::sv39_decrement_stats(&mut self.stats, shm_cap);
+        Self::decrement_stats(&mut self.stats, shm_cap);
         Ok(())
     }
 
-    /// Moves *without* decrementing the Sv39 stats. So it's still reserved and
+    /// Moves *without* decrementing the stats. So it's still reserved and
     /// can be moved back in.
     ///
     /// Precondition: Must be released. And probably must not be depended on by
     /// any other dependents.
     pub fn move_shm_cap_to_other_space(&mut self, shm_cap_id: ShmCapId) -> Option<ShmCap> {
+        self.mapping_generation.bump();
         self.space.remove(&shm_cap_id)
     }
 
-    /// Moves *without* incrementing the Sv39 stats. Because it wasn't
+    /// Moves *without* incrementing the stats. Because it wasn't
     /// decremented when it was moved out.
     pub fn move_shm_cap_back_into_space(&mut self, shm_cap_id: ShmCapId, shm_cap: ShmCap) {
+        self.mapping_generation.bump();
         self.space.insert(shm_cap_id, shm_cap);
     }
 
-    pub fn walk(&self, vaddr: u64) -> Result<WalkResult<'_>, PageTableError> {
-        self.acquisitions.walk(vaddr, &self.space)
+    /// Takes caps out of the space for good, so that they can be put into
+    /// another app's space with `transfer_shm_caps_in`. Either all of them are
+    /// taken, or none are.
+    ///
+    /// Only app caps that aren't acquired can be transferred, so that a cap is
+    /// never mapped into more than one app.
+    ///
+    /// Precondition: `shm_cap_ids` has no duplicates.
+    pub fn transfer_shm_caps_out(&mut self, shm_cap_ids: &[ShmCapId]) -> Result<Vec<ShmCap>, ShmSpaceError> {
+        for &shm_cap_id in shm_cap_ids {
+            let shm_cap = self.space.get(&shm_cap_id).ok_or_else(|| CapNotFoundSnafu.build())?;
+            if shm_cap.cap_type() != CapType::AppCap {
+                return PermissionDeniedSnafu.fail();
+            }
+            self.acquisitions.check_not_acquired(shm_cap_id).map_err(|address| TransferringCurrentlyAcquiredCapSnafu { address }.build())?;
+        }
+
+        let mut shm_caps = Vec::with_capacity(shm_cap_ids.len());
+        for &shm_cap_id in shm_cap_ids {
+            // Present, because of the checks above and the precondition.
+            let shm_cap = self.space.remove(&shm_cap_id).ok_or_else(|| AcquireReleaseInternalSnafu.build())?;
+            self.id_pool.release(shm_cap_id);
+            Self::decrement_stats_transferred(&mut self.stats, &shm_cap);
+            shm_caps.push(shm_cap);
+        }
+        self.mapping_generation.bump();
+
+        Ok(shm_caps)
+    }
+
+    /// Puts caps taken out of another app's space with `transfer_shm_caps_out`
+    /// into this one, released, and with new IDs. Either all of them are put
+    /// in, or none are, in which case they're handed back with the error.
+    pub fn transfer_shm_caps_in(&mut self, shm_caps: Vec<ShmCap>) -> Result<Vec<ShmCapId>, (ShmSpaceError, Vec<ShmCap>)> {
+        let mut shm_cap_ids = Vec::with_capacity(shm_caps.len());
+        let mut shm_caps = shm_caps.into_iter();
+
+        let (shm_space_error, failed_shm_cap) = loop {
+            let Some(shm_cap) = shm_caps.next() else {
+                self.mapping_generation.bump();
+                return Ok(shm_cap_ids);
+            };
+            match self.transfer_shm_cap_in(shm_cap) {
+                Ok(shm_cap_id) => shm_cap_ids.push(shm_cap_id),
+                Err(error_and_shm_cap) => break error_and_shm_cap,
+            }
+        };
+
+        // Roll back the ones that were put in.
+        let mut returned_shm_caps: Vec<ShmCap> = shm_cap_ids.into_iter()
+            .filter_map(|shm_cap_id| {
+                let shm_cap = self.space.remove(&shm_cap_id)?;
+                self.id_pool.release(shm_cap_id);
+                Self::decrement_stats_transferred(&mut self.stats, &shm_cap);
+                Some(shm_cap)
+            })
+            .collect();
+        returned_shm_caps.push(failed_shm_cap);
+        returned_shm_caps.extend(shm_caps);
+
+        Err((shm_space_error, returned_shm_caps))
+    }
+
+    fn transfer_shm_cap_in(&mut self, shm_cap: ShmCap) -> Result<ShmCapId, (ShmSpaceError, ShmCap)> {
+        let shm_type = shm_cap.shm_type();
+        if !self.paging_scheme().supports(shm_type) {
+            return Err((UnsupportedShmTypeSnafu { shm_type, paging_scheme: self.paging_scheme() }.build(), shm_cap));
+        }
+        if shm_cap.length_u64() > self.available_pages(shm_type) {
+            return Err((CapacityNotAvailableSnafu.build(), shm_cap));
+        }
+        if let Err(shm_space_error) = self.check_max_memory_bytes(shm_type, shm_cap.length_u64()) {
+            return Err((shm_space_error, shm_cap));
+        }
+        let (cap_type, stats_length) = (shm_cap.cap_type(), shm_cap.length_u64());
+
+        let id = match self.id_pool.try_allocate() {
+            Ok(id) => id,
+            Err(ReusableIdPoolError::TooManyLiveIDs) => return Err((ExhaustedSnafu.build(), shm_cap)),
+        };
+
+        match self.space.entry(id) {
+            Entry::Occupied(_) => Err((DuplicateIdSnafu.build(), shm_cap)),
+            Entry::Vacant(vacant_entry) => {
+                vacant_entry.insert(shm_cap);
+                Self::increment_stats(&mut self.stats, shm_type, cap_type, stats_length);
+                Ok(id)
+            }
+        }
+    }
+
+    pub fn translate(&self, vaddr: u64, required_permissions: Sv39Flags) -> Result<PageTranslation, PageTableError> {
+        self.acquisitions.translate(vaddr, &self.space, required_permissions)
     }
 
-    pub fn walk_mut(&mut self, vaddr: u64) -> Result<WalkResultMut<'_>, PageTableError> {
-        self.acquisitions.walk_mut(vaddr, &mut self.space)
+    /// The page that `page_translation` translated `vaddr` to. The translation
+    /// must be from the current `mapping_generation`.
+    pub fn translated_page(&self, page_translation: &PageTranslation, vaddr: u64) -> Result<WalkResult<'_>, PageTableError> {
+        let shm_cap = self.space.get(&page_translation.shm_cap_id)
+            .ok_or(PageTableError::PageEntryCorrupted { shm_cap_id: page_translation.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None })?;
+        Ok(WalkResult {
+            space_slice: &shm_cap.backing()[page_translation.byte_start..page_translation.byte_end],
+            byte_offset_in_space_slice: page_translation.byte_offset_in_page(vaddr),
+        })
     }
 
-    pub fn walk_execute(&self, vaddr: u64) -> Result<WalkResult<'_>, PageTableError> {
-        self.acquisitions.walk_execute(vaddr, &self.space)
+    /// The same as `translated_page`, but mutable.
+    pub fn translated_page_mut(&mut self, page_translation: &PageTranslation, vaddr: u64) -> Result<WalkResultMut<'_>, PageTableError> {
+        let shm_cap = self.space.get_mut(&page_translation.shm_cap_id)
+            .ok_or(PageTableError::PageEntryCorrupted { shm_cap_id: page_translation.shm_cap_id, mismatched_entry_found_at_level: None, shm_cap_offset: None, shm_cap_length: None })?;
+        Ok(WalkResultMut {
+            space_slice: &mut shm_cap.backing_mut()[page_translation.byte_start..page_translation.byte_end],
+            byte_offset_in_space_slice: page_translation.byte_offset_in_page(vaddr),
+        })
     }
 
     pub fn get_shm_cap_app(&self, shm_cap_id: ShmCapId) -> Result<&ShmCap, ShmSpaceError> {
@@ -257,6 +805,15 @@ impl ShmSpace {
         Ok(shm_cap)
     }
 
+    pub fn get_mut_shm_cap_app(&mut self, shm_cap_id: ShmCapId) -> Result<&mut ShmCap, ShmSpaceError> {
+        let shm_cap = self.space.get_mut(&shm_cap_id).ok_or_else(|| CapNotFoundSnafu.build())?;
+        if !matches!(shm_cap.cap_type(), CapType::AppCap) {
+            return PermissionDeniedSnafu.fail();
+        }
+
+        Ok(shm_cap)
+    }
+
     pub fn get_mut_shm_cap_elf(&mut self, shm_cap_id: ShmCapId) -> Result<&mut ShmCap, ShmSpaceError> {
         let shm_cap = self.space.get_mut(&shm_cap_id).ok_or_else(|| CapNotFoundSnafu.build())?;
         if !matches!(shm_cap.cap_type(), CapType::ElfCap) {
@@ -266,50 +823,51 @@ impl ShmSpace {
         Ok(shm_cap)
     }
 
-    /// This assumes that all pages can be arranged as: all 1 GiB ones first,
-    /// then all 2 MiB ones, then all 4 KiB ones. This is probably a very dumb
-    /// assumption and I might regret it later.
-    fn sv39_available_pages(&self, shm_type: ShmType) -> u32 {
-        match shm_type {
-            ShmType::FourKiB => {
-                let four_kib_equivalent_used = (self.stats[0] << 18) + (self.stats[1] << 9) + self.stats[2];
-                let four_kib_total: u32 = 1 << (SV39_BITS - 12);
-                four_kib_total - four_kib_equivalent_used
-            }
-            ShmType::TwoMiB => {
-                let two_mib_equivalent_used = (self.stats[0] << 9) + self.stats[1] + ((self.stats[2] >> 9) + (self.stats[2] & ((1 << 9) - 1) != 0) as u32);
-                let two_mib_total: u32 = 1 << (SV39_BITS - 21);
-                two_mib_total - two_mib_equivalent_used
-            }
-            ShmType::OneGiB => {
-                let four_kib_equivalent_used_excl_one_gib = (self.stats[1] << 9) + self.stats[2];
-                let one_gib_equivalent_used_by_non_one_gib = (four_kib_equivalent_used_excl_one_gib >> 18) + (four_kib_equivalent_used_excl_one_gib & ((1 << 18) - 1) != 0) as u32;
-                let one_gib_equivalent_used = self.stats[0] + one_gib_equivalent_used_by_non_one_gib;
-                let one_gib_total: u32 = 1 << (SV39_BITS - 30);
-                one_gib_total - one_gib_equivalent_used
+    /// This assumes that all pages can be arranged biggest first, e.g. in Sv39:
+    /// all 1 GiB ones first, then all 2 MiB ones, then all 4 KiB ones. So pages
+    /// smaller than `shm_type` use up whole `shm_type` pages between them,
+    /// rounded up. This is probably a very dumb assumption and I might regret
+    /// it later.
+    fn available_pages(&self, shm_type: ShmType) -> u64 {
+        let paging_scheme = self.paging_scheme();
+        if !paging_scheme.supports(shm_type) {
+            return 0;
+        }
+
+        let page_bits = shm_type.page_bits();
+        let (mut equivalent_used, mut smaller_bytes_used) = (0, 0);
+        for used_shm_type in paging_scheme.shm_types() {
+            let used = self.stats.pages[used_shm_type.stats_index()];
+            match used_shm_type.page_bits().checked_sub(page_bits) {
+                Some(bits_bigger) => equivalent_used += used << bits_bigger,
+                None => smaller_bytes_used += used << used_shm_type.page_bits(),
             }
         }
+        equivalent_used += smaller_bytes_used.div_ceil(shm_type.page_bytes());
+
+        let total: u64 = 1 << (paging_scheme.bits() - page_bits);
+        total - equivalent_used
     }
 
-    /// sv39_available_pages(...) MUST be checked before calling this, otherwise
+    /// available_pages(...) MUST be checked before calling this, otherwise
     /// this can cause an overflow.
-    fn sv39_increment_stats(stats: &mut Sv39SpaceStats, shm_type: ShmType, sv39_length: u32) {
-        match shm_type {
-            ShmType::FourKiB => stats[2] += sv39_length,
-            ShmType::TwoMiB => stats[1] += sv39_length,
-            ShmType::OneGiB => stats[0] += sv39_length,
-        }
+    fn increment_stats(stats: &mut SpaceStats, shm_type: ShmType, cap_type: CapType, stats_length: u64) {
+        stats.pages[shm_type.stats_index()] += stats_length;
+        stats.cap_type_bytes[cap_type.stats_index()] += stats_length << shm_type.page_bits();
     }
 
     /// The passed-in ShmCap must be one removed from a real space that we have
     /// bookkept correctly, otherwise this could underflow. To help achieve
     /// this, this accepts a ShmCap that is moved in, not borrowed.
-    fn sv39_decrement_stats(stats: &mut Sv39SpaceStats, shm_cap: ShmCap) {
-        match shm_cap.shm_type {
-            ShmType::FourKiB => stats[2] -= shm_cap.length.get() as u32,
-            ShmType::TwoMiB => stats[1] -= shm_cap.length.get() as u32,
-            ShmType::OneGiB => stats[0] -= shm_cap.length.get() as u32,
-        }
+    fn decrement_stats(stats: &mut SpaceStats, shm_cap: ShmCap) {
+        Self::decrement_stats_transferred(stats, &shm_cap);
+    }
+
+    /// The same as `decrement_stats`, for caps that are leaving the space but
+    /// live on in another one, so the cap is only borrowed.
+    fn decrement_stats_transferred(stats: &mut SpaceStats, shm_cap: &ShmCap) {
+        stats.pages[shm_cap.shm_type.stats_index()] -= shm_cap.length.get();
+        stats.cap_type_bytes[shm_cap.cap_type.stats_index()] -= shm_cap.length.get() << shm_cap.shm_type.page_bits();
     }
 }
 
@@ -319,10 +877,14 @@ pub enum ShmSpaceError {
     DuplicateId,
     #[snafu(display("The maximum amount of SHM capabilities have been used for this app. Please destroy some capabilities."))]
     Exhausted,
+    #[snafu(display("{shm_type:?} pages are not available in the {paging_scheme:?} scheme that this app uses."))]
+    UnsupportedShmType { shm_type: ShmType, paging_scheme: PagingScheme },
     #[snafu(display("The length provided was invalid, for example 0 is invalid."))]
     InvalidLength,
     #[snafu(display("There is not enough available capacity to support this length of this SHM type."))]
     CapacityNotAvailable,
+    #[snafu(display("This needs another {requested_bytes} bytes of SHM caps, but the tab already has {used_bytes} bytes of its maximum of {max_memory_bytes} bytes."))]
+    MaxMemoryBytesExceeded { requested_bytes: u64, used_bytes: u64, max_memory_bytes: u64 },
     #[snafu(display("There is not enough available backing capacity, currently using mmap, to support this length of this SHM type."))]
     BackingCapacityNotAvailable { source: io::Error },
     #[snafu(display("The requested capacity in bytes overflows either u64 or usize on this platform. Note that length in the system call arguments is number of this SHM type's pages, not number of bytes."))]
@@ -331,11 +893,19 @@ pub enum ShmSpaceError {
     CurrentlyAcquiredCap { address: u64 },
     #[snafu(display("The requested cap is currently acquired at address 0x{address:x} and thus cannot be destroyed. Please release it first."))]
     DestroyingCurrentlyAcquiredCap { address: u64 },
+    #[snafu(display("The requested cap is currently acquired at address 0x{address:x} and thus cannot be transferred to another app. Please release it first."))]
+    TransferringCurrentlyAcquiredCap { address: u64 },
+    #[snafu(display("The requested cap is not currently acquired, so its permissions cannot be changed. Please acquire it first."))]
+    NotAcquiredCap,
+    #[snafu(display("The requested cap cannot be both writable and executable."))]
+    WriteAndExecute,
+    #[snafu(display("The requested cap cannot be writable without also being readable."))]
+    WriteWithoutRead,
     #[snafu(display("A cap with the requested cap ID was not found."))]
     CapNotFound,
-    #[snafu(display("The requested acquisition address is not within Sv39 (39-bit virtual addressing) bounds."))]
+    #[snafu(display("The requested acquisition address is not within the bounds of the app's virtual addressing, e.g. 39 bits for Sv39."))]
     AddressOutOfBounds,
-    #[snafu(display("The requested acquisition address is not aligned at the SHM cap's type (e.g. 4 KiB-aligned, 2 MiB-aligned or 1 GiB-aligned)."))]
+    #[snafu(display("The requested acquisition address is not aligned at the SHM cap's type (e.g. 4 KiB-aligned, 2 MiB-aligned, 4 MiB-aligned or 1 GiB-aligned)."))]
     AddressNotAligned,
     #[snafu(display("The specified address combined with the length in the cap forms a range that overlaps an existing acquisition. Please choose a different address."))]
     OverlapsExistingAcquisition,
@@ -347,52 +917,408 @@ pub enum ShmSpaceError {
 
 #[cfg(test)]
 mod tests {
+    use super::translation_cache::TranslationCache;
+
     use super::*;
 
     #[test]
     fn shm_space_sv39_available_pages_none_used() {
-        let shm_space = ShmSpace::new();
+        let shm_space = ShmSpace::new(PagingScheme::Sv39);
 
-        assert_eq!(512, shm_space.sv39_available_pages(ShmType::OneGiB));
-        assert_eq!(1 << (SV39_BITS - 21), shm_space.sv39_available_pages(ShmType::TwoMiB));
-        assert_eq!(1 << (SV39_BITS - 12), shm_space.sv39_available_pages(ShmType::FourKiB));
+        assert_eq!(512, shm_space.available_pages(ShmType::OneGiB));
+        assert_eq!(1 << (SV39_BITS - 21), shm_space.available_pages(ShmType::TwoMiB));
+        assert_eq!(1 << (SV39_BITS - 12), shm_space.available_pages(ShmType::FourKiB));
     }
 
     #[test]
     fn shm_space_sv39_available_pages_one_gibs_used() {
-        let mut shm_space = ShmSpace::new();
-        shm_space.stats = [3, 0, 0];
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        shm_space.stats.pages = [3, 0, 0, 0, 0];
 
-        assert_eq!(509, shm_space.sv39_available_pages(ShmType::OneGiB));
-        assert_eq!((1 << (SV39_BITS - 21)) - (3 << 9), shm_space.sv39_available_pages(ShmType::TwoMiB));
-        assert_eq!((1 << (SV39_BITS - 12)) - (3 << 18), shm_space.sv39_available_pages(ShmType::FourKiB));
+        assert_eq!(509, shm_space.available_pages(ShmType::OneGiB));
+        assert_eq!((1 << (SV39_BITS - 21)) - (3 << 9), shm_space.available_pages(ShmType::TwoMiB));
+        assert_eq!((1 << (SV39_BITS - 12)) - (3 << 18), shm_space.available_pages(ShmType::FourKiB));
     }
 
     #[test]
     fn shm_space_sv39_available_pages_all_types_used() {
-        let mut shm_space = ShmSpace::new();
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
 
         // Make a layout where a 1 GiB slot isn't completely used by 2 MiB
         // pages, and then 4 KiB pages fill the remainder and go over to the
         // next space.
-        shm_space.stats = [3, 511, 513];
+        shm_space.stats.pages = [3, 511, 513, 0, 0];
 
-        assert_eq!(507, shm_space.sv39_available_pages(ShmType::OneGiB));
-        assert_eq!((1 << (SV39_BITS - 21)) - (4 << 9) - 1, shm_space.sv39_available_pages(ShmType::TwoMiB));
-        assert_eq!((1 << (SV39_BITS - 12)) - (4 << 18) - 1, shm_space.sv39_available_pages(ShmType::FourKiB));
+        assert_eq!(507, shm_space.available_pages(ShmType::OneGiB));
+        assert_eq!((1 << (SV39_BITS - 21)) - (4 << 9) - 1, shm_space.available_pages(ShmType::TwoMiB));
+        assert_eq!((1 << (SV39_BITS - 12)) - (4 << 18) - 1, shm_space.available_pages(ShmType::FourKiB));
 
         // Same, but 4 KiB pages fill exactly the remainder.
-        shm_space.stats = [3, 511, 512];
+        shm_space.stats.pages = [3, 511, 512, 0, 0];
 
-        assert_eq!(508, shm_space.sv39_available_pages(ShmType::OneGiB));
-        assert_eq!((1 << (SV39_BITS - 21)) - (4 << 9), shm_space.sv39_available_pages(ShmType::TwoMiB));
-        assert_eq!((1 << (SV39_BITS - 12)) - (4 << 18), shm_space.sv39_available_pages(ShmType::FourKiB));
+        assert_eq!(508, shm_space.available_pages(ShmType::OneGiB));
+        assert_eq!((1 << (SV39_BITS - 21)) - (4 << 9), shm_space.available_pages(ShmType::TwoMiB));
+        assert_eq!((1 << (SV39_BITS - 12)) - (4 << 18), shm_space.available_pages(ShmType::FourKiB));
 
         // Same, but 4 KiB pages fill almost the remainder except one.
-        shm_space.stats = [3, 511, 511];
+        shm_space.stats.pages = [3, 511, 511, 0, 0];
+
+        assert_eq!(508, shm_space.available_pages(ShmType::OneGiB));
+        assert_eq!((1 << (SV39_BITS - 21)) - (4 << 9), shm_space.available_pages(ShmType::TwoMiB));
+        assert_eq!((1 << (SV39_BITS - 12)) - (4 << 18) + 1, shm_space.available_pages(ShmType::FourKiB));
+    }
+
+    #[test]
+    fn shm_space_sv32_available_pages() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv32);
+
+        assert_eq!(1024, shm_space.available_pages(ShmType::FourMiB));
+        assert_eq!(1 << (SV32_BITS - 12), shm_space.available_pages(ShmType::FourKiB));
+
+        // 4 KiB pages go over into the next 4 MiB slot.
+        shm_space.stats.pages = [0, 3, 1025, 0, 0];
+
+        assert_eq!(1019, shm_space.available_pages(ShmType::FourMiB));
+        assert_eq!((1 << (SV32_BITS - 12)) - (3 << 10) - 1025, shm_space.available_pages(ShmType::FourKiB));
+    }
+
+    #[test]
+    fn shm_space_sv48_and_sv57_available_pages() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv48);
+
+        assert_eq!(512, shm_space.available_pages(ShmType::FiveTwelveGiB));
+        assert_eq!(1 << (SV48_BITS - 12), shm_space.available_pages(ShmType::FourKiB));
+        assert_eq!(0, shm_space.available_pages(ShmType::TwoFiftySixTiB));
+
+        // The 1 GiB page uses up a 512 GiB slot.
+        shm_space.stats.pages = [1, 0, 0, 2, 0];
+
+        assert_eq!(509, shm_space.available_pages(ShmType::FiveTwelveGiB));
+        assert_eq!((1 << (SV48_BITS - 30)) - (2 << 9) - 1, shm_space.available_pages(ShmType::OneGiB));
+
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv57);
+
+        assert_eq!(512, shm_space.available_pages(ShmType::TwoFiftySixTiB));
+        assert_eq!(1 << (SV57_BITS - 12), shm_space.available_pages(ShmType::FourKiB));
+
+        // The 4 KiB page uses up a 256 TiB slot.
+        shm_space.stats.pages = [0, 0, 1, 0, 1];
+
+        assert_eq!(510, shm_space.available_pages(ShmType::TwoFiftySixTiB));
+        assert_eq!((1 << (SV57_BITS - 39)) - (1 << 9) - 1, shm_space.available_pages(ShmType::FiveTwelveGiB));
+    }
+
+    #[test]
+    fn shm_space_new_shm_cap_rejects_shm_types_of_other_paging_scheme() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        assert!(matches!(
+            shm_space.new_shm_cap(ShmType::FourMiB, 1, CapType::AppCap),
+            Err(ShmSpaceError::UnsupportedShmType { shm_type: ShmType::FourMiB, paging_scheme: PagingScheme::Sv39 }),
+        ));
+
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv32);
+        assert!(matches!(
+            shm_space.new_shm_cap(ShmType::TwoMiB, 1, CapType::AppCap),
+            Err(ShmSpaceError::UnsupportedShmType { shm_type: ShmType::TwoMiB, paging_scheme: PagingScheme::Sv32 }),
+        ));
+    }
+
+    #[test]
+    fn shm_space_sv32_acquire_and_walk() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv32);
+        let (shm_cap_id, shm_cap) = shm_space.new_shm_cap(ShmType::FourMiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_cap.backing_mut()[0x1234] = 0xcd;
+
+        assert!(matches!(shm_space.acquire_shm_cap_app(shm_cap_id, 0xffc0_0000 + (1 << 22)), Err(ShmSpaceError::AddressOutOfBounds)));
+        shm_space.acquire_shm_cap_app(shm_cap_id, 0xffc0_0000).expect("Should succeed");
+
+        let walked = TranslationCache::new().walk(&shm_space, 0xffc0_1234).expect("Should succeed");
+        assert_eq!(0xcd, walked.space_slice[walked.byte_offset_in_space_slice]);
+    }
+
+    #[test]
+    fn shm_space_sv48_acquire_and_walk_above_sv39() {
+        let mut sv39_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, _) = sv39_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        assert!(matches!(sv39_space.acquire_shm_cap_app(shm_cap_id, 1 << 40), Err(ShmSpaceError::AddressOutOfBounds)));
+
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv48);
+        let (shm_cap_id, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_cap.backing_mut()[0x234] = 0xcd;
+
+        assert!(matches!(shm_space.acquire_shm_cap_app(shm_cap_id, 1 << 48), Err(ShmSpaceError::AddressOutOfBounds)));
+        shm_space.acquire_shm_cap_app(shm_cap_id, 1 << 40).expect("Should succeed");
+
+        let walked = TranslationCache::new().walk(&shm_space, (1 << 40) + 0x234).expect("Should succeed");
+        assert_eq!(0xcd, walked.space_slice[walked.byte_offset_in_space_slice]);
+        // The same Sv39 bits, but in a different 512 GiB region.
+        assert!(TranslationCache::new().walk(&shm_space, 0x234).is_err());
+    }
+
+    #[test]
+    fn shm_space_mutex_is_contended_while_something_waits() {
+        let shm_space_mutex = ShmSpaceMutex::new(ShmSpace::new(PagingScheme::Sv39));
+        let held = shm_space_mutex.lock().expect("Should succeed");
+        assert!(!shm_space_mutex.is_contended());
+
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| drop(shm_space_mutex.lock().expect("Should succeed")));
+            while !shm_space_mutex.is_contended() {
+                std::thread::yield_now();
+            }
+            drop(held);
+            waiting.join().expect("Should not panic");
+        });
+
+        assert!(!shm_space_mutex.is_contended());
+    }
+
+    #[test]
+    fn shm_space_mapping_generation_changes_on_acquire_and_release() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+
+        shm_space.acquire_shm_cap_app(shm_cap_id, 0x10000).expect("Should succeed");
+        assert_eq!(1, shm_space.mapping_generation());
+
+        assert!(shm_space.acquire_shm_cap_app(shm_cap_id, 0x20000).is_err());
+        assert_eq!(1, shm_space.mapping_generation());
+
+        shm_space.release_shm_cap_app(shm_cap_id).expect("Should succeed");
+        shm_space.release_shm_cap_app(shm_cap_id).expect("Should succeed");
+        assert_eq!(2, shm_space.mapping_generation());
+    }
+
+    #[test]
+    fn shm_space_acquire_with_flags_and_protect_enforce_w_xor_x() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        let mut translation_cache = TranslationCache::new();
+
+        assert!(matches!(shm_space.protect_shm_cap_app(shm_cap_id, ShmFlags::R), Err(ShmSpaceError::NotAcquiredCap)));
+        assert!(matches!(shm_space.acquire_shm_cap_app_with_flags(shm_cap_id, 0x10000, ShmFlags::all()), Err(ShmSpaceError::WriteAndExecute)));
+        assert!(matches!(shm_space.acquire_shm_cap_app_with_flags(shm_cap_id, 0x10000, ShmFlags::W), Err(ShmSpaceError::WriteWithoutRead)));
+
+        shm_space.acquire_shm_cap_app_with_flags(shm_cap_id, 0x10000, ShmFlags::R | ShmFlags::W).expect("Should succeed");
+        translation_cache.walk_mut(&mut shm_space, 0x10000).expect("Should succeed").space_slice[0] = 0x13;
+        assert!(translation_cache.walk_execute(&shm_space, 0x10000).is_err());
+
+        shm_space.protect_shm_cap_app(shm_cap_id, ShmFlags::R | ShmFlags::X).expect("Should succeed");
+        assert_eq!(0x13, translation_cache.walk_execute(&shm_space, 0x10000).expect("Should succeed").space_slice[0]);
+        assert!(translation_cache.walk_mut(&mut shm_space, 0x10000).is_err());
+
+        // A guard region.
+        shm_space.protect_shm_cap_app(shm_cap_id, ShmFlags::empty()).expect("Should succeed");
+        assert!(translation_cache.walk(&shm_space, 0x10000).is_err());
+        assert!(matches!(shm_space.acquisitions.acquisition(shm_cap_id), Some((0x10000, flags)) if flags.is_empty()));
+    }
+
+    #[test]
+    fn shm_space_protect_refuses_elf_and_stack_caps() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        // The hypervisor makes both the ELF segments' caps and the stack's cap
+        // as ELF caps.
+        let (code_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+        let (stack_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_elf(code_id, 0x10000, Sv39Flags::R | Sv39Flags::X).expect("Should succeed");
+        shm_space.acquire_shm_cap_elf(stack_id, 0x20000, Sv39Flags::RW).expect("Should succeed");
+        let mapping_generation = shm_space.mapping_generation();
+
+        assert!(matches!(shm_space.protect_shm_cap_app(code_id, ShmFlags::R | ShmFlags::W), Err(ShmSpaceError::PermissionDenied)));
+        assert!(matches!(shm_space.protect_shm_cap_app(stack_id, ShmFlags::R | ShmFlags::X), Err(ShmSpaceError::PermissionDenied)));
+
+        assert!(matches!(shm_space.acquisitions.acquisition(code_id), Some((0x10000, flags)) if flags == Sv39Flags::R | Sv39Flags::X));
+        assert!(matches!(shm_space.acquisitions.acquisition(stack_id), Some((0x20000, Sv39Flags::RW))));
+        assert_eq!(mapping_generation, shm_space.mapping_generation());
+    }
+
+    #[test]
+    fn shm_space_protect_refuses_unacquired_cap() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+
+        assert!(matches!(shm_space.protect_shm_cap_app(shm_cap_id, ShmFlags::R), Err(ShmSpaceError::NotAcquiredCap)));
+
+        // Once released, it's unacquired again.
+        shm_space.acquire_shm_cap_app(shm_cap_id, 0x10000).expect("Should succeed");
+        shm_space.release_shm_cap_app(shm_cap_id).expect("Should succeed");
+        assert!(matches!(shm_space.protect_shm_cap_app(shm_cap_id, ShmFlags::R | ShmFlags::X), Err(ShmSpaceError::NotAcquiredCap)));
+        assert!(shm_space.acquisitions.acquisition(shm_cap_id).is_none());
+    }
+
+    #[test]
+    fn shm_space_transferred_cap_is_still_w_xor_x() {
+        let mut sending_space = ShmSpace::new(PagingScheme::Sv39);
+        let (sent_id, _) = sending_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        sending_space.acquire_shm_cap_app_with_flags(sent_id, 0x10000, ShmFlags::R | ShmFlags::W).expect("Should succeed");
+        TranslationCache::new().walk_mut(&mut sending_space, 0x10000).expect("Should succeed").space_slice[0] = 0x13;
+        sending_space.release_shm_cap_app(sent_id).expect("Should succeed");
+        let shm_caps = sending_space.transfer_shm_caps_out(&[sent_id]).expect("Should succeed");
+
+        let mut receiving_space = ShmSpace::new(PagingScheme::Sv39);
+        let received_id = receiving_space.transfer_shm_caps_in(shm_caps).map_err(|(shm_space_error, _)| shm_space_error).expect("Should succeed")[0];
+        let mut translation_cache = TranslationCache::new();
+
+        assert!(matches!(receiving_space.acquire_shm_cap_app_with_flags(received_id, 0x30000, ShmFlags::all()), Err(ShmSpaceError::WriteAndExecute)));
+        assert!(matches!(receiving_space.acquire_shm_cap_app_with_flags(received_id, 0x30000, ShmFlags::W | ShmFlags::X), Err(ShmSpaceError::WriteAndExecute)));
+        assert!(receiving_space.acquisitions.acquisition(received_id).is_none());
+
+        receiving_space.acquire_shm_cap_app_with_flags(received_id, 0x30000, ShmFlags::R | ShmFlags::X).expect("Should succeed");
+        assert_eq!(0x13, translation_cache.walk_execute(&receiving_space, 0x30000).expect("Should succeed").space_slice[0]);
+        assert!(translation_cache.walk_mut(&mut receiving_space, 0x30000).is_err());
+        assert!(matches!(receiving_space.protect_shm_cap_app(received_id, ShmFlags::all()), Err(ShmSpaceError::WriteAndExecute)));
+    }
+
+    #[test]
+    fn shm_space_resize_keeps_contents_and_updates_stats() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 2, CapType::AppCap).expect("Should succeed");
+        shm_cap.backing_mut()[4097] = 0xab;
+        let (elf_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+
+        assert!(matches!(shm_space.resize_shm_cap_app(elf_id, 2), Err(ShmSpaceError::PermissionDenied)));
+        assert!(matches!(shm_space.resize_shm_cap_app(shm_cap_id, 0), Err(ShmSpaceError::InvalidLength)));
+        assert!(matches!(shm_space.resize_shm_cap_app(shm_cap_id, 1 << (SV39_BITS - 12)), Err(ShmSpaceError::CapacityNotAvailable)));
+
+        shm_space.resize_shm_cap_app(shm_cap_id, 5).expect("Should succeed");
+        let shm_cap = shm_space.get_shm_cap_app(shm_cap_id).expect("Should succeed");
+        assert_eq!(5, shm_cap.length_u64());
+        assert_eq!(5 * 4096, shm_cap.backing().len());
+        assert_eq!(0xab, shm_cap.backing()[4097]);
+        assert_eq!([0, 0, 6, 0, 0], shm_space.stats.pages);
+
+        shm_space.resize_shm_cap_app(shm_cap_id, 1).expect("Should succeed");
+        assert_eq!(4096, shm_space.get_shm_cap_app(shm_cap_id).expect("Should succeed").backing().len());
+        assert_eq!([0, 0, 2, 0, 0], shm_space.stats.pages);
+        // Everything except the ELF cap is available again.
+        assert_eq!((1 << (SV39_BITS - 12)) - 2, shm_space.available_pages(ShmType::FourKiB));
+    }
+
+    #[test]
+    fn shm_space_max_memory_bytes_limits_new_resized_and_transferred_caps() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        shm_space.set_max_memory_bytes(Some(3 * 4096));
+        let (elf_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+
+        assert!(matches!(
+            shm_space.new_shm_cap(ShmType::TwoMiB, 1, CapType::AppCap),
+            Err(ShmSpaceError::MaxMemoryBytesExceeded { requested_bytes: 0x20_0000, used_bytes: 0x2000, max_memory_bytes: 0x3000 }),
+        ));
+        assert!(matches!(shm_space.resize_shm_cap_app(shm_cap_id, 3), Err(ShmSpaceError::MaxMemoryBytesExceeded { .. })));
+        shm_space.resize_shm_cap_app(shm_cap_id, 2).expect("Should succeed");
+
+        let mut other_space = ShmSpace::new(PagingScheme::Sv39);
+        let (other_id, _) = other_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        let other_shm_caps = other_space.transfer_shm_caps_out(&[other_id]).expect("Should succeed");
+        assert!(matches!(shm_space.transfer_shm_caps_in(other_shm_caps), Err((ShmSpaceError::MaxMemoryBytesExceeded { .. }, _))));
+
+        assert_eq!(
+            MemoryUsage {
+                shm_type_bytes: vec![(ShmType::OneGiB, 0), (ShmType::TwoMiB, 0), (ShmType::FourKiB, 3 * 4096)],
+                app_cap_bytes: 2 * 4096,
+                elf_cap_bytes: 4096,
+                max_memory_bytes: Some(3 * 4096),
+            },
+            shm_space.memory_usage(),
+        );
+
+        shm_space.destroy_shm_cap(elf_id, CapType::ElfCap).expect("Should succeed");
+        assert_eq!(2 * 4096, shm_space.memory_usage().total_bytes());
+        shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+    }
+
+    #[test]
+    fn shm_space_resize_acquired_cap_maps_and_unmaps_at_the_end() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        let (other_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_app_with_flags(shm_cap_id, 0x10000, ShmFlags::R | ShmFlags::W).expect("Should succeed");
+        shm_space.acquire_shm_cap_app(other_id, 0x13000).expect("Should succeed");
+        let mut translation_cache = TranslationCache::new();
+        assert!(translation_cache.walk(&shm_space, 0x11000).is_err());
+
+        assert!(matches!(shm_space.resize_shm_cap_app(shm_cap_id, 4), Err(ShmSpaceError::OverlapsExistingAcquisition)));
+        shm_space.release_shm_cap_app(other_id).expect("Should succeed");
+        shm_space.acquire_shm_cap_app(other_id, (1 << SV39_BITS) - 0x1000).expect("Should succeed");
+        assert!(matches!(shm_space.resize_shm_cap_app(other_id, 2), Err(ShmSpaceError::AddressOutOfBounds)));
+        assert_eq!(1, shm_space.get_shm_cap_app(shm_cap_id).expect("Should succeed").length_u64());
+
+        shm_space.resize_shm_cap_app(shm_cap_id, 3).expect("Should succeed");
+        translation_cache.walk_mut(&mut shm_space, 0x12fff).expect("Should succeed").space_slice[0xfff] = 0xcd;
+        assert_eq!(0xcd, shm_space.get_shm_cap_app(shm_cap_id).expect("Should succeed").backing()[0x2fff]);
+        assert!(matches!(shm_space.acquisitions.acquisition(shm_cap_id), Some((0x10000, Sv39Flags::RW))));
+
+        shm_space.resize_shm_cap_app(shm_cap_id, 1).expect("Should succeed");
+        assert!(translation_cache.walk(&shm_space, 0x11000).is_err());
+        assert!(translation_cache.walk(&shm_space, 0x10000).is_ok());
+        // The space freed by shrinking can be acquired.
+        let (third_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 2, CapType::AppCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_app(third_id, 0x11000).expect("Should succeed");
+    }
+
+    #[test]
+    fn shm_space_snapshot_restore_round_trips_backing_and_acquisitions() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (acquired_id, shm_cap) = shm_space.new_shm_cap(ShmType::FourKiB, 2, CapType::AppCap).expect("Should succeed");
+        shm_cap.backing_mut()[4097] = 0xab;
+        shm_space.acquire_shm_cap_app(acquired_id, 0x10000).expect("Should succeed");
+        let (destroyed_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        let (elf_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::ElfCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_elf(elf_id, 0x20000, Sv39Flags::RX).expect("Should succeed");
+        shm_space.destroy_shm_cap(destroyed_id, CapType::AppCap).expect("Should succeed");
+
+        let mut restored = ShmSpace::restore(shm_space.snapshot()).expect("Should succeed");
+
+        assert_eq!(shm_space.stats, restored.stats);
+        let walk_result = TranslationCache::new().walk(&restored, 0x11001).expect("Should succeed");
+        assert_eq!(0xab, walk_result.space_slice[walk_result.byte_offset_in_space_slice]);
+        assert!(matches!(restored.acquisitions.acquisition(elf_id), Some((0x20000, Sv39Flags::RX))));
+        // The destroyed cap's ID is free again.
+        assert_eq!(destroyed_id, restored.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed").0);
+    }
+
+    #[test]
+    fn shm_space_transfer_shm_caps_moves_released_app_caps() {
+        let mut sv39_space = ShmSpace::new(PagingScheme::Sv39);
+        let (four_kib_id, shm_cap) = sv39_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_cap.backing_mut()[0] = 0xab;
+        let (two_mib_id, _) = sv39_space.new_shm_cap(ShmType::TwoMiB, 1, CapType::AppCap).expect("Should succeed");
+        sv39_space.acquire_shm_cap_app(two_mib_id, 0x20_0000).expect("Should succeed");
+
+        assert!(matches!(sv39_space.transfer_shm_caps_out(&[four_kib_id, two_mib_id]), Err(ShmSpaceError::TransferringCurrentlyAcquiredCap { address: 0x20_0000 })));
+        assert!(sv39_space.get_shm_cap_app(four_kib_id).is_ok());
+
+        sv39_space.release_shm_cap_app(two_mib_id).expect("Should succeed");
+        let shm_caps = sv39_space.transfer_shm_caps_out(&[four_kib_id, two_mib_id]).expect("Should succeed");
+        assert_eq!([0, 0, 0, 0, 0], sv39_space.stats.pages);
+
+        // Sv32 doesn't have 2 MiB pages, so neither cap goes in.
+        let mut sv32_space = ShmSpace::new(PagingScheme::Sv32);
+        let Err((ShmSpaceError::UnsupportedShmType { .. }, mut shm_caps)) = sv32_space.transfer_shm_caps_in(shm_caps) else {
+            panic!("Should fail");
+        };
+        assert_eq!([0, 0, 0, 0, 0], sv32_space.stats.pages);
+        assert_eq!(2, shm_caps.len());
+
+        shm_caps.truncate(1);
+        let shm_cap_ids = sv32_space.transfer_shm_caps_in(shm_caps).expect("Should succeed");
+        assert_eq!(0xab, sv32_space.get_shm_cap_app(shm_cap_ids[0]).expect("Should succeed").backing()[0]);
+        assert_eq!([0, 0, 1, 0, 0], sv32_space.stats.pages);
+    }
+
+    #[test]
+    fn shm_space_restore_rejects_overlapping_acquisitions() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_app(id, 0x10000).expect("Should succeed");
+        let mut shm_space_snapshot = shm_space.snapshot();
+
+        for shm_cap_snapshot in shm_space_snapshot.shm_caps.iter_mut() {
+            shm_cap_snapshot.acquisition = Some((0x10000, Sv39Flags::RW.bits()));
+        }
 
-        assert_eq!(508, shm_space.sv39_available_pages(ShmType::OneGiB));
-        assert_eq!((1 << (SV39_BITS - 21)) - (4 << 9), shm_space.sv39_available_pages(ShmType::TwoMiB));
-        assert_eq!((1 << (SV39_BITS - 12)) - (4 << 18) + 1, shm_space.sv39_available_pages(ShmType::FourKiB));
+        assert!(matches!(ShmSpace::restore(shm_space_snapshot), Err(SnapshotError::Corrupted { .. })));
     }
 }
@@ -0,0 +1,107 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A per-machine cache of page table walks, like a TLB.
+//!
+//! Entries are cap IDs and byte ranges rather than slices, so the cache can
+//! outlive the subsystem lock it was filled under. A hit is still checked
+//! against the page's permissions, and the whole cache is thrown away whenever
+//! the space's `mapping_generation` changes, which is on every acquire,
+//! release, and move of a cap into or out of the space (e.g. into a deferred
+//! task).
+
+use super::ShmSpace;
+use super::acquisitions_and_page_table::{PageTableError, PageTranslation, Sv39Flags, WalkResult, WalkResultMut};
+
+const ENTRIES: usize = 256;
+
+pub struct TranslationCache {
+    /// Direct-mapped on the 4 KiB virtual page number, whatever the size of the
+    /// page it's in. Each slot remembers which virtual page number it's for.
+    entries: Box<[Option<(u64, PageTranslation)>]>,
+    /// The `ShmSpace::mapping_generation` that `entries` are valid for.
+    mapping_generation: u64,
+}
+
+impl TranslationCache {
+    pub fn new() -> Self {
+        Self { entries: vec![None; ENTRIES].into_boxed_slice(), mapping_generation: 0 }
+    }
+
+    pub fn walk<'space>(&mut self, shm_space: &'space ShmSpace, vaddr: u64) -> Result<WalkResult<'space>, PageTableError> {
+        let page_translation = self.translate(shm_space, vaddr, Sv39Flags::R)?;
+        shm_space.translated_page(&page_translation, vaddr)
+    }
+
+    pub fn walk_mut<'space>(&mut self, shm_space: &'space mut ShmSpace, vaddr: u64) -> Result<WalkResultMut<'space>, PageTableError> {
+        let page_translation = self.translate(shm_space, vaddr, Sv39Flags::RW)?;
+        shm_space.translated_page_mut(&page_translation, vaddr)
+    }
+
+    pub fn walk_execute<'space>(&mut self, shm_space: &'space ShmSpace, vaddr: u64) -> Result<WalkResult<'space>, PageTableError> {
+        let page_translation = self.translate(shm_space, vaddr, Sv39Flags::X)?;
+        shm_space.translated_page(&page_translation, vaddr)
+    }
+
+    /// On a miss, or a hit without `required_permissions`, this walks the page
+    /// table, so that the error is the same as without the cache.
+    fn translate(&mut self, shm_space: &ShmSpace, vaddr: u64, required_permissions: Sv39Flags) -> Result<PageTranslation, PageTableError> {
+        if shm_space.mapping_generation() != self.mapping_generation {
+            self.entries.fill(None);
+            self.mapping_generation = shm_space.mapping_generation();
+        }
+
+        let vpn = vaddr >> 12;
+        let index = vpn as usize & (ENTRIES - 1);
+        if let Some((cached_vpn, page_translation)) = self.entries[index] {
+            if cached_vpn == vpn && page_translation.flags.contains(required_permissions) {
+                return Ok(page_translation);
+            }
+        }
+
+        let page_translation = shm_space.translate(vaddr, required_permissions)?;
+        self.entries[index] = Some((vpn, page_translation));
+        Ok(page_translation)
+    }
+}
+
+impl Default for TranslationCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shm_space::{CapType, PagingScheme, ShmType};
+
+    use super::*;
+
+    #[test]
+    fn walk_hits_within_superpage_and_misses_after_release() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, shm_cap) = shm_space.new_shm_cap(ShmType::TwoMiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_cap.backing_mut()[0x1_2345] = 0xab;
+        shm_space.acquire_shm_cap_app(shm_cap_id, 0x20_0000).expect("Should succeed");
+        let mut translation_cache = TranslationCache::new();
+
+        translation_cache.walk_mut(&mut shm_space, 0x20_0000).expect("Should succeed");
+        let walked = translation_cache.walk(&shm_space, 0x21_2345).expect("Should succeed");
+        assert_eq!(1 << 21, walked.space_slice.len());
+        assert_eq!(0xab, walked.space_slice[walked.byte_offset_in_space_slice]);
+
+        shm_space.release_shm_cap_app(shm_cap_id).expect("Should succeed");
+        assert!(matches!(translation_cache.walk(&shm_space, 0x21_2345), Err(PageTableError::PageNotFound)));
+    }
+
+    #[test]
+    fn walk_execute_checks_permissions_on_hit() {
+        let mut shm_space = ShmSpace::new(PagingScheme::Sv39);
+        let (shm_cap_id, _) = shm_space.new_shm_cap(ShmType::FourKiB, 1, CapType::AppCap).expect("Should succeed");
+        shm_space.acquire_shm_cap_app(shm_cap_id, 0x1000).expect("Should succeed");
+        let mut translation_cache = TranslationCache::new();
+
+        translation_cache.walk(&shm_space, 0x1000).expect("Should succeed");
+        assert!(matches!(translation_cache.walk_execute(&shm_space, 0x1000), Err(PageTableError::PermissionDenied { .. })));
+    }
+}
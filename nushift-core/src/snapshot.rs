@@ -0,0 +1,151 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots of an app's complete state, for saving a tab to a file and
+//! loading it into a new tab later.
+//!
+//! Each space knows how to snapshot and restore itself. This module puts the
+//! pieces together and handles the file format, which is a magic number and a
+//! version, followed by the Postcard-encoded `AppSnapshot`.
+
+use postcard::Error as PostcardError;
+use reusable_id_pool::ReusableIdPoolManual;
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+use crate::nushift_subsystem::NushiftSubsystemSnapshot;
+use crate::process_control_block::CpuSnapshot;
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"NUSHSNAP";
+
+/// Bump this whenever the shape of `AppSnapshot` (or anything in it) changes.
+/// There is no migration of old snapshots, they are just rejected.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    magic: [u8; 8],
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AppSnapshot {
+    pub(crate) cpu: CpuSnapshot,
+    pub(crate) subsystem: NushiftSubsystemSnapshot,
+}
+
+pub(crate) fn encode(app_snapshot: &AppSnapshot) -> Result<Vec<u8>, SnapshotError> {
+    let mut bytes = postcard::to_stdvec(&SnapshotHeader { magic: SNAPSHOT_MAGIC, version: SNAPSHOT_VERSION }).context(SerializeSnafu)?;
+    bytes.extend(postcard::to_stdvec(app_snapshot).context(SerializeSnafu)?);
+    Ok(bytes)
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<AppSnapshot, SnapshotError> {
+    let (header, rest): (SnapshotHeader, _) = postcard::take_from_bytes(bytes).map_err(|_| NotASnapshotSnafu.build())?;
+    ensure!(header.magic == SNAPSHOT_MAGIC, NotASnapshotSnafu);
+    ensure!(header.version == SNAPSHOT_VERSION, UnsupportedVersionSnafu { version: header.version });
+
+    postcard::from_bytes(rest).context(DeserializeSnafu)
+}
+
+/// Recreate an ID pool in which exactly `live_ids` are allocated.
+///
+/// The free list won't be in the same order as the original pool's, so the
+/// restored app may be handed different IDs than it would have been, but
+/// apps can't rely on the order anyway.
+pub(crate) fn restore_id_pool<I>(live_ids: I) -> Result<ReusableIdPoolManual, SnapshotError>
+where
+    I: IntoIterator<Item = u64>,
+{
+    let mut live_ids: Vec<u64> = live_ids.into_iter().collect();
+    live_ids.sort_unstable();
+    if let Some(duplicate_ids) = live_ids.windows(2).find(|ids| ids[0] == ids[1]) {
+        return CorruptedSnafu { reason: format!("ID {} is used more than once", duplicate_ids[0]) }.fail();
+    }
+
+    let mut id_pool = ReusableIdPoolManual::new();
+    let Some(&max_id) = live_ids.last() else {
+        return Ok(id_pool);
+    };
+    // Allocating is the only way to move the frontier forwards. Guard against
+    // a crafted snapshot making us allocate forever.
+    ensure!(max_id < MAX_RESTORED_ID, CorruptedSnafu { reason: format!("ID {max_id} is too large") });
+
+    for _ in 0..=max_id {
+        id_pool.allocate();
+    }
+    let mut live_ids = live_ids.into_iter().peekable();
+    for id in 0..=max_id {
+        if live_ids.next_if_eq(&id).is_none() {
+            id_pool.release(id);
+        }
+    }
+
+    Ok(id_pool)
+}
+
+/// IDs are handed out lowest-first, so a real app can't get anywhere near
+/// this without also running out of memory.
+const MAX_RESTORED_ID: u64 = 1 << 24;
+
+/// The context selectors are `pub(crate)` because each space checks its own
+/// part of the snapshot.
+#[derive(Snafu, SnafuCliDebug)]
+#[snafu(visibility(pub(crate)))]
+pub enum SnapshotError {
+    #[snafu(display("The tab was not found."))]
+    TabNotFound,
+    #[snafu(display("Nothing is running in the tab."))]
+    NotRunning,
+    #[snafu(display("The tab must be paused before it can be snapshotted."))]
+    NotPaused,
+    #[snafu(display("The app has deferred tasks that haven't been processed yet. Resume the tab briefly, pause it, and try again."))]
+    TasksInProgress,
+    #[snafu(display("The app has threads other than its main thread, which can't be snapshotted yet. Join them first."))]
+    MultipleThreads,
+    #[snafu(display("The app has channels open to other apps, which can't be snapshotted. Destroy them first."))]
+    OpenChannels,
+    #[snafu(display("Error serialising the snapshot: {source}"))]
+    SerializeError { source: PostcardError },
+    #[snafu(display("This is not a Nushift snapshot."))]
+    NotASnapshot,
+    #[snafu(display("Snapshots of version {version} are not supported, only version {SNAPSHOT_VERSION} is."))]
+    UnsupportedVersion { version: u32 },
+    #[snafu(display("Error deserialising the snapshot: {source}"))]
+    DeserializeError { source: PostcardError },
+    #[snafu(display("The snapshot is corrupted: {reason}"))]
+    Corrupted { reason: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_rejects_other_files() {
+        assert!(matches!(decode(b"\x7fELF not a snapshot"), Err(SnapshotError::NotASnapshot)));
+    }
+
+    #[test]
+    fn decode_rejects_other_versions() {
+        let bytes = postcard::to_stdvec(&SnapshotHeader { magic: SNAPSHOT_MAGIC, version: SNAPSHOT_VERSION + 1 }).expect("Should succeed");
+
+        assert!(matches!(decode(&bytes), Err(SnapshotError::UnsupportedVersion { version }) if version == SNAPSHOT_VERSION + 1));
+    }
+
+    #[test]
+    fn restore_id_pool_allocates_exactly_live_ids() {
+        let mut id_pool = restore_id_pool([3, 0, 5]).expect("Should succeed");
+
+        let mut next_ids: Vec<u64> = (0..4).map(|_| id_pool.allocate()).collect();
+        next_ids.sort_unstable();
+
+        assert_eq!(vec![1, 2, 4, 6], next_ids);
+    }
+
+    #[test]
+    fn restore_id_pool_rejects_duplicates() {
+        assert!(matches!(restore_id_pool([1, 1]), Err(SnapshotError::Corrupted { .. })));
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Opt-in strace-like tracing of the syscalls a tab's app makes, and of the
+//! deferred tasks they start finishing.
+
+use core::fmt::{self, Debug, Write as _};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallTraceEvent {
+    /// Recorded once the syscall has returned to the app.
+    Syscall {
+        number: u64,
+        /// `None` if the app asked for a syscall that doesn't exist.
+        syscall: Option<String>,
+        /// All four argument registers, whether or not the syscall uses them.
+        args: [u64; 4],
+        result: SyscallTraceResult,
+        /// The deferred task the syscall started, if any.
+        allocated_task_id: Option<u64>,
+    },
+    /// Recorded when the hypervisor has processed a deferred task, i.e. when a
+    /// `BlockOnDeferredTasks` on it would stop blocking.
+    DeferredTaskFinished {
+        task_id: u64,
+        task: String,
+        succeeded: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallTraceResult {
+    Success(u64),
+    /// The name of the `SyscallError`, or its number if it doesn't have one.
+    Error(String),
+    Exit(u64),
+}
+
+impl SyscallTraceEvent {
+    /// One line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        match self {
+            Self::Syscall { number, syscall, args, result, allocated_task_id } => {
+                let _ = write!(json, r#"{{"event":"syscall","number":{number},"syscall":{},"args":[{},{},{},{}],"result":"#,
+                    json_optional_string(syscall.as_deref()), args[0], args[1], args[2], args[3]);
+                let _ = match result {
+                    SyscallTraceResult::Success(return_value) => write!(json, r#"{{"success":{return_value}}}"#),
+                    SyscallTraceResult::Error(error) => write!(json, r#"{{"error":{}}}"#, json_string(error)),
+                    SyscallTraceResult::Exit(exit_reason) => write!(json, r#"{{"exit":{exit_reason}}}"#),
+                };
+                let _ = match allocated_task_id {
+                    Some(task_id) => write!(json, r#","allocated_task_id":{task_id}}}"#),
+                    None => write!(json, r#","allocated_task_id":null}}"#),
+                };
+            }
+            Self::DeferredTaskFinished { task_id, task, succeeded } => {
+                let _ = write!(json, r#"{{"event":"deferred_task_finished","task_id":{task_id},"task":{},"succeeded":{succeeded}}}"#, json_string(task));
+            }
+        }
+        json
+    }
+}
+
+fn json_optional_string(string: Option<&str>) -> String {
+    string.map_or_else(|| "null".to_string(), json_string)
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for ch in string.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            ch if ch.is_control() => { let _ = write!(json, "\\u{:04x}", ch as u32); }
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Where a tab's syscall trace goes. Set it in the tab's `TabConfig`.
+///
+/// The tracer is called on the tab's hypervisor thread, while the app is
+/// waiting on the syscall, so it should be quick.
+#[derive(Clone)]
+pub struct SyscallTracer(Arc<dyn Fn(&SyscallTraceEvent) + Send + Sync>);
+
+impl SyscallTracer {
+    pub fn callback<F>(callback: F) -> Self
+    where
+        F: Fn(&SyscallTraceEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+
+    /// Write each event to `writer` as a line of JSON.
+    pub fn json_lines<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let writer = Mutex::new(writer);
+        Self::callback(move |syscall_trace_event| {
+            let mut writer = writer.lock().unwrap();
+            if let Err(io_error) = writeln!(writer, "{}", syscall_trace_event.to_json()).and_then(|_| writer.flush()) {
+                tracing::debug!("Could not write syscall trace: {io_error}");
+            }
+        })
+    }
+
+    pub(crate) fn trace(&self, syscall_trace_event: SyscallTraceEvent) {
+        (self.0)(&syscall_trace_event);
+    }
+}
+
+impl Debug for SyscallTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SyscallTracer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syscall_to_json() {
+        let syscall_trace_event = SyscallTraceEvent::Syscall {
+            number: 11,
+            syscall: Some("TitlePublish".into()),
+            args: [1, 2, 3, 0],
+            result: SyscallTraceResult::Success(4),
+            allocated_task_id: Some(4),
+        };
+
+        assert_eq!(
+            r#"{"event":"syscall","number":11,"syscall":"TitlePublish","args":[1,2,3,0],"result":{"success":4},"allocated_task_id":4}"#,
+            syscall_trace_event.to_json(),
+        );
+    }
+
+    #[test]
+    fn unknown_syscall_error_to_json() {
+        let syscall_trace_event = SyscallTraceEvent::Syscall {
+            number: 999,
+            syscall: None,
+            args: [0; 4],
+            result: SyscallTraceResult::Error("UnknownSyscall".into()),
+            allocated_task_id: None,
+        };
+
+        assert_eq!(
+            r#"{"event":"syscall","number":999,"syscall":null,"args":[0,0,0,0],"result":{"error":"UnknownSyscall"},"allocated_task_id":null}"#,
+            syscall_trace_event.to_json(),
+        );
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(r#""a\"b\\c\n\u0001""#, json_string("a\"b\\c\n\u{1}"));
+    }
+}
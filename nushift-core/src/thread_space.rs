@@ -0,0 +1,212 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The app's threads, each of which runs on its own hart. All of an app's
+//! harts share its SHM space, so a thread is just a PC, a stack pointer and an
+//! argument to start with.
+//!
+//! The main thread is the one the app starts with, and it isn't in the space:
+//! it can't be joined, since the app stops when it does.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+
+use reusable_id_pool::{ReusableIdPoolManual, ReusableIdPoolError};
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+use crate::hypervisor::tab_control::TabControl;
+
+pub type ThreadId = u64;
+
+/// The thread that the app starts with.
+pub(crate) const MAIN_THREAD_ID: ThreadId = 0;
+
+/// Including the main thread. Each thread takes two OS threads, one running
+/// the hart and one handling its syscalls, so this can't be unlimited.
+const MAX_THREADS: usize = 64;
+
+/// Where a spawned thread starts. `argument` is passed in `a0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ThreadStart {
+    pub(crate) pc: u64,
+    pub(crate) stack_pointer: u64,
+    pub(crate) argument: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ThreadState {
+    /// Spawned, but its hart hasn't been started yet (see `start_threads`).
+    Spawned(ThreadStart),
+    /// Running or exited, but not joined yet. Exit values are kept in
+    /// `TabControl::thread_exits` until they're joined.
+    Started,
+    /// Another thread is blocked in `ThreadJoin` on this one.
+    Joining,
+}
+
+pub struct ThreadSpace {
+    id_pool: ReusableIdPoolManual,
+    threads: HashMap<ThreadId, ThreadState>,
+}
+
+impl ThreadSpace {
+    pub(crate) fn new() -> Self {
+        let mut id_pool = ReusableIdPoolManual::new();
+        // The first ID out of a new pool is 0, so this is MAIN_THREAD_ID, and
+        // it's never released.
+        let main_thread_id = id_pool.allocate();
+        debug_assert_eq!(MAIN_THREAD_ID, main_thread_id);
+
+        Self { id_pool, threads: HashMap::new() }
+    }
+
+    /// Whether the app has no threads other than the main thread, not even
+    /// exited ones that haven't been joined.
+    pub(crate) fn is_single_threaded(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Allocates the new thread's ID. Its hart is started by whoever handles
+    /// the syscall, with `start_threads`, before the app gets the ID back.
+    pub fn spawn_thread(&mut self, thread_start: ThreadStart) -> Result<ThreadId, ThreadSpaceError> {
+        ensure!(self.threads.len() + 1 < MAX_THREADS, ExhaustedSnafu);
+        let thread_id = self.id_pool.try_allocate()
+            .map_err(|rip_err| match rip_err { ReusableIdPoolError::TooManyLiveIDs => ExhaustedSnafu.build() })?;
+
+        match self.threads.entry(thread_id) {
+            Entry::Occupied(_) => DuplicateIdSnafu.fail(),
+            Entry::Vacant(vacant_entry) => {
+                vacant_entry.insert(ThreadState::Spawned(thread_start));
+                Ok(thread_id)
+            }
+        }
+    }
+
+    /// Marks all spawned threads as started, and returns them, so that their
+    /// harts can be started.
+    pub fn start_threads(&mut self) -> Vec<(ThreadId, ThreadStart)> {
+        let mut thread_starts = vec![];
+        for (&thread_id, thread_state) in self.threads.iter_mut() {
+            if let ThreadState::Spawned(thread_start) = *thread_state {
+                *thread_state = ThreadState::Started;
+                thread_starts.push((thread_id, thread_start));
+            }
+        }
+        thread_starts
+    }
+
+    /// Blocks `joining_thread_id` until `thread_id` exits, then returns its
+    /// exit value and frees its ID.
+    ///
+    /// Like `AppGlobalDeferredSpace::block_on_deferred_tasks`, the space is
+    /// only locked while checking `thread_id`, not while waiting, so that the
+    /// app's other threads can carry on making syscalls.
+    pub fn join_thread(space: &Mutex<Self>, joining_thread_id: ThreadId, thread_id: ThreadId, tab_control: &TabControl) -> Result<u64, ThreadSpaceError> {
+        ensure!(thread_id != joining_thread_id, JoiningSelfSnafu);
+        {
+            let mut space = space.lock().unwrap();
+            match space.threads.get_mut(&thread_id) {
+                None => return NotFoundSnafu { thread_id }.fail(),
+                Some(ThreadState::Joining) => return AlreadyBeingJoinedSnafu { thread_id }.fail(),
+                Some(thread_state) => *thread_state = ThreadState::Joining,
+            }
+        }
+
+        let exit_value = tab_control.wait_for_thread_exit(thread_id).context(CancelledSnafu)?;
+
+        let mut space = space.lock().unwrap();
+        space.threads.remove(&thread_id);
+        space.id_pool.release(thread_id);
+        Ok(exit_value)
+    }
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum ThreadSpaceError {
+    #[snafu(display("The app has the maximum number of threads"))]
+    Exhausted,
+    #[snafu(display("The new ID was already present in the space. This should never happen, and indicates a bug in Nushift's code."))]
+    DuplicateId,
+    #[snafu(display("The thread with ID {thread_id} does not exist, or has already been joined"))]
+    NotFound { thread_id: ThreadId },
+    #[snafu(display("A thread can't join itself"))]
+    JoiningSelf,
+    #[snafu(display("Another thread is already joining the thread with ID {thread_id}"))]
+    AlreadyBeingJoined { thread_id: ThreadId },
+    #[snafu(display("The app stopped while waiting for the thread to exit"))]
+    Cancelled,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THREAD_START: ThreadStart = ThreadStart { pc: 0x1000, stack_pointer: 0x2000, argument: 3 };
+
+    #[test]
+    fn spawn_thread_is_started_once() {
+        let mut space = ThreadSpace::new();
+
+        let thread_id = space.spawn_thread(THREAD_START).expect("Should succeed");
+
+        assert_ne!(MAIN_THREAD_ID, thread_id);
+        assert!(!space.is_single_threaded());
+        assert_eq!(vec![(thread_id, THREAD_START)], space.start_threads());
+        assert!(space.start_threads().is_empty());
+    }
+
+    #[test]
+    fn spawn_thread_is_limited() {
+        let mut space = ThreadSpace::new();
+
+        for _ in 1..MAX_THREADS {
+            space.spawn_thread(THREAD_START).expect("Should succeed");
+        }
+
+        assert!(matches!(space.spawn_thread(THREAD_START), Err(ThreadSpaceError::Exhausted)));
+    }
+
+    #[test]
+    fn join_thread_waits_for_exit_then_frees_id() {
+        let tab_control = TabControl::new();
+        let mut space = ThreadSpace::new();
+        let thread_id = space.spawn_thread(THREAD_START).expect("Should succeed");
+        space.start_threads();
+        let space = Mutex::new(space);
+
+        let result = std::thread::scope(|scope| {
+            let joining = scope.spawn(|| ThreadSpace::join_thread(&space, MAIN_THREAD_ID, thread_id, &tab_control));
+
+            while space.lock().unwrap().threads.get(&thread_id) != Some(&ThreadState::Joining) {
+                std::thread::yield_now();
+            }
+            assert!(matches!(
+                ThreadSpace::join_thread(&space, MAIN_THREAD_ID, thread_id, &tab_control),
+                Err(ThreadSpaceError::AlreadyBeingJoined { .. }),
+            ));
+            tab_control.thread_exited(thread_id, 42);
+
+            joining.join().expect("Should not panic")
+        });
+
+        assert!(matches!(result, Ok(42)));
+        assert!(space.lock().unwrap().is_single_threaded());
+        assert!(matches!(
+            ThreadSpace::join_thread(&space, MAIN_THREAD_ID, thread_id, &tab_control),
+            Err(ThreadSpaceError::NotFound { .. }),
+        ));
+    }
+
+    #[test]
+    fn join_thread_rejects_self() {
+        let tab_control = TabControl::new();
+        let space = Mutex::new(ThreadSpace::new());
+
+        assert!(matches!(
+            ThreadSpace::join_thread(&space, MAIN_THREAD_ID, MAIN_THREAD_ID, &tab_control),
+            Err(ThreadSpaceError::JoiningSelf),
+        ));
+    }
+}
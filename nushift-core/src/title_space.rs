@@ -5,10 +5,11 @@ use std::sync::Arc;
 
 use serde::Deserialize;
 
-use crate::deferred_space::{self, DeferredSpace, DefaultDeferredSpace, DeferredSpacePublish, DeferredError, DeferredSpaceError};
+use crate::deferred_space::{self, DeferredSpace, DefaultDeferredSpace, DefaultDeferredSpaceSnapshot, DeferredSpacePublish, DeferredError, DeferredSpaceError};
 use crate::hypervisor::hypervisor_event::{UnboundHypervisorEvent, HypervisorEventError};
 use crate::hypervisor::tab_context::TabContext;
-use crate::shm_space::{ShmCapId, ShmCap, ShmSpace};
+use crate::shm_space::{ShmCapId, ShmCap, ShmSpace, ShmSpaceMutex};
+use crate::snapshot::SnapshotError;
 
 pub type TitleCapId = u64;
 const TITLE_CONTEXT: &str = "title";
@@ -62,6 +63,19 @@ impl TitleSpace {
         }
     }
 
+    /// The title itself lives in the shell, which will get it again when the
+    /// restored app next publishes one.
+    pub(crate) fn snapshot(&self) -> Result<DefaultDeferredSpaceSnapshot, SnapshotError> {
+        self.deferred_space.snapshot()
+    }
+
+    pub(crate) fn restore(tab_context: Arc<dyn TabContext>, title_space_snapshot: DefaultDeferredSpaceSnapshot) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            deferred_space: DefaultDeferredSpace::restore(title_space_snapshot)?,
+            title_space_specific: TitleSpaceSpecific::new(tab_context),
+        })
+    }
+
     pub fn new_title_cap(&mut self) -> Result<TitleCapId, DeferredSpaceError> {
         self.deferred_space.new_cap(TITLE_CONTEXT)
     }
@@ -70,7 +84,7 @@ impl TitleSpace {
         self.deferred_space.publish_blocking(TITLE_CONTEXT, title_cap_id, input_shm_cap_id, output_shm_cap_id, shm_space)
     }
 
-    pub fn publish_title_deferred(&mut self, title_cap_id: TitleCapId, shm_space: &mut ShmSpace) -> Result<(), ()> {
+    pub fn publish_title_deferred(&mut self, title_cap_id: TitleCapId, shm_space: &ShmSpaceMutex) -> Result<(), ()> {
         self.deferred_space.publish_deferred(&mut self.title_space_specific, title_cap_id, shm_space)
     }
 
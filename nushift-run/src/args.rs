@@ -0,0 +1,277 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use nushift_core::GdbServerAddress;
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+pub const USAGE: &str = "\
+Usage: nushift-run [OPTIONS] <ELF>
+
+Runs a Nushift app without the shell, writing every presented frame to a
+numbered image file.
+
+Options:
+  --size <WIDTHxHEIGHT>   Size of the gfx output in px [default: 1280x720]
+  --scale <SCALE>         Scale of the gfx output [default: 1]
+  --frames <N>            Stop after N frames have been presented
+  --time <SECONDS>        Stop after this many seconds
+  --max-instructions <N>  Stop the app after it executes N instructions
+  --max-ips <N>           Throttle the app to N instructions per second
+  --max-memory <BYTES>    Limit the app's SHM caps, including its ELF and
+                          stack, to this many bytes in total
+  --gdb <ADDR>            Wait for a GDB debugger to connect at ADDR, which is
+                          a loopback socket address like 127.0.0.1:1234, or
+                          unix:<PATH>
+  --trace-syscalls <FILE> Write every syscall the app makes to FILE, as JSON lines
+  --stack-size <BYTES>    Size of the app's stack, unless its ELF asks for one
+                          [default: 1 MiB]
+  --url <URL>             URL to pass to the app as the one it was launched from
+  --locale <TAG>          Locale to pass to the app, e.g. en-AU
+  --out <DIR>             Directory to write frames into [default: .]
+  --format <png|ppm>      Image format of the frames [default: png]
+  -h, --help              Print this help";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Ppm => "ppm",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub elf_path: PathBuf,
+    pub size_px: (u64, u64),
+    pub scale: f64,
+    pub frame_limit: Option<u64>,
+    pub time_limit: Option<Duration>,
+    pub max_instructions: Option<u64>,
+    pub max_instructions_per_second: Option<u64>,
+    pub max_memory_bytes: Option<u64>,
+    pub gdb_server_address: Option<GdbServerAddress>,
+    pub syscall_trace_path: Option<PathBuf>,
+    pub stack_size: Option<u64>,
+    pub url: Option<String>,
+    pub locale: Option<String>,
+    pub out_dir: PathBuf,
+    pub image_format: ImageFormat,
+}
+
+pub enum ParsedArgs {
+    Run(Box<Args>),
+    Help,
+}
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<ParsedArgs, ArgsError> {
+    let mut args = args.into_iter();
+
+    let mut elf_path = None;
+    let mut size_px = (1280, 720);
+    let mut scale = 1.0;
+    let mut frame_limit = None;
+    let mut time_limit = None;
+    let mut max_instructions = None;
+    let mut max_instructions_per_second = None;
+    let mut max_memory_bytes = None;
+    let mut gdb_server_address = None;
+    let mut syscall_trace_path = None;
+    let mut stack_size = None;
+    let mut url = None;
+    let mut locale = None;
+    let mut out_dir = PathBuf::from(".");
+    let mut image_format = ImageFormat::Png;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(ParsedArgs::Help),
+            "--size" => size_px = parse_size(&value_for(&mut args, &arg)?)?,
+            "--scale" => {
+                let value = value_for(&mut args, &arg)?;
+                scale = value.parse().ok().filter(|scale: &f64| scale.is_finite() && *scale > 0.0).context(InvalidValueSnafu { option: arg, value })?;
+            }
+            "--frames" => {
+                let value = value_for(&mut args, &arg)?;
+                frame_limit = Some(value.parse::<u64>().ok().context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--time" => {
+                let value = value_for(&mut args, &arg)?;
+                let seconds = value.parse().ok().and_then(|seconds: f64| Duration::try_from_secs_f64(seconds).ok());
+                time_limit = Some(seconds.context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--max-instructions" => {
+                let value = value_for(&mut args, &arg)?;
+                max_instructions = Some(value.parse::<u64>().ok().context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--max-ips" => {
+                let value = value_for(&mut args, &arg)?;
+                max_instructions_per_second = Some(value.parse::<u64>().ok().filter(|&ips| ips > 0).context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--max-memory" => {
+                let value = value_for(&mut args, &arg)?;
+                max_memory_bytes = Some(value.parse::<u64>().ok().context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--gdb" => {
+                let value = value_for(&mut args, &arg)?;
+                gdb_server_address = Some(value.parse().ok().context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--trace-syscalls" => syscall_trace_path = Some(PathBuf::from(value_for(&mut args, &arg)?)),
+            "--stack-size" => {
+                let value = value_for(&mut args, &arg)?;
+                stack_size = Some(value.parse::<u64>().ok().filter(|&stack_size| stack_size > 0).context(InvalidValueSnafu { option: arg, value })?);
+            }
+            "--url" => url = Some(value_for(&mut args, &arg)?),
+            "--locale" => locale = Some(value_for(&mut args, &arg)?),
+            "--out" => out_dir = PathBuf::from(value_for(&mut args, &arg)?),
+            "--format" => {
+                let value = value_for(&mut args, &arg)?;
+                image_format = match value.as_str() {
+                    "png" => ImageFormat::Png,
+                    "ppm" => ImageFormat::Ppm,
+                    _ => return InvalidValueSnafu { option: arg, value }.fail(),
+                };
+            }
+            _ if arg.starts_with('-') => return UnknownOptionSnafu { option: arg }.fail(),
+            _ => {
+                ensure!(elf_path.is_none(), UnexpectedArgumentSnafu { argument: arg });
+                elf_path = Some(PathBuf::from(arg));
+            }
+        }
+    }
+
+    Ok(ParsedArgs::Run(Box::new(Args {
+        elf_path: elf_path.context(MissingElfPathSnafu)?,
+        size_px,
+        scale,
+        frame_limit,
+        time_limit,
+        max_instructions,
+        max_instructions_per_second,
+        max_memory_bytes,
+        gdb_server_address,
+        syscall_trace_path,
+        stack_size,
+        url,
+        locale,
+        out_dir,
+        image_format,
+    })))
+}
+
+fn value_for<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, ArgsError> {
+    args.next().context(MissingValueSnafu { option })
+}
+
+fn parse_size(value: &str) -> Result<(u64, u64), ArgsError> {
+    value.split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .filter(|&(width, height)| width > 0 && height > 0)
+        .context(InvalidValueSnafu { option: "--size", value })
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum ArgsError {
+    #[snafu(display("No ELF path was given"))]
+    MissingElfPath,
+    #[snafu(display("{option} requires a value"))]
+    MissingValue { option: String },
+    #[snafu(display("Invalid value for {option}: {value}"))]
+    InvalidValue { option: String, value: String },
+    #[snafu(display("Unknown option {option}"))]
+    UnknownOption { option: String },
+    #[snafu(display("Unexpected argument {argument}"))]
+    UnexpectedArgument { argument: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_strs(args: &[&str]) -> Result<ParsedArgs, ArgsError> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_uses_defaults() {
+        let Ok(ParsedArgs::Run(args)) = parse_strs(&["app.elf"]) else { panic!("Should be Run") };
+
+        assert_eq!(Args {
+            elf_path: PathBuf::from("app.elf"),
+            size_px: (1280, 720),
+            scale: 1.0,
+            frame_limit: None,
+            time_limit: None,
+            max_instructions: None,
+            max_instructions_per_second: None,
+            max_memory_bytes: None,
+            gdb_server_address: None,
+            syscall_trace_path: None,
+            stack_size: None,
+            url: None,
+            locale: None,
+            out_dir: PathBuf::from("."),
+            image_format: ImageFormat::Png,
+        }, *args);
+    }
+
+    #[test]
+    fn parse_reads_all_options() {
+        let Ok(ParsedArgs::Run(args)) = parse_strs(&["--size", "640x480", "--scale", "2", "--frames", "10", "--time", "1.5", "--max-instructions", "1000000", "--max-ips", "5000", "--max-memory", "16777216", "--gdb", "127.0.0.1:1234", "--trace-syscalls", "trace.jsonl", "--stack-size", "65536", "--url", "https://example.com/?a=1", "--locale", "en-AU", "--out", "frames", "--format", "ppm", "app.elf"]) else { panic!("Should be Run") };
+
+        assert_eq!(Args {
+            elf_path: PathBuf::from("app.elf"),
+            size_px: (640, 480),
+            scale: 2.0,
+            frame_limit: Some(10),
+            time_limit: Some(Duration::from_millis(1500)),
+            max_instructions: Some(1_000_000),
+            max_instructions_per_second: Some(5000),
+            max_memory_bytes: Some(16 << 20),
+            gdb_server_address: Some(GdbServerAddress::Tcp("127.0.0.1:1234".parse().unwrap())),
+            syscall_trace_path: Some(PathBuf::from("trace.jsonl")),
+            stack_size: Some(65536),
+            url: Some("https://example.com/?a=1".into()),
+            locale: Some("en-AU".into()),
+            out_dir: PathBuf::from("frames"),
+            image_format: ImageFormat::Ppm,
+        }, *args);
+    }
+
+    #[test]
+    fn parse_rejects_bad_size() {
+        assert!(matches!(parse_strs(&["--size", "640", "app.elf"]), Err(ArgsError::InvalidValue { .. })));
+        assert!(matches!(parse_strs(&["--size", "0x480", "app.elf"]), Err(ArgsError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn parse_rejects_bad_gdb_address() {
+        assert!(matches!(parse_strs(&["--gdb", "1234", "app.elf"]), Err(ArgsError::InvalidValue { .. })));
+        assert!(matches!(parse_strs(&["--gdb", "0.0.0.0:1234", "app.elf"]), Err(ArgsError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn parse_requires_elf_path() {
+        assert!(matches!(parse_strs(&["--frames", "1"]), Err(ArgsError::MissingElfPath)));
+    }
+
+    #[test]
+    fn parse_requires_option_value() {
+        assert!(matches!(parse_strs(&["app.elf", "--frames"]), Err(ArgsError::MissingValue { .. })));
+    }
+
+    #[test]
+    fn parse_returns_help() {
+        assert!(matches!(parse_strs(&["app.elf", "--help"]), Ok(ParsedArgs::Help)));
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use nushift_core::PresentBufferFormat;
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+use crate::args::ImageFormat;
+
+/// Writes presented frames to `frame-00000.png`, `frame-00001.png`, etc.
+pub struct FrameWriter {
+    out_dir: PathBuf,
+    image_format: ImageFormat,
+    frames_written: u64,
+}
+
+impl FrameWriter {
+    pub fn new(out_dir: PathBuf, image_format: ImageFormat) -> Self {
+        Self { out_dir, image_format, frames_written: 0 }
+    }
+
+    pub fn frames_written(&self) -> u64 {
+        self.frames_written
+    }
+
+    pub fn write_frame(&mut self, present_buffer_format: PresentBufferFormat, size_px: &[u64], buffer: &[u8]) -> Result<PathBuf, FrameWriterError> {
+        let &[width, height] = size_px else {
+            return UnsupportedDimensionsSnafu { dimensions: size_px.len() }.fail();
+        };
+        let (width, height) = match (u32::try_from(width), u32::try_from(height)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => return FrameTooLargeSnafu { width, height }.fail(),
+        };
+
+        let bytes_per_pixel = match present_buffer_format {
+            PresentBufferFormat::R8g8b8UintSrgb => 3,
+        };
+        let expected_len = u64::from(width) * u64::from(height) * bytes_per_pixel;
+        ensure!(expected_len == buffer.len() as u64, BufferLengthMismatchSnafu { expected_len, actual_len: buffer.len() });
+
+        let path = self.out_dir.join(format!("frame-{:05}.{}", self.frames_written, self.image_format.extension()));
+        let file = File::create(&path).context(CreateFileSnafu { path: &path })?;
+        let mut writer = BufWriter::new(file);
+
+        match self.image_format {
+            ImageFormat::Png => write_png(&mut writer, width, height, buffer).context(EncodePngSnafu { path: &path })?,
+            ImageFormat::Ppm => write_ppm(&mut writer, width, height, buffer).context(WriteFileSnafu { path: &path })?,
+        }
+        writer.flush().context(WriteFileSnafu { path: &path })?;
+
+        self.frames_written += 1;
+        Ok(path)
+    }
+}
+
+fn write_png<W: Write>(writer: W, width: u32, height: u32, buffer: &[u8]) -> Result<(), png::EncodingError> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+
+    let mut png_writer = encoder.write_header()?;
+    png_writer.write_image_data(buffer)?;
+    png_writer.finish()
+}
+
+/// Binary PPM (P6), which is exactly the R8G8B8 layout we already have.
+fn write_ppm<W: Write>(mut writer: W, width: u32, height: u32, buffer: &[u8]) -> io::Result<()> {
+    write!(writer, "P6\n{width} {height}\n255\n")?;
+    writer.write_all(buffer)
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+pub enum FrameWriterError {
+    #[snafu(display("Only 2D frames can be written, but the frame has {dimensions} dimensions"))]
+    UnsupportedDimensions { dimensions: usize },
+    #[snafu(display("The frame is too large to be written: {width}x{height}"))]
+    FrameTooLarge { width: u64, height: u64 },
+    #[snafu(display("Expected a buffer of {expected_len} bytes but got {actual_len}"))]
+    BufferLengthMismatch { expected_len: u64, actual_len: usize },
+    #[snafu(display("Could not create {}", path.display()))]
+    CreateFileError { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not write {}", path.display()))]
+    WriteFileError { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not encode {} as PNG", path.display()))]
+    EncodePngError { path: PathBuf, source: png::EncodingError },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_ppm_writes_header_and_pixels() {
+        let mut output = Vec::new();
+
+        write_ppm(&mut output, 2, 1, &[255, 0, 0, 0, 255, 0]).expect("Should succeed");
+
+        assert_eq!(b"P6\n2 1\n255\n\xff\x00\x00\x00\xff\x00".as_slice(), output.as_slice());
+    }
+
+    #[test]
+    fn write_frame_rejects_wrong_buffer_length() {
+        let mut frame_writer = FrameWriter::new(std::env::temp_dir(), ImageFormat::Ppm);
+
+        let result = frame_writer.write_frame(PresentBufferFormat::R8g8b8UintSrgb, &[2, 2], &[0; 3]);
+
+        assert!(matches!(result, Err(FrameWriterError::BufferLengthMismatch { expected_len: 12, actual_len: 3 })));
+        assert_eq!(0, frame_writer.frames_written());
+    }
+
+    #[test]
+    fn write_frame_rejects_non_2d_frames() {
+        let mut frame_writer = FrameWriter::new(std::env::temp_dir(), ImageFormat::Ppm);
+
+        let result = frame_writer.write_frame(PresentBufferFormat::R8g8b8UintSrgb, &[2], &[0; 6]);
+
+        assert!(matches!(result, Err(FrameWriterError::UnsupportedDimensions { dimensions: 1 })));
+    }
+}
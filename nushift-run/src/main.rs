@@ -0,0 +1,156 @@
+// Copyright 2023 The Nushift Authors.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs a Nushift app from the terminal, without the shell.
+
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use nushift_core::{ExitReason, GfxOutput, Hypervisor, HypervisorEvent, LaunchInfo, SyscallTracer, TabConfig, TabImageSource, TabLoadError, TabRunError};
+use snafu::prelude::*;
+use snafu_cli_debug::SnafuCliDebug;
+
+mod args;
+mod frame_writer;
+
+use self::args::{Args, ParsedArgs, USAGE};
+use self::frame_writer::FrameWriter;
+
+/// How often to check whether the app has finished or the time limit is up,
+/// when no events are arriving.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let args = match args::parse(std::env::args().skip(1)) {
+        Ok(ParsedArgs::Run(args)) => *args,
+        Ok(ParsedArgs::Help) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(args_error) => {
+            eprintln!("{args_error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args) {
+        Ok(RunOutcome::Exited(ExitReason::UserExit { exit_reason })) => {
+            eprintln!("App exited with exit reason {exit_reason}");
+            // Only the low 8 bits survive as a process exit status anyway, but
+            // don't let a nonzero exit reason like 256 look like success.
+            match exit_reason as u8 {
+                0 if exit_reason != 0 => ExitCode::FAILURE,
+                status => ExitCode::from(status),
+            }
+        }
+        Ok(RunOutcome::Exited(exit_reason)) => {
+            eprintln!("App stopped: {exit_reason:?}");
+            ExitCode::FAILURE
+        }
+        Ok(RunOutcome::LimitReached) => {
+            eprintln!("Limit reached, stopping app");
+            ExitCode::SUCCESS
+        }
+        Err(run_error) => {
+            eprintln!("{run_error:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum RunOutcome {
+    Exited(ExitReason),
+    LimitReached,
+}
+
+fn run(args: Args) -> Result<RunOutcome, RunError> {
+    let Args { elf_path, size_px, scale, frame_limit, time_limit, max_instructions, max_instructions_per_second, max_memory_bytes, gdb_server_address, syscall_trace_path, stack_size, url, locale, out_dir, image_format } = args;
+
+    let (mut hypervisor, events) = Hypervisor::new_headless();
+    let gfx_output = GfxOutput::new(0, vec![size_px.0, size_px.1], vec![scale, scale]);
+    let syscall_tracer = match syscall_trace_path {
+        Some(path) => Some(SyscallTracer::json_lines(File::create(&path).context(CreateSyscallTraceFileSnafu { path })?)),
+        None => None,
+    };
+    let launch_info = LaunchInfo { url, locale };
+    let tab_config = TabConfig { max_instructions, max_instructions_per_second, max_memory_bytes, gdb_server_address, syscall_tracer, stack_size, launch_info };
+    let tab_id = hypervisor.add_new_tab_with_config(gfx_output, TabImageSource::Path(elf_path), tab_config).context(LoadSnafu)?;
+
+    let deadline = time_limit.map(|time_limit| Instant::now() + time_limit);
+    let mut frame_writer = FrameWriter::new(out_dir, image_format);
+
+    let mut handle_event = |hypervisor_event: HypervisorEvent| {
+        match hypervisor_event {
+            HypervisorEvent::TitleChange(_, title) => println!("Title: {title}"),
+            HypervisorEvent::DebugPrint(_, message) => println!("{message}"),
+            HypervisorEvent::GfxCpuPresent(_, present_buffer_format, size_px, buffer) => {
+                match frame_writer.write_frame(present_buffer_format, &size_px, &buffer) {
+                    Ok(path) => eprintln!("Wrote {}", path.display()),
+                    Err(frame_writer_error) => eprintln!("Skipped frame: {frame_writer_error}"),
+                }
+            }
+            // How the app stopped is reported once it has been waited on.
+            HypervisorEvent::TabExited(..) => {}
+            HypervisorEvent::TabCrashed(_, _, Some(pc)) => eprintln!("App crashed at PC {pc:#x}"),
+            HypervisorEvent::TabCrashed(_, _, None) => {}
+        }
+
+        frame_limit.is_some_and(|frame_limit| frame_writer.frames_written() >= frame_limit)
+    };
+
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(RunOutcome::LimitReached);
+        }
+
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(hypervisor_event) => {
+                if handle_event(hypervisor_event) {
+                    return Ok(RunOutcome::LimitReached);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if hypervisor.is_tab_finished(&tab_id) != Some(false) {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // The app has finished, but it may have sent events just before it did.
+    for hypervisor_event in events.try_iter() {
+        if handle_event(hypervisor_event) {
+            break;
+        }
+    }
+
+    let run_result = hypervisor.wait_for_tab(&tab_id);
+    if let Some(cycles) = hypervisor.tab_cycles(&tab_id) {
+        eprintln!("App ran for {cycles} cycles");
+    }
+    if let Some(memory_usage) = hypervisor.tab_memory_usage(&tab_id) {
+        eprintln!("App had {} bytes of SHM caps when it stopped ({} made by the app, {} for its ELF and stack)", memory_usage.total_bytes(), memory_usage.app_cap_bytes, memory_usage.elf_cap_bytes);
+    }
+
+    match run_result {
+        Some(run_result) => run_result.map(RunOutcome::Exited).context(RunSnafu),
+        None => Ok(RunOutcome::Exited(ExitReason::NotExited)),
+    }
+}
+
+#[derive(Snafu, SnafuCliDebug)]
+enum RunError {
+    #[snafu(display("Could not create the syscall trace file {}", path.display()))]
+    CreateSyscallTraceFile { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not load the app"))]
+    Load { source: TabLoadError },
+    #[snafu(display("The app did not run to completion"))]
+    Run { source: TabRunError },
+}
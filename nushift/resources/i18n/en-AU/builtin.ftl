@@ -3,3 +3,4 @@
 nushift = Nushift
 nushift-no-tabs = No tabs
 nushift-new-tab = New tab
+nushift-crashed-tab = Crashed
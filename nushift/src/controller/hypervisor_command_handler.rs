@@ -7,11 +7,11 @@ use nushift_core::HypervisorEvent;
 use crate::selector::{HYPERVISOR_EVENT, InspectBeforeSingleUse};
 
 pub struct HypervisorCommandHandler<T> {
-    action: Box<dyn Fn(&InspectBeforeSingleUse<HypervisorEvent>, &mut T)>,
+    action: Box<dyn Fn(&InspectBeforeSingleUse<HypervisorEvent>, &mut T, &Env)>,
 }
 
 impl<T> HypervisorCommandHandler<T> {
-    pub(crate) fn new(action: impl Fn(&InspectBeforeSingleUse<HypervisorEvent>, &mut T) + 'static) -> Self {
+    pub(crate) fn new(action: impl Fn(&InspectBeforeSingleUse<HypervisorEvent>, &mut T, &Env) + 'static) -> Self {
         Self { action: Box::new(action) }
     }
 }
@@ -21,7 +21,7 @@ impl<T: Data, W: Widget<T>> Controller<T, W> for HypervisorCommandHandler<T> {
         match event {
             Event::Command(cmd) => {
                 if let Some(hypervisor_event) = cmd.get(HYPERVISOR_EVENT) {
-                    (self.action)(hypervisor_event, data);
+                    (self.action)(hypervisor_event, data, env);
                 }
             }
             _ => child.event(ctx, event, data, env),
@@ -4,7 +4,7 @@
 use druid::{
     text::ArcStr,
     widget::{Painter, Flex, MainAxisAlignment, Label},
-    Color, RenderContext, WidgetExt, MouseButton, Widget,
+    Color, RenderContext, WidgetExt, MouseButton, Widget, LocalizedString,
 };
 use nushift_core::HypervisorEvent;
 
@@ -54,7 +54,7 @@ pub fn tab() -> impl Widget<RootAndTabData> {
                 _ => {}
             }
         }))
-        .controller(HypervisorCommandHandler::new(|hypervisor_event, root_and_tab_data: &mut RootAndTabData| {
+        .controller(HypervisorCommandHandler::new(|hypervisor_event, root_and_tab_data: &mut RootAndTabData, env| {
             let tab_data = root_and_tab_data.tab_data_mut();
 
             // If tab ID matches, then take.
@@ -66,6 +66,17 @@ pub fn tab() -> impl Widget<RootAndTabData> {
                     Some(HypervisorEvent::GfxCpuPresent(_, present_buffer_format, size_px, framebuffer)) => {
                         tab_data.client_framebuffer = Some(ClientFramebuffer { present_buffer_format, size_px: size_px.into(), framebuffer });
                     }
+                    Some(HypervisorEvent::TabCrashed(_, error, pc)) => {
+                        tracing::error!("App crashed, PC {pc:#x?}: {error}");
+                        let mut crashed_title = LocalizedString::new("nushift-crashed-tab");
+                        crashed_title.resolve(root_and_tab_data, env);
+
+                        let tab_data = root_and_tab_data.tab_data_mut();
+                        tab_data.title = crashed_title.localized_str();
+                        // Don't leave the last frame up as if the app were
+                        // still running.
+                        tab_data.client_framebuffer = None;
+                    }
                     _ => {}
                 }
             }